@@ -0,0 +1,158 @@
+//! Task events, `api/events/task.proto`, and the ttrpc events service of containerd they are
+//! forwarded to, `api/services/ttrpc/events/v1/events.proto`.
+use super::{Any, Mount, Timestamp};
+
+/// Name of the service the events are forwarded to
+pub const EVENTS_SERVICE: &str = "containerd.services.events.ttrpc.v1.Events";
+
+/// A task event, published on its topic.
+pub trait TaskEvent: prost::Message + Sized {
+    const TOPIC: &'static str;
+    const TYPE_URL: &'static str;
+
+    fn to_any(&self) -> Any {
+        Any::new(Self::TYPE_URL, self)
+    }
+}
+
+macro_rules! task_event {
+    ($event:ident, $topic:literal) => {
+        impl TaskEvent for $event {
+            const TOPIC: &'static str = $topic;
+            const TYPE_URL: &'static str = concat!("containerd.events.", stringify!($event));
+        }
+    };
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TaskIo {
+    #[prost(string, tag = "1")]
+    pub stdin: String,
+    #[prost(string, tag = "2")]
+    pub stdout: String,
+    #[prost(string, tag = "3")]
+    pub stderr: String,
+    #[prost(bool, tag = "4")]
+    pub terminal: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TaskCreate {
+    #[prost(string, tag = "1")]
+    pub container_id: String,
+    #[prost(string, tag = "2")]
+    pub bundle: String,
+    #[prost(message, repeated, tag = "3")]
+    pub rootfs: Vec<Mount>,
+    #[prost(message, optional, tag = "4")]
+    pub io: Option<TaskIo>,
+    #[prost(string, tag = "5")]
+    pub checkpoint: String,
+    #[prost(uint32, tag = "6")]
+    pub pid: u32,
+}
+task_event!(TaskCreate, "/tasks/create");
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TaskStart {
+    #[prost(string, tag = "1")]
+    pub container_id: String,
+    #[prost(uint32, tag = "2")]
+    pub pid: u32,
+}
+task_event!(TaskStart, "/tasks/start");
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TaskDelete {
+    #[prost(string, tag = "1")]
+    pub container_id: String,
+    #[prost(uint32, tag = "2")]
+    pub pid: u32,
+    #[prost(uint32, tag = "3")]
+    pub exit_status: u32,
+    #[prost(message, optional, tag = "4")]
+    pub exited_at: Option<Timestamp>,
+    #[prost(string, tag = "5")]
+    pub id: String,
+}
+task_event!(TaskDelete, "/tasks/delete");
+
+/// The init process or an exec of the task exited
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TaskExit {
+    #[prost(string, tag = "1")]
+    pub container_id: String,
+    /// Id of the exec, or of the container for its init process
+    #[prost(string, tag = "2")]
+    pub id: String,
+    #[prost(uint32, tag = "3")]
+    pub pid: u32,
+    #[prost(uint32, tag = "4")]
+    pub exit_status: u32,
+    #[prost(message, optional, tag = "5")]
+    pub exited_at: Option<Timestamp>,
+}
+task_event!(TaskExit, "/tasks/exit");
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TaskOom {
+    #[prost(string, tag = "1")]
+    pub container_id: String,
+}
+
+impl TaskEvent for TaskOom {
+    const TOPIC: &'static str = "/tasks/oom";
+    const TYPE_URL: &'static str = "containerd.events.TaskOOM";
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TaskExecAdded {
+    #[prost(string, tag = "1")]
+    pub container_id: String,
+    #[prost(string, tag = "2")]
+    pub exec_id: String,
+}
+task_event!(TaskExecAdded, "/tasks/exec-added");
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TaskExecStarted {
+    #[prost(string, tag = "1")]
+    pub container_id: String,
+    #[prost(string, tag = "2")]
+    pub exec_id: String,
+    #[prost(uint32, tag = "3")]
+    pub pid: u32,
+}
+task_event!(TaskExecStarted, "/tasks/exec-started");
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TaskPaused {
+    #[prost(string, tag = "1")]
+    pub container_id: String,
+}
+task_event!(TaskPaused, "/tasks/paused");
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TaskResumed {
+    #[prost(string, tag = "1")]
+    pub container_id: String,
+}
+task_event!(TaskResumed, "/tasks/resumed");
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Envelope {
+    #[prost(message, optional, tag = "1")]
+    pub timestamp: Option<Timestamp>,
+    #[prost(string, tag = "2")]
+    pub namespace: String,
+    #[prost(string, tag = "3")]
+    pub topic: String,
+    #[prost(message, optional, tag = "4")]
+    pub event: Option<Any>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ForwardRequest {
+    #[prost(message, optional, tag = "1")]
+    pub envelope: Option<Envelope>,
+}
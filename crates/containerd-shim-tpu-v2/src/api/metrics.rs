@@ -0,0 +1,457 @@
+//! Statistics of a task, in the cgroup metrics types of containerd,
+//! `github.com/containerd/cgroups/cgroup1/stats` and `cgroup2/stats`.
+//!
+//! The statistics come from [`cgroup_stats`](libcontainer::container::events::cgroup_stats),
+//! with the cpu times in nanoseconds. cgroup v2 metrics carry them in microseconds.
+use libcgroups::stats::{BlkioDeviceStat, Stats};
+
+use super::Any;
+
+/// Type of the metrics of cgroup v1
+pub const V1_METRICS_TYPE: &str = "io.containerd.cgroups.v1.Metrics";
+/// Type of the metrics of cgroup v2
+pub const V2_METRICS_TYPE: &str = "io.containerd.cgroups.v2.Metrics";
+
+/// Returns the statistics in the metrics type of the cgroup version.
+pub fn to_any(stats: &Stats, cgroup_v1: bool) -> Any {
+    if cgroup_v1 {
+        Any::new(V1_METRICS_TYPE, &v1::Metrics::from(stats))
+    } else {
+        Any::new(V2_METRICS_TYPE, &v2::Metrics::from(stats))
+    }
+}
+
+pub mod v1 {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Metrics {
+        #[prost(message, repeated, tag = "1")]
+        pub hugetlb: Vec<HugetlbStat>,
+        #[prost(message, optional, tag = "2")]
+        pub pids: Option<PidsStat>,
+        #[prost(message, optional, tag = "3")]
+        pub cpu: Option<CpuStat>,
+        #[prost(message, optional, tag = "4")]
+        pub memory: Option<MemoryStat>,
+        #[prost(message, optional, tag = "5")]
+        pub blkio: Option<BlkIoStat>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct HugetlbStat {
+        #[prost(uint64, tag = "1")]
+        pub usage: u64,
+        #[prost(uint64, tag = "2")]
+        pub max: u64,
+        #[prost(uint64, tag = "3")]
+        pub failcnt: u64,
+        #[prost(string, tag = "4")]
+        pub pagesize: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PidsStat {
+        #[prost(uint64, tag = "1")]
+        pub current: u64,
+        #[prost(uint64, tag = "2")]
+        pub limit: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CpuStat {
+        #[prost(message, optional, tag = "1")]
+        pub usage: Option<CpuUsage>,
+        #[prost(message, optional, tag = "2")]
+        pub throttling: Option<Throttle>,
+    }
+
+    /// Cpu times in nanoseconds
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CpuUsage {
+        #[prost(uint64, tag = "1")]
+        pub total: u64,
+        #[prost(uint64, tag = "2")]
+        pub kernel: u64,
+        #[prost(uint64, tag = "3")]
+        pub user: u64,
+        #[prost(uint64, repeated, tag = "4")]
+        pub per_cpu: Vec<u64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Throttle {
+        #[prost(uint64, tag = "1")]
+        pub periods: u64,
+        #[prost(uint64, tag = "2")]
+        pub throttled_periods: u64,
+        #[prost(uint64, tag = "3")]
+        pub throttled_time: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct MemoryStat {
+        #[prost(uint64, tag = "1")]
+        pub cache: u64,
+        #[prost(message, optional, tag = "33")]
+        pub usage: Option<MemoryEntry>,
+        #[prost(message, optional, tag = "34")]
+        pub swap: Option<MemoryEntry>,
+        #[prost(message, optional, tag = "35")]
+        pub kernel: Option<MemoryEntry>,
+        #[prost(message, optional, tag = "36")]
+        pub kernel_tcp: Option<MemoryEntry>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct MemoryEntry {
+        #[prost(uint64, tag = "1")]
+        pub limit: u64,
+        #[prost(uint64, tag = "2")]
+        pub usage: u64,
+        #[prost(uint64, tag = "3")]
+        pub max: u64,
+        #[prost(uint64, tag = "4")]
+        pub failcnt: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct BlkIoStat {
+        #[prost(message, repeated, tag = "1")]
+        pub io_service_bytes_recursive: Vec<BlkIoEntry>,
+        #[prost(message, repeated, tag = "2")]
+        pub io_serviced_recursive: Vec<BlkIoEntry>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct BlkIoEntry {
+        #[prost(string, tag = "1")]
+        pub op: String,
+        #[prost(string, tag = "2")]
+        pub device: String,
+        #[prost(uint64, tag = "3")]
+        pub major: u64,
+        #[prost(uint64, tag = "4")]
+        pub minor: u64,
+        #[prost(uint64, tag = "5")]
+        pub value: u64,
+    }
+
+    fn memory_entry(data: &libcgroups::stats::MemoryData) -> Option<MemoryEntry> {
+        Some(MemoryEntry {
+            limit: data.limit,
+            usage: data.usage,
+            max: data.max_usage,
+            failcnt: data.fail_count,
+        })
+    }
+
+    fn blkio_entries(stats: &[BlkioDeviceStat]) -> Vec<BlkIoEntry> {
+        stats
+            .iter()
+            .map(|stat| BlkIoEntry {
+                op: stat.op_type.clone().unwrap_or_default(),
+                device: String::new(),
+                major: stat.major,
+                minor: stat.minor,
+                value: stat.value,
+            })
+            .collect()
+    }
+
+    impl From<&Stats> for Metrics {
+        fn from(stats: &Stats) -> Self {
+            let hugetlb = stats
+                .hugetlb
+                .iter()
+                .collect::<BTreeMap<_, _>>()
+                .into_iter()
+                .map(|(pagesize, stat)| HugetlbStat {
+                    usage: stat.usage,
+                    max: stat.max_usage,
+                    failcnt: stat.fail_count,
+                    pagesize: pagesize.clone(),
+                })
+                .collect();
+
+            let usage = &stats.cpu.usage;
+            let throttling = &stats.cpu.throttling;
+            let memory = &stats.memory;
+            Self {
+                hugetlb,
+                pids: Some(PidsStat {
+                    current: stats.pids.current,
+                    limit: stats.pids.limit,
+                }),
+                cpu: Some(CpuStat {
+                    usage: Some(CpuUsage {
+                        total: usage.usage_total,
+                        kernel: usage.usage_kernel,
+                        user: usage.usage_user,
+                        per_cpu: usage.per_core_usage_total.clone(),
+                    }),
+                    throttling: Some(Throttle {
+                        periods: throttling.periods,
+                        throttled_periods: throttling.throttled_periods,
+                        throttled_time: throttling.throttled_time,
+                    }),
+                }),
+                memory: Some(MemoryStat {
+                    cache: memory.cache,
+                    usage: memory_entry(&memory.memory),
+                    swap: memory_entry(&memory.memswap),
+                    kernel: memory_entry(&memory.kernel),
+                    kernel_tcp: memory_entry(&memory.kernel_tcp),
+                }),
+                blkio: Some(BlkIoStat {
+                    io_service_bytes_recursive: blkio_entries(&stats.blkio.service_bytes),
+                    io_serviced_recursive: blkio_entries(&stats.blkio.serviced),
+                }),
+            }
+        }
+    }
+}
+
+pub mod v2 {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Metrics {
+        #[prost(message, optional, tag = "1")]
+        pub pids: Option<PidsStat>,
+        #[prost(message, optional, tag = "2")]
+        pub cpu: Option<CpuStat>,
+        #[prost(message, optional, tag = "4")]
+        pub memory: Option<MemoryStat>,
+        #[prost(message, optional, tag = "6")]
+        pub io: Option<IoStat>,
+        #[prost(message, repeated, tag = "7")]
+        pub hugetlb: Vec<HugeTlbStat>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PidsStat {
+        #[prost(uint64, tag = "1")]
+        pub current: u64,
+        #[prost(uint64, tag = "2")]
+        pub limit: u64,
+    }
+
+    /// Cpu times in microseconds
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CpuStat {
+        #[prost(uint64, tag = "1")]
+        pub usage_usec: u64,
+        #[prost(uint64, tag = "2")]
+        pub user_usec: u64,
+        #[prost(uint64, tag = "3")]
+        pub system_usec: u64,
+        #[prost(uint64, tag = "4")]
+        pub nr_periods: u64,
+        #[prost(uint64, tag = "5")]
+        pub nr_throttled: u64,
+        #[prost(uint64, tag = "6")]
+        pub throttled_usec: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct MemoryStat {
+        #[prost(uint64, tag = "32")]
+        pub usage: u64,
+        #[prost(uint64, tag = "33")]
+        pub usage_limit: u64,
+        #[prost(uint64, tag = "34")]
+        pub swap_usage: u64,
+        #[prost(uint64, tag = "35")]
+        pub swap_limit: u64,
+        #[prost(uint64, tag = "36")]
+        pub max_usage: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct IoStat {
+        #[prost(message, repeated, tag = "1")]
+        pub usage: Vec<IoEntry>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct IoEntry {
+        #[prost(uint64, tag = "1")]
+        pub major: u64,
+        #[prost(uint64, tag = "2")]
+        pub minor: u64,
+        #[prost(uint64, tag = "3")]
+        pub rbytes: u64,
+        #[prost(uint64, tag = "4")]
+        pub wbytes: u64,
+        #[prost(uint64, tag = "5")]
+        pub rios: u64,
+        #[prost(uint64, tag = "6")]
+        pub wios: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct HugeTlbStat {
+        #[prost(uint64, tag = "1")]
+        pub current: u64,
+        #[prost(uint64, tag = "2")]
+        pub max: u64,
+        #[prost(string, tag = "3")]
+        pub pagesize: String,
+    }
+
+    /// Merges the per operation statistics of the devices into one entry per device.
+    fn io_entries(stats: &Stats) -> Vec<IoEntry> {
+        let mut devices: BTreeMap<(u64, u64), IoEntry> = BTreeMap::new();
+        let blkio = &stats.blkio;
+        for (stat, bytes) in blkio
+            .service_bytes
+            .iter()
+            .map(|stat| (stat, true))
+            .chain(blkio.serviced.iter().map(|stat| (stat, false)))
+        {
+            let entry = devices
+                .entry((stat.major, stat.minor))
+                .or_insert_with(|| IoEntry {
+                    major: stat.major,
+                    minor: stat.minor,
+                    ..Default::default()
+                });
+            let field = match (stat.op_type.as_deref(), bytes) {
+                (Some("read" | "Read"), true) => &mut entry.rbytes,
+                (Some("write" | "Write"), true) => &mut entry.wbytes,
+                (Some("read" | "Read"), false) => &mut entry.rios,
+                (Some("write" | "Write"), false) => &mut entry.wios,
+                _ => continue,
+            };
+            *field += stat.value;
+        }
+        devices.into_values().collect()
+    }
+
+    impl From<&Stats> for Metrics {
+        fn from(stats: &Stats) -> Self {
+            let usage = &stats.cpu.usage;
+            let throttling = &stats.cpu.throttling;
+            let memory = &stats.memory;
+            let hugetlb = stats
+                .hugetlb
+                .iter()
+                .collect::<BTreeMap<_, _>>()
+                .into_iter()
+                .map(|(pagesize, stat)| HugeTlbStat {
+                    current: stat.usage,
+                    max: stat.max_usage,
+                    pagesize: pagesize.clone(),
+                })
+                .collect();
+            Self {
+                pids: Some(PidsStat {
+                    current: stats.pids.current,
+                    limit: stats.pids.limit,
+                }),
+                cpu: Some(CpuStat {
+                    usage_usec: usage.usage_total / 1000,
+                    user_usec: usage.usage_user / 1000,
+                    system_usec: usage.usage_kernel / 1000,
+                    nr_periods: throttling.periods,
+                    nr_throttled: throttling.throttled_periods,
+                    throttled_usec: throttling.throttled_time / 1000,
+                }),
+                memory: Some(MemoryStat {
+                    usage: memory.memory.usage,
+                    usage_limit: memory.memory.limit,
+                    swap_usage: memory.memswap.usage,
+                    swap_limit: memory.memswap.limit,
+                    max_usage: memory.memory.max_usage,
+                }),
+                io: Some(IoStat {
+                    usage: io_entries(stats),
+                }),
+                hugetlb,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libcgroups::stats::HugeTlbStats;
+    use prost::Message;
+
+    use super::*;
+
+    fn device_stat(op: &str, value: u64) -> BlkioDeviceStat {
+        BlkioDeviceStat {
+            major: 8,
+            minor: 0,
+            op_type: Some(op.to_owned()),
+            value,
+        }
+    }
+
+    #[test]
+    fn test_v2_metrics() {
+        let mut stats = Stats::default();
+        stats.cpu.usage.usage_total = 3_000_000;
+        stats.cpu.usage.usage_user = 2_000_000;
+        stats.cpu.throttling.throttled_time = 5_000;
+        stats.memory.memory.usage = 4096;
+        stats.pids.current = 3;
+        stats.blkio.service_bytes = vec![device_stat("Read", 512), device_stat("Write", 1024)];
+        stats.blkio.serviced = vec![device_stat("Read", 1), device_stat("Write", 2)];
+        stats.hugetlb.insert(
+            "2MB".to_owned(),
+            HugeTlbStats {
+                usage: 2 << 20,
+                ..Default::default()
+            },
+        );
+
+        let any = to_any(&stats, false);
+        assert_eq!(any.type_url, V2_METRICS_TYPE);
+        let metrics = v2::Metrics::decode(any.value.as_slice()).unwrap();
+        let cpu = metrics.cpu.unwrap();
+        assert_eq!(cpu.usage_usec, 3000);
+        assert_eq!(cpu.user_usec, 2000);
+        assert_eq!(cpu.throttled_usec, 5);
+        assert_eq!(metrics.memory.unwrap().usage, 4096);
+        assert_eq!(metrics.pids.unwrap().current, 3);
+        assert_eq!(
+            metrics.io.unwrap().usage,
+            vec![v2::IoEntry {
+                major: 8,
+                minor: 0,
+                rbytes: 512,
+                wbytes: 1024,
+                rios: 1,
+                wios: 2,
+            }]
+        );
+        assert_eq!(metrics.hugetlb[0].pagesize, "2MB");
+        assert_eq!(metrics.hugetlb[0].current, 2 << 20);
+    }
+
+    #[test]
+    fn test_v1_metrics() {
+        let mut stats = Stats::default();
+        stats.cpu.usage.usage_total = 3_000_000;
+        stats.cpu.usage.per_core_usage_total = vec![1_000_000, 2_000_000];
+        stats.memory.cache = 128;
+        stats.memory.memory.limit = 1 << 30;
+
+        let any = to_any(&stats, true);
+        assert_eq!(any.type_url, V1_METRICS_TYPE);
+        let metrics = v1::Metrics::decode(any.value.as_slice()).unwrap();
+        let usage = metrics.cpu.unwrap().usage.unwrap();
+        assert_eq!(usage.total, 3_000_000);
+        assert_eq!(usage.per_cpu, vec![1_000_000, 2_000_000]);
+        let memory = metrics.memory.unwrap();
+        assert_eq!(memory.cache, 128);
+        assert_eq!(memory.usage.unwrap().limit, 1 << 30);
+    }
+}
@@ -0,0 +1,321 @@
+//! Messages of the containerd shim v2 Task service, `containerd.task.v2.Task`.
+//!
+//! The messages mirror `api/runtime/task/v2/shim.proto` of containerd and the types it
+//! imports field for field, only the tags matter on the wire. Fields the shim does not use
+//! are left out, prost skips them.
+pub mod events;
+pub mod metrics;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the service containerd calls
+pub const TASK_SERVICE: &str = "containerd.task.v2.Task";
+
+/// `google.protobuf.Any`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Any {
+    #[prost(string, tag = "1")]
+    pub type_url: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: Vec<u8>,
+}
+
+impl Any {
+    pub fn new<M: prost::Message>(type_url: &str, message: &M) -> Self {
+        Self {
+            type_url: type_url.to_owned(),
+            value: message.encode_to_vec(),
+        }
+    }
+}
+
+/// `google.protobuf.Timestamp`
+#[derive(Clone, Copy, PartialEq, Eq, prost::Message)]
+pub struct Timestamp {
+    #[prost(int64, tag = "1")]
+    pub seconds: i64,
+    #[prost(int32, tag = "2")]
+    pub nanos: i32,
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Self {
+            seconds: since_epoch.as_secs() as i64,
+            nanos: since_epoch.subsec_nanos() as i32,
+        }
+    }
+}
+
+/// `google.protobuf.Empty`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Empty {}
+
+/// `containerd.types.Mount`, a mount making up the root file system
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Mount {
+    #[prost(string, tag = "1")]
+    pub r#type: String,
+    #[prost(string, tag = "2")]
+    pub source: String,
+    #[prost(string, tag = "3")]
+    pub target: String,
+    #[prost(string, repeated, tag = "4")]
+    pub options: Vec<String>,
+}
+
+/// `containerd.v1.types.Status`, the status of a task or an exec
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum TaskStatus {
+    Unknown = 0,
+    Created = 1,
+    Running = 2,
+    Stopped = 3,
+    Paused = 5,
+    Pausing = 6,
+}
+
+/// `containerd.runc.v1.Options`, the runtime options of a task
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RuncOptions {
+    /// Root directory of the container states
+    #[prost(string, tag = "7")]
+    pub root: String,
+    #[prost(bool, tag = "9")]
+    pub systemd_cgroup: bool,
+}
+
+/// Type of [`RuncOptions`]
+pub const RUNC_OPTIONS_TYPE: &str = "containerd.runc.v1.Options";
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CreateTaskRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub bundle: String,
+    #[prost(message, repeated, tag = "3")]
+    pub rootfs: Vec<Mount>,
+    #[prost(bool, tag = "4")]
+    pub terminal: bool,
+    #[prost(string, tag = "5")]
+    pub stdin: String,
+    #[prost(string, tag = "6")]
+    pub stdout: String,
+    #[prost(string, tag = "7")]
+    pub stderr: String,
+    #[prost(string, tag = "8")]
+    pub checkpoint: String,
+    #[prost(string, tag = "9")]
+    pub parent_checkpoint: String,
+    #[prost(message, optional, tag = "10")]
+    pub options: Option<Any>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CreateTaskResponse {
+    #[prost(uint32, tag = "1")]
+    pub pid: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StateRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub exec_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StateResponse {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub bundle: String,
+    #[prost(uint32, tag = "3")]
+    pub pid: u32,
+    #[prost(enumeration = "TaskStatus", tag = "4")]
+    pub status: i32,
+    #[prost(string, tag = "5")]
+    pub stdin: String,
+    #[prost(string, tag = "6")]
+    pub stdout: String,
+    #[prost(string, tag = "7")]
+    pub stderr: String,
+    #[prost(bool, tag = "8")]
+    pub terminal: bool,
+    #[prost(uint32, tag = "9")]
+    pub exit_status: u32,
+    #[prost(message, optional, tag = "10")]
+    pub exited_at: Option<Timestamp>,
+    #[prost(string, tag = "11")]
+    pub exec_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StartRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub exec_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StartResponse {
+    #[prost(uint32, tag = "1")]
+    pub pid: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeleteRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub exec_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeleteResponse {
+    #[prost(uint32, tag = "1")]
+    pub pid: u32,
+    #[prost(uint32, tag = "2")]
+    pub exit_status: u32,
+    #[prost(message, optional, tag = "3")]
+    pub exited_at: Option<Timestamp>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ExecProcessRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub exec_id: String,
+    #[prost(bool, tag = "3")]
+    pub terminal: bool,
+    #[prost(string, tag = "4")]
+    pub stdin: String,
+    #[prost(string, tag = "5")]
+    pub stdout: String,
+    #[prost(string, tag = "6")]
+    pub stderr: String,
+    /// The OCI process of the exec, as JSON
+    #[prost(message, optional, tag = "7")]
+    pub spec: Option<Any>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct KillRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub exec_id: String,
+    #[prost(uint32, tag = "3")]
+    pub signal: u32,
+    #[prost(bool, tag = "4")]
+    pub all: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CloseIoRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub exec_id: String,
+    #[prost(bool, tag = "3")]
+    pub stdin: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PidsRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ProcessInfo {
+    #[prost(uint32, tag = "1")]
+    pub pid: u32,
+    #[prost(message, optional, tag = "2")]
+    pub info: Option<Any>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PidsResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub processes: Vec<ProcessInfo>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PauseRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ResumeRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UpdateTaskRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    /// The OCI Linux resources, as JSON
+    #[prost(message, optional, tag = "2")]
+    pub resources: Option<Any>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WaitRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub exec_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WaitResponse {
+    #[prost(uint32, tag = "1")]
+    pub exit_status: u32,
+    #[prost(message, optional, tag = "2")]
+    pub exited_at: Option<Timestamp>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StatsRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StatsResponse {
+    #[prost(message, optional, tag = "1")]
+    pub stats: Option<Any>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConnectRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConnectResponse {
+    #[prost(uint32, tag = "1")]
+    pub shim_pid: u32,
+    #[prost(uint32, tag = "2")]
+    pub task_pid: u32,
+    #[prost(string, tag = "3")]
+    pub version: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ShutdownRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(bool, tag = "2")]
+    pub now: bool,
+}
@@ -0,0 +1,125 @@
+//! Publishing of the task events to containerd.
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::api::events::{Envelope, ForwardRequest, TaskEvent, EVENTS_SERVICE};
+use crate::api::{Any, Empty};
+use crate::ttrpc::{Client, TtrpcError};
+
+/// Environment variable containerd passes the address of its ttrpc socket in
+pub const TTRPC_ADDRESS_ENV: &str = "TTRPC_ADDRESS";
+
+/// Delivers the task events.
+pub trait Publisher: Send + Sync + 'static {
+    fn publish(&self, topic: &str, event: Any) -> Result<(), TtrpcError>;
+}
+
+/// Publishes an event on its topic. A failure is logged, the task does not fail because
+/// containerd missed one of its events.
+pub fn publish<E: TaskEvent>(publisher: &dyn Publisher, event: &E) {
+    if let Err(err) = publisher.publish(E::TOPIC, event.to_any()) {
+        log::warn!("failed to publish {}: {err}", E::TOPIC);
+    }
+}
+
+/// Forwards the events to the ttrpc events service of containerd.
+pub struct RemotePublisher {
+    address: PathBuf,
+    namespace: String,
+    client: Mutex<Option<Client>>,
+}
+
+impl RemotePublisher {
+    pub fn new<P: Into<PathBuf>, N: Into<String>>(address: P, namespace: N) -> Self {
+        Self {
+            address: address.into(),
+            namespace: namespace.into(),
+            client: Mutex::new(None),
+        }
+    }
+}
+
+impl Publisher for RemotePublisher {
+    fn publish(&self, topic: &str, event: Any) -> Result<(), TtrpcError> {
+        let request = ForwardRequest {
+            envelope: Some(Envelope {
+                timestamp: Some(SystemTime::now().into()),
+                namespace: self.namespace.clone(),
+                topic: topic.to_owned(),
+                event: Some(event),
+            }),
+        };
+
+        let mut client = self.client.lock().unwrap();
+        if client.is_none() {
+            *client = Some(Client::connect(&self.address)?);
+        }
+        let result: Result<Empty, _> =
+            client
+                .as_mut()
+                .unwrap()
+                .call(EVENTS_SERVICE, "Forward", &request);
+        if let Err(TtrpcError::Io(_) | TtrpcError::Closed) = result {
+            // containerd restarted, connect again with the next event
+            *client = None;
+        }
+        result.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    use prost::Message;
+
+    use super::*;
+    use crate::api::events::TaskStart;
+    use crate::ttrpc::{decode, Code, Handler, Server, Status};
+
+    struct Events(Mutex<mpsc::Sender<Envelope>>);
+
+    impl Handler for Events {
+        fn handle(&self, service: &str, method: &str, payload: &[u8]) -> Result<Vec<u8>, Status> {
+            if (service, method) != (EVENTS_SERVICE, "Forward") {
+                return Err(Status::new(Code::Unimplemented, method));
+            }
+            let request: ForwardRequest = decode(payload)?;
+            self.0
+                .lock()
+                .unwrap()
+                .send(request.envelope.unwrap())
+                .unwrap();
+            Ok(Empty {}.encode_to_vec())
+        }
+    }
+
+    #[test]
+    fn test_remote_publisher() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("containerd.sock.ttrpc");
+        let (sender, receiver) = mpsc::channel();
+        let server = Server::new(
+            UnixListener::bind(&socket).unwrap(),
+            Arc::new(Events(Mutex::new(sender))),
+        );
+        thread::spawn(move || server.serve());
+
+        let publisher = RemotePublisher::new(&socket, "k8s.io");
+        let start = TaskStart {
+            container_id: "abc".to_owned(),
+            pid: 42,
+        };
+        publish(&publisher, &start);
+
+        let envelope = receiver.recv().unwrap();
+        assert_eq!(envelope.namespace, "k8s.io");
+        assert_eq!(envelope.topic, "/tasks/start");
+        let event = envelope.event.unwrap();
+        assert_eq!(event.type_url, "containerd.events.TaskStart");
+        assert_eq!(TaskStart::decode(event.value.as_slice()).unwrap(), start);
+    }
+}
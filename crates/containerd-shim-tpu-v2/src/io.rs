@@ -0,0 +1,179 @@
+//! Standard streams of the task processes, as containerd passes them.
+//!
+//! containerd creates FIFOs and opens their other ends before it creates a process, an
+//! empty path leaves the stream closed. The process only gets the reading end of stdin and
+//! the writing ends of stdout and stderr, so that it sees the end of stdin once containerd
+//! closes it. The FIFOs are opened without blocking, a missing other end fails the open
+//! rather than hanging it. `file://` URIs, which `ctr` passes for log files, are opened for
+//! appending.
+use std::fs::{File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use libcontainer::container::builder::ContainerBuilder;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+
+#[derive(Debug, thiserror::Error)]
+pub enum IoError {
+    #[error("failed to open {path}")]
+    Open {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("unsupported standard stream {0}")]
+    Unsupported(String),
+}
+
+type Result<T> = std::result::Result<T, IoError>;
+
+/// Which way a stream flows, seen from the process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+/// Paths of the standard streams of a process
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stdio {
+    pub stdin: String,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Stdio {
+    pub fn new<I, O, E>(stdin: I, stdout: O, stderr: E) -> Self
+    where
+        I: Into<String>,
+        O: Into<String>,
+        E: Into<String>,
+    {
+        Self {
+            stdin: stdin.into(),
+            stdout: stdout.into(),
+            stderr: stderr.into(),
+        }
+    }
+
+    /// Opens the streams and hands them to the builder of the process.
+    pub fn apply(&self, mut builder: ContainerBuilder) -> Result<ContainerBuilder> {
+        if let Some(stdin) = open(&self.stdin, Direction::Input)? {
+            builder = builder.with_stdin(stdin);
+        }
+        if let Some(stdout) = open(&self.stdout, Direction::Output)? {
+            builder = builder.with_stdout(stdout);
+        }
+        if let Some(stderr) = open(&self.stderr, Direction::Output)? {
+            builder = builder.with_stderr(stderr);
+        }
+        Ok(builder)
+    }
+}
+
+/// Opens a stream, `None` if the path is empty
+pub fn open(uri: &str, direction: Direction) -> Result<Option<File>> {
+    if uri.is_empty() {
+        return Ok(None);
+    }
+    let (path, append) = match uri.split_once("://") {
+        None => (uri, false),
+        Some(("file", path)) => (path, true),
+        Some(_) => return Err(IoError::Unsupported(uri.to_owned())),
+    };
+    let path = Path::new(path);
+
+    let mut options = OpenOptions::new();
+    match (direction, append) {
+        (Direction::Input, false) => options.read(true),
+        (Direction::Input, true) => return Err(IoError::Unsupported(uri.to_owned())),
+        (Direction::Output, false) => options.write(true),
+        (Direction::Output, true) => options.create(true).append(true),
+    };
+    let file = options
+        .custom_flags(OFlag::O_NONBLOCK.bits())
+        .open(path)
+        .and_then(|file| {
+            // the process expects blocking streams
+            let flags = OFlag::from_bits_truncate(fcntl(file.as_raw_fd(), FcntlArg::F_GETFL)?);
+            fcntl(
+                file.as_raw_fd(),
+                FcntlArg::F_SETFL(flags - OFlag::O_NONBLOCK),
+            )?;
+            Ok(file)
+        });
+    file.map(Some).map_err(|source| IoError::Open {
+        source,
+        path: path.to_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use nix::sys::stat::Mode;
+
+    use super::*;
+
+    #[test]
+    fn test_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("stdout");
+        nix::unistd::mkfifo(&fifo, Mode::from_bits_truncate(0o600)).unwrap();
+        let fifo = fifo.to_str().unwrap();
+
+        // fails instead of waiting for a reader
+        assert!(matches!(
+            open(fifo, Direction::Output),
+            Err(IoError::Open { .. })
+        ));
+        let mut reader = OpenOptions::new()
+            .read(true)
+            .custom_flags(OFlag::O_NONBLOCK.bits())
+            .open(fifo)
+            .unwrap();
+        let mut stdout = open(fifo, Direction::Output).unwrap().unwrap();
+        assert!(stdout.read(&mut [0; 1]).is_err());
+        stdout.write_all(b"hello").unwrap();
+        let mut read = [0; 5];
+        reader.read_exact(&mut read).unwrap();
+        assert_eq!(&read, b"hello");
+
+        let log = dir.path().join("log");
+        let log_uri = format!("file://{}", log.display());
+        let mut stderr = open(&log_uri, Direction::Output).unwrap().unwrap();
+        stderr.write_all(b"error").unwrap();
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "error");
+
+        assert!(open("", Direction::Input).unwrap().is_none());
+        assert!(matches!(
+            open("binary:///usr/bin/logger", Direction::Output),
+            Err(IoError::Unsupported(_))
+        ));
+        assert!(matches!(
+            open(&log_uri, Direction::Input),
+            Err(IoError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_stdin_ends_when_containerd_closes_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("stdin");
+        nix::unistd::mkfifo(&fifo, Mode::from_bits_truncate(0o600)).unwrap();
+
+        // does not wait for a writer
+        let mut stdin = open(fifo.to_str().unwrap(), Direction::Input)
+            .unwrap()
+            .unwrap();
+        assert!(stdin.write_all(b"x").is_err());
+
+        let mut writer = OpenOptions::new().write(true).open(&fifo).unwrap();
+        writer.write_all(b"input").unwrap();
+        drop(writer);
+        let mut read = String::new();
+        stdin.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "input");
+    }
+}
@@ -0,0 +1,11 @@
+//! # containerd-shim-tpu-v2
+//! A containerd shim v2 running the tasks with libcontainer and giving them the Edge TPUs
+//! they ask for. containerd starts it as the runtime `io.containerd.tpu.v2` and talks to it
+//! over ttrpc.
+pub mod api;
+pub mod events;
+pub mod io;
+pub mod mount;
+pub mod reaper;
+pub mod service;
+pub mod ttrpc;
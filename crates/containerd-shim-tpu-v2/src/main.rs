@@ -0,0 +1,353 @@
+//! The shim binary containerd runs for the tasks of the `io.containerd.tpu.v2` runtime.
+//!
+//! containerd runs `containerd-shim-tpu-v2 [flags] start` in the bundle of a new task: the
+//! shim binds its ttrpc socket, starts a copy of itself serving it in the background and
+//! prints its address. `delete` cleans up after a shim that died. The flags follow the Go
+//! `flag` package containerd expects its shims to use.
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context, Result};
+use containerd_shim_tpu_v2::api::DeleteResponse;
+use containerd_shim_tpu_v2::events::{RemotePublisher, TTRPC_ADDRESS_ENV};
+use containerd_shim_tpu_v2::io::Direction;
+use containerd_shim_tpu_v2::mount;
+use containerd_shim_tpu_v2::service::{TaskService, DEFAULT_ROOT, KILLED_EXIT_STATUS, ROOT_FILE};
+use containerd_shim_tpu_v2::ttrpc::Server;
+use libcontainer::container::Container;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use prost::Message;
+use sha2::{Digest, Sha256};
+
+/// Directory of the sockets of the shims
+const SOCKET_DIR: &str = "/run/containerd/s";
+/// File descriptor `start` passes the listening socket to the shim on
+const LISTENER_FD: RawFd = 3;
+/// File of the bundle containerd reads the address of the shim from
+const ADDRESS_FILE: &str = "address";
+/// FIFO of the bundle containerd reads the logs of the shim from
+const LOG_FILE: &str = "log";
+/// How long the requests being answered are given when the shim shuts down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+/// Annotations grouping the containers of a pod in the shim of its sandbox
+const GROUP_ANNOTATIONS: [&str; 2] = [
+    "io.kubernetes.cri.sandbox-id",
+    "io.containerd.runc.v2.group",
+];
+
+/// The flags containerd passes to its shims
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Flags {
+    namespace: String,
+    id: String,
+    /// Address of the containerd socket
+    address: String,
+    publish_binary: String,
+    bundle: String,
+    debug: bool,
+    version: bool,
+    /// `start`, `delete`, or none to serve
+    action: Option<String>,
+}
+
+fn parse_flags<I: IntoIterator<Item = String>>(args: I) -> Result<Flags> {
+    let mut flags = Flags::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--").or_else(|| arg.strip_prefix('-')) else {
+            flags.action = Some(arg);
+            break;
+        };
+        if flag.is_empty() {
+            // `--` ends the flags
+            flags.action = args.next();
+            break;
+        }
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_owned())),
+            None => (flag, None),
+        };
+        let target = match name {
+            "debug" | "v" => {
+                let value = match value.as_deref() {
+                    None | Some("true") => true,
+                    Some("false") => false,
+                    Some(value) => bail!("invalid boolean value {value:?} for -{name}"),
+                };
+                if name == "debug" {
+                    flags.debug = value;
+                } else {
+                    flags.version = value;
+                }
+                continue;
+            }
+            "namespace" => &mut flags.namespace,
+            "id" => &mut flags.id,
+            "address" => &mut flags.address,
+            "publish-binary" => &mut flags.publish_binary,
+            "bundle" => &mut flags.bundle,
+            _ => bail!("flag provided but not defined: -{name}"),
+        };
+        *target = match value.or_else(|| args.next()) {
+            Some(value) => value,
+            None => bail!("flag needs an argument: -{name}"),
+        };
+    }
+    Ok(flags)
+}
+
+/// Returns the socket of the shim of a task, named after containerd and the task like the
+/// shims of containerd name theirs.
+fn socket_path(address: &str, namespace: &str, id: &str) -> PathBuf {
+    let digest = Sha256::digest(format!("{address}/{namespace}/{id}"));
+    let name: String = digest.iter().map(|byte| format!("{byte:02x}")).collect();
+    Path::new(SOCKET_DIR).join(name)
+}
+
+/// Returns the id of the group of the task, the containers of a pod share a shim.
+fn group_id(bundle: &Path) -> Option<String> {
+    let config = fs::read(bundle.join("config.json")).ok()?;
+    let config: serde_json::Value = serde_json::from_slice(&config).ok()?;
+    let annotations = config.get("annotations")?;
+    GROUP_ANNOTATIONS
+        .iter()
+        .find_map(|annotation| annotations.get(annotation)?.as_str())
+        .map(str::to_owned)
+}
+
+fn start(flags: &Flags) -> Result<()> {
+    let bundle = env::current_dir().context("failed to find the bundle")?;
+    let group = group_id(&bundle).unwrap_or_else(|| flags.id.clone());
+    let socket = socket_path(&flags.address, &flags.namespace, &group);
+    let address = format!("unix://{}", socket.display());
+    fs::create_dir_all(SOCKET_DIR).with_context(|| format!("failed to create {SOCKET_DIR}"))?;
+
+    let listener = match UnixListener::bind(&socket) {
+        Ok(listener) => listener,
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+            if UnixStream::connect(&socket).is_ok() {
+                // the shim of the group serves the task
+                fs::write(bundle.join(ADDRESS_FILE), &address)?;
+                print!("{address}");
+                return Ok(());
+            }
+            // left behind by a shim that died
+            fs::remove_file(&socket)?;
+            UnixListener::bind(&socket)?
+        }
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to bind {}", socket.display()))
+        }
+    };
+
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(["-namespace", &flags.namespace])
+        .args(["-id", &flags.id])
+        .args(["-address", &flags.address])
+        .args(["-publish-binary", &flags.publish_binary])
+        .current_dir(&bundle)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    if flags.debug {
+        command.arg("-debug");
+    }
+    let listener_fd = listener.as_raw_fd();
+    // SAFETY: only async signal safe calls are made between the fork and the exec
+    unsafe {
+        command.pre_exec(move || {
+            // survives containerd restarting
+            nix::unistd::setsid()?;
+            if listener_fd == LISTENER_FD {
+                fcntl(LISTENER_FD, FcntlArg::F_SETFD(FdFlag::empty()))?;
+            } else {
+                nix::unistd::dup2(listener_fd, LISTENER_FD)?;
+            }
+            Ok(())
+        });
+    }
+    let shim = command.spawn().context("failed to start the shim")?;
+    log::debug!("shim of task {} started as process {}", flags.id, shim.id());
+
+    fs::write(bundle.join(ADDRESS_FILE), &address)?;
+    print!("{address}");
+    Ok(())
+}
+
+/// Cleans up after a shim that died, `containerd` reads the protobuf response on stdout.
+fn delete(flags: &Flags) -> Result<()> {
+    let bundle = match flags.bundle.as_str() {
+        "" => env::current_dir().context("failed to find the bundle")?,
+        bundle => PathBuf::from(bundle),
+    };
+    let root = fs::read_to_string(bundle.join(ROOT_FILE))
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| Path::new(DEFAULT_ROOT).join(&flags.namespace));
+    let container_root = root.join(&flags.id);
+    if container_root.exists() {
+        match Container::load(container_root) {
+            Ok(mut container) => {
+                if let Err(err) = container.delete(true) {
+                    log::warn!("failed to delete container {}: {err}", flags.id);
+                }
+            }
+            Err(err) => log::warn!("failed to load container {}: {err}", flags.id),
+        }
+    }
+    if let Err(err) = mount::unmount_rootfs(&bundle.join("rootfs")) {
+        log::warn!("{err}");
+    }
+
+    let response = DeleteResponse {
+        pid: 0,
+        exit_status: KILLED_EXIT_STATUS,
+        exited_at: Some(SystemTime::now().into()),
+    };
+    io::stdout().write_all(&response.encode_to_vec())?;
+    Ok(())
+}
+
+/// Logs to the FIFO containerd reads the logs of the shim from.
+fn init_logging(debug: bool) {
+    let level = if debug {
+        log::LevelFilter::Debug
+    } else {
+        log::LevelFilter::Info
+    };
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level);
+    if Path::new(LOG_FILE).exists() {
+        if let Ok(Some(log)) = containerd_shim_tpu_v2::io::open(LOG_FILE, Direction::Output) {
+            builder.target(env_logger::Target::Pipe(Box::new(log)));
+        }
+    }
+    builder.init();
+}
+
+fn serve(flags: &Flags) -> Result<()> {
+    init_logging(flags.debug);
+    // SAFETY: `start` passes the listening socket on this descriptor, nothing else owns it
+    let listener = UnixListener::from(unsafe { OwnedFd::from_raw_fd(LISTENER_FD) });
+    let socket = listener
+        .local_addr()
+        .ok()
+        .and_then(|address| address.as_pathname().map(Path::to_owned));
+
+    let events_address =
+        env::var(TTRPC_ADDRESS_ENV).unwrap_or_else(|_| format!("{}.ttrpc", flags.address));
+    let publisher = Arc::new(RemotePublisher::new(events_address, &flags.namespace));
+    let service = Arc::new(TaskService::new(&flags.namespace, publisher));
+    let server = Server::new(listener, Arc::clone(&service) as _);
+    let active_requests = server.active_requests();
+    thread::spawn(move || {
+        if let Err(err) = server.serve() {
+            log::error!("failed to accept connections: {err}");
+            std::process::exit(1);
+        }
+    });
+
+    service.wait_shutdown();
+    log::info!("shutting down");
+    // answers the Shutdown call, a Wait for a task that is still running never is
+    active_requests.wait_idle(SHUTDOWN_TIMEOUT);
+    if let Some(socket) = socket {
+        let _ = fs::remove_file(socket);
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let flags = match parse_flags(env::args().skip(1)) {
+        Ok(flags) => flags,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::from(2);
+        }
+    };
+    if flags.version {
+        println!(
+            "{}:\n  Version: {}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        );
+        return ExitCode::SUCCESS;
+    }
+
+    let result = match flags.action.as_deref() {
+        Some("start") => start(&flags),
+        Some("delete") => delete(&flags),
+        Some(action) => Err(anyhow::anyhow!("unknown action {action}")),
+        None => serve(&flags),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        let flags = parse_flags(args(&[
+            "-namespace",
+            "k8s.io",
+            "--address=/run/containerd/containerd.sock",
+            "-publish-binary",
+            "/usr/bin/containerd",
+            "-id",
+            "abc",
+            "-debug",
+            "start",
+        ]))
+        .unwrap();
+        assert_eq!(
+            flags,
+            Flags {
+                namespace: "k8s.io".to_owned(),
+                id: "abc".to_owned(),
+                address: "/run/containerd/containerd.sock".to_owned(),
+                publish_binary: "/usr/bin/containerd".to_owned(),
+                debug: true,
+                action: Some("start".to_owned()),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(parse_flags(args(&["-v"])).unwrap().action, None);
+        assert!(parse_flags(args(&["-v"])).unwrap().version);
+        assert!(parse_flags(args(&["-unknown"])).is_err());
+        assert!(parse_flags(args(&["-id"])).is_err());
+        assert!(parse_flags(args(&["-debug=maybe"])).is_err());
+    }
+
+    #[test]
+    fn test_socket_path() {
+        let socket = socket_path("/run/containerd/containerd.sock", "k8s.io", "abc");
+        assert_eq!(socket.parent().unwrap(), Path::new(SOCKET_DIR));
+        assert_eq!(socket.file_name().unwrap().len(), 64);
+        assert_ne!(
+            socket,
+            socket_path("/run/containerd/containerd.sock", "default", "abc")
+        );
+    }
+}
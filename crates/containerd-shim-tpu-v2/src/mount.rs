@@ -0,0 +1,142 @@
+//! Root file system of a task, the mounts of the snapshot containerd prepared for it.
+//!
+//! The mounts are stacked on the `rootfs` directory of the bundle when the task is created
+//! and detached when it is deleted. Without mounts the bundle brings its own root file
+//! system.
+use std::path::{Path, PathBuf};
+
+use libcontainer::oci_spec::runtime::MountBuilder;
+use libcontainer::rootfs::utils::{parse_mount, MountOptionConfig};
+use libcontainer::rootfs::RootfsError;
+use nix::errno::Errno;
+use nix::mount::{MntFlags, MsFlags};
+
+use crate::api::Mount;
+
+/// Most mounts stacked on a root file system
+const MAX_STACKED_MOUNTS: usize = 16;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MountError {
+    #[error("invalid options of the {kind} mount of {source_path}")]
+    Options {
+        source: RootfsError,
+        kind: String,
+        source_path: String,
+    },
+    #[error("failed to mount {source_path} on {target}")]
+    Mount {
+        source: Errno,
+        source_path: String,
+        target: PathBuf,
+    },
+    #[error("failed to unmount {target}")]
+    Unmount { source: Errno, target: PathBuf },
+}
+
+type Result<T> = std::result::Result<T, MountError>;
+
+/// Returns the flags and data of the options of a mount.
+pub fn options(mount: &Mount) -> Result<MountOptionConfig> {
+    let spec_mount = MountBuilder::default()
+        .destination(&mount.target)
+        .typ(&mount.r#type)
+        .source(&mount.source)
+        .options(mount.options.clone())
+        .build()
+        .expect("all the fields of the mount are set");
+    parse_mount(&spec_mount).map_err(|err| MountError::Options {
+        source: err.into(),
+        kind: mount.r#type.clone(),
+        source_path: mount.source.clone(),
+    })
+}
+
+/// Mounts the root file system on `target`, returns whether anything was mounted.
+pub fn mount_rootfs(mounts: &[Mount], target: &Path) -> Result<bool> {
+    for (mounted, mount) in mounts.iter().enumerate() {
+        let options = options(mount)?;
+        let data = Some(options.data.as_str()).filter(|data| !data.is_empty());
+        if let Err(source) = nix::mount::mount(
+            Some(mount.source.as_str()),
+            target,
+            Some(mount.r#type.as_str()),
+            options.flags,
+            data,
+        ) {
+            // do not leave the mounts stacked so far behind
+            for _ in 0..mounted {
+                let _ = nix::mount::umount2(target, MntFlags::MNT_DETACH);
+            }
+            return Err(MountError::Mount {
+                source,
+                source_path: mount.source.clone(),
+                target: target.to_owned(),
+            });
+        }
+        if options.flags.contains(MsFlags::MS_BIND) && options.flags.contains(MsFlags::MS_RDONLY) {
+            // a bind mount ignores the read only flag, it takes a remount
+            let flags = options.flags | MsFlags::MS_REMOUNT;
+            let _ = nix::mount::mount(None::<&str>, target, None::<&str>, flags, None::<&str>);
+        }
+    }
+    Ok(!mounts.is_empty())
+}
+
+/// Detaches all the mounts stacked on `target`.
+pub fn unmount_rootfs(target: &Path) -> Result<()> {
+    for _ in 0..MAX_STACKED_MOUNTS {
+        match nix::mount::umount2(target, MntFlags::MNT_DETACH) {
+            Ok(()) => {}
+            // nothing is mounted anymore
+            Err(Errno::EINVAL | Errno::ENOENT) => return Ok(()),
+            Err(source) => {
+                return Err(MountError::Unmount {
+                    source,
+                    target: target.to_owned(),
+                })
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mount(kind: &str, options: &[&str]) -> Mount {
+        Mount {
+            r#type: kind.to_owned(),
+            source: kind.to_owned(),
+            target: String::new(),
+            options: options.iter().map(|option| option.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_options() {
+        let overlay = options(&mount(
+            "overlay",
+            &["index=off", "lowerdir=/l2:/l1", "upperdir=/u", "workdir=/w"],
+        ))
+        .unwrap();
+        assert_eq!(overlay.flags, MsFlags::empty());
+        assert_eq!(
+            overlay.data,
+            "index=off,lowerdir=/l2:/l1,upperdir=/u,workdir=/w"
+        );
+
+        let bind = options(&mount("bind", &["rbind", "ro"])).unwrap();
+        assert_eq!(
+            bind.flags,
+            MsFlags::MS_BIND | MsFlags::MS_REC | MsFlags::MS_RDONLY
+        );
+        assert!(bind.data.is_empty());
+
+        assert!(matches!(
+            options(&mount("bind", &["idmap"])),
+            Err(MountError::Options { .. })
+        ));
+    }
+}
@@ -0,0 +1,119 @@
+//! Reaping of the task processes.
+//!
+//! The container processes are started with `CLONE_PARENT`, the init process and the execs
+//! are children of the shim. Only the watched processes are waited for: libcontainer waits
+//! for its intermediate processes itself and must not find them reaped.
+use std::collections::HashSet;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use nix::errno::Errno;
+use nix::sys::signal;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+
+/// Time between two checks of the watched processes
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Exit status of a process that exited without being reaped by the shim
+const UNKNOWN_EXIT_STATUS: u32 = 255;
+
+/// A watched process exited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exit {
+    pub pid: Pid,
+    /// The exit code, or 128 plus the number of the signal that killed the process
+    pub status: u32,
+    pub at: SystemTime,
+}
+
+/// Waits for the watched processes and sends their exits.
+#[derive(Clone)]
+pub struct Reaper {
+    watched: Arc<Mutex<HashSet<Pid>>>,
+}
+
+impl Reaper {
+    /// Starts reaping, the exits are sent to `exits` until it is dropped.
+    pub fn start(exits: Sender<Exit>) -> Self {
+        let watched = Arc::new(Mutex::new(HashSet::new()));
+        let reaper = Self {
+            watched: Arc::clone(&watched),
+        };
+        thread::spawn(move || loop {
+            thread::sleep(POLL_INTERVAL);
+            let pids: Vec<Pid> = watched.lock().unwrap().iter().copied().collect();
+            for pid in pids {
+                let Some(status) = poll(pid) else {
+                    continue;
+                };
+                watched.lock().unwrap().remove(&pid);
+                let exit = Exit {
+                    pid,
+                    status,
+                    at: SystemTime::now(),
+                };
+                if exits.send(exit).is_err() {
+                    return;
+                }
+            }
+        });
+        reaper
+    }
+
+    pub fn watch(&self, pid: Pid) {
+        self.watched.lock().unwrap().insert(pid);
+    }
+
+    pub fn forget(&self, pid: Pid) {
+        self.watched.lock().unwrap().remove(&pid);
+    }
+}
+
+/// Returns the exit status of the process if it exited.
+fn poll(pid: Pid) -> Option<u32> {
+    match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+        Ok(WaitStatus::Exited(_, code)) => Some(code as u32),
+        Ok(WaitStatus::Signaled(_, signal, _)) => Some(128 + signal as u32),
+        Ok(_) => None,
+        // not a child of the shim, it can only be told apart from a running process
+        // once it is gone
+        Err(Errno::ECHILD) => match signal::kill(pid, None) {
+            Err(Errno::ESRCH) => Some(UNKNOWN_EXIT_STATUS),
+            _ => None,
+        },
+        Err(err) => {
+            log::warn!("failed to wait for process {pid}: {err}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn test_reaper() {
+        let (sender, exits) = mpsc::channel();
+        let reaper = Reaper::start(sender);
+
+        // reaped by the reaper, not by std
+        let spawn = |command: &mut Command| Pid::from_raw(command.spawn().unwrap().id() as i32);
+        let exited = spawn(Command::new("sh").args(["-c", "exit 3"]));
+        reaper.watch(exited);
+        let killed = spawn(Command::new("sleep").arg("60"));
+        reaper.watch(killed);
+
+        let exit = exits.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!((exit.pid, exit.status), (exited, 3));
+
+        signal::kill(killed, signal::SIGKILL).unwrap();
+        let exit = exits.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!((exit.pid, exit.status), (killed, 137));
+    }
+}
@@ -0,0 +1,1312 @@
+//! The Task service containerd drives the tasks of the shim with.
+//!
+//! A task is a container created by libcontainer, its init process and the processes
+//! executed in it. The Edge TPUs it asks for are leased and injected when it is created,
+//! like `tpu-container-runtime create` does, and released when it is deleted. The exits of
+//! the processes are published as `/tasks/exit` events and answer the `Wait` calls.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio as ProcessStdio};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use edgetpu::cdi::CdiInjector;
+use edgetpu::health::HealthLedger;
+use edgetpu::image::frequency::{FrequencyPolicy, PolicyError, DEFAULT_POLICY_PATH};
+use edgetpu::image::HostLibraries;
+use edgetpu::injection::EdgeTpuInjector;
+use edgetpu::model::validate::ModelValidator;
+use libcgroups::common::{
+    AnyCgroupManager, AnyManagerError, CgroupConfig, CgroupManager, ControllerOpt,
+};
+use libcgroups::oom::OomNotifier;
+use libcontainer::container::builder::ContainerBuilder;
+use libcontainer::container::events::cgroup_stats;
+use libcontainer::container::{Container, ContainerStatus};
+use libcontainer::error::LibcontainerError;
+use libcontainer::lease::LeaseLedger;
+use libcontainer::oci_spec::runtime::LinuxResources;
+use libcontainer::signal::Signal;
+use libcontainer::syscall::syscall::SyscallType;
+use nix::errno::Errno;
+use nix::sys::signal;
+use nix::unistd::Pid;
+use prost::Message;
+
+use crate::api::events::{
+    TaskCreate, TaskDelete, TaskExecAdded, TaskExecStarted, TaskExit, TaskIo, TaskOom, TaskPaused,
+    TaskResumed, TaskStart,
+};
+use crate::api::*;
+use crate::events::{publish, Publisher};
+use crate::io::{IoError, Stdio};
+use crate::mount::{self, MountError};
+use crate::reaper::{Exit, Reaper};
+use crate::ttrpc::{decode, Code, Handler, Status};
+
+/// Root directory of the container states when containerd passes none
+pub const DEFAULT_ROOT: &str = "/run/youki";
+/// File of the bundle the root directory of the container state is written to, for the
+/// `delete` action of the shim
+pub const ROOT_FILE: &str = "runtime-root";
+/// Prefix of the leases of the USB Edge TPUs, by port
+const USB_LEASE_PREFIX: &str = "usb-";
+/// How long a deleted task is given to report the exit of its init process
+const EXIT_GRACE_PERIOD: Duration = Duration::from_secs(1);
+/// Time between two checks for the OOM kills of a task
+const OOM_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Exit status of a process killed by SIGKILL
+pub const KILLED_EXIT_STATUS: u32 = 128 + 9;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TaskError {
+    #[error("task {0} not found")]
+    NotFound(String),
+    #[error("process {exec_id} of task {id} not found")]
+    ProcessNotFound { id: String, exec_id: String },
+    #[error("{0} already exists")]
+    AlreadyExists(String),
+    #[error("{0}")]
+    FailedPrecondition(String),
+    #[error("{0} is not supported")]
+    Unimplemented(&'static str),
+    #[error("invalid {0}")]
+    InvalidArgument(String),
+    #[error(transparent)]
+    Container(#[from] LibcontainerError),
+    #[error(transparent)]
+    Cgroup(#[from] AnyManagerError),
+    #[error(transparent)]
+    Io(#[from] IoError),
+    #[error(transparent)]
+    Mount(#[from] MountError),
+    #[error("failed to load the frequency policy of the Edge TPUs")]
+    Policy(#[from] PolicyError),
+    #[error("failed to write {path}")]
+    Write {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("failed to signal process {pid}")]
+    Signal { source: Errno, pid: Pid },
+}
+
+type Result<T> = std::result::Result<T, TaskError>;
+
+impl From<TaskError> for Status {
+    fn from(err: TaskError) -> Self {
+        let code = match &err {
+            TaskError::NotFound(_) | TaskError::ProcessNotFound { .. } => Code::NotFound,
+            TaskError::AlreadyExists(_) | TaskError::Container(LibcontainerError::Exist) => {
+                Code::AlreadyExists
+            }
+            TaskError::FailedPrecondition(_)
+            | TaskError::Container(LibcontainerError::IncorrectStatus) => Code::FailedPrecondition,
+            TaskError::Unimplemented(_) => Code::Unimplemented,
+            TaskError::InvalidArgument(_) => Code::InvalidArgument,
+            _ => Code::Unknown,
+        };
+        // containerd only shows the message, it carries the causes
+        let mut message = err.to_string();
+        let mut source = std::error::Error::source(&err);
+        while let Some(cause) = source {
+            message = format!("{message}: {cause}");
+            source = cause.source();
+        }
+        Status::new(code, message)
+    }
+}
+
+/// The init process of a task or a process executed in it
+#[derive(Debug, Default)]
+struct Process {
+    /// Set once the process is created
+    pid: Option<Pid>,
+    stdio: Stdio,
+    exit: Option<Exit>,
+    /// The OCI process of an exec, as JSON
+    spec: Vec<u8>,
+    /// Set while the process is built, without holding the lock
+    starting: bool,
+}
+
+impl Process {
+    fn is_running(&self) -> bool {
+        self.pid.is_some() && self.exit.is_none()
+    }
+}
+
+#[derive(Debug)]
+struct Task {
+    bundle: PathBuf,
+    /// Root directory of the state of the container
+    root: PathBuf,
+    rootfs_mounted: bool,
+    init: Process,
+    execs: HashMap<String, Process>,
+    /// Set while the container is deleted, without holding the lock
+    deleting: bool,
+}
+
+impl Task {
+    fn load_container(&self, id: &str) -> Result<Container> {
+        Ok(Container::load(self.root.join(id))?)
+    }
+
+    fn ensure_not_deleting(&self, id: &str) -> Result<()> {
+        if self.deleting {
+            return Err(TaskError::FailedPrecondition(format!(
+                "task {id} is being deleted"
+            )));
+        }
+        Ok(())
+    }
+
+    fn process(&self, id: &str, exec_id: &str) -> Result<&Process> {
+        if exec_id.is_empty() {
+            return Ok(&self.init);
+        }
+        self.execs
+            .get(exec_id)
+            .ok_or_else(|| TaskError::ProcessNotFound {
+                id: id.to_owned(),
+                exec_id: exec_id.to_owned(),
+            })
+    }
+}
+
+#[derive(Default)]
+struct State {
+    tasks: HashMap<String, Task>,
+    /// Ids of the tasks being created
+    creating: HashSet<String>,
+    shutdown: bool,
+}
+
+impl State {
+    fn task(&self, id: &str) -> Result<&Task> {
+        self.tasks
+            .get(id)
+            .ok_or_else(|| TaskError::NotFound(id.to_owned()))
+    }
+
+    fn task_mut(&mut self, id: &str) -> Result<&mut Task> {
+        self.tasks
+            .get_mut(id)
+            .ok_or_else(|| TaskError::NotFound(id.to_owned()))
+    }
+
+    /// Returns the task and the exec id of the running process `pid`.
+    fn find_running(&mut self, pid: Pid) -> Option<(&str, &str, &mut Process)> {
+        self.tasks.iter_mut().find_map(|(id, task)| {
+            if task.init.is_running() && task.init.pid == Some(pid) {
+                return Some((id.as_str(), "", &mut task.init));
+            }
+            task.execs
+                .iter_mut()
+                .find(|(_, exec)| exec.is_running() && exec.pid == Some(pid))
+                .map(|(exec_id, exec)| (id.as_str(), exec_id.as_str(), exec))
+        })
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Notified when a process exits, a task is deleted or the shim shuts down
+    changed: Condvar,
+    publisher: Arc<dyn Publisher>,
+    reaper: Reaper,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Publishes the exits of the processes and records them. An exit is published first,
+    /// containerd sees it before the `Wait` and `Delete` responses depending on it.
+    fn handle_exits(&self, exits: Receiver<Exit>) {
+        for exit in exits {
+            let event = {
+                let mut state = self.lock();
+                let Some((id, exec_id, _)) = state.find_running(exit.pid) else {
+                    continue;
+                };
+                TaskExit {
+                    container_id: id.to_owned(),
+                    id: if exec_id.is_empty() { id } else { exec_id }.to_owned(),
+                    pid: exit.pid.as_raw() as u32,
+                    exit_status: exit.status,
+                    exited_at: Some(exit.at.into()),
+                }
+            };
+            publish(self.publisher.as_ref(), &event);
+            // only this thread records exits, the process is still running unless deleted
+            if let Some((_, _, process)) = self.lock().find_running(exit.pid) {
+                process.exit = Some(exit);
+            }
+            self.changed.notify_all();
+        }
+    }
+
+    /// Publishes the OOM kills in the cgroup of the task until its init process exits.
+    fn watch_oom(&self, id: &str, pid: Pid) {
+        let mut notifier = match OomNotifier::for_process(pid) {
+            Ok(notifier) => notifier,
+            Err(err) => {
+                log::warn!("OOM kills of task {id} will not be reported: {err}");
+                return;
+            }
+        };
+        loop {
+            let kills = match notifier.wait(OOM_POLL_INTERVAL) {
+                Ok(kills) => kills,
+                Err(err) => {
+                    log::warn!("stopped watching the OOM kills of task {id}: {err}");
+                    return;
+                }
+            };
+            for _ in 0..kills {
+                let event = TaskOom {
+                    container_id: id.to_owned(),
+                };
+                publish(self.publisher.as_ref(), &event);
+            }
+            let running = self
+                .lock()
+                .tasks
+                .get(id)
+                .is_some_and(|task| task.init.is_running());
+            if !running {
+                return;
+            }
+        }
+    }
+}
+
+/// Creates, starts and deletes the containers of the tasks. Stubs stand in for it to test
+/// the service.
+pub trait Containers: Send + Sync {
+    /// Creates container `id` of the bundle and returns the pid of its init process.
+    fn create(
+        &self,
+        id: &str,
+        bundle: &Path,
+        root: &Path,
+        options: &RuncOptions,
+        stdio: &Stdio,
+    ) -> Result<Pid>;
+
+    fn start(&self, id: &str, root: &Path) -> Result<()>;
+
+    /// Executes the OCI process `process`, as JSON, in container `id` and returns its pid.
+    fn exec(
+        &self,
+        id: &str,
+        exec_id: &str,
+        root: &Path,
+        process: &[u8],
+        stdio: &Stdio,
+    ) -> Result<Pid>;
+
+    /// Deletes container `id`, a created one is killed, a running one must have stopped.
+    fn delete(&self, id: &str, root: &Path) -> Result<()>;
+}
+
+/// Runs the containers with libcontainer, injecting the Edge TPUs they ask for.
+#[derive(Debug, Clone, Default)]
+pub struct Libcontainer;
+
+impl Containers for Libcontainer {
+    fn create(
+        &self,
+        id: &str,
+        bundle: &Path,
+        root: &Path,
+        options: &RuncOptions,
+        stdio: &Stdio,
+    ) -> Result<Pid> {
+        let policy = FrequencyPolicy::load(DEFAULT_POLICY_PATH)?;
+        let edgetpu_injector = EdgeTpuInjector::new()
+            .with_leases(LeaseLedger::new(root), id)
+            .with_health(HealthLedger::new(root))
+            .with_host_libraries(HostLibraries::new().with_frequency_policy(policy));
+
+        let builder = ContainerBuilder::new(id.to_owned(), SyscallType::default())
+            .with_root_path(root)?
+            .validate_id()?;
+        stdio
+            .apply(builder)?
+            .as_init(bundle)
+            .with_spec_modifier(edgetpu_injector)
+            .with_spec_modifier(CdiInjector::new())
+            .with_spec_modifier(ModelValidator::new())
+            .with_systemd(options.systemd_cgroup)
+            .with_detach(true)
+            .build()?
+            .pid()
+            .ok_or_else(|| TaskError::FailedPrecondition("the container has no pid".into()))
+    }
+
+    fn start(&self, id: &str, root: &Path) -> Result<()> {
+        Ok(Container::load(root.join(id))?.start()?)
+    }
+
+    fn exec(
+        &self,
+        id: &str,
+        exec_id: &str,
+        root: &Path,
+        process: &[u8],
+        stdio: &Stdio,
+    ) -> Result<Pid> {
+        // the process of a tenant is read from a file
+        let process_path = root.join(id).join(format!("exec-{exec_id}.json"));
+        fs::write(&process_path, process).map_err(|source| TaskError::Write {
+            source,
+            path: process_path.clone(),
+        })?;
+        let builder = ContainerBuilder::new(id.to_owned(), SyscallType::default())
+            .with_root_path(root)
+            .map_err(TaskError::from);
+        let pid = builder
+            .and_then(|builder| Ok(stdio.apply(builder)?))
+            .and_then(|builder| {
+                Ok(builder
+                    .as_tenant()
+                    .with_process(Some(&process_path))
+                    .with_detach(true)
+                    .build()?)
+            });
+        let _ = fs::remove_file(&process_path);
+        pid
+    }
+
+    fn delete(&self, id: &str, root: &Path) -> Result<()> {
+        Ok(Container::load(root.join(id))?.delete(false)?)
+    }
+}
+
+/// Serves the Task service of containerd.
+pub struct TaskService {
+    shared: Arc<Shared>,
+    namespace: String,
+    root: PathBuf,
+    containers: Box<dyn Containers>,
+}
+
+impl TaskService {
+    pub fn new<N: Into<String>>(namespace: N, publisher: Arc<dyn Publisher>) -> Self {
+        let (sender, exits) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+            publisher,
+            reaper: Reaper::start(sender),
+        });
+        let handler = Arc::clone(&shared);
+        thread::spawn(move || handler.handle_exits(exits));
+
+        Self {
+            shared,
+            namespace: namespace.into(),
+            root: PathBuf::from(DEFAULT_ROOT),
+            containers: Box::new(Libcontainer),
+        }
+    }
+
+    /// Sets how the containers of the tasks are run
+    pub fn with_containers(mut self, containers: impl Containers + 'static) -> Self {
+        self.containers = Box::new(containers);
+        self
+    }
+
+    /// Sets the root directory of the container states, used unless the runtime options of
+    /// a task set another one. The states are kept apart by namespace below it.
+    pub fn with_root<P: Into<PathBuf>>(mut self, root: P) -> Self {
+        self.root = root.into();
+        self
+    }
+
+    /// Blocks until containerd shuts the shim down.
+    pub fn wait_shutdown(&self) {
+        let mut state = self.shared.lock();
+        while !state.shutdown {
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+
+    fn publish<E: crate::api::events::TaskEvent>(&self, event: &E) {
+        publish(self.shared.publisher.as_ref(), event);
+    }
+
+    fn create(&self, request: CreateTaskRequest) -> Result<CreateTaskResponse> {
+        if request.terminal {
+            return Err(TaskError::Unimplemented("terminal"));
+        }
+        if !request.checkpoint.is_empty() {
+            return Err(TaskError::Unimplemented("restoring a checkpoint"));
+        }
+        let options = match &request.options {
+            Some(options) if options.type_url.ends_with(RUNC_OPTIONS_TYPE) => {
+                RuncOptions::decode(options.value.as_slice())
+                    .map_err(|err| TaskError::InvalidArgument(format!("runtime options: {err}")))?
+            }
+            _ => RuncOptions::default(),
+        };
+        let root = match options.root.as_str() {
+            "" => self.root.join(&self.namespace),
+            root => Path::new(root).join(&self.namespace),
+        };
+        fs::create_dir_all(&root).map_err(|source| TaskError::Write {
+            source,
+            path: root.clone(),
+        })?;
+
+        // the id is reserved while the container is built, without holding the lock
+        {
+            let mut state = self.shared.lock();
+            if state.tasks.contains_key(&request.id) || !state.creating.insert(request.id.clone()) {
+                return Err(TaskError::AlreadyExists(format!("task {}", request.id)));
+            }
+        }
+        let created = self.create_task(&request, &root, &options);
+        let mut state = self.shared.lock();
+        state.creating.remove(&request.id);
+        let task = created?;
+        let pid = task
+            .init
+            .pid
+            .expect("the init process is created with the task");
+        // watched once the task is there to record its exit
+        self.shared.reaper.watch(pid);
+        state.tasks.insert(request.id.clone(), task);
+        drop(state);
+
+        if let Err(err) = follow_usb_tpus(&root, &request.id) {
+            log::warn!(
+                "USB Edge TPUs of task {} will not be followed: {err:#}",
+                request.id
+            );
+        }
+
+        self.publish(&TaskCreate {
+            container_id: request.id,
+            bundle: request.bundle,
+            rootfs: request.rootfs,
+            io: Some(TaskIo {
+                stdin: request.stdin,
+                stdout: request.stdout,
+                stderr: request.stderr,
+                terminal: false,
+            }),
+            checkpoint: String::new(),
+            pid: pid.as_raw() as u32,
+        });
+        Ok(CreateTaskResponse {
+            pid: pid.as_raw() as u32,
+        })
+    }
+
+    /// Mounts the root file system of the task and creates its container.
+    fn create_task(
+        &self,
+        request: &CreateTaskRequest,
+        root: &Path,
+        options: &RuncOptions,
+    ) -> Result<Task> {
+        let bundle = PathBuf::from(&request.bundle);
+        let root_file = bundle.join(ROOT_FILE);
+        fs::write(&root_file, root.as_os_str().as_encoded_bytes()).map_err(|source| {
+            TaskError::Write {
+                source,
+                path: root_file,
+            }
+        })?;
+        let rootfs = bundle.join("rootfs");
+        let rootfs_mounted = mount::mount_rootfs(&request.rootfs, &rootfs)?;
+        let stdio = Stdio::new(&request.stdin, &request.stdout, &request.stderr);
+        let pid = match self
+            .containers
+            .create(&request.id, &bundle, root, options, &stdio)
+        {
+            Ok(pid) => pid,
+            Err(err) => {
+                // the state of a container with this id is not ours to remove
+                if !matches!(err, TaskError::Container(LibcontainerError::Exist)) {
+                    remove_container(root, &request.id);
+                }
+                if rootfs_mounted {
+                    if let Err(err) = mount::unmount_rootfs(&rootfs) {
+                        log::warn!("failed to unmount the root file system: {err}");
+                    }
+                }
+                return Err(err);
+            }
+        };
+
+        Ok(Task {
+            bundle,
+            root: root.to_owned(),
+            rootfs_mounted,
+            init: Process {
+                pid: Some(pid),
+                stdio,
+                ..Default::default()
+            },
+            execs: HashMap::new(),
+            deleting: false,
+        })
+    }
+
+    fn start(&self, request: StartRequest) -> Result<StartResponse> {
+        let mut state = self.shared.lock();
+        let task = state.task_mut(&request.id)?;
+        task.ensure_not_deleting(&request.id)?;
+        if request.exec_id.is_empty() {
+            let pid = task
+                .init
+                .pid
+                .expect("the init process is created with the task");
+            self.containers.start(&request.id, &task.root)?;
+            drop(state);
+
+            let shared = Arc::clone(&self.shared);
+            let id = request.id.clone();
+            thread::spawn(move || shared.watch_oom(&id, pid));
+            self.publish(&TaskStart {
+                container_id: request.id,
+                pid: pid.as_raw() as u32,
+            });
+            return Ok(StartResponse {
+                pid: pid.as_raw() as u32,
+            });
+        }
+
+        let root = task.root.clone();
+        let exec =
+            task.execs
+                .get_mut(&request.exec_id)
+                .ok_or_else(|| TaskError::ProcessNotFound {
+                    id: request.id.clone(),
+                    exec_id: request.exec_id.clone(),
+                })?;
+        if exec.pid.is_some() || exec.starting {
+            return Err(TaskError::FailedPrecondition(format!(
+                "process {} was already started",
+                request.exec_id
+            )));
+        }
+        // the exec is reserved while it is built, without holding the lock
+        exec.starting = true;
+        let spec = exec.spec.clone();
+        let stdio = exec.stdio.clone();
+        drop(state);
+
+        let built = self
+            .containers
+            .exec(&request.id, &request.exec_id, &root, &spec, &stdio);
+        let mut state = self.shared.lock();
+        // neither the task nor the exec are deleted while the exec is starting
+        let exec = state
+            .task_mut(&request.id)?
+            .execs
+            .get_mut(&request.exec_id)
+            .expect("a starting exec is not deleted");
+        exec.starting = false;
+        let pid = built?;
+        exec.pid = Some(pid);
+        self.shared.reaper.watch(pid);
+        drop(state);
+
+        self.publish(&TaskExecStarted {
+            container_id: request.id,
+            exec_id: request.exec_id,
+            pid: pid.as_raw() as u32,
+        });
+        Ok(StartResponse {
+            pid: pid.as_raw() as u32,
+        })
+    }
+
+    fn delete(&self, request: DeleteRequest) -> Result<DeleteResponse> {
+        let mut state = self.shared.lock();
+        let task = state.task_mut(&request.id)?;
+        if !request.exec_id.is_empty() {
+            let exec = task.process(&request.id, &request.exec_id)?;
+            if exec.is_running() || exec.starting {
+                return Err(TaskError::FailedPrecondition(format!(
+                    "process {} is running",
+                    request.exec_id
+                )));
+            }
+            let exec = task.execs.remove(&request.exec_id).unwrap();
+            drop(state);
+            self.shared.changed.notify_all();
+            return Ok(delete_response(&exec));
+        }
+
+        task.ensure_not_deleting(&request.id)?;
+        if task
+            .execs
+            .values()
+            .any(|exec| exec.is_running() || exec.starting)
+        {
+            return Err(TaskError::FailedPrecondition(format!(
+                "task {} has running processes",
+                request.id
+            )));
+        }
+        // the task is reserved while its container is deleted, without holding the lock
+        task.deleting = true;
+        let root = task.root.clone();
+        drop(state);
+
+        let deleted = self.containers.delete(&request.id, &root);
+        let mut state = self.shared.lock();
+        if let Err(err) = deleted {
+            state.task_mut(&request.id)?.deleting = false;
+            return Err(err);
+        }
+
+        let deadline = Instant::now() + EXIT_GRACE_PERIOD;
+        while state
+            .tasks
+            .get(&request.id)
+            .is_some_and(|task| task.init.exit.is_none())
+        {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                break;
+            }
+            state = self.shared.changed.wait_timeout(state, timeout).unwrap().0;
+        }
+        let task = state
+            .tasks
+            .remove(&request.id)
+            .ok_or_else(|| TaskError::NotFound(request.id.clone()))?;
+        drop(state);
+        self.shared.changed.notify_all();
+
+        let pid = task
+            .init
+            .pid
+            .expect("the init process is created with the task");
+        self.shared.reaper.forget(pid);
+        if task.rootfs_mounted {
+            mount::unmount_rootfs(&task.bundle.join("rootfs"))?;
+        }
+        let response = delete_response(&task.init);
+        self.publish(&TaskDelete {
+            container_id: request.id.clone(),
+            pid: response.pid,
+            exit_status: response.exit_status,
+            exited_at: response.exited_at,
+            id: request.id,
+        });
+        Ok(response)
+    }
+
+    fn state(&self, request: StateRequest) -> Result<StateResponse> {
+        let state = self.shared.lock();
+        let task = state.task(&request.id)?;
+        let process = task.process(&request.id, &request.exec_id)?;
+        let status = match (process.pid, &process.exit) {
+            (_, Some(_)) => TaskStatus::Stopped,
+            (None, None) => TaskStatus::Created,
+            (Some(_), None) if !request.exec_id.is_empty() => TaskStatus::Running,
+            (Some(_), None) => {
+                let mut container = task.load_container(&request.id)?;
+                container.refresh_status()?;
+                match container.status() {
+                    ContainerStatus::Creating => TaskStatus::Unknown,
+                    ContainerStatus::Created => TaskStatus::Created,
+                    ContainerStatus::Running => TaskStatus::Running,
+                    ContainerStatus::Stopped => TaskStatus::Stopped,
+                    ContainerStatus::Paused => TaskStatus::Paused,
+                }
+            }
+        };
+        Ok(StateResponse {
+            id: request.id,
+            bundle: task.bundle.to_string_lossy().into_owned(),
+            pid: process.pid.map_or(0, |pid| pid.as_raw() as u32),
+            status: status as i32,
+            stdin: process.stdio.stdin.clone(),
+            stdout: process.stdio.stdout.clone(),
+            stderr: process.stdio.stderr.clone(),
+            terminal: false,
+            exit_status: process.exit.map_or(0, |exit| exit.status),
+            exited_at: process.exit.map(|exit| exit.at.into()),
+            exec_id: request.exec_id,
+        })
+    }
+
+    fn exec(&self, request: ExecProcessRequest) -> Result<Empty> {
+        if request.terminal {
+            return Err(TaskError::Unimplemented("terminal"));
+        }
+        let spec = request
+            .spec
+            .ok_or_else(|| TaskError::InvalidArgument("exec without a process".into()))?;
+        let mut state = self.shared.lock();
+        let task = state.task_mut(&request.id)?;
+        task.ensure_not_deleting(&request.id)?;
+        if !task.init.is_running() {
+            return Err(TaskError::FailedPrecondition(format!(
+                "task {} is not running",
+                request.id
+            )));
+        }
+        if task.execs.contains_key(&request.exec_id) {
+            return Err(TaskError::AlreadyExists(format!(
+                "process {}",
+                request.exec_id
+            )));
+        }
+        task.execs.insert(
+            request.exec_id.clone(),
+            Process {
+                stdio: Stdio::new(request.stdin, request.stdout, request.stderr),
+                spec: spec.value,
+                ..Default::default()
+            },
+        );
+        drop(state);
+
+        self.publish(&TaskExecAdded {
+            container_id: request.id,
+            exec_id: request.exec_id,
+        });
+        Ok(Empty {})
+    }
+
+    fn kill(&self, request: KillRequest) -> Result<Empty> {
+        let state = self.shared.lock();
+        let task = state.task(&request.id)?;
+        let process = task.process(&request.id, &request.exec_id)?;
+        if process.exit.is_some() && !request.all {
+            return Err(TaskError::NotFound(format!(
+                "process {} already finished",
+                process.pid.map_or(0, Pid::as_raw)
+            )));
+        }
+        let signal = Signal::try_from(request.signal as i32)
+            .map_err(|_| TaskError::InvalidArgument(format!("signal {}", request.signal)))?;
+        if request.exec_id.is_empty() {
+            task.load_container(&request.id)?
+                .kill(signal, request.all)?;
+            return Ok(Empty {});
+        }
+
+        let Some(pid) = process.pid else {
+            return Err(TaskError::FailedPrecondition(format!(
+                "process {} is not started",
+                request.exec_id
+            )));
+        };
+        let signal = signal::Signal::try_from(request.signal as i32)
+            .expect("the signal was already validated");
+        signal::kill(pid, signal).map_err(|source| TaskError::Signal { source, pid })?;
+        Ok(Empty {})
+    }
+
+    /// The process holds the only reading end of its stdin and the shim holds no writing
+    /// end, the process sees the end of the stream once containerd closes its own.
+    fn close_io(&self, request: CloseIoRequest) -> Result<Empty> {
+        let state = self.shared.lock();
+        state
+            .task(&request.id)?
+            .process(&request.id, &request.exec_id)?;
+        Ok(Empty {})
+    }
+
+    fn pids(&self, request: PidsRequest) -> Result<PidsResponse> {
+        let container = self
+            .shared
+            .lock()
+            .task(&request.id)?
+            .load_container(&request.id)?;
+        let pids = cgroup_manager(&container)?.get_all_pids()?;
+        Ok(PidsResponse {
+            processes: pids
+                .into_iter()
+                .map(|pid| ProcessInfo {
+                    pid: pid.as_raw() as u32,
+                    info: None,
+                })
+                .collect(),
+        })
+    }
+
+    fn pause(&self, request: PauseRequest) -> Result<Empty> {
+        self.shared
+            .lock()
+            .task(&request.id)?
+            .load_container(&request.id)?
+            .pause()?;
+        self.publish(&TaskPaused {
+            container_id: request.id,
+        });
+        Ok(Empty {})
+    }
+
+    fn resume(&self, request: ResumeRequest) -> Result<Empty> {
+        self.shared
+            .lock()
+            .task(&request.id)?
+            .load_container(&request.id)?
+            .resume()?;
+        self.publish(&TaskResumed {
+            container_id: request.id,
+        });
+        Ok(Empty {})
+    }
+
+    fn update(&self, request: UpdateTaskRequest) -> Result<Empty> {
+        let resources = request
+            .resources
+            .ok_or_else(|| TaskError::InvalidArgument("update without resources".into()))?;
+        let resources: LinuxResources = serde_json::from_slice(&resources.value)
+            .map_err(|err| TaskError::InvalidArgument(format!("resources: {err}")))?;
+        let container = self
+            .shared
+            .lock()
+            .task(&request.id)?
+            .load_container(&request.id)?;
+        cgroup_manager(&container)?.apply(&ControllerOpt {
+            resources: &resources,
+            disable_oom_killer: false,
+            oom_score_adj: None,
+            freezer_state: None,
+        })?;
+        Ok(Empty {})
+    }
+
+    fn wait(&self, request: WaitRequest) -> Result<WaitResponse> {
+        let mut state = self.shared.lock();
+        loop {
+            let process = state
+                .task(&request.id)?
+                .process(&request.id, &request.exec_id)?;
+            if let Some(exit) = process.exit {
+                return Ok(WaitResponse {
+                    exit_status: exit.status,
+                    exited_at: Some(exit.at.into()),
+                });
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+
+    fn stats(&self, request: StatsRequest) -> Result<StatsResponse> {
+        let container = self
+            .shared
+            .lock()
+            .task(&request.id)?
+            .load_container(&request.id)?;
+        let cgroup_manager = cgroup_manager(&container)?;
+        let stats = cgroup_stats(&cgroup_manager)?;
+        let cgroup_v1 = matches!(cgroup_manager, AnyCgroupManager::V1(_));
+        Ok(StatsResponse {
+            stats: Some(metrics::to_any(&stats, cgroup_v1)),
+        })
+    }
+
+    fn connect(&self, request: ConnectRequest) -> Result<ConnectResponse> {
+        let state = self.shared.lock();
+        let task_pid = match state.tasks.get(&request.id) {
+            Some(task) => task.init.pid.map_or(0, |pid| pid.as_raw() as u32),
+            None => 0,
+        };
+        Ok(ConnectResponse {
+            shim_pid: std::process::id(),
+            task_pid,
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+        })
+    }
+
+    fn shutdown(&self, request: ShutdownRequest) -> Result<Empty> {
+        let mut state = self.shared.lock();
+        // the shim serves the other tasks of its group
+        let idle = state.tasks.is_empty() && state.creating.is_empty();
+        if !request.now && !idle {
+            return Ok(Empty {});
+        }
+        state.shutdown = true;
+        drop(state);
+        self.shared.changed.notify_all();
+        Ok(Empty {})
+    }
+
+    /// Decodes the request, calls the method and encodes its response.
+    fn call<Req, Resp>(
+        &self,
+        payload: &[u8],
+        method: fn(&Self, Req) -> Result<Resp>,
+    ) -> std::result::Result<Vec<u8>, Status>
+    where
+        Req: Message + Default,
+        Resp: Message,
+    {
+        let request = decode(payload)?;
+        Ok(method(self, request)?.encode_to_vec())
+    }
+}
+
+impl Handler for TaskService {
+    fn handle(
+        &self,
+        service: &str,
+        method: &str,
+        payload: &[u8],
+    ) -> std::result::Result<Vec<u8>, Status> {
+        if service != TASK_SERVICE {
+            return Err(Status::new(
+                Code::Unimplemented,
+                format!("unknown service {service}"),
+            ));
+        }
+        log::debug!("{method}");
+        match method {
+            "State" => self.call(payload, Self::state),
+            "Create" => self.call(payload, Self::create),
+            "Start" => self.call(payload, Self::start),
+            "Delete" => self.call(payload, Self::delete),
+            "Pids" => self.call(payload, Self::pids),
+            "Pause" => self.call(payload, Self::pause),
+            "Resume" => self.call(payload, Self::resume),
+            "Kill" => self.call(payload, Self::kill),
+            "Exec" => self.call(payload, Self::exec),
+            "Update" => self.call(payload, Self::update),
+            "Wait" => self.call(payload, Self::wait),
+            "Stats" => self.call(payload, Self::stats),
+            "Connect" => self.call(payload, Self::connect),
+            "Shutdown" => self.call(payload, Self::shutdown),
+            "CloseIO" => self.call(payload, Self::close_io),
+            "Checkpoint" | "ResizePty" => Err(Status::new(
+                Code::Unimplemented,
+                format!("{method} is not supported"),
+            )),
+            _ => Err(Status::new(
+                Code::Unimplemented,
+                format!("unknown method {method}"),
+            )),
+        }
+    }
+}
+
+fn delete_response(process: &Process) -> DeleteResponse {
+    // a process that never reported its exit was killed with the task
+    let (exit_status, exited_at) = match process.exit {
+        Some(exit) => (exit.status, exit.at),
+        None => (KILLED_EXIT_STATUS, SystemTime::now()),
+    };
+    DeleteResponse {
+        pid: process.pid.map_or(0, |pid| pid.as_raw() as u32),
+        exit_status,
+        exited_at: Some(exited_at.into()),
+    }
+}
+
+/// Removes the state and the leases a failed creation left behind, the container process
+/// did not start.
+fn remove_container(root: &Path, id: &str) {
+    let container_dir = root.join(id);
+    if container_dir.exists() {
+        if let Err(err) = fs::remove_dir_all(&container_dir) {
+            log::warn!("failed to remove {}: {err}", container_dir.display());
+        }
+    }
+    if let Err(err) = LeaseLedger::new(root).release(id) {
+        log::warn!("failed to release the leases of task {id}: {err}");
+    }
+}
+
+fn cgroup_manager(container: &Container) -> Result<AnyCgroupManager> {
+    libcgroups::common::create_cgroup_manager(CgroupConfig {
+        cgroup_path: container.spec()?.cgroup_path,
+        systemd_cgroup: container.systemd(),
+        container_name: container.id().to_owned(),
+    })
+    .map_err(|err| LibcontainerError::from(err).into())
+}
+
+/// Starts `tpu-container-runtime usb-follow` if USB accelerators were leased to the task,
+/// so that they stay in the container when they re-enumerate.
+fn follow_usb_tpus(root: &Path, id: &str) -> anyhow::Result<()> {
+    let leases = LeaseLedger::new(root).leases()?;
+    if !leases
+        .iter()
+        .any(|lease| lease.container_id == id && lease.device.starts_with(USB_LEASE_PREFIX))
+    {
+        return Ok(());
+    }
+
+    // installed next to the shim, or on the path
+    let runtime = std::env::current_exe()
+        .ok()
+        .and_then(|shim| Some(shim.parent()?.join("tpu-container-runtime")))
+        .filter(|runtime| runtime.exists())
+        .unwrap_or_else(|| PathBuf::from("tpu-container-runtime"));
+    let mut child = Command::new(runtime)
+        .arg("--root")
+        .arg(root)
+        .arg("usb-follow")
+        .arg(id)
+        .stdin(ProcessStdio::null())
+        .stdout(ProcessStdio::null())
+        .stderr(ProcessStdio::null())
+        .spawn()
+        .context("failed to start following the USB Edge TPUs")?;
+    log::debug!(
+        "following the USB Edge TPUs of task {id} in process {}",
+        child.id()
+    );
+    thread::spawn(move || child.wait());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::net::UnixListener;
+    use std::process::ChildStdin;
+
+    use super::*;
+    use crate::ttrpc::{Client, Server, TtrpcError};
+
+    /// Records the published events
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<(String, Any)>>);
+
+    impl Recorder {
+        fn topics(&self) -> Vec<String> {
+            let events = self.0.lock().unwrap();
+            events.iter().map(|(topic, _)| topic.clone()).collect()
+        }
+    }
+
+    impl Publisher for Recorder {
+        fn publish(&self, topic: &str, event: Any) -> std::result::Result<(), TtrpcError> {
+            self.0.lock().unwrap().push((topic.to_owned(), event));
+            Ok(())
+        }
+    }
+
+    /// Runs the init processes as `sh` children of the test that exit with status 7 once
+    /// started
+    #[derive(Default)]
+    struct StubContainers {
+        /// The stdin of the created processes, a line starts them
+        created: Mutex<HashMap<String, ChildStdin>>,
+    }
+
+    impl Containers for StubContainers {
+        // the processes are reaped by the service, not by std
+        #[allow(clippy::zombie_processes)]
+        fn create(&self, id: &str, _: &Path, _: &Path, _: &RuncOptions, _: &Stdio) -> Result<Pid> {
+            let child = Command::new("sh")
+                .args(["-c", "read _; exit 7"])
+                .stdin(ProcessStdio::piped())
+                .spawn()
+                .unwrap();
+            let pid = Pid::from_raw(child.id() as i32);
+            let stdin = child.stdin.unwrap();
+            self.created.lock().unwrap().insert(id.to_owned(), stdin);
+            Ok(pid)
+        }
+
+        fn start(&self, id: &str, _: &Path) -> Result<()> {
+            let mut stdin = self.created.lock().unwrap().remove(id).unwrap();
+            writeln!(stdin).unwrap();
+            Ok(())
+        }
+
+        fn exec(&self, _: &str, _: &str, _: &Path, _: &[u8], _: &Stdio) -> Result<Pid> {
+            Err(TaskError::Unimplemented("exec"))
+        }
+
+        fn delete(&self, _: &str, _: &Path) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn serve(dir: &Path) -> (Arc<TaskService>, Arc<Recorder>, Client) {
+        serve_with(dir, Libcontainer)
+    }
+
+    fn serve_with(
+        dir: &Path,
+        containers: impl Containers + 'static,
+    ) -> (Arc<TaskService>, Arc<Recorder>, Client) {
+        let recorder = Arc::new(Recorder::default());
+        let service = Arc::new(
+            TaskService::new("default", Arc::clone(&recorder) as Arc<dyn Publisher>)
+                .with_root(dir.join("root"))
+                .with_containers(containers),
+        );
+        let socket = dir.join("shim.sock");
+        let server = Server::new(
+            UnixListener::bind(&socket).unwrap(),
+            Arc::clone(&service) as Arc<dyn Handler>,
+        );
+        thread::spawn(move || server.serve());
+        (service, recorder, Client::connect(&socket).unwrap())
+    }
+
+    fn status_code<T: std::fmt::Debug>(result: std::result::Result<T, TtrpcError>) -> Code {
+        match result {
+            Err(TtrpcError::Status(status)) => status.code(),
+            other => panic!("unexpected result {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_task_service() {
+        let dir = tempfile::tempdir().unwrap();
+        let (service, recorder, mut client) = serve(dir.path());
+
+        let connected: ConnectResponse = client
+            .call(TASK_SERVICE, "Connect", &ConnectRequest::default())
+            .unwrap();
+        assert_eq!(connected.shim_pid, std::process::id());
+        assert_eq!(connected.task_pid, 0);
+
+        let state = StateRequest {
+            id: "missing".to_owned(),
+            ..Default::default()
+        };
+        let result = client.call::<_, StateResponse>(TASK_SERVICE, "State", &state);
+        assert_eq!(status_code(result), Code::NotFound);
+        let wait = WaitRequest {
+            id: "missing".to_owned(),
+            ..Default::default()
+        };
+        let result = client.call::<_, WaitResponse>(TASK_SERVICE, "Wait", &wait);
+        assert_eq!(status_code(result), Code::NotFound);
+        let result = client.call::<_, Empty>(TASK_SERVICE, "ResizePty", &Empty {});
+        assert_eq!(status_code(result), Code::Unimplemented);
+        let close_io = CloseIoRequest {
+            id: "missing".to_owned(),
+            stdin: true,
+            ..Default::default()
+        };
+        let result = client.call::<_, Empty>(TASK_SERVICE, "CloseIO", &close_io);
+        assert_eq!(status_code(result), Code::NotFound);
+
+        let terminal = CreateTaskRequest {
+            id: "tty".to_owned(),
+            bundle: dir.path().display().to_string(),
+            terminal: true,
+            ..Default::default()
+        };
+        let result = client.call::<_, CreateTaskResponse>(TASK_SERVICE, "Create", &terminal);
+        assert_eq!(status_code(result), Code::Unimplemented);
+
+        // the bundle has no config.json
+        let create = CreateTaskRequest {
+            id: "nobundle".to_owned(),
+            bundle: dir.path().join("missing").display().to_string(),
+            ..Default::default()
+        };
+        let result = client.call::<_, CreateTaskResponse>(TASK_SERVICE, "Create", &create);
+        assert!(matches!(result, Err(TtrpcError::Status(_))));
+        // the id is not held by the failed creation
+        let result = client.call::<_, CreateTaskResponse>(TASK_SERVICE, "Create", &create);
+        assert_eq!(status_code(result), Code::Unknown);
+        assert!(recorder.0.lock().unwrap().is_empty());
+
+        // the state of another container with the id is left alone
+        let bundle = dir.path().join("bundle");
+        fs::create_dir_all(bundle.join("rootfs")).unwrap();
+        libcontainer::oci_spec::runtime::Spec::default()
+            .save(bundle.join("config.json"))
+            .unwrap();
+        let taken = dir.path().join("root/default/taken");
+        fs::create_dir_all(&taken).unwrap();
+        let create = CreateTaskRequest {
+            id: "taken".to_owned(),
+            bundle: bundle.display().to_string(),
+            ..Default::default()
+        };
+        let result = client.call::<_, CreateTaskResponse>(TASK_SERVICE, "Create", &create);
+        assert_eq!(status_code(result), Code::AlreadyExists);
+        assert!(taken.exists());
+
+        let shutdown = ShutdownRequest {
+            id: "nobundle".to_owned(),
+            now: false,
+        };
+        let _: Empty = client.call(TASK_SERVICE, "Shutdown", &shutdown).unwrap();
+        service.wait_shutdown();
+    }
+
+    #[test]
+    fn test_task_lifecycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_service, recorder, mut client) = serve_with(dir.path(), StubContainers::default());
+        let bundle = dir.path().join("bundle");
+        fs::create_dir_all(&bundle).unwrap();
+
+        let create = CreateTaskRequest {
+            id: "task".to_owned(),
+            bundle: bundle.display().to_string(),
+            ..Default::default()
+        };
+        let created: CreateTaskResponse = client.call(TASK_SERVICE, "Create", &create).unwrap();
+        let start = StartRequest {
+            id: "task".to_owned(),
+            ..Default::default()
+        };
+        let started: StartResponse = client.call(TASK_SERVICE, "Start", &start).unwrap();
+        assert_eq!(started.pid, created.pid);
+
+        let wait = WaitRequest {
+            id: "task".to_owned(),
+            ..Default::default()
+        };
+        let waited: WaitResponse = client.call(TASK_SERVICE, "Wait", &wait).unwrap();
+        assert_eq!(waited.exit_status, 7);
+
+        let delete = DeleteRequest {
+            id: "task".to_owned(),
+            ..Default::default()
+        };
+        let deleted: DeleteResponse = client.call(TASK_SERVICE, "Delete", &delete).unwrap();
+        assert_eq!((deleted.pid, deleted.exit_status), (created.pid, 7));
+        assert_eq!(deleted.exited_at, waited.exited_at);
+        let state = StateRequest {
+            id: "task".to_owned(),
+            ..Default::default()
+        };
+        let result = client.call::<_, StateResponse>(TASK_SERVICE, "State", &state);
+        assert_eq!(status_code(result), Code::NotFound);
+
+        assert_eq!(
+            recorder.topics(),
+            vec![
+                "/tasks/create",
+                "/tasks/start",
+                "/tasks/exit",
+                "/tasks/delete"
+            ]
+        );
+        let events = recorder.0.lock().unwrap();
+        let exit = TaskExit::decode(events[2].1.value.as_slice()).unwrap();
+        assert_eq!(
+            exit,
+            TaskExit {
+                container_id: "task".to_owned(),
+                id: "task".to_owned(),
+                pid: created.pid,
+                exit_status: 7,
+                exited_at: waited.exited_at,
+            }
+        );
+    }
+}
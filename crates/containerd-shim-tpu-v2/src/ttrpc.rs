@@ -0,0 +1,466 @@
+//! The subset of ttrpc containerd talks to its shims with.
+//!
+//! ttrpc is gRPC without HTTP/2: every message is a 10 bytes header, the big endian length
+//! of the payload and stream id, the message type and flags, followed by a protobuf
+//! [`Request`] or [`Response`]. Clients use odd stream ids and may send a request before
+//! the previous one is answered. Streams are not supported, no method of the Task service
+//! needs them.
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use prost::Message;
+
+use crate::api::Any;
+
+/// Largest message containerd sends or accepts
+pub const MAX_MESSAGE_SIZE: usize = 4 << 20;
+const HEADER_SIZE: usize = 10;
+const MESSAGE_TYPE_REQUEST: u8 = 1;
+const MESSAGE_TYPE_RESPONSE: u8 = 2;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TtrpcError {
+    #[error("ttrpc connection failed")]
+    Io(#[from] io::Error),
+    #[error("connection closed")]
+    Closed,
+    #[error("message of {0} bytes is too large")]
+    TooLarge(usize),
+    #[error("unexpected message type {0}")]
+    UnexpectedType(u8),
+    #[error("malformed ttrpc message")]
+    Decode(#[from] prost::DecodeError),
+    #[error("{}: {}", .0.code(), .0.message)]
+    Status(Status),
+}
+
+type Result<T> = std::result::Result<T, TtrpcError>;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Request {
+    #[prost(string, tag = "1")]
+    pub service: String,
+    #[prost(string, tag = "2")]
+    pub method: String,
+    #[prost(bytes = "vec", tag = "3")]
+    pub payload: Vec<u8>,
+    #[prost(int64, tag = "4")]
+    pub timeout_nano: i64,
+    #[prost(message, repeated, tag = "5")]
+    pub metadata: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct KeyValue {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Response {
+    #[prost(message, optional, tag = "1")]
+    pub status: Option<Status>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub payload: Vec<u8>,
+}
+
+/// `google.rpc.Status`, the error of a call
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Status {
+    #[prost(int32, tag = "1")]
+    pub code: i32,
+    #[prost(string, tag = "2")]
+    pub message: String,
+    #[prost(message, repeated, tag = "3")]
+    pub details: Vec<Any>,
+}
+
+impl Status {
+    pub fn new<S: Into<String>>(code: Code, message: S) -> Self {
+        Self {
+            code: code as i32,
+            message: message.into(),
+            details: Vec::new(),
+        }
+    }
+
+    pub fn code(&self) -> Code {
+        Code::from_i32(self.code)
+    }
+}
+
+/// The gRPC status codes containerd maps to its errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Code {
+    Ok = 0,
+    Unknown = 2,
+    InvalidArgument = 3,
+    NotFound = 5,
+    AlreadyExists = 6,
+    FailedPrecondition = 9,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+}
+
+impl Code {
+    fn from_i32(code: i32) -> Self {
+        match code {
+            0 => Self::Ok,
+            3 => Self::InvalidArgument,
+            5 => Self::NotFound,
+            6 => Self::AlreadyExists,
+            9 => Self::FailedPrecondition,
+            12 => Self::Unimplemented,
+            13 => Self::Internal,
+            14 => Self::Unavailable,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl std::fmt::Display for Code {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// Answers the requests of a [`Server`]. Every request is handled on its own thread.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(
+        &self,
+        service: &str,
+        method: &str,
+        payload: &[u8],
+    ) -> std::result::Result<Vec<u8>, Status>;
+}
+
+/// Decodes the payload of a request, a malformed one is an invalid argument
+pub fn decode<M: Message + Default>(payload: &[u8]) -> std::result::Result<M, Status> {
+    M::decode(payload).map_err(|err| Status::new(Code::InvalidArgument, err.to_string()))
+}
+
+fn read_message(stream: &mut impl Read) -> Result<(u32, u8, Vec<u8>)> {
+    let mut header = [0; HEADER_SIZE];
+    match stream.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Err(TtrpcError::Closed),
+        Err(err) => return Err(err.into()),
+    }
+    let length = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+    let stream_id = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    if length > MAX_MESSAGE_SIZE {
+        return Err(TtrpcError::TooLarge(length));
+    }
+    let mut payload = vec![0; length];
+    stream.read_exact(&mut payload)?;
+    Ok((stream_id, header[8], payload))
+}
+
+fn write_message(
+    stream: &mut impl Write,
+    stream_id: u32,
+    message_type: u8,
+    payload: &[u8],
+) -> Result<()> {
+    if payload.len() > MAX_MESSAGE_SIZE {
+        return Err(TtrpcError::TooLarge(payload.len()));
+    }
+    let mut message = Vec::with_capacity(HEADER_SIZE + payload.len());
+    message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    message.extend_from_slice(&stream_id.to_be_bytes());
+    message.extend_from_slice(&[message_type, 0]);
+    message.extend_from_slice(payload);
+    stream.write_all(&message)?;
+    Ok(())
+}
+
+/// Counts the requests being answered, so that the shim answers them before it exits.
+#[derive(Clone, Default)]
+pub struct ActiveRequests(Arc<(Mutex<usize>, Condvar)>);
+
+impl ActiveRequests {
+    fn begin(&self) {
+        *self.0 .0.lock().unwrap() += 1;
+    }
+
+    fn end(&self) {
+        *self.0 .0.lock().unwrap() -= 1;
+        self.0 .1.notify_all();
+    }
+
+    /// Waits at most `timeout` for all the requests to be answered, returns whether they were.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let (active, idle) = &*self.0;
+        let active = active.lock().unwrap();
+        let (active, _) = idle
+            .wait_timeout_while(active, timeout, |active| *active > 0)
+            .unwrap();
+        *active == 0
+    }
+}
+
+/// Serves a [`Handler`] on a unix socket.
+pub struct Server {
+    listener: UnixListener,
+    handler: Arc<dyn Handler>,
+    active: ActiveRequests,
+}
+
+impl Server {
+    pub fn new(listener: UnixListener, handler: Arc<dyn Handler>) -> Self {
+        Self {
+            listener,
+            handler,
+            active: ActiveRequests::default(),
+        }
+    }
+
+    pub fn active_requests(&self) -> ActiveRequests {
+        self.active.clone()
+    }
+
+    /// Accepts connections until the listener fails, serving each on its own thread.
+    pub fn serve(self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let handler = Arc::clone(&self.handler);
+            let active = self.active.clone();
+            thread::spawn(move || match serve_connection(stream, handler, active) {
+                Ok(()) | Err(TtrpcError::Closed) => {}
+                Err(err) => log::warn!("ttrpc connection failed: {err}"),
+            });
+        }
+        Ok(())
+    }
+}
+
+fn serve_connection(
+    mut stream: UnixStream,
+    handler: Arc<dyn Handler>,
+    active: ActiveRequests,
+) -> Result<()> {
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    loop {
+        let (stream_id, message_type, payload) = read_message(&mut stream)?;
+        if message_type != MESSAGE_TYPE_REQUEST {
+            return Err(TtrpcError::UnexpectedType(message_type));
+        }
+        let request = Request::decode(payload.as_slice())?;
+        let handler = Arc::clone(&handler);
+        let writer = Arc::clone(&writer);
+        let active = active.clone();
+        active.begin();
+        // Wait blocks until the process exits, the other requests must not wait for it
+        thread::spawn(move || {
+            let response = match handler.handle(&request.service, &request.method, &request.payload)
+            {
+                Ok(payload) => Response {
+                    status: None,
+                    payload,
+                },
+                Err(status) => {
+                    log::debug!(
+                        "{}/{} failed: {}",
+                        request.service,
+                        request.method,
+                        status.message
+                    );
+                    Response {
+                        status: Some(status),
+                        payload: Vec::new(),
+                    }
+                }
+            };
+            let mut writer = writer.lock().unwrap();
+            if let Err(err) = write_message(
+                &mut *writer,
+                stream_id,
+                MESSAGE_TYPE_RESPONSE,
+                &response.encode_to_vec(),
+            ) {
+                log::warn!(
+                    "failed to answer {}/{}: {err}",
+                    request.service,
+                    request.method
+                );
+            }
+            drop(writer);
+            active.end();
+        });
+    }
+}
+
+/// Calls the methods of a ttrpc service, one at a time.
+pub struct Client {
+    stream: UnixStream,
+    next_stream_id: u32,
+}
+
+impl Client {
+    /// Connects to `path`, or to the path of a `unix://` address
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let path = path
+            .to_str()
+            .and_then(|address| address.strip_prefix("unix://"))
+            .map_or(path, Path::new);
+        Ok(Self {
+            stream: UnixStream::connect(path)?,
+            next_stream_id: 1,
+        })
+    }
+
+    pub fn call<Req: Message, Resp: Message + Default>(
+        &mut self,
+        service: &str,
+        method: &str,
+        request: &Req,
+    ) -> Result<Resp> {
+        let stream_id = self.next_stream_id;
+        self.next_stream_id += 2;
+        let request = Request {
+            service: service.to_owned(),
+            method: method.to_owned(),
+            payload: request.encode_to_vec(),
+            ..Default::default()
+        };
+        write_message(
+            &mut self.stream,
+            stream_id,
+            MESSAGE_TYPE_REQUEST,
+            &request.encode_to_vec(),
+        )?;
+
+        loop {
+            let (id, message_type, payload) = read_message(&mut self.stream)?;
+            if message_type != MESSAGE_TYPE_RESPONSE {
+                return Err(TtrpcError::UnexpectedType(message_type));
+            }
+            // the answer to a call that failed on our side
+            if id != stream_id {
+                continue;
+            }
+            let response = Response::decode(payload.as_slice())?;
+            return match response.status {
+                Some(status) if status.code() != Code::Ok => Err(TtrpcError::Status(status)),
+                _ => Ok(Resp::decode(response.payload.as_slice())?),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::api::Empty;
+
+    /// Echoes `Echo`, answers `Block` once `Release` was called
+    struct Echo {
+        released: Mutex<Option<mpsc::Receiver<()>>>,
+        release: Mutex<mpsc::Sender<()>>,
+    }
+
+    impl Handler for Echo {
+        fn handle(
+            &self,
+            service: &str,
+            method: &str,
+            payload: &[u8],
+        ) -> std::result::Result<Vec<u8>, Status> {
+            match (service, method) {
+                ("test.Echo", "Echo") => Ok(payload.to_vec()),
+                ("test.Echo", "Block") => {
+                    let released = self.released.lock().unwrap().take().unwrap();
+                    released.recv().unwrap();
+                    Ok(Vec::new())
+                }
+                ("test.Echo", "Release") => {
+                    self.release.lock().unwrap().send(()).unwrap();
+                    Ok(Vec::new())
+                }
+                _ => Err(Status::new(
+                    Code::Unimplemented,
+                    format!("{service}/{method}"),
+                )),
+            }
+        }
+    }
+
+    fn start_server(path: &Path) {
+        let (release, released) = mpsc::channel();
+        let handler = Echo {
+            released: Mutex::new(Some(released)),
+            release: Mutex::new(release),
+        };
+        let server = Server::new(UnixListener::bind(path).unwrap(), Arc::new(handler));
+        thread::spawn(move || server.serve());
+    }
+
+    #[test]
+    fn test_call() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("ttrpc.sock");
+        start_server(&socket);
+
+        let mut client = Client::connect(format!("unix://{}", socket.display())).unwrap();
+        let any = Any {
+            type_url: "test".to_owned(),
+            value: vec![1, 2, 3],
+        };
+        let echoed: Any = client.call("test.Echo", "Echo", &any).unwrap();
+        assert_eq!(echoed, any);
+
+        let result: Result<Empty> = client.call("test.Echo", "Other", &Empty {});
+        assert!(matches!(
+            result,
+            Err(TtrpcError::Status(ref status))
+                if status.code() == Code::Unimplemented && status.message == "test.Echo/Other"
+        ));
+        // the connection is still usable after an error
+        let echoed: Any = client.call("test.Echo", "Echo", &any).unwrap();
+        assert_eq!(echoed, any);
+    }
+
+    #[test]
+    fn test_blocked_call_does_not_block_the_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("ttrpc.sock");
+        start_server(&socket);
+
+        // a blocked request and another one on the same connection
+        let mut stream = UnixStream::connect(&socket).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        for (stream_id, method) in [(1, "Block"), (3, "Release")] {
+            let request = Request {
+                service: "test.Echo".to_owned(),
+                method: method.to_owned(),
+                ..Default::default()
+            };
+            write_message(
+                &mut stream,
+                stream_id,
+                MESSAGE_TYPE_REQUEST,
+                &request.encode_to_vec(),
+            )
+            .unwrap();
+        }
+
+        // releasing the blocked call races with answering the release, either comes first
+        let mut answered: Vec<_> = (0..2)
+            .map(|_| read_message(&mut stream).unwrap().0)
+            .collect();
+        answered.sort_unstable();
+        assert_eq!(answered, vec![1, 3]);
+    }
+}
@@ -1,11 +1,12 @@
 use clap::{arg, ArgMatches, Command};
-use edgetpu::dep::download::download_edgetpu_runtime;
-use edgetpu::dep::install::run_install;
+use edgetpu::dep::download::{Downloader, Manifest, DEFAULT_RELEASE};
+use edgetpu::dep::install::{run_install, InstallOptions};
+use edgetpu::dep::udev::{UdevRules, DEFAULT_CONFIG_PATH};
 use edgetpu::dep::util::init_logger;
 use log::info;
 use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize the logger
@@ -40,6 +41,21 @@ fn get_arguments() -> ArgMatches {
             .value_parser(["normal", "max"])
             .default_value("normal"),
         )
+        .arg(arg!(--mirror <URL> "Base URL of the runtime releases").required(false))
+        .arg(arg!(--manifest <FILE> "SHA-256 manifest of the runtime releases").required(false))
+        .arg(arg!(--sha256 <DIGEST> "SHA-256 of the runtime archive").required(false))
+        .arg(arg!(--"cache-dir" <DIR> "Directory verified archives are kept in").required(false))
+        .arg(
+            arg!(--offline <ZIP> "Install from a runtime archive instead of downloading it")
+                .required(false),
+        )
+        .arg(arg!(--"allow-unverified" "Installs an --offline archive no SHA-256 is pinned for"))
+        .arg(arg!(--root <DIR> "Installs under this directory instead of /").required(false))
+        .arg(arg!(--"dry-run" "Prints the install plan without changing anything"))
+        .arg(arg!(--"install-packages" "Installs the missing libraries with apt, dnf or apk"))
+        .arg(arg!(--hints "Tells how to install the missing libraries"))
+        .arg(arg!(--"udev-config" <FILE> "Configuration of the udev rules").required(false))
+        .arg(arg!(--group <GROUP> "Group owning the Edge TPU devices").required(false))
         .get_matches()
 }
 
@@ -52,10 +68,50 @@ fn execute_installation(
     download_path: &PathBuf,
     args: &ArgMatches,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let runtime_dir = download_edgetpu_runtime(download_path)?;
+    let mut downloader = Downloader::new();
+    if let Some(mirror) = args.get_one::<String>("mirror") {
+        downloader = downloader.with_mirror(mirror);
+    }
+    if let Some(manifest) = args.get_one::<String>("manifest") {
+        downloader = downloader.with_manifest(Manifest::load(manifest)?);
+    }
+    let offline = args.get_one::<String>("offline").map(Path::new);
+    if let Some(sha256) = args.get_one::<String>("sha256") {
+        // pins the archive installed, whatever its name
+        if let Some(file) = offline.and_then(Path::file_name) {
+            downloader = downloader.with_release(DEFAULT_RELEASE, file.to_string_lossy());
+        }
+        downloader = downloader.with_sha256(sha256);
+    }
+    downloader = downloader.with_allow_unverified(args.get_flag("allow-unverified"));
+    if let Some(cache_dir) = args.get_one::<String>("cache-dir") {
+        downloader = downloader.with_cache_dir(cache_dir);
+    }
+    let runtime_dir = match offline {
+        Some(archive) => downloader.extract_offline(archive, download_path)?,
+        None => downloader.download_to(download_path)?,
+    };
     let freq = args.get_one::<String>("freq").unwrap();
 
-    run_install(runtime_dir, freq == "max")
+    let mut options = InstallOptions {
+        dry_run: args.get_flag("dry-run"),
+        install_packages: args.get_flag("install-packages"),
+        hints: args.get_flag("hints"),
+        ..Default::default()
+    };
+    if let Some(root) = args.get_one::<String>("root") {
+        options.root = PathBuf::from(root);
+    }
+    let udev_config = args
+        .get_one::<String>("udev-config")
+        .map(String::as_str)
+        .unwrap_or(DEFAULT_CONFIG_PATH);
+    options.udev = UdevRules::load(udev_config)?;
+    if let Some(group) = args.get_one::<String>("group") {
+        options.udev = options.udev.with_group(group);
+    }
+
+    run_install(runtime_dir, freq == "max", &options)
 }
 
 fn cleanup(download_path: &PathBuf) {
@@ -1,9 +1,25 @@
+use clap::{arg, Command};
+use edgetpu::dep::install::InstallOptions;
 use edgetpu::dep::uninstall::run_uninstall;
 use edgetpu::dep::util::init_logger;
+use std::path::PathBuf;
 
 pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize the logger
     init_logger();
 
-    run_uninstall()
+    let args = Command::new("Edge TPU Uninstaller")
+        .about("Removes what the Edge TPU installer installed")
+        .arg(arg!(--root <DIR> "Uninstalls from this directory instead of /").required(false))
+        .arg(arg!(--"dry-run" "Prints the uninstall plan without changing anything"))
+        .get_matches();
+    let mut options = InstallOptions {
+        dry_run: args.get_flag("dry-run"),
+        ..Default::default()
+    };
+    if let Some(root) = args.get_one::<String>("root") {
+        options.root = PathBuf::from(root);
+    }
+
+    run_uninstall(&options)
 }
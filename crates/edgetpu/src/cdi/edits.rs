@@ -0,0 +1,542 @@
+//! Merging of CDI container edits and their application to an OCI runtime spec.
+//!
+//! Edits of the requested devices are first merged together: two devices that disagree on
+//! the value of an environment variable, on a device node, on a mount or on the Intel RDT
+//! settings are a conflict and nothing is applied. The merged edits then override whatever
+//! the spec already contains for the same variable, device path or mount destination, as the
+//! CDI specification requires.
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+
+use libcontainer::oci_spec::runtime::{
+    Hook as OciHook, HookBuilder, Hooks, Linux, LinuxBuilder, LinuxDevice, LinuxDeviceBuilder,
+    LinuxDeviceCgroup, LinuxDeviceCgroupBuilder, LinuxDeviceType, LinuxIntelRdtBuilder,
+    LinuxResources, Mount as OciMount, MountBuilder, Process, Spec as OciSpec,
+};
+use nix::sys::stat::{major, minor};
+
+use super::spec::{ContainerEdits, DeviceNode, Hook, IntelRdt, Mount};
+use super::CdiError;
+
+const DEFAULT_PERMISSIONS: &str = "rwm";
+
+/// Names of the OCI hooks a CDI hook may be attached to.
+pub(super) const HOOK_NAMES: [&str; 6] = [
+    "prestart",
+    "createRuntime",
+    "createContainer",
+    "startContainer",
+    "poststart",
+    "poststop",
+];
+
+/// Accumulates the edits of several devices, remembering which device contributed each
+/// entry to report conflicts.
+#[derive(Debug, Default)]
+pub(super) struct EditsMerger {
+    edits: ContainerEdits,
+    owners: HashMap<(&'static str, String), String>,
+}
+
+impl EditsMerger {
+    pub fn add(&mut self, owner: &str, edits: &ContainerEdits) -> Result<(), CdiError> {
+        for env in edits.env.iter().flatten() {
+            let name = env_name(env);
+            let existing = self
+                .edits
+                .env
+                .iter()
+                .flatten()
+                .find(|e| env_name(e) == name)
+                .map(|e| e == env);
+            if self.claim("environment variable", name, owner, existing)? {
+                self.edits
+                    .env
+                    .get_or_insert_with(Vec::new)
+                    .push(env.clone());
+            }
+        }
+
+        for node in edits.device_nodes.iter().flatten() {
+            let existing = self
+                .edits
+                .device_nodes
+                .iter()
+                .flatten()
+                .find(|n| n.path == node.path)
+                .map(|n| n == node);
+            if self.claim("device node", &node.path, owner, existing)? {
+                self.edits
+                    .device_nodes
+                    .get_or_insert_with(Vec::new)
+                    .push(node.clone());
+            }
+        }
+
+        for mount in edits.mounts.iter().flatten() {
+            let existing = self
+                .edits
+                .mounts
+                .iter()
+                .flatten()
+                .find(|m| m.container_path == mount.container_path)
+                .map(|m| m == mount);
+            if self.claim("mount", &mount.container_path, owner, existing)? {
+                self.edits
+                    .mounts
+                    .get_or_insert_with(Vec::new)
+                    .push(mount.clone());
+            }
+        }
+
+        for hook in edits.hooks.iter().flatten() {
+            let merged = self.edits.hooks.get_or_insert_with(Vec::new);
+            if !merged.contains(hook) {
+                merged.push(hook.clone());
+            }
+        }
+
+        if let Some(intel_rdt) = &edits.intel_rdt {
+            let existing = self.edits.intel_rdt.as_ref().map(|r| r == intel_rdt);
+            if self.claim("Intel RDT configuration", "intelRdt", owner, existing)? {
+                self.edits.intel_rdt = Some(intel_rdt.clone());
+            }
+        }
+
+        for gid in edits.additional_gids.iter().flatten() {
+            let merged = self.edits.additional_gids.get_or_insert_with(Vec::new);
+            if !merged.contains(gid) {
+                merged.push(*gid);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> ContainerEdits {
+        self.edits
+    }
+
+    /// Records `owner` as the source of the entry. `existing` tells whether an entry with the
+    /// same key was already merged and, if so, whether it is identical. Returns whether the
+    /// entry still has to be added.
+    fn claim(
+        &mut self,
+        kind: &'static str,
+        key: &str,
+        owner: &str,
+        existing: Option<bool>,
+    ) -> Result<bool, CdiError> {
+        match existing {
+            None => {
+                self.owners.insert((kind, key.to_owned()), owner.to_owned());
+                Ok(true)
+            }
+            Some(true) => Ok(false),
+            Some(false) => Err(CdiError::Conflict {
+                kind,
+                key: key.to_owned(),
+                first: self
+                    .owners
+                    .get(&(kind, key.to_owned()))
+                    .cloned()
+                    .unwrap_or_default(),
+                second: owner.to_owned(),
+            }),
+        }
+    }
+}
+
+/// Applies merged container edits to the runtime spec, all of them or none.
+pub(super) fn apply(edits: &ContainerEdits, spec: &mut OciSpec) -> Result<(), CdiError> {
+    let mut edited = spec.clone();
+    apply_all(edits, &mut edited)?;
+    *spec = edited;
+    Ok(())
+}
+
+fn apply_all(edits: &ContainerEdits, spec: &mut OciSpec) -> Result<(), CdiError> {
+    for env in edits.env.iter().flatten() {
+        set_env(spec, env);
+    }
+
+    for node in edits.device_nodes.iter().flatten() {
+        add_device_node(spec, node)?;
+    }
+
+    for mount in edits.mounts.iter().flatten() {
+        add_mount(spec, mount)?;
+    }
+
+    for hook in edits.hooks.iter().flatten() {
+        add_hook(spec, hook)?;
+    }
+
+    if let Some(intel_rdt) = &edits.intel_rdt {
+        set_intel_rdt(spec, intel_rdt)?;
+    }
+
+    for gid in edits.additional_gids.iter().flatten() {
+        // the root group is never handed out through CDI
+        if *gid == 0 {
+            return Err(CdiError::InvalidEdits(
+                "additional GID 0, the root group, is not allowed".to_owned(),
+            ));
+        }
+        let user = process_mut(spec).user_mut();
+        let gids = user.additional_gids_mut().get_or_insert_with(Vec::new);
+        if !gids.contains(gid) {
+            gids.push(*gid);
+        }
+    }
+
+    Ok(())
+}
+
+fn env_name(env: &str) -> &str {
+    env.split_once('=').map_or(env, |(name, _)| name)
+}
+
+fn set_env(spec: &mut OciSpec, env: &str) {
+    let name = env_name(env);
+    let prefix = format!("{name}=");
+    let vars = process_mut(spec).env_mut().get_or_insert_with(Vec::new);
+    vars.retain(|e| !e.starts_with(&prefix) && e != name);
+    vars.push(env.to_owned());
+}
+
+fn add_device_node(spec: &mut OciSpec, node: &DeviceNode) -> Result<(), CdiError> {
+    let node = complete_device_node(node)?;
+    let typ = match node.r#type.as_deref() {
+        Some("c") | Some("u") => LinuxDeviceType::C,
+        Some("b") => LinuxDeviceType::B,
+        Some("p") => LinuxDeviceType::P,
+        other => {
+            return Err(CdiError::InvalidEdits(format!(
+                "device node {} has invalid type {other:?}",
+                node.path
+            )))
+        }
+    };
+    let (major, minor) = (
+        node.major.unwrap_or_default(),
+        node.minor.unwrap_or_default(),
+    );
+
+    let mut builder = LinuxDeviceBuilder::default()
+        .path(&node.path)
+        .typ(typ)
+        .major(major)
+        .minor(minor);
+    if let Some(file_mode) = node.file_mode {
+        builder = builder.file_mode(file_mode);
+    }
+    if let Some(uid) = node.uid {
+        builder = builder.uid(uid);
+    }
+    if let Some(gid) = node.gid {
+        builder = builder.gid(gid);
+    }
+    let device = builder.build()?;
+
+    let linux = linux_mut(spec)?;
+    let devices: &mut Vec<LinuxDevice> = linux.devices_mut().get_or_insert_with(Vec::new);
+    devices.retain(|d| d.path() != Path::new(&node.path));
+    devices.push(device);
+
+    if typ == LinuxDeviceType::P {
+        return Ok(());
+    }
+    let rule = LinuxDeviceCgroupBuilder::default()
+        .allow(true)
+        .typ(typ)
+        .major(major)
+        .minor(minor)
+        .access(node.permissions.as_deref().unwrap_or(DEFAULT_PERMISSIONS))
+        .build()?;
+    let resources = linux
+        .resources_mut()
+        .get_or_insert_with(LinuxResources::default);
+    let rules: &mut Vec<LinuxDeviceCgroup> = resources.devices_mut().get_or_insert_with(Vec::new);
+    if !rules.contains(&rule) {
+        rules.push(rule);
+    }
+    Ok(())
+}
+
+/// Fills in the type and device numbers of a node from the host device when the spec leaves
+/// them out.
+fn complete_device_node(node: &DeviceNode) -> Result<DeviceNode, CdiError> {
+    let mut node = node.clone();
+    if node.r#type.is_some() && node.major.is_some() && node.minor.is_some() {
+        return Ok(node);
+    }
+
+    let host_path = node.host_path.clone().unwrap_or_else(|| node.path.clone());
+    let metadata = fs::metadata(&host_path).map_err(|err| CdiError::DeviceNode {
+        source: err,
+        path: host_path.clone().into(),
+    })?;
+    let file_type = metadata.file_type();
+    let typ = if file_type.is_char_device() {
+        "c"
+    } else if file_type.is_block_device() {
+        "b"
+    } else if file_type.is_fifo() {
+        "p"
+    } else {
+        return Err(CdiError::InvalidEdits(format!(
+            "{host_path} is not a device node"
+        )));
+    };
+
+    node.r#type.get_or_insert_with(|| typ.to_owned());
+    if typ != "p" {
+        let rdev = metadata.rdev();
+        node.major.get_or_insert(major(rdev) as i64);
+        node.minor.get_or_insert(minor(rdev) as i64);
+    }
+    Ok(node)
+}
+
+fn add_mount(spec: &mut OciSpec, mount: &Mount) -> Result<(), CdiError> {
+    // the spec leaves the type of host path mounts open, they are bind mounts
+    let typ = mount.r#type.clone().unwrap_or_else(|| "bind".to_owned());
+    let mut options = mount.options.clone().unwrap_or_default();
+    if typ == "bind" && !options.iter().any(|o| o == "bind" || o == "rbind") {
+        options.push("bind".to_owned());
+    }
+
+    let oci_mount = MountBuilder::default()
+        .destination(&mount.container_path)
+        .typ(typ)
+        .source(&mount.host_path)
+        .options(options)
+        .build()?;
+
+    let mounts: &mut Vec<OciMount> = spec.mounts_mut().get_or_insert_with(Vec::new);
+    mounts.retain(|m| m.destination() != Path::new(&mount.container_path));
+    mounts.push(oci_mount);
+    Ok(())
+}
+
+fn add_hook(spec: &mut OciSpec, hook: &Hook) -> Result<(), CdiError> {
+    let mut builder = HookBuilder::default().path(&hook.path);
+    if let Some(args) = &hook.args {
+        builder = builder.args(args.clone());
+    }
+    if let Some(env) = &hook.env {
+        builder = builder.env(env.clone());
+    }
+    if let Some(timeout) = hook.timeout {
+        builder = builder.timeout(i64::from(timeout));
+    }
+    let oci_hook = builder.build()?;
+
+    let hooks = spec.hooks_mut().get_or_insert_with(Hooks::default);
+    let list: &mut Option<Vec<OciHook>> = match hook.hook_name.as_str() {
+        "prestart" => hooks.prestart_mut(),
+        "createRuntime" => hooks.create_runtime_mut(),
+        "createContainer" => hooks.create_container_mut(),
+        "startContainer" => hooks.start_container_mut(),
+        "poststart" => hooks.poststart_mut(),
+        "poststop" => hooks.poststop_mut(),
+        other => {
+            return Err(CdiError::InvalidEdits(format!(
+                "unknown hook name {other:?}"
+            )))
+        }
+    };
+    list.get_or_insert_with(Vec::new).push(oci_hook);
+    Ok(())
+}
+
+fn set_intel_rdt(spec: &mut OciSpec, intel_rdt: &IntelRdt) -> Result<(), CdiError> {
+    let mut builder = LinuxIntelRdtBuilder::default();
+    if let Some(clos_id) = &intel_rdt.clos_id {
+        builder = builder.clos_id(clos_id);
+    }
+    if let Some(schema) = &intel_rdt.l3_cache_schema {
+        builder = builder.l3_cache_schema(schema);
+    }
+    if let Some(schema) = &intel_rdt.mem_bw_schema {
+        builder = builder.mem_bw_schema(schema);
+    }
+    if let Some(enable) = intel_rdt.enable_cmt {
+        builder = builder.enable_cmt(enable);
+    }
+    if let Some(enable) = intel_rdt.enable_mbm {
+        builder = builder.enable_mbm(enable);
+    }
+    linux_mut(spec)?.set_intel_rdt(Some(builder.build()?));
+    Ok(())
+}
+
+fn process_mut(spec: &mut OciSpec) -> &mut Process {
+    spec.process_mut().get_or_insert_with(Process::default)
+}
+
+fn linux_mut(spec: &mut OciSpec) -> Result<&mut Linux, CdiError> {
+    if spec.linux().is_none() {
+        spec.set_linux(Some(LinuxBuilder::default().build()?));
+    }
+    Ok(spec.linux_mut().as_mut().expect("linux was just set"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(path: &str, minor: i64) -> DeviceNode {
+        DeviceNode {
+            path: path.to_owned(),
+            r#type: Some("c".to_owned()),
+            major: Some(120),
+            minor: Some(minor),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_merge_conflicts() {
+        let mut merger = EditsMerger::default();
+        let first = ContainerEdits {
+            env: Some(vec!["A=1".to_owned()]),
+            device_nodes: Some(vec![node("/dev/apex_0", 0)]),
+            additional_gids: Some(vec![44]),
+            ..Default::default()
+        };
+        merger.add("vendor.com/a=x", &first).unwrap();
+        // identical entries from another device are fine
+        merger.add("vendor.com/a=y", &first).unwrap();
+
+        let conflicting_env = ContainerEdits {
+            env: Some(vec!["A=2".to_owned()]),
+            ..Default::default()
+        };
+        let err = merger.add("vendor.com/a=z", &conflicting_env).unwrap_err();
+        assert!(matches!(
+            err,
+            CdiError::Conflict { kind: "environment variable", ref key, ref first, ref second }
+                if key == "A" && first == "vendor.com/a=x" && second == "vendor.com/a=z"
+        ));
+
+        let conflicting_node = ContainerEdits {
+            device_nodes: Some(vec![node("/dev/apex_0", 1)]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            merger.add("vendor.com/a=z", &conflicting_node),
+            Err(CdiError::Conflict {
+                kind: "device node",
+                ..
+            })
+        ));
+
+        let edits = merger.finish();
+        assert_eq!(edits.env, Some(vec!["A=1".to_owned()]));
+        assert_eq!(edits.device_nodes.unwrap().len(), 1);
+        assert_eq!(edits.additional_gids, Some(vec![44]));
+    }
+
+    #[test]
+    fn test_apply_edits() {
+        let mut spec = OciSpec::default();
+        let edits = ContainerEdits {
+            env: Some(vec!["PATH=/cdi/bin".to_owned(), "TPU=1".to_owned()]),
+            device_nodes: Some(vec![node("/dev/apex_0", 0)]),
+            mounts: Some(vec![Mount {
+                host_path: "/usr/lib/libedgetpu.so.1".to_owned(),
+                container_path: "/usr/lib/libedgetpu.so.1".to_owned(),
+                options: Some(vec!["ro".to_owned()]),
+                r#type: None,
+            }]),
+            hooks: Some(vec![Hook {
+                hook_name: "createContainer".to_owned(),
+                path: "/usr/bin/ldconfig".to_owned(),
+                args: None,
+                env: None,
+                timeout: Some(5),
+            }]),
+            additional_gids: Some(vec![44]),
+            ..Default::default()
+        };
+        apply(&edits, &mut spec).unwrap();
+
+        let process = spec.process().as_ref().unwrap();
+        let env = process.env().as_ref().unwrap();
+        assert!(env.contains(&"PATH=/cdi/bin".to_owned()));
+        assert_eq!(env.iter().filter(|e| e.starts_with("PATH=")).count(), 1);
+        assert!(env.contains(&"TPU=1".to_owned()));
+        assert_eq!(process.user().additional_gids(), &Some(vec![44]));
+
+        let linux = spec.linux().as_ref().unwrap();
+        let device = &linux.devices().as_ref().unwrap()[0];
+        assert_eq!(device.path(), Path::new("/dev/apex_0"));
+        assert_eq!((device.major(), device.minor()), (120, 0));
+        let rules = linux
+            .resources()
+            .as_ref()
+            .unwrap()
+            .devices()
+            .clone()
+            .unwrap();
+        assert!(rules
+            .iter()
+            .any(|r| r.allow() && r.major() == Some(120) && r.access().as_deref() == Some("rwm")));
+
+        let mount = spec
+            .mounts()
+            .as_ref()
+            .unwrap()
+            .iter()
+            .find(|m| m.destination() == Path::new("/usr/lib/libedgetpu.so.1"))
+            .unwrap();
+        assert_eq!(mount.typ().as_deref(), Some("bind"));
+        assert_eq!(
+            mount.options(),
+            &Some(vec!["ro".to_owned(), "bind".to_owned()])
+        );
+
+        let hooks = spec.hooks().as_ref().unwrap();
+        let hook = &hooks.create_container().as_ref().unwrap()[0];
+        assert_eq!(hook.path(), Path::new("/usr/bin/ldconfig"));
+        assert_eq!(hook.timeout(), Some(5));
+    }
+
+    #[test]
+    fn test_apply_nothing_on_error() {
+        let mut spec = OciSpec::default();
+        let original = spec.clone();
+        let edits = ContainerEdits {
+            env: Some(vec!["TPU=1".to_owned()]),
+            device_nodes: Some(vec![node("/dev/apex_0", 0)]),
+            additional_gids: Some(vec![44, 0]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            apply(&edits, &mut spec),
+            Err(CdiError::InvalidEdits(_))
+        ));
+        assert_eq!(spec, original);
+    }
+
+    #[test]
+    fn test_apply_device_node_from_host() {
+        let mut spec = OciSpec::default();
+        let edits = ContainerEdits {
+            device_nodes: Some(vec![DeviceNode {
+                path: "/dev/null".to_owned(),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+        apply(&edits, &mut spec).unwrap();
+
+        let device = spec.linux().as_ref().unwrap().devices().as_ref().unwrap()[0].clone();
+        assert_eq!(device.typ(), LinuxDeviceType::C);
+        assert_eq!((device.major(), device.minor()), (1, 3));
+    }
+}
@@ -0,0 +1,298 @@
+//! Generation of the CDI spec describing the Edge TPUs of the host.
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use super::parser::QualifiedName;
+use super::spec::{ContainerEdits, Device, DeviceNode, Mount, Spec, CURRENT_VERSION};
+use super::CdiError;
+use crate::dep::util::install_path_of;
+use crate::discovery::{DeviceType, Discovery, EdgeTpuDevice};
+use crate::injection::{edits::container_dev_path, LIBEDGETPU_FILES};
+
+pub const VENDOR: &str = "coral.ai";
+pub const CLASS: &str = "edgetpu";
+/// Name of the device that stands for every Edge TPU of the host.
+pub const ALL_DEVICES: &str = "all";
+
+/// Serialization format of a spec file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecFormat {
+    Json,
+    Yaml,
+}
+
+impl SpecFormat {
+    /// Guesses the format from the extension of the file, YAML unless it is `.json`.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Self::Json,
+            _ => Self::Yaml,
+        }
+    }
+
+    pub fn encode(&self, spec: &Spec) -> Result<String, CdiError> {
+        let encoded = match self {
+            Self::Json => serde_json::to_string_pretty(spec).map_err(|err| err.to_string()),
+            Self::Yaml => serde_yaml::to_string(spec).map_err(|err| err.to_string()),
+        };
+        encoded.map_err(CdiError::Encode)
+    }
+}
+
+/// Builds the `coral.ai/edgetpu` spec of the Edge TPUs found on the host.
+///
+/// Every TPU is a device named after its discovery index and, when it has one, after its
+/// serial number. The `all` device holds every TPU. The spec-wide edits mount the host
+/// libedgetpu and add the group owning the apex device nodes, so that unprivileged
+/// processes of the container can open them.
+///
+/// USB accelerators change device node when their firmware is loaded, the spec has to be
+/// generated again afterwards.
+///
+/// # Example
+///
+/// ```no_run
+/// use edgetpu::cdi::generate::{SpecFormat, SpecGenerator};
+///
+/// let spec = SpecGenerator::new().generate().unwrap();
+/// println!("{}", SpecFormat::Yaml.encode(&spec).unwrap());
+/// ```
+#[derive(Debug, Clone)]
+pub struct SpecGenerator {
+    discovery: Discovery,
+    library_dir: PathBuf,
+}
+
+impl Default for SpecGenerator {
+    fn default() -> Self {
+        Self {
+            discovery: Discovery::default(),
+            library_dir: install_path_of(),
+        }
+    }
+}
+
+impl SpecGenerator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how Edge TPUs are discovered on the host
+    pub fn with_discovery(mut self, discovery: Discovery) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    /// Sets the host directory libedgetpu is installed in
+    pub fn with_library_dir<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.library_dir = path.into();
+        self
+    }
+
+    pub fn generate(&self) -> Result<Spec, CdiError> {
+        let tpus = self.discovery.discover()?;
+
+        let mut devices = Vec::new();
+        let mut names = HashSet::new();
+        let mut all_nodes = Vec::new();
+        for (index, tpu) in tpus.iter().enumerate() {
+            let node = self.device_node(tpu);
+            let mut tpu_names = vec![index.to_string()];
+            tpu_names.extend(tpu.serial.clone());
+
+            for name in tpu_names {
+                if let Err(err) = QualifiedName::new(VENDOR, CLASS, &name) {
+                    log::warn!("skipping CDI device name of {}: {err}", tpu.name());
+                    continue;
+                }
+                if !names.insert(name.clone()) {
+                    log::warn!("CDI device name {name} is not unique, skipping it");
+                    continue;
+                }
+                devices.push(Device {
+                    name,
+                    annotations: None,
+                    container_edits: ContainerEdits {
+                        device_nodes: Some(vec![node.clone()]),
+                        ..Default::default()
+                    },
+                });
+            }
+            all_nodes.push(node);
+        }
+
+        // a spec without devices is invalid, `all` is always there
+        devices.push(Device {
+            name: ALL_DEVICES.to_owned(),
+            annotations: None,
+            container_edits: ContainerEdits {
+                device_nodes: Some(all_nodes),
+                ..Default::default()
+            },
+        });
+
+        Ok(Spec {
+            version: CURRENT_VERSION.to_owned(),
+            kind: format!("{VENDOR}/{CLASS}"),
+            annotations: None,
+            devices,
+            container_edits: Some(self.common_edits(&tpus)),
+        })
+    }
+
+    fn device_node(&self, tpu: &EdgeTpuDevice) -> DeviceNode {
+        let path = container_dev_path(tpu, self.discovery.dev_root());
+        let host_path = (path != tpu.dev_node).then(|| tpu.dev_node.to_string_lossy().into());
+        DeviceNode {
+            path: path.to_string_lossy().into_owned(),
+            host_path,
+            r#type: Some("c".to_owned()),
+            major: Some(tpu.major.into()),
+            minor: Some(tpu.minor.into()),
+            permissions: Some("rwm".to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn common_edits(&self, tpus: &[EdgeTpuDevice]) -> ContainerEdits {
+        let mounts: Vec<Mount> = LIBEDGETPU_FILES
+            .iter()
+            .map(|file| self.library_dir.join(file))
+            .filter(|path| path.exists())
+            .map(|path| Mount {
+                host_path: path.to_string_lossy().into_owned(),
+                container_path: path.to_string_lossy().into_owned(),
+                options: Some(vec![
+                    "ro".to_owned(),
+                    "nosuid".to_owned(),
+                    "nodev".to_owned(),
+                    "bind".to_owned(),
+                ]),
+                r#type: Some("bind".to_owned()),
+            })
+            .collect();
+        if mounts.is_empty() {
+            log::warn!(
+                "libedgetpu was not found in {}, the spec will not mount it",
+                self.library_dir.display()
+            );
+        }
+
+        // the apex nodes belong to the group the udev rules set, usually `apex`
+        let mut gids = Vec::new();
+        for tpu in tpus {
+            if tpu.device_type() != DeviceType::ApexPCI {
+                continue;
+            }
+            match fs::metadata(&tpu.dev_node) {
+                Ok(metadata) if metadata.gid() != 0 && !gids.contains(&metadata.gid()) => {
+                    gids.push(metadata.gid())
+                }
+                Ok(_) => {}
+                Err(err) => log::warn!("failed to stat {}: {err}", tpu.dev_node.display()),
+            }
+        }
+
+        ContainerEdits {
+            mounts: (!mounts.is_empty()).then_some(mounts),
+            additional_gids: (!gids.is_empty()).then_some(gids),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+    use crate::cdi::Registry;
+    use crate::discovery::fixture::{add_apex, add_usb};
+
+    #[test]
+    fn test_generate_spec() {
+        let sysfs = tempfile::tempdir().unwrap();
+        add_apex(sysfs.path(), 0, "0000:01:00.0");
+        add_usb(sysfs.path(), "2-1", ("18d1", "9302"), 2, 3, "0a1b2c");
+        let dev = tempfile::tempdir().unwrap();
+        fs::write(dev.path().join("apex_0"), "").unwrap();
+        let libs = tempfile::tempdir().unwrap();
+        fs::write(libs.path().join("libedgetpu.so.1"), "").unwrap();
+
+        let spec = SpecGenerator::new()
+            .with_discovery(
+                Discovery::new()
+                    .with_sysfs_root(sysfs.path())
+                    .with_dev_root(dev.path()),
+            )
+            .with_library_dir(libs.path())
+            .generate()
+            .unwrap();
+
+        assert_eq!(spec.kind, "coral.ai/edgetpu");
+        let names: Vec<&str> = spec.devices.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, vec!["0", "1", "0a1b2c", "all"]);
+
+        let apex = &spec.devices[0]
+            .container_edits
+            .device_nodes
+            .as_ref()
+            .unwrap()[0];
+        assert_eq!(apex.path, "/dev/apex_0");
+        assert_eq!(
+            apex.host_path.as_deref(),
+            Some(dev.path().join("apex_0").to_str().unwrap())
+        );
+        assert_eq!((apex.major, apex.minor), (Some(120), Some(0)));
+        let all = spec.devices[3]
+            .container_edits
+            .device_nodes
+            .as_ref()
+            .unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[1].path, "/dev/bus/usb/002/003");
+
+        let common = spec.container_edits.as_ref().unwrap();
+        let mounts = common.mounts.as_ref().unwrap();
+        assert_eq!(mounts.len(), 1);
+        assert!(mounts[0].host_path.ends_with("libedgetpu.so.1"));
+        let gid = fs::metadata(dev.path().join("apex_0")).unwrap().gid();
+        let expected_gids = (gid != 0).then(|| vec![gid]);
+        assert_eq!(common.additional_gids, expected_gids);
+    }
+
+    #[test]
+    fn test_generated_spec_loads() {
+        let sysfs = tempfile::tempdir().unwrap();
+        add_apex(sysfs.path(), 0, "0000:01:00.0");
+        add_apex(sysfs.path(), 1, "0000:02:00.0");
+        let spec = SpecGenerator::new()
+            .with_discovery(Discovery::new().with_sysfs_root(sysfs.path()))
+            .with_library_dir(sysfs.path())
+            .generate()
+            .unwrap();
+
+        for (format, file) in [
+            (SpecFormat::Json, "coral.json"),
+            (SpecFormat::Yaml, "coral.yaml"),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            assert_eq!(SpecFormat::from_path(Path::new(file)), format);
+            fs::write(dir.path().join(file), format.encode(&spec).unwrap()).unwrap();
+
+            let registry = Registry::load(&[dir.path()]).unwrap();
+            let names: Vec<String> = registry.device_names().map(ToString::to_string).collect();
+            assert_eq!(
+                names,
+                vec![
+                    "coral.ai/edgetpu=0",
+                    "coral.ai/edgetpu=1",
+                    "coral.ai/edgetpu=all"
+                ]
+            );
+            assert_eq!(registry.specs()[0].spec(), &spec);
+        }
+    }
+}
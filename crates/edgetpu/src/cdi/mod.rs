@@ -1 +1,189 @@
-pub mod modifier;
\ No newline at end of file
+//! Container Device Interface support.
+//!
+//! CDI describes devices in spec files (`/etc/cdi/*.yaml`, `/var/run/cdi/*.json`) as a set
+//! of edits to apply to the runtime spec of the containers that use them. Containers request
+//! devices by their qualified name, `vendor/class=name`, in `cdi.k8s.io/*` annotations. The
+//! [`CdiInjector`] resolves those annotations against the [`Registry`] and merges the edits
+//! into the spec.
+mod edits;
+pub mod generate;
+mod parser;
+mod registry;
+pub mod spec;
+
+use std::path::PathBuf;
+
+use libcontainer::oci_spec::runtime::Spec as OciSpec;
+use libcontainer::oci_spec::OciSpecError;
+use libcontainer::spec_modifier::{SpecModifier, SpecModifierError};
+
+pub use parser::{annotated_devices, QualifiedName, ANNOTATION_PREFIX};
+pub use registry::{LoadedSpec, Registry};
+
+/// Spec directories, by increasing priority.
+pub const DEFAULT_SPEC_DIRS: [&str; 2] = ["/etc/cdi", "/var/run/cdi"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum CdiError {
+    #[error("failed to read {path:?}")]
+    Read {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("failed to parse CDI spec {path:?}: {reason}")]
+    Parse { path: PathBuf, reason: String },
+    #[error("invalid CDI spec {path:?}: {reason}")]
+    InvalidSpec { path: PathBuf, reason: String },
+    #[error("invalid CDI device name {name:?}: {reason}")]
+    InvalidName { name: String, reason: String },
+    #[error("invalid CDI annotation {key:?}: {reason}")]
+    InvalidAnnotation { key: String, reason: String },
+    #[error("CDI device {name} is defined by both {first:?} and {second:?}")]
+    DuplicateDevice {
+        name: String,
+        first: PathBuf,
+        second: PathBuf,
+    },
+    #[error("unresolvable CDI devices: {}", .0.join(", "))]
+    Unresolved(Vec<String>),
+    #[error("conflicting {kind} {key:?} from {first} and {second}")]
+    Conflict {
+        kind: &'static str,
+        key: String,
+        first: String,
+        second: String,
+    },
+    #[error("failed to stat device node {path:?}")]
+    DeviceNode {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("invalid CDI container edits: {0}")]
+    InvalidEdits(String),
+    #[error("failed to encode CDI spec: {0}")]
+    Encode(String),
+    #[error(transparent)]
+    Discovery(#[from] crate::discovery::DiscoveryError),
+    #[error("failed to build spec entry")]
+    Spec(#[from] OciSpecError),
+}
+
+/// Injects the CDI devices requested by the annotations of a container.
+///
+/// # Example
+///
+/// ```no_run
+/// use edgetpu::cdi::CdiInjector;
+/// use libcontainer::oci_spec::runtime::Spec;
+///
+/// let mut spec = Spec::load("config.json").unwrap();
+/// let injected = CdiInjector::new().inject(&mut spec).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct CdiInjector {
+    spec_dirs: Vec<PathBuf>,
+}
+
+impl Default for CdiInjector {
+    fn default() -> Self {
+        Self {
+            spec_dirs: DEFAULT_SPEC_DIRS.iter().map(PathBuf::from).collect(),
+        }
+    }
+}
+
+impl CdiInjector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the directories spec files are loaded from, by increasing priority
+    pub fn with_spec_dirs<P: Into<PathBuf>>(mut self, dirs: impl IntoIterator<Item = P>) -> Self {
+        self.spec_dirs = dirs.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn spec_dirs(&self) -> &[PathBuf] {
+        &self.spec_dirs
+    }
+
+    /// Applies the edits of the annotated devices and returns their names. The spec files are
+    /// only read when the container requests a device.
+    pub fn inject(&self, spec: &mut OciSpec) -> Result<Vec<QualifiedName>, CdiError> {
+        let devices = match spec.annotations() {
+            Some(annotations) => annotated_devices(annotations)?,
+            None => Vec::new(),
+        };
+        if devices.is_empty() {
+            return Ok(devices);
+        }
+
+        log::debug!("injecting CDI devices {devices:?}");
+        Registry::load(&self.spec_dirs)?.inject(spec, &devices)?;
+        Ok(devices)
+    }
+}
+
+impl SpecModifier for CdiInjector {
+    fn name(&self) -> &str {
+        "cdi"
+    }
+
+    fn modify(&self, spec: &mut OciSpec) -> Result<(), SpecModifierError> {
+        self.inject(spec)
+            .map(|_| ())
+            .map_err(|err| SpecModifierError::Modify {
+                name: self.name().to_owned(),
+                source: Box::new(err),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_inject_annotated_devices() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(
+            tmp.path().join("vendor.yaml"),
+            r#"
+cdiVersion: "0.8.0"
+kind: "vendor.com/device"
+devices:
+  - name: "foo"
+    containerEdits:
+      env: ["FOO=1"]
+"#,
+        )
+        .unwrap();
+        let injector = CdiInjector::new().with_spec_dirs([tmp.path()]);
+
+        let mut spec = OciSpec::default();
+        let original = spec.clone();
+        assert!(injector.inject(&mut spec).unwrap().is_empty());
+        assert_eq!(spec, original);
+
+        spec.set_annotations(Some(HashMap::from([(
+            "cdi.k8s.io/test".to_owned(),
+            "vendor.com/device=foo".to_owned(),
+        )])));
+        let injected = injector.inject(&mut spec).unwrap();
+        assert_eq!(injected.len(), 1);
+        let env = spec.process().as_ref().unwrap().env().as_ref().unwrap();
+        assert!(env.contains(&"FOO=1".to_owned()));
+
+        spec.set_annotations(Some(HashMap::from([(
+            "cdi.k8s.io/test".to_owned(),
+            "vendor.com/device=bar".to_owned(),
+        )])));
+        assert!(matches!(
+            injector.modify(&mut spec),
+            Err(SpecModifierError::Modify { .. })
+        ));
+    }
+}
@@ -0,0 +1,231 @@
+//! Qualified device names (`vendor/class=name`) and the annotations that carry them.
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use super::CdiError;
+
+/// Prefix of the annotation keys that request CDI devices.
+pub const ANNOTATION_PREFIX: &str = "cdi.k8s.io/";
+
+/// Fully qualified name of a CDI device, `vendor/class=name`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct QualifiedName {
+    vendor: String,
+    class: String,
+    name: String,
+}
+
+impl QualifiedName {
+    pub fn new(vendor: &str, class: &str, name: &str) -> Result<Self, CdiError> {
+        let invalid = |reason: String| CdiError::InvalidName {
+            name: format!("{vendor}/{class}={name}"),
+            reason,
+        };
+        validate_vendor(vendor).map_err(invalid)?;
+        validate_class(class).map_err(invalid)?;
+        validate_device_name(name).map_err(invalid)?;
+
+        Ok(Self {
+            vendor: vendor.to_owned(),
+            class: class.to_owned(),
+            name: name.to_owned(),
+        })
+    }
+
+    pub fn vendor(&self) -> &str {
+        &self.vendor
+    }
+
+    pub fn class(&self) -> &str {
+        &self.class
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The `vendor/class` kind of the device.
+    pub fn kind(&self) -> String {
+        format!("{}/{}", self.vendor, self.class)
+    }
+}
+
+impl FromStr for QualifiedName {
+    type Err = CdiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, name) = s.split_once('=').ok_or_else(|| CdiError::InvalidName {
+            name: s.to_owned(),
+            reason: "missing '=' between kind and device name".to_owned(),
+        })?;
+        let (vendor, class) = parse_kind(kind).map_err(|reason| CdiError::InvalidName {
+            name: s.to_owned(),
+            reason,
+        })?;
+        Self::new(vendor, class, name)
+    }
+}
+
+impl fmt::Display for QualifiedName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}={}", self.vendor, self.class, self.name)
+    }
+}
+
+/// Splits and validates a `vendor/class` kind.
+pub(super) fn parse_kind(kind: &str) -> Result<(&str, &str), String> {
+    let (vendor, class) = kind
+        .split_once('/')
+        .ok_or_else(|| format!("kind {kind:?} is not of the form vendor/class"))?;
+    validate_vendor(vendor)?;
+    validate_class(class)?;
+    Ok((vendor, class))
+}
+
+/// Returns the devices requested through `cdi.k8s.io/*` annotations, sorted and without
+/// duplicates.
+pub fn annotated_devices(
+    annotations: &HashMap<String, String>,
+) -> Result<Vec<QualifiedName>, CdiError> {
+    let mut devices = Vec::new();
+    for (key, value) in annotations {
+        let Some(suffix) = key.strip_prefix(ANNOTATION_PREFIX) else {
+            continue;
+        };
+        validate_annotation_suffix(suffix).map_err(|reason| CdiError::InvalidAnnotation {
+            key: key.clone(),
+            reason,
+        })?;
+
+        for device in value.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            devices.push(device.parse()?);
+        }
+    }
+
+    devices.sort();
+    devices.dedup();
+    Ok(devices)
+}
+
+fn validate_vendor(vendor: &str) -> Result<(), String> {
+    validate_component("vendor", vendor, |c| c == '-' || c == '_' || c == '.')?;
+    if !vendor.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return Err(format!("vendor {vendor:?} must start with a letter"));
+    }
+    Ok(())
+}
+
+fn validate_class(class: &str) -> Result<(), String> {
+    validate_component("class", class, |c| c == '-' || c == '_')?;
+    if !class.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return Err(format!("class {class:?} must start with a letter"));
+    }
+    Ok(())
+}
+
+fn validate_device_name(name: &str) -> Result<(), String> {
+    validate_component("device name", name, |c| {
+        c == '-' || c == '_' || c == '.' || c == ':'
+    })
+}
+
+fn validate_annotation_suffix(suffix: &str) -> Result<(), String> {
+    if suffix.len() > 63 {
+        return Err("the name after the prefix is longer than 63 characters".to_owned());
+    }
+    validate_component("annotation name", suffix, |c| {
+        c == '-' || c == '_' || c == '.'
+    })
+}
+
+/// Checks that a name component is non-empty, starts and ends with an alphanumeric
+/// character and otherwise only contains alphanumerics and the allowed punctuation.
+fn validate_component(
+    what: &str,
+    value: &str,
+    allowed: impl Fn(char) -> bool,
+) -> Result<(), String> {
+    if value.is_empty() {
+        return Err(format!("empty {what}"));
+    }
+    if let Some(c) = value
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && !allowed(*c))
+    {
+        return Err(format!("invalid character {c:?} in {what} {value:?}"));
+    }
+    let alphanumeric = |c: char| c.is_ascii_alphanumeric();
+    if !value.starts_with(alphanumeric) || !value.ends_with(alphanumeric) {
+        return Err(format!(
+            "{what} {value:?} must start and end with a letter or digit"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_qualified_name() {
+        let name: QualifiedName = "coral.ai/edgetpu=apex_0".parse().unwrap();
+        assert_eq!(name.vendor(), "coral.ai");
+        assert_eq!(name.class(), "edgetpu");
+        assert_eq!(name.name(), "apex_0");
+        assert_eq!(name.kind(), "coral.ai/edgetpu");
+        assert_eq!(name.to_string(), "coral.ai/edgetpu=apex_0");
+
+        assert!("vendor.com/class=usb:2-1.4"
+            .parse::<QualifiedName>()
+            .is_ok());
+        for invalid in [
+            "apex_0",
+            "coral.ai=apex_0",
+            "/edgetpu=apex_0",
+            "coral.ai/=apex_0",
+            "coral.ai/edgetpu=",
+            "1coral.ai/edgetpu=apex_0",
+            "coral.ai/edge.tpu=apex_0",
+            "coral.ai/edgetpu=_apex",
+            "coral.ai/edgetpu=apex 0",
+        ] {
+            assert!(
+                matches!(
+                    invalid.parse::<QualifiedName>(),
+                    Err(CdiError::InvalidName { .. })
+                ),
+                "{invalid} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn test_annotated_devices() {
+        let annotations = HashMap::from([
+            (
+                "cdi.k8s.io/device-plugin_0".to_owned(),
+                "coral.ai/edgetpu=1, coral.ai/edgetpu=0".to_owned(),
+            ),
+            (
+                "cdi.k8s.io/other".to_owned(),
+                "coral.ai/edgetpu=0".to_owned(),
+            ),
+            ("unrelated".to_owned(), "not a device".to_owned()),
+        ]);
+        let devices: Vec<_> = annotated_devices(&annotations)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(devices, vec!["coral.ai/edgetpu=0", "coral.ai/edgetpu=1"]);
+
+        let annotations =
+            HashMap::from([("cdi.k8s.io/".to_owned(), "coral.ai/edgetpu=0".to_owned())]);
+        assert!(matches!(
+            annotated_devices(&annotations),
+            Err(CdiError::InvalidAnnotation { .. })
+        ));
+    }
+}
@@ -0,0 +1,455 @@
+//! Loading of CDI spec files from the spec directories.
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use libcontainer::oci_spec::runtime::Spec as OciSpec;
+
+use super::edits::{self, EditsMerger, HOOK_NAMES};
+use super::parser::{parse_kind, QualifiedName};
+use super::spec::{ContainerEdits, Device, Spec, SUPPORTED_VERSIONS};
+use super::CdiError;
+
+/// A spec file that was loaded from one of the spec directories.
+#[derive(Debug, Clone)]
+pub struct LoadedSpec {
+    path: PathBuf,
+    priority: usize,
+    spec: Spec,
+}
+
+impl LoadedSpec {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn spec(&self) -> &Spec {
+        &self.spec
+    }
+}
+
+/// The CDI devices defined by the spec files of a set of directories.
+///
+/// Directories are listed by increasing priority: a device defined in a later directory
+/// shadows the device with the same name in an earlier one, a device defined twice in the
+/// same directory is an error.
+///
+/// # Example
+///
+/// ```no_run
+/// use edgetpu::cdi::{Registry, DEFAULT_SPEC_DIRS};
+///
+/// let registry = Registry::load(&DEFAULT_SPEC_DIRS).unwrap();
+/// for name in registry.device_names() {
+///     println!("{name}");
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    specs: Vec<LoadedSpec>,
+    /// Index of the spec and of the device in it, for each device name
+    devices: BTreeMap<QualifiedName, (usize, usize)>,
+}
+
+impl Registry {
+    pub fn load<P: AsRef<Path>>(spec_dirs: &[P]) -> Result<Self, CdiError> {
+        let mut registry = Self::default();
+        for (priority, dir) in spec_dirs.iter().enumerate() {
+            for path in spec_files(dir.as_ref())? {
+                let spec = read_spec(&path)?;
+                registry.add(LoadedSpec {
+                    path,
+                    priority,
+                    spec,
+                })?;
+            }
+        }
+
+        Ok(registry)
+    }
+
+    fn add(&mut self, loaded: LoadedSpec) -> Result<(), CdiError> {
+        let (vendor, class) =
+            parse_kind(&loaded.spec.kind).map_err(|reason| CdiError::InvalidSpec {
+                path: loaded.path.clone(),
+                reason,
+            })?;
+
+        let index = self.specs.len();
+        for (device_index, device) in loaded.spec.devices.iter().enumerate() {
+            let name = QualifiedName::new(vendor, class, &device.name)?;
+            if let Some((other, _)) = self.devices.get(&name) {
+                let other = &self.specs[*other];
+                if other.priority == loaded.priority {
+                    return Err(CdiError::DuplicateDevice {
+                        name: name.to_string(),
+                        first: other.path.clone(),
+                        second: loaded.path.clone(),
+                    });
+                }
+                log::debug!(
+                    "CDI device {name} of {:?} shadows the one of {:?}",
+                    loaded.path,
+                    other.path
+                );
+            }
+            self.devices.insert(name, (index, device_index));
+        }
+
+        self.specs.push(loaded);
+        Ok(())
+    }
+
+    /// All loaded spec files, in load order.
+    pub fn specs(&self) -> &[LoadedSpec] {
+        &self.specs
+    }
+
+    /// Names of all known devices, sorted.
+    pub fn device_names(&self) -> impl Iterator<Item = &QualifiedName> {
+        self.devices.keys()
+    }
+
+    pub fn device(&self, name: &QualifiedName) -> Option<&Device> {
+        self.devices
+            .get(name)
+            .map(|(spec, device)| &self.specs[*spec].spec.devices[*device])
+    }
+
+    /// The spec file the device is taken from.
+    pub fn spec_of(&self, name: &QualifiedName) -> Option<&LoadedSpec> {
+        self.devices.get(name).map(|(spec, _)| &self.specs[*spec])
+    }
+
+    /// Returns the merged edits of the devices, including the spec-wide edits of every spec
+    /// that defines one of them.
+    pub fn resolve(&self, names: &[QualifiedName]) -> Result<ContainerEdits, CdiError> {
+        let unresolved: Vec<String> = names
+            .iter()
+            .filter(|name| !self.devices.contains_key(name))
+            .map(ToString::to_string)
+            .collect();
+        if !unresolved.is_empty() {
+            return Err(CdiError::Unresolved(unresolved));
+        }
+
+        let mut merger = EditsMerger::default();
+        let mut merged_specs = HashSet::new();
+        for name in names {
+            let (spec_index, device_index) = self.devices[name];
+            let loaded = &self.specs[spec_index];
+            if merged_specs.insert(spec_index) {
+                if let Some(edits) = &loaded.spec.container_edits {
+                    merger.add(&loaded.path.to_string_lossy(), edits)?;
+                }
+            }
+            let device = &loaded.spec.devices[device_index];
+            merger.add(&name.to_string(), &device.container_edits)?;
+        }
+
+        Ok(merger.finish())
+    }
+
+    /// Applies the edits of the devices to the runtime spec. Nothing is applied if a device
+    /// is unknown or the devices conflict with each other.
+    pub fn inject(&self, spec: &mut OciSpec, names: &[QualifiedName]) -> Result<(), CdiError> {
+        let edits = self.resolve(names)?;
+        edits::apply(&edits, spec)
+    }
+}
+
+/// Lists the spec files of a directory in name order. A missing directory has no spec.
+fn spec_files(dir: &Path) -> Result<Vec<PathBuf>, CdiError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(CdiError::Read {
+                source: err,
+                path: dir.to_owned(),
+            })
+        }
+    };
+
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|err| CdiError::Read {
+                source: err,
+                path: dir.to_owned(),
+            })?
+            .path();
+        let is_spec = matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("json" | "yaml" | "yml")
+        );
+        if is_spec && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn read_spec(path: &Path) -> Result<Spec, CdiError> {
+    let content = fs::read_to_string(path).map_err(|err| CdiError::Read {
+        source: err,
+        path: path.to_owned(),
+    })?;
+    let parse_error = |reason: String| CdiError::Parse {
+        path: path.to_owned(),
+        reason,
+    };
+    let spec: Spec = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&content).map_err(|err| parse_error(err.to_string()))?
+    } else {
+        serde_yaml::from_str(&content).map_err(|err| parse_error(err.to_string()))?
+    };
+
+    validate_spec(&spec).map_err(|reason| CdiError::InvalidSpec {
+        path: path.to_owned(),
+        reason,
+    })?;
+    Ok(spec)
+}
+
+fn validate_spec(spec: &Spec) -> Result<(), String> {
+    if !SUPPORTED_VERSIONS.contains(&spec.version.as_str()) {
+        return Err(format!("unsupported cdiVersion {:?}", spec.version));
+    }
+    if spec.devices.is_empty() {
+        return Err("no devices defined".to_owned());
+    }
+
+    let mut names = HashSet::new();
+    for device in &spec.devices {
+        if !names.insert(device.name.as_str()) {
+            return Err(format!("device {:?} is defined twice", device.name));
+        }
+        validate_edits(&device.container_edits)?;
+    }
+    if let Some(edits) = &spec.container_edits {
+        validate_edits(edits)?;
+    }
+    Ok(())
+}
+
+fn validate_edits(edits: &ContainerEdits) -> Result<(), String> {
+    for env in edits.env.iter().flatten() {
+        match env.split_once('=') {
+            Some((name, _)) if !name.is_empty() => {}
+            _ => return Err(format!("invalid environment variable {env:?}")),
+        }
+    }
+    for node in edits.device_nodes.iter().flatten() {
+        if !node.path.starts_with('/') {
+            return Err(format!("device node path {:?} is not absolute", node.path));
+        }
+        if let Some(permissions) = &node.permissions {
+            if permissions.is_empty() || !permissions.chars().all(|c| "rwm".contains(c)) {
+                return Err(format!("invalid device permissions {permissions:?}"));
+            }
+        }
+    }
+    for mount in edits.mounts.iter().flatten() {
+        if mount.host_path.is_empty() || mount.container_path.is_empty() {
+            return Err("mount without host or container path".to_owned());
+        }
+    }
+    for hook in edits.hooks.iter().flatten() {
+        if !HOOK_NAMES.contains(&hook.hook_name.as_str()) {
+            return Err(format!("unknown hook name {:?}", hook.hook_name));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APEX_YAML: &str = r#"
+cdiVersion: "0.6.0"
+kind: "coral.ai/edgetpu"
+containerEdits:
+  env:
+    - "EDGETPU_CDI=1"
+devices:
+  - name: "0"
+    containerEdits:
+      deviceNodes:
+        - path: /dev/apex_0
+          type: c
+          major: 120
+          minor: 0
+  - name: "1"
+    containerEdits:
+      deviceNodes:
+        - path: /dev/apex_1
+          type: c
+          major: 120
+          minor: 1
+"#;
+
+    const USB_JSON: &str = r#"{
+  "cdiVersion": "0.8.0",
+  "kind": "coral.ai/edgetpu",
+  "devices": [
+    {
+      "name": "usb-2-1",
+      "containerEdits": {
+        "deviceNodes": [{"path": "/dev/bus/usb/002/003", "type": "c", "major": 189, "minor": 130}],
+        "additionalGIDs": [46]
+      }
+    }
+  ]
+}"#;
+
+    fn write(dir: &Path, name: &str, content: &str) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join(name), content).unwrap();
+    }
+
+    fn names(registry: &Registry) -> Vec<String> {
+        registry.device_names().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_load_json_and_yaml() {
+        let tmp = tempfile::tempdir().unwrap();
+        let etc = tmp.path().join("etc/cdi");
+        write(&etc, "apex.yaml", APEX_YAML);
+        write(&etc, "usb.json", USB_JSON);
+        write(&etc, "README", "not a spec");
+
+        let registry = Registry::load(&[etc, tmp.path().join("missing")]).unwrap();
+        assert_eq!(registry.specs().len(), 2);
+        assert_eq!(
+            names(&registry),
+            vec![
+                "coral.ai/edgetpu=0",
+                "coral.ai/edgetpu=1",
+                "coral.ai/edgetpu=usb-2-1"
+            ]
+        );
+        let device = registry
+            .device(&"coral.ai/edgetpu=usb-2-1".parse().unwrap())
+            .unwrap();
+        assert_eq!(device.container_edits.additional_gids, Some(vec![46]));
+    }
+
+    #[test]
+    fn test_later_dir_shadows_earlier() {
+        let tmp = tempfile::tempdir().unwrap();
+        let etc = tmp.path().join("etc/cdi");
+        let run = tmp.path().join("run/cdi");
+        write(&etc, "apex.yaml", APEX_YAML);
+        write(
+            &run,
+            "apex.yaml",
+            &APEX_YAML.replace("minor: 1", "minor: 7"),
+        );
+
+        let registry = Registry::load(&[&etc, &run]).unwrap();
+        let device = registry
+            .device(&"coral.ai/edgetpu=1".parse().unwrap())
+            .unwrap();
+        let node = &device.container_edits.device_nodes.as_ref().unwrap()[0];
+        assert_eq!(node.minor, Some(7));
+
+        // the same device twice in one directory is ambiguous
+        write(&etc, "apex-copy.yaml", APEX_YAML);
+        assert!(matches!(
+            Registry::load(&[&etc]),
+            Err(CdiError::DuplicateDevice { .. })
+        ));
+    }
+
+    #[test]
+    fn test_invalid_specs() {
+        let cases = [
+            APEX_YAML.replace("0.6.0", "9.9.9"),
+            APEX_YAML.replace("coral.ai/edgetpu", "coral.ai"),
+            APEX_YAML.replace("name: \"1\"", "name: \"0\""),
+            APEX_YAML.replace("EDGETPU_CDI=1", "=1"),
+            APEX_YAML.replace("path: /dev/apex_1", "path: dev/apex_1"),
+        ];
+        for content in cases {
+            let tmp = tempfile::tempdir().unwrap();
+            write(tmp.path(), "spec.yaml", &content);
+            assert!(
+                matches!(
+                    Registry::load(&[tmp.path()]),
+                    Err(CdiError::InvalidSpec { .. })
+                ),
+                "{content}"
+            );
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        write(tmp.path(), "spec.json", "{not json");
+        assert!(matches!(
+            Registry::load(&[tmp.path()]),
+            Err(CdiError::Parse { .. })
+        ));
+    }
+
+    #[test]
+    fn test_resolve_and_inject() {
+        let tmp = tempfile::tempdir().unwrap();
+        write(tmp.path(), "apex.yaml", APEX_YAML);
+        write(tmp.path(), "usb.json", USB_JSON);
+        let registry = Registry::load(&[tmp.path()]).unwrap();
+
+        let names: Vec<QualifiedName> = ["coral.ai/edgetpu=0", "coral.ai/edgetpu=1"]
+            .iter()
+            .map(|n| n.parse().unwrap())
+            .collect();
+        let edits = registry.resolve(&names).unwrap();
+        // the spec-wide edits are applied once
+        assert_eq!(edits.env, Some(vec!["EDGETPU_CDI=1".to_owned()]));
+        assert_eq!(edits.device_nodes.as_ref().unwrap().len(), 2);
+
+        let mut spec = OciSpec::default();
+        registry.inject(&mut spec, &names).unwrap();
+        let devices = spec.linux().as_ref().unwrap().devices().clone().unwrap();
+        assert_eq!(devices.len(), 2);
+
+        let unknown = vec!["coral.ai/edgetpu=9".parse().unwrap()];
+        let original = spec.clone();
+        assert!(matches!(
+            registry.inject(&mut spec, &unknown),
+            Err(CdiError::Unresolved(names)) if names == vec!["coral.ai/edgetpu=9"]
+        ));
+        assert_eq!(spec, original);
+    }
+
+    #[test]
+    fn test_conflicting_devices() {
+        let tmp = tempfile::tempdir().unwrap();
+        write(
+            tmp.path(),
+            "apex.yaml",
+            &APEX_YAML.replace("path: /dev/apex_1", "path: /dev/apex_0"),
+        );
+        let registry = Registry::load(&[tmp.path()]).unwrap();
+
+        let names: Vec<QualifiedName> = ["coral.ai/edgetpu=0", "coral.ai/edgetpu=1"]
+            .iter()
+            .map(|n| n.parse().unwrap())
+            .collect();
+        let mut spec = OciSpec::default();
+        assert!(matches!(
+            registry.inject(&mut spec, &names),
+            Err(CdiError::Conflict {
+                kind: "device node",
+                ..
+            })
+        ));
+        assert!(spec
+            .linux()
+            .as_ref()
+            .and_then(|l| l.devices().as_ref())
+            .is_none());
+    }
+}
@@ -0,0 +1,522 @@
+//! Host libraries the Edge TPU runtime links against.
+//!
+//! The shared objects the runtime needs, and the symbol versions it needs of them, are read
+//! from its ELF dynamic section, then resolved the way the dynamic loader does: through the
+//! ld.so cache, then the standard library directories. Nothing depends on the distribution;
+//! [`PackageManager`] only maps what is missing to packages, for hints or to install them.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use object::elf;
+use object::read::elf::{Dyn, FileHeader};
+use object::Endianness;
+
+use crate::dep::transaction::under_root;
+use crate::dep::util::install_path_of;
+
+pub const LD_CACHE_PATH: &str = "/etc/ld.so.cache";
+/// Directories the dynamic loader searches after its cache
+const STANDARD_LIBRARY_DIRS: &[&str] = &["/lib64", "/usr/lib64", "/lib", "/usr/lib"];
+const LD_CACHE_MAGIC: &[u8] = b"glibc-ld.so.cache1.1";
+/// Format of glibc before 2.32, followed by the current one
+const LD_CACHE_OLD_MAGIC: &[u8] = b"ld.so-1.7.0";
+
+#[derive(Debug, thiserror::Error)]
+pub enum DependencyError {
+    #[error("failed to read {path:?}")]
+    Io {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("{path:?} is not an ELF shared object: {reason}")]
+    Elf { path: PathBuf, reason: String },
+}
+
+type Result<T> = std::result::Result<T, DependencyError>;
+
+/// Dynamic linking information of an ELF file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SharedObject {
+    /// `e_machine` of the header, objects only link with objects of the same machine
+    pub machine: u16,
+    /// Shared objects of the `DT_NEEDED` entries
+    pub needed: Vec<String>,
+    /// Symbol versions needed of each shared object, e.g. `GLIBC_2.28` of `libc.so.6`
+    pub version_needs: BTreeMap<String, Vec<String>>,
+    /// Symbol versions the object defines
+    pub version_defs: Vec<String>,
+}
+
+impl SharedObject {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path).map_err(|source| DependencyError::Io {
+            source,
+            path: path.to_owned(),
+        })?;
+        let parsed = match object::FileKind::parse(&*data) {
+            Ok(object::FileKind::Elf32) => parse::<elf::FileHeader32<Endianness>>(&data),
+            Ok(object::FileKind::Elf64) => parse::<elf::FileHeader64<Endianness>>(&data),
+            Ok(kind) => {
+                return Err(DependencyError::Elf {
+                    path: path.to_owned(),
+                    reason: format!("unexpected {kind:?} file"),
+                })
+            }
+            Err(err) => Err(err),
+        };
+        parsed.map_err(|err| DependencyError::Elf {
+            path: path.to_owned(),
+            reason: err.to_string(),
+        })
+    }
+}
+
+fn parse<Elf: FileHeader<Endian = Endianness>>(data: &[u8]) -> object::read::Result<SharedObject> {
+    let lossy = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+    let header = Elf::parse(data)?;
+    let endian = header.endian()?;
+    let sections = header.sections(endian, data)?;
+    let mut object = SharedObject {
+        machine: header.e_machine(endian),
+        ..Default::default()
+    };
+
+    if let Some((dynamic, link)) = sections.dynamic(endian, data)? {
+        let strings = sections.strings(endian, data, link)?;
+        for entry in dynamic {
+            if entry.tag32(endian) == Some(elf::DT_NEEDED) {
+                object.needed.push(lossy(entry.string(endian, strings)?));
+            }
+        }
+    }
+    if let Some((mut needs, link)) = sections.gnu_verneed(endian, data)? {
+        let strings = sections.strings(endian, data, link)?;
+        while let Some((need, mut versions)) = needs.next()? {
+            let file = lossy(need.file(endian, strings)?);
+            let needed = object.version_needs.entry(file).or_default();
+            while let Some(version) = versions.next()? {
+                needed.push(lossy(version.name(endian, strings)?));
+            }
+        }
+    }
+    if let Some((mut defs, link)) = sections.gnu_verdef(endian, data)? {
+        let strings = sections.strings(endian, data, link)?;
+        while let Some((_, mut names)) = defs.next()? {
+            // the first name is the version, the others are the versions it extends
+            if let Some(name) = names.next()? {
+                object.version_defs.push(lossy(name.name(endian, strings)?));
+            }
+        }
+    }
+    Ok(object)
+}
+
+/// A dependency the host does not satisfy.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Missing {
+    Library(String),
+    /// The library is too old for the versions
+    Versions {
+        soname: String,
+        path: PathBuf,
+        versions: Vec<String>,
+    },
+}
+
+impl Missing {
+    pub fn soname(&self) -> &str {
+        match self {
+            Self::Library(soname) | Self::Versions { soname, .. } => soname,
+        }
+    }
+}
+
+impl fmt::Display for Missing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Library(soname) => write!(f, "{soname} is not found"),
+            Self::Versions {
+                soname,
+                path,
+                versions,
+            } => write!(
+                f,
+                "{soname} at {} lacks the symbol versions {}",
+                path.display(),
+                versions.join(", ")
+            ),
+        }
+    }
+}
+
+/// Checks the host provides what a shared object links against.
+///
+/// # Example
+///
+/// ```no_run
+/// use edgetpu::dep::dependency::DependencyCheck;
+///
+/// let missing = DependencyCheck::new()
+///     .check("edgetpu_runtime/libedgetpu/direct/k8/libedgetpu.so.1.0")
+///     .unwrap();
+/// for dependency in missing {
+///     println!("{dependency}");
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct DependencyCheck {
+    root: PathBuf,
+    ld_cache: PathBuf,
+    library_dirs: Vec<PathBuf>,
+}
+
+impl Default for DependencyCheck {
+    fn default() -> Self {
+        // the multiarch directories come first on Debian-like hosts
+        let mut library_dirs = Vec::new();
+        if let Some(triplet) = install_path_of().file_name() {
+            library_dirs.push(Path::new("/lib").join(triplet));
+            library_dirs.push(Path::new("/usr/lib").join(triplet));
+        }
+        library_dirs.extend(STANDARD_LIBRARY_DIRS.iter().map(PathBuf::from));
+        Self {
+            root: PathBuf::from("/"),
+            ld_cache: PathBuf::from(LD_CACHE_PATH),
+            library_dirs,
+        }
+    }
+}
+
+impl DependencyCheck {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the root prefix of the cache and of the library directories
+    pub fn with_root<P: Into<PathBuf>>(mut self, root: P) -> Self {
+        self.root = root.into();
+        self
+    }
+
+    /// Searches `dir` before the standard library directories
+    pub fn with_library_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.library_dirs.insert(0, dir.into());
+        self
+    }
+
+    /// Lists what the host lacks of the dependencies of `library`.
+    pub fn check<P: AsRef<Path>>(&self, library: P) -> Result<Vec<Missing>> {
+        let object = SharedObject::read(library)?;
+        let cache = self.ld_cache();
+
+        let mut missing = Vec::new();
+        for soname in &object.needed {
+            let Some((path, dependency)) = self.resolve_with(&cache, soname, object.machine) else {
+                missing.push(Missing::Library(soname.clone()));
+                continue;
+            };
+            let versions: Vec<String> = object
+                .version_needs
+                .get(soname)
+                .into_iter()
+                .flatten()
+                .filter(|version| !dependency.version_defs.contains(version))
+                .cloned()
+                .collect();
+            if !versions.is_empty() {
+                missing.push(Missing::Versions {
+                    soname: soname.clone(),
+                    path,
+                    versions,
+                });
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Finds the shared object of `soname` built for `machine`, as a path of the host.
+    pub fn resolve(&self, soname: &str, machine: u16) -> Option<PathBuf> {
+        self.resolve_with(&self.ld_cache(), soname, machine)
+            .map(|(path, _)| path)
+    }
+
+    fn resolve_with(
+        &self,
+        cache: &[(String, PathBuf)],
+        soname: &str,
+        machine: u16,
+    ) -> Option<(PathBuf, SharedObject)> {
+        let cached = cache
+            .iter()
+            .filter(|(name, _)| name == soname)
+            .map(|(_, path)| path.clone());
+        let searched = self.library_dirs.iter().map(|dir| dir.join(soname));
+        cached.chain(searched).find_map(|path| {
+            let object = SharedObject::read(under_root(&self.root, &path)).ok()?;
+            (object.machine == machine).then_some((path, object))
+        })
+    }
+
+    fn ld_cache(&self) -> Vec<(String, PathBuf)> {
+        let path = under_root(&self.root, &self.ld_cache);
+        match fs::read(&path) {
+            Ok(data) => parse_ld_cache(&data).unwrap_or_else(|| {
+                log::warn!("unsupported ld.so cache format of {}", path.display());
+                Vec::new()
+            }),
+            Err(err) => {
+                log::debug!("no ld.so cache at {}: {err}", path.display());
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// Entries of an ld.so cache as soname and path, `None` for an unknown format
+fn parse_ld_cache(data: &[u8]) -> Option<Vec<(String, PathBuf)>> {
+    let u32_at = |offset: usize| -> Option<u32> {
+        Some(u32::from_ne_bytes(
+            data.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+    let base = if data.starts_with(LD_CACHE_MAGIC) {
+        0
+    } else if data.starts_with(LD_CACHE_OLD_MAGIC) {
+        // 12 bytes per entry of the old format, then the new format aligned on 8 bytes
+        let old_entries = u32_at(12)? as usize;
+        (16 + old_entries * 12).next_multiple_of(8)
+    } else {
+        return None;
+    };
+    if !data.get(base..)?.starts_with(LD_CACHE_MAGIC) {
+        return None;
+    }
+
+    let count = u32_at(base + LD_CACHE_MAGIC.len())? as usize;
+    // string offsets are relative to the header of the new format
+    let string_at = |offset: u32| -> Option<String> {
+        let bytes = data.get(base + offset as usize..)?;
+        let end = bytes.iter().position(|&byte| byte == 0)?;
+        Some(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    };
+    let mut entries = Vec::with_capacity(count);
+    for index in 0..count {
+        // flags, key, value, osversion and hwcap
+        let entry = base + 48 + index * 24;
+        let soname = string_at(u32_at(entry + 4)?)?;
+        let path = string_at(u32_at(entry + 8)?)?;
+        entries.push((soname, PathBuf::from(path)));
+    }
+    Some(entries)
+}
+
+/// Package managers the installer can install missing libraries with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageManager {
+    Apt,
+    Dnf,
+    Apk,
+}
+
+/// Packages providing each shared object, for apt, dnf and apk
+const PACKAGES: &[(&str, [&str; 3])] = &[
+    ("libc.so.6", ["libc6", "glibc", "gcompat"]),
+    ("libm.so.6", ["libc6", "glibc", "gcompat"]),
+    ("libdl.so.2", ["libc6", "glibc", "gcompat"]),
+    ("libpthread.so.0", ["libc6", "glibc", "gcompat"]),
+    ("librt.so.1", ["libc6", "glibc", "gcompat"]),
+    ("ld-linux-x86-64.so.2", ["libc6", "glibc", "gcompat"]),
+    ("ld-linux-aarch64.so.1", ["libc6", "glibc", "gcompat"]),
+    ("ld-linux-armhf.so.3", ["libc6", "glibc", "gcompat"]),
+    ("libgcc_s.so.1", ["libgcc-s1", "libgcc", "libgcc"]),
+    ("libstdc++.so.6", ["libstdc++6", "libstdc++", "libstdc++"]),
+    ("libusb-1.0.so.0", ["libusb-1.0-0", "libusb1", "libusb"]),
+];
+
+impl PackageManager {
+    /// Finds the package manager of the host in `root`.
+    pub fn detect(root: &Path) -> Option<Self> {
+        [
+            (Self::Apt, "/usr/bin/apt-get"),
+            (Self::Dnf, "/usr/bin/dnf"),
+            (Self::Apk, "/sbin/apk"),
+        ]
+        .into_iter()
+        .find(|(_, program)| under_root(root, program).exists())
+        .map(|(manager, _)| manager)
+    }
+
+    /// Package providing `soname`
+    pub fn package_of(&self, soname: &str) -> Option<&'static str> {
+        let column = match self {
+            Self::Apt => 0,
+            Self::Dnf => 1,
+            Self::Apk => 2,
+        };
+        PACKAGES
+            .iter()
+            .find(|(name, _)| *name == soname)
+            .map(|(_, packages)| packages[column])
+    }
+
+    /// Packages providing the missing dependencies, skipping those without a known package
+    pub fn packages_for(&self, missing: &[Missing]) -> Vec<String> {
+        let mut packages: Vec<String> = Vec::new();
+        for dependency in missing {
+            match self.package_of(dependency.soname()) {
+                Some(package) if !packages.iter().any(|known| known == package) => {
+                    packages.push(package.to_owned())
+                }
+                Some(_) => {}
+                None => log::warn!("no known {self} package provides {}", dependency.soname()),
+            }
+        }
+        packages
+    }
+
+    /// Commands installing or upgrading `packages`
+    pub fn install_commands(&self, packages: &[String]) -> Vec<Vec<String>> {
+        let command = |args: &[&str]| -> Vec<String> {
+            args.iter()
+                .map(|arg| arg.to_string())
+                .chain(packages.iter().cloned())
+                .collect()
+        };
+        match self {
+            Self::Apt => vec![
+                vec!["apt-get".to_owned(), "update".to_owned()],
+                command(&["apt-get", "install", "-y"]),
+            ],
+            Self::Dnf => vec![command(&["dnf", "install", "-y"])],
+            Self::Apk => vec![command(&["apk", "add"])],
+        }
+    }
+}
+
+impl fmt::Display for PackageManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Apt => write!(f, "apt"),
+            Self::Dnf => write!(f, "dnf"),
+            Self::Apk => write!(f, "apk"),
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux", target_env = "gnu"))]
+mod tests {
+    use super::*;
+
+    /// ld.so cache of the current format mapping sonames to paths
+    fn ld_cache(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut strings = Vec::new();
+        let mut offsets = Vec::new();
+        let strings_start = 48 + entries.len() * 24;
+        for (soname, path) in entries {
+            let key = strings_start + strings.len();
+            strings.extend_from_slice(soname.as_bytes());
+            strings.push(0);
+            let value = strings_start + strings.len();
+            strings.extend_from_slice(path.as_bytes());
+            strings.push(0);
+            offsets.push((key as u32, value as u32));
+        }
+
+        let mut cache = LD_CACHE_MAGIC.to_vec();
+        cache.extend_from_slice(&(entries.len() as u32).to_ne_bytes());
+        cache.extend_from_slice(&(strings.len() as u32).to_ne_bytes());
+        cache.resize(48, 0);
+        for (key, value) in offsets {
+            cache.extend_from_slice(&0x0303i32.to_ne_bytes());
+            cache.extend_from_slice(&key.to_ne_bytes());
+            cache.extend_from_slice(&value.to_ne_bytes());
+            cache.resize(cache.len() + 12, 0);
+        }
+        cache.extend_from_slice(&strings);
+        cache
+    }
+
+    #[test]
+    fn test_read_shared_object() {
+        let object = SharedObject::read(std::env::current_exe().unwrap()).unwrap();
+        assert!(object.needed.contains(&"libc.so.6".to_owned()));
+        assert!(object.version_needs["libc.so.6"]
+            .iter()
+            .all(|version| version.starts_with("GLIBC_")));
+
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("libedgetpu.so.1"), "not a library").unwrap();
+        assert!(matches!(
+            SharedObject::read(tmp.path().join("libedgetpu.so.1")),
+            Err(DependencyError::Elf { .. })
+        ));
+    }
+
+    #[test]
+    fn test_check_host() {
+        // the test runs, so the host satisfies its dependencies
+        let exe = std::env::current_exe().unwrap();
+        assert_eq!(DependencyCheck::new().check(&exe).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn test_check_fake_root() {
+        let exe = std::env::current_exe().unwrap();
+        let object = SharedObject::read(&exe).unwrap();
+        let libgcc = DependencyCheck::new()
+            .resolve("libgcc_s.so.1", object.machine)
+            .unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let check = DependencyCheck::new().with_root(root.path());
+        let missing = check.check(&exe).unwrap();
+        assert_eq!(missing.len(), object.needed.len());
+        assert!(missing.contains(&Missing::Library("libc.so.6".to_owned())));
+
+        // found through the cache, but it does not define the versions of glibc
+        fs::create_dir_all(root.path().join("opt/glibc")).unwrap();
+        fs::copy(libgcc, root.path().join("opt/glibc/libc.so.6")).unwrap();
+        fs::create_dir_all(root.path().join("etc")).unwrap();
+        fs::write(
+            root.path().join("etc/ld.so.cache"),
+            ld_cache(&[("libc.so.6", "/opt/glibc/libc.so.6")]),
+        )
+        .unwrap();
+        let missing = check.check(&exe).unwrap();
+        let Some(Missing::Versions { path, versions, .. }) = missing
+            .iter()
+            .find(|dependency| dependency.soname() == "libc.so.6")
+        else {
+            panic!("libc.so.6 should lack versions: {missing:?}");
+        };
+        assert_eq!(path, Path::new("/opt/glibc/libc.so.6"));
+        assert_eq!(versions, &object.version_needs["libc.so.6"]);
+    }
+
+    #[test]
+    fn test_packages() {
+        let root = tempfile::tempdir().unwrap();
+        assert_eq!(PackageManager::detect(root.path()), None);
+        fs::create_dir_all(root.path().join("sbin")).unwrap();
+        fs::write(root.path().join("sbin/apk"), "").unwrap();
+        assert_eq!(
+            PackageManager::detect(root.path()),
+            Some(PackageManager::Apk)
+        );
+
+        let missing = vec![
+            Missing::Library("libc.so.6".to_owned()),
+            Missing::Library("libm.so.6".to_owned()),
+            Missing::Library("libusb-1.0.so.0".to_owned()),
+            Missing::Library("libunknown.so.1".to_owned()),
+        ];
+        let packages = PackageManager::Dnf.packages_for(&missing);
+        assert_eq!(packages, vec!["glibc".to_owned(), "libusb1".to_owned()]);
+        assert_eq!(
+            PackageManager::Dnf.install_commands(&packages),
+            vec![vec!["dnf", "install", "-y", "glibc", "libusb1"]]
+        );
+    }
+}
@@ -1,47 +1,722 @@
+//! Verified download of the Edge TPU runtime release archive.
+//!
+//! Archives are fetched from a mirror of the GitHub releases and checked against a SHA-256
+//! pinned in a [`Manifest`] before anything is extracted. Verified archives are kept in a
+//! cache keyed by their digest, so that later installs and hosts without network access
+//! reuse them. An archive already on disk is installed with [`Downloader::extract_offline`].
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
 use reqwest::blocking::Client;
-use std::fs::{create_dir_all, File};
-use std::io::Write;
-use std::path::PathBuf;
+use sha2::{Digest, Sha256};
 use zip::ZipArchive;
 
-pub fn download_edgetpu_runtime(out_path: &PathBuf) -> Result<PathBuf, Box<dyn std::error::Error>> {
-    create_dir_all(out_path)?;
+pub const DEFAULT_MIRROR: &str = "https://github.com/google-coral/libedgetpu/releases/download";
+pub const DEFAULT_RELEASE: &str = "release-grouper";
+pub const DEFAULT_ARCHIVE: &str = "edgetpu_runtime_20221024.zip";
+pub const DEFAULT_CACHE_DIR: &str = "/var/cache/tpu-container-runtime/artifacts";
+/// Directory at the root of the runtime archive
+pub const RUNTIME_DIR: &str = "edgetpu_runtime";
+
+/// Digests pinned with the sources, in the format of [`Manifest`]
+const PINNED_MANIFEST: &str = include_str!("SHA256SUMS");
+/// File written next to an extracted runtime with the digest of its archive
+const DIGEST_FILE: &str = ".sha256";
+
+#[derive(Debug, thiserror::Error)]
+pub enum DownloadError {
+    #[error("failed to download {url}")]
+    Http { source: reqwest::Error, url: String },
+    #[error("failed to download {url}: HTTP {status}")]
+    Status { status: u16, url: String },
+    #[error("no SHA-256 is pinned for {0}, pass one with --sha256 or a manifest with --manifest")]
+    Unpinned(String),
+    #[error("{artifact} has SHA-256 {actual}, expected {expected}")]
+    Digest {
+        artifact: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("invalid manifest line {line}: {reason}")]
+    Manifest { line: usize, reason: String },
+    #[error("invalid archive {path:?}")]
+    Zip {
+        source: zip::result::ZipError,
+        path: PathBuf,
+    },
+    #[error("archive entry {0:?} would be extracted outside of the destination")]
+    UnsafeEntry(String),
+    #[error("I/O error on {path:?}")]
+    Io { source: io::Error, path: PathBuf },
+}
+
+type Result<T> = std::result::Result<T, DownloadError>;
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> DownloadError + '_ {
+    move |source| DownloadError::Io {
+        source,
+        path: path.to_owned(),
+    }
+}
+
+/// SHA-256 digests of release artifacts, one `<hex digest>  <release>/<file>` per line as
+/// written by `sha256sum`. Empty lines and lines starting with `#` are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    entries: Vec<(String, String)>,
+}
+
+impl Manifest {
+    /// The digests pinned with the sources
+    pub fn pinned() -> Self {
+        PINNED_MANIFEST
+            .parse()
+            .expect("the pinned manifest is valid")
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        fs::read_to_string(path).map_err(io_error(path))?.parse()
+    }
+
+    /// Pins the digest of an artifact, replacing any other
+    pub fn with_digest<A: Into<String>>(mut self, artifact: A, sha256: &str) -> Self {
+        let artifact = artifact.into();
+        self.entries.retain(|(name, _)| *name != artifact);
+        self.entries.push((artifact, sha256.to_ascii_lowercase()));
+        self
+    }
+
+    /// Digest of `<release>/<file>`
+    pub fn digest(&self, artifact: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(name, _)| name == artifact)
+            .map(|(_, digest)| digest.as_str())
+    }
+
+    /// Digests of the artifacts with this file name, whatever their release
+    fn digests_of_file<'a>(&'a self, file: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(name, _)| name.rsplit('/').next() == Some(file))
+            .map(|(_, digest)| digest.as_str())
+    }
+}
+
+impl FromStr for Manifest {
+    type Err = DownloadError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut manifest = Self::default();
+        for (index, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| DownloadError::Manifest {
+                line: index + 1,
+                reason: reason.to_owned(),
+            };
+            let (digest, artifact) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| invalid("expected a digest and an artifact"))?;
+            // `sha256sum` marks binary mode with a `*` before the name
+            let artifact = artifact.trim_start().trim_start_matches('*');
+            if digest.len() != 64 || !digest.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                return Err(invalid("the digest is not a hex SHA-256"));
+            }
+            if artifact.is_empty() {
+                return Err(invalid("the artifact is missing"));
+            }
+            manifest = manifest.with_digest(artifact, digest);
+        }
+        Ok(manifest)
+    }
+}
+
+/// Fetches, verifies, caches and extracts the runtime archive.
+///
+/// # Example
+///
+/// ```no_run
+/// use edgetpu::dep::download::Downloader;
+///
+/// let runtime_dir = Downloader::new()
+///     .with_mirror("https://mirror.example.com/libedgetpu")
+///     .download_to("downloads".as_ref())
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Downloader {
+    mirror: String,
+    release: String,
+    archive: String,
+    manifest: Manifest,
+    cache_dir: PathBuf,
+    allow_unverified: bool,
+}
+
+impl Default for Downloader {
+    fn default() -> Self {
+        Self {
+            mirror: DEFAULT_MIRROR.to_owned(),
+            release: DEFAULT_RELEASE.to_owned(),
+            archive: DEFAULT_ARCHIVE.to_owned(),
+            manifest: Manifest::pinned(),
+            cache_dir: PathBuf::from(DEFAULT_CACHE_DIR),
+            allow_unverified: false,
+        }
+    }
+}
+
+impl Downloader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the base URL the releases are downloaded from, as `<mirror>/<release>/<file>`
+    pub fn with_mirror<S: Into<String>>(mut self, mirror: S) -> Self {
+        self.mirror = mirror.into().trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// Sets the release and the file of the archive
+    pub fn with_release<R: Into<String>, A: Into<String>>(
+        mut self,
+        release: R,
+        archive: A,
+    ) -> Self {
+        self.release = release.into();
+        self.archive = archive.into();
+        self
+    }
+
+    /// Sets the digests archives are checked against
+    pub fn with_manifest(mut self, manifest: Manifest) -> Self {
+        self.manifest = manifest;
+        self
+    }
+
+    /// Pins the digest of the archive, of the release set so far
+    pub fn with_sha256(mut self, sha256: &str) -> Self {
+        let artifact = self.artifact();
+        self.manifest = std::mem::take(&mut self.manifest).with_digest(artifact, sha256);
+        self
+    }
+
+    /// Sets the directory verified archives are kept in
+    pub fn with_cache_dir<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.cache_dir = path.into();
+        self
+    }
+
+    /// Lets [`Downloader::extract_offline`] install archives no digest is pinned for
+    pub fn with_allow_unverified(mut self, allow: bool) -> Self {
+        self.allow_unverified = allow;
+        self
+    }
+
+    /// The archive as named in manifests, `<release>/<file>`
+    pub fn artifact(&self) -> String {
+        format!("{}/{}", self.release, self.archive)
+    }
+
+    pub fn url(&self) -> String {
+        format!("{}/{}", self.mirror, self.artifact())
+    }
+
+    /// Returns the verified archive, from the cache when it is there.
+    pub fn fetch(&self) -> Result<PathBuf> {
+        let artifact = self.artifact();
+        let expected = self
+            .manifest
+            .digest(&artifact)
+            .ok_or_else(|| DownloadError::Unpinned(artifact.clone()))?;
+        let cached = self.cache_dir.join(format!("{expected}.zip"));
+        if cached.exists() {
+            let actual = sha256_of(&cached)?;
+            if actual == expected {
+                log::info!("using the cached {artifact}");
+                return Ok(cached);
+            }
+            log::warn!(
+                "the cached {} is corrupted, downloading it again",
+                cached.display()
+            );
+            fs::remove_file(&cached).map_err(io_error(&cached))?;
+        }
+
+        fs::create_dir_all(&self.cache_dir).map_err(io_error(&self.cache_dir))?;
+        let partial = self.cache_dir.join(format!(".{expected}.zip.partial"));
+        let url = self.url();
+        log::info!("downloading {url}");
+        let actual = match download(&url, &partial) {
+            Ok(actual) => actual,
+            Err(err) => {
+                let _ = fs::remove_file(&partial);
+                return Err(err);
+            }
+        };
+        if actual != expected {
+            let _ = fs::remove_file(&partial);
+            return Err(DownloadError::Digest {
+                artifact,
+                expected: expected.to_owned(),
+                actual,
+            });
+        }
+        fs::rename(&partial, &cached).map_err(io_error(&cached))?;
+        Ok(cached)
+    }
+
+    /// Fetches the archive and extracts it in `out_dir`, returning the runtime directory.
+    pub fn download_to(&self, out_dir: &Path) -> Result<PathBuf> {
+        let archive = self.fetch()?;
+        extract_runtime(&archive, &sha256_of(&archive)?, out_dir)
+    }
+
+    /// Extracts an archive already on disk in `out_dir`, returning the runtime directory.
+    /// The archive must match the digests pinned for its file name, an archive without any
+    /// is only installed when unverified archives are allowed.
+    pub fn extract_offline(&self, archive: &Path, out_dir: &Path) -> Result<PathBuf> {
+        let actual = sha256_of(archive)?;
+        let file = archive
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let pinned: Vec<&str> = self.manifest.digests_of_file(&file).collect();
+        if pinned.is_empty() {
+            if !self.allow_unverified {
+                return Err(DownloadError::Unpinned(file));
+            }
+            log::warn!(
+                "no SHA-256 is pinned for {file}, installing {} unverified (SHA-256 {actual})",
+                archive.display()
+            );
+        } else if !pinned.contains(&actual.as_str()) {
+            return Err(DownloadError::Digest {
+                artifact: archive.display().to_string(),
+                expected: pinned.join(" or "),
+                actual,
+            });
+        }
+        extract_runtime(archive, &actual, out_dir)
+    }
+}
 
-    let url = "https://github.com/google-coral/libedgetpu/releases/download/release-grouper/edgetpu_runtime_20221024.zip";
-    let zip_path = out_path.join("edgetpu_runtime.zip");
-    let edgetpu_path = out_path.join("edgetpu_runtime");
+/// Downloads the default release in `out_path` and returns the runtime directory.
+pub fn download_edgetpu_runtime(out_path: &Path) -> Result<PathBuf> {
+    Downloader::new().download_to(out_path)
+}
 
-    if !zip_path.exists() {
-        download_file(url, &zip_path)?;
+/// Streams the body of `url` into `dest`, returning its SHA-256.
+fn download(url: &str, dest: &Path) -> Result<String> {
+    let http_error = |source| DownloadError::Http {
+        source,
+        url: url.to_owned(),
+    };
+    let mut response = Client::new().get(url).send().map_err(http_error)?;
+    if !response.status().is_success() {
+        return Err(DownloadError::Status {
+            status: response.status().as_u16(),
+            url: url.to_owned(),
+        });
+    }
 
-        if zip_path.metadata()?.len() == 0 {
-            return Err("Download failed: ZIP file is empty".into());
+    let mut file = File::create(dest).map_err(io_error(dest))?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let read = response.read(&mut buf).map_err(io_error(dest))?;
+        if read == 0 {
+            break;
         }
+        hasher.update(&buf[..read]);
+        file.write_all(&buf[..read]).map_err(io_error(dest))?;
     }
+    file.sync_all().map_err(io_error(dest))?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+pub fn sha256_of(path: &Path) -> Result<String> {
+    let mut file = File::open(path).map_err(io_error(path))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).map_err(io_error(path))?;
+    Ok(hex::encode(hasher.finalize()))
+}
 
-    if !edgetpu_path.exists() {
-        extract_zip(&zip_path, out_path)?;
+/// Extracts the runtime unless the same archive already was, tracked by its digest.
+fn extract_runtime(archive: &Path, sha256: &str, out_dir: &Path) -> Result<PathBuf> {
+    let runtime_dir = out_dir.join(RUNTIME_DIR);
+    let digest_file = runtime_dir.join(DIGEST_FILE);
+    if fs::read_to_string(&digest_file).is_ok_and(|digest| digest.trim() == sha256) {
+        return Ok(runtime_dir);
     }
 
-    Ok(edgetpu_path)
+    // extracted aside, so that an interrupted extraction is never taken for a runtime
+    let staging = out_dir.join(format!(".{RUNTIME_DIR}.{sha256}"));
+    if staging.exists() {
+        fs::remove_dir_all(&staging).map_err(io_error(&staging))?;
+    }
+    extract_zip(archive, &staging)?;
+    let extracted = staging.join(RUNTIME_DIR);
+    fs::write(extracted.join(DIGEST_FILE), sha256).map_err(io_error(&extracted))?;
+    if runtime_dir.exists() {
+        fs::remove_dir_all(&runtime_dir).map_err(io_error(&runtime_dir))?;
+    }
+    fs::rename(&extracted, &runtime_dir).map_err(io_error(&runtime_dir))?;
+    fs::remove_dir_all(&staging).map_err(io_error(&staging))?;
+    Ok(runtime_dir)
 }
 
-pub fn download_file(url: &str, dest: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
-    let client = Client::new();
-    let response = client.get(url).send()?;
-    if response.status().is_success() {
-        let mut file = File::create(dest)?;
-        let content = response.bytes()?;
-        file.write_all(&content)?;
-    } else {
-        return Err(format!("Failed to download file: HTTP {}", response.status()).into());
+/// Extracts every entry of the archive in `extract_to`. Entries whose path or link target
+/// would leave `extract_to`, or that would be written through a link extracted before them,
+/// abort the extraction.
+pub fn extract_zip(zip_path: &Path, extract_to: &Path) -> Result<()> {
+    let zip_error = |source| DownloadError::Zip {
+        source,
+        path: zip_path.to_owned(),
+    };
+    let zip_file = File::open(zip_path).map_err(io_error(zip_path))?;
+    let mut archive = ZipArchive::new(zip_file).map_err(zip_error)?;
+
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).map_err(zip_error)?;
+        let relative = contained_path(entry.name())
+            .ok_or_else(|| DownloadError::UnsafeEntry(entry.name().to_owned()))?;
+        let path = extract_to.join(&relative);
+
+        if entry.is_dir() {
+            check_no_symlink(extract_to, &relative, entry.name())?;
+            fs::create_dir_all(&path).map_err(io_error(&path))?;
+            continue;
+        }
+        let parent = relative.parent().unwrap_or(Path::new(""));
+        check_no_symlink(extract_to, parent, entry.name())?;
+        fs::create_dir_all(extract_to.join(parent)).map_err(io_error(&path))?;
+
+        if entry.is_symlink() {
+            let mut target = String::new();
+            entry.read_to_string(&mut target).map_err(io_error(&path))?;
+            let resolved = parent.join(&target);
+            if Path::new(&target).is_absolute()
+                || contained_path(&resolved.to_string_lossy()).is_none()
+            {
+                return Err(DownloadError::UnsafeEntry(entry.name().to_owned()));
+            }
+            std::os::unix::fs::symlink(&target, &path).map_err(io_error(&path))?;
+            continue;
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&path)
+            .map_err(io_error(&path))?;
+        io::copy(&mut entry, &mut file).map_err(io_error(&path))?;
+        if let Some(mode) = entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o777))
+                .map_err(io_error(&path))?;
+        }
     }
     Ok(())
 }
 
-pub fn extract_zip(zip_path: &PathBuf, extract_to: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
-    let zip_file = File::open(zip_path)?;
-    let mut archive = ZipArchive::new(zip_file)?;
-    archive.extract(extract_to)?;
+/// Fails when a component of `relative` already extracted in `extract_to` is a symbolic
+/// link: the entry `name` would be written wherever it points.
+fn check_no_symlink(extract_to: &Path, relative: &Path, name: &str) -> Result<()> {
+    let mut path = extract_to.to_owned();
+    for component in relative.components() {
+        path.push(component);
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(DownloadError::UnsafeEntry(name.to_owned()))
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => break,
+            Err(err) => return Err(io_error(&path)(err)),
+        }
+    }
     Ok(())
 }
+
+/// The path relative to the extraction directory, `None` when it would leave it.
+fn contained_path(name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !path.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!path.as_os_str().is_empty()).then_some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    use super::*;
+
+    /// Stand-in for the mirror, serving `body` on every path but `/missing`
+    struct Mirror {
+        url: String,
+        requests: Arc<AtomicUsize>,
+    }
+
+    impl Mirror {
+        fn serve(body: Vec<u8>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(AtomicUsize::new(0));
+            let counter = requests.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut request_line = String::new();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    reader.read_line(&mut request_line).unwrap();
+                    // drain the headers
+                    let mut header = String::new();
+                    while reader.read_line(&mut header).unwrap() > 2 {
+                        header.clear();
+                    }
+                    counter.fetch_add(1, Ordering::SeqCst);
+
+                    if request_line.contains("/missing/") {
+                        stream
+                            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                            .unwrap();
+                        continue;
+                    }
+                    let header = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    stream.write_all(header.as_bytes()).unwrap();
+                    stream.write_all(&body).unwrap();
+                }
+            });
+            Self { url, requests }
+        }
+
+        fn requests(&self) -> usize {
+            self.requests.load(Ordering::SeqCst)
+        }
+    }
+
+    fn runtime_zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(io::Cursor::new(Vec::new()));
+        for (name, content) in entries {
+            writer
+                .start_file(*name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(content).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        hex::encode(Sha256::digest(data))
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let digest = "a".repeat(64);
+        let manifest: Manifest = format!(
+            "# pinned digests\n{digest}  release-grouper/edgetpu_runtime.zip\n\n{}  *r/b.zip\n",
+            "B".repeat(64)
+        )
+        .parse()
+        .unwrap();
+        assert_eq!(
+            manifest.digest("release-grouper/edgetpu_runtime.zip"),
+            Some(digest.as_str())
+        );
+        assert_eq!(manifest.digest("r/b.zip"), Some("b".repeat(64).as_str()));
+
+        assert!(matches!(
+            "abc  r/a.zip".parse::<Manifest>(),
+            Err(DownloadError::Manifest { line: 1, .. })
+        ));
+        Manifest::pinned();
+    }
+
+    #[test]
+    fn test_download_is_verified_and_cached() {
+        let zip = runtime_zip(&[("edgetpu_runtime/install.sh", b"#!/bin/sh\n")]);
+        let mirror = Mirror::serve(zip.clone());
+        let cache = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let downloader = Downloader::new()
+            .with_mirror(format!("{}/", mirror.url))
+            .with_cache_dir(cache.path())
+            .with_manifest(Manifest::default())
+            .with_sha256(&sha256(&zip));
+
+        let runtime_dir = downloader.download_to(out.path()).unwrap();
+        assert_eq!(runtime_dir, out.path().join(RUNTIME_DIR));
+        assert_eq!(
+            fs::read(runtime_dir.join("install.sh")).unwrap(),
+            b"#!/bin/sh\n"
+        );
+        assert_eq!(
+            fs::read(cache.path().join(format!("{}.zip", sha256(&zip)))).unwrap(),
+            zip
+        );
+
+        downloader.download_to(out.path()).unwrap();
+        assert_eq!(mirror.requests(), 1);
+
+        // a truncated archive in the cache is downloaded again
+        let cached = downloader.fetch().unwrap();
+        fs::write(&cached, &zip[..zip.len() / 2]).unwrap();
+        assert_eq!(downloader.fetch().unwrap(), cached);
+        assert_eq!(mirror.requests(), 2);
+        assert_eq!(fs::read(&cached).unwrap(), zip);
+    }
+
+    #[test]
+    fn test_download_is_rejected() {
+        let zip = runtime_zip(&[("edgetpu_runtime/install.sh", b"tampered")]);
+        let mirror = Mirror::serve(zip);
+        let cache = tempfile::tempdir().unwrap();
+        let downloader = Downloader::new()
+            .with_mirror(&mirror.url)
+            .with_cache_dir(cache.path())
+            .with_manifest(Manifest::default());
+
+        assert!(matches!(
+            downloader.fetch(),
+            Err(DownloadError::Unpinned(_))
+        ));
+        assert_eq!(mirror.requests(), 0);
+
+        let pinned = downloader.clone().with_sha256(&"0".repeat(64));
+        assert!(matches!(pinned.fetch(), Err(DownloadError::Digest { .. })));
+        assert_eq!(fs::read_dir(cache.path()).unwrap().count(), 0);
+
+        let missing = pinned
+            .with_release("missing", DEFAULT_ARCHIVE)
+            .with_sha256(&"0".repeat(64));
+        assert!(matches!(
+            missing.fetch(),
+            Err(DownloadError::Status { status: 404, .. })
+        ));
+    }
+
+    #[test]
+    fn test_extract_offline() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join(DEFAULT_ARCHIVE);
+        let zip = runtime_zip(&[("edgetpu_runtime/libedgetpu/edgetpu.h", b"// header")]);
+        fs::write(&archive, &zip).unwrap();
+
+        let out = tmp.path().join("out");
+        let unpinned = Downloader::new().with_manifest(Manifest::default());
+        assert!(matches!(
+            unpinned.extract_offline(&archive, &out),
+            Err(DownloadError::Unpinned(_))
+        ));
+        assert!(!out.join(RUNTIME_DIR).exists());
+
+        let runtime_dir = unpinned
+            .clone()
+            .with_sha256(&sha256(&zip))
+            .extract_offline(&archive, &out)
+            .unwrap();
+        assert!(runtime_dir.join("libedgetpu/edgetpu.h").exists());
+        fs::remove_dir_all(&runtime_dir).unwrap();
+        unpinned
+            .with_allow_unverified(true)
+            .extract_offline(&archive, &out)
+            .unwrap();
+        assert!(runtime_dir.join("libedgetpu/edgetpu.h").exists());
+
+        let pinned_elsewhere = Manifest::default().with_digest(
+            format!("{DEFAULT_RELEASE}/{DEFAULT_ARCHIVE}"),
+            &"0".repeat(64),
+        );
+        assert!(matches!(
+            Downloader::new()
+                .with_manifest(pinned_elsewhere)
+                .extract_offline(&archive, &out),
+            Err(DownloadError::Digest { .. })
+        ));
+    }
+
+    #[test]
+    fn test_zip_slip_is_rejected() {
+        let tmp = tempfile::tempdir().unwrap();
+        for name in ["edgetpu_runtime/../../evil", "/etc/evil"] {
+            let archive = tmp.path().join("slip.zip");
+            fs::write(&archive, runtime_zip(&[(name, b"evil")])).unwrap();
+            let out = tmp.path().join("out");
+            assert!(
+                matches!(
+                    extract_zip(&archive, &out),
+                    Err(DownloadError::UnsafeEntry(_))
+                ),
+                "{name}"
+            );
+        }
+        assert!(!tmp.path().join("evil").exists());
+
+        let mut writer = ZipWriter::new(io::Cursor::new(Vec::new()));
+        writer
+            .add_symlink(
+                "edgetpu_runtime/lib",
+                "../../..",
+                SimpleFileOptions::default(),
+            )
+            .unwrap();
+        let archive = tmp.path().join("link.zip");
+        fs::write(&archive, writer.finish().unwrap().into_inner()).unwrap();
+        assert!(matches!(
+            extract_zip(&archive, &tmp.path().join("out")),
+            Err(DownloadError::UnsafeEntry(_))
+        ));
+
+        // each link stays in the archive, the chain of them does not
+        let mut writer = ZipWriter::new(io::Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default();
+        writer.add_directory("d/e", options).unwrap();
+        writer.add_symlink("d/e/l", "..", options).unwrap();
+        writer.add_symlink("d/e/l/m", "..", options).unwrap();
+        writer.add_symlink("d/e/l/m/n", "..", options).unwrap();
+        writer.start_file("d/e/l/m/n/evil", options).unwrap();
+        writer.write_all(b"evil").unwrap();
+        let archive = tmp.path().join("chain.zip");
+        fs::write(&archive, writer.finish().unwrap().into_inner()).unwrap();
+        let out = tmp.path().join("chain");
+        assert!(matches!(
+            extract_zip(&archive, &out),
+            Err(DownloadError::UnsafeEntry(name)) if name == "d/e/l/m"
+        ));
+        assert!(!tmp.path().join("evil").exists());
+
+        assert_eq!(
+            contained_path("edgetpu_runtime/./a/../b"),
+            Some(PathBuf::from("edgetpu_runtime/b"))
+        );
+    }
+}
@@ -1,9 +1,18 @@
+use crate::dep::udev::UdevRules;
 use crate::dep::util::{check_privileges, install_path_of};
+use crate::image::frequency::Frequency;
 use log::{info, warn};
+#[cfg(not(target_os = "linux"))]
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+#[cfg(not(target_os = "linux"))]
 use std::process::Command;
 
+#[cfg(target_os = "linux")]
+use crate::dep::dependency::{DependencyCheck, PackageManager};
+#[cfg(target_os = "linux")]
+use crate::dep::transaction::{CommandRunner, FileKind, InstallManifest, Plan, Step, SystemRunner};
+
 #[cfg(any(target_os = "macos"))]
 use nix::unistd::Uid;
 
@@ -13,20 +22,65 @@ use nix::unistd::User;
 #[cfg(any(target_os = "macos"))]
 use nix::sys::utsname::uname;
 
-pub fn run_install(runtime_dir: PathBuf, max_freq: bool) -> Result<(), Box<dyn std::error::Error>> {
-    if !check_privileges() {
+/// Path of the udev rules of the Edge TPUs
+#[cfg(target_os = "linux")]
+pub const UDEV_RULES_PATH: &str = "/etc/udev/rules.d/99-edgetpu-accelerator.rules";
+
+/// How the installer and the uninstaller change the host
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstallOptions {
+    /// Prefix of every path the install touches, `/` for the host
+    pub root: PathBuf,
+    /// Only print the plan
+    pub dry_run: bool,
+    /// Install the missing libraries with the package manager of the host
+    pub install_packages: bool,
+    /// Tell how to install the missing libraries with the package manager of the host
+    pub hints: bool,
+    /// Rules giving access to the Edge TPUs
+    pub udev: UdevRules,
+}
+
+impl Default for InstallOptions {
+    fn default() -> Self {
+        Self {
+            root: PathBuf::from("/"),
+            dry_run: false,
+            install_packages: false,
+            hints: false,
+            udev: UdevRules::default(),
+        }
+    }
+}
+
+impl InstallOptions {
+    /// Whether the changes need root privileges
+    pub fn privileged(&self) -> bool {
+        !self.dry_run && self.root == Path::new("/")
+    }
+}
+
+pub fn run_install(
+    runtime_dir: PathBuf,
+    max_freq: bool,
+    options: &InstallOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if options.privileged() && !check_privileges() {
         return Err("Root privileges are required to install the Edge TPU driver.".into());
     }
 
-    let freq_dir = if max_freq {
+    let frequency = if max_freq {
         info!("Using the maximum operating frequency(500Mhz) for Coral USB devices.");
-        "direct"
+        Frequency::Max
     } else {
         info!("Using the reduced operating frequency(200Mhz) for Coral USB devices.");
-        "throttled"
+        Frequency::Reduced
     };
 
-    install_dependencies(&runtime_dir, freq_dir)
+    #[cfg(target_os = "linux")]
+    return install_linux(&runtime_dir, frequency, options);
+    #[cfg(not(target_os = "linux"))]
+    install_dependencies(&runtime_dir, frequency.variant_name())
 }
 
 #[cfg(target_os = "windows")]
@@ -161,58 +215,120 @@ fn get_install_command() -> Result<String, Box<dyn std::error::Error>> {
     Err("Homebrew or MacPorts not found".into())
 }
 
+/// Plans the install of the runtime extracted in `runtime_dir` under the root of `options`.
 #[cfg(target_os = "linux")]
-fn install_dependencies(
-    runtime_dir: &PathBuf,
-    freq_dir: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let packages = ["libc6", "libgcc1", "libstdc++6", "libusb-1.0-0"];
-    
+pub fn plan_install(
+    runtime_dir: &Path,
+    frequency: Frequency,
+    options: &InstallOptions,
+    runner: &dyn CommandRunner,
+) -> Result<Plan, Box<dyn std::error::Error>> {
+    let root = options.root.as_path();
+    let mut plan = Plan::new(root);
+    let libedgetpu_dir = runtime_dir.join("libedgetpu");
+
     info!("Checking library dependencies...");
-    let missing_packages: Vec<&str> = packages
-        .iter()
-        .filter(|&&pkg| Command::new("dpkg").arg("-l").arg(pkg).output().is_err())
-        .copied()
-        .collect();
-
-    if !missing_packages.is_empty() {
-        info!("Installing library dependencies: {:?}...", missing_packages);
-        Command::new("apt-get").arg("update").status()?;
-        Command::new("apt-get")
-            .arg("install")
-            .arg("-y")
-            .args(&missing_packages)
-            .status()?;
-        info!("Done.");
+    let library =
+        edgetpu_path_of(&libedgetpu_dir, frequency.variant_name()).join("libedgetpu.so.1.0");
+    let missing = DependencyCheck::new().with_root(root).check(&library)?;
+    if !missing.is_empty() {
+        for dependency in &missing {
+            warn!("{dependency}");
+        }
+        let manager = PackageManager::detect(root);
+        if options.install_packages {
+            let manager = manager.ok_or("no supported package manager (apt, dnf or apk) found")?;
+            let packages = manager.packages_for(&missing);
+            if !packages.is_empty() {
+                plan = plan.with_step(Step::InstallPackages { manager, packages });
+            }
+        } else if options.hints {
+            match manager.map(|manager| (manager, manager.packages_for(&missing))) {
+                Some((manager, packages)) if !packages.is_empty() => {
+                    for command in manager.install_commands(&packages) {
+                        info!("hint: run `{}`", command.join(" "));
+                    }
+                }
+                _ => info!(
+                    "hint: install the packages of your distribution providing these libraries"
+                ),
+            }
+        } else {
+            warn!("the runtime will not load without these libraries, see --install-packages");
+        }
     }
 
-    if !Command::new("udevadm").output().is_ok() {
+    if runner.run("udevadm", &["--version".to_owned()]).is_err() {
         return Err("udevadm not found".into());
     }
 
-    info!("Registering edgetpu device driver...");
-    let rules_file = runtime_dir.join("libedgetpu").join("edgetpu-accelerator.rules");
-    overwrite(
-        &rules_file,
-        &PathBuf::from("/etc/udev/rules.d/99-edgetpu-accelerator.rules"),
-    )?;
-    Command::new("udevadm")
-        .arg("control")
-        .arg("--reload-rules")
-        .status()?;
-    Command::new("udevadm").arg("trigger").status()?;
+    plan = plan
+        .with_step(Step::WriteFile {
+            contents: options.udev.render(),
+            destination: PathBuf::from(UDEV_RULES_PATH),
+            mode: 0o644,
+            kind: FileKind::UdevRules,
+        })
+        .with_step(Step::Run {
+            program: "udevadm".to_owned(),
+            args: vec!["control".to_owned(), "--reload-rules".to_owned()],
+        })
+        .with_step(Step::Run {
+            program: "udevadm".to_owned(),
+            args: vec!["trigger".to_owned()],
+        })
+        .with_step(Step::InstallFile {
+            source: edgetpu_path_of(&libedgetpu_dir, frequency.variant_name())
+                .join("libedgetpu.so.1.0"),
+            destination: install_path_of().join("libedgetpu.so.1.0"),
+            mode: 0o644,
+            kind: FileKind::Library,
+        });
+    // containers pick their frequency, both builds are kept next to the default one
+    for frequency in [Frequency::Reduced, Frequency::Max] {
+        plan = plan.with_step(Step::InstallFile {
+            source: edgetpu_path_of(&libedgetpu_dir, frequency.variant_name())
+                .join("libedgetpu.so.1.0"),
+            destination: frequency
+                .variant_dir(&install_path_of())
+                .join("libedgetpu.so.1.0"),
+            mode: 0o644,
+            kind: FileKind::Library,
+        });
+    }
 
-    info!("Installing Edge TPU runtime library...");
-    overwrite(
-        &edgetpu_path_of(&runtime_dir.join("libedgetpu"), freq_dir).join("libedgetpu.so.1.0"),
-        &install_path_of().join("libedgetpu.so.1.0"),
-    )?;
-    Command::new("ldconfig").status()?;
-    
-    info!("Install complete!");
+    let mut ldconfig_args = Vec::new();
+    if root != Path::new("/") {
+        ldconfig_args = vec!["-r".to_owned(), root.to_string_lossy().into_owned()];
+    }
+    Ok(plan.with_step(Step::Run {
+        program: "ldconfig".to_owned(),
+        args: ldconfig_args,
+    }))
+}
+
+#[cfg(target_os = "linux")]
+fn install_linux(
+    runtime_dir: &Path,
+    frequency: Frequency,
+    options: &InstallOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let runner = SystemRunner;
+    let plan = plan_install(runtime_dir, frequency, options, &runner)?;
+    if options.dry_run {
+        println!("{plan}");
+        return Ok(());
+    }
+
+    plan.apply(&runner)?;
+    info!(
+        "Install complete! The install manifest is {}",
+        InstallManifest::path(&options.root).display()
+    );
     Ok(())
 }
 
+#[cfg(not(target_os = "linux"))]
 fn overwrite(src: &PathBuf, dst: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
     if dst.exists() {
         warn!("File already exists. Replacing it...");
@@ -257,3 +373,67 @@ fn edgetpu_path_of(lib_root_path: &PathBuf, freq_dir: &str) -> PathBuf {
 fn edgetpu_path_of(lib_root_path: &PathBuf, freq_dir: &str) -> PathBuf {
     lib_root_path.join(freq_dir).join("aarch64")
 }
+
+#[cfg(all(test, target_os = "linux", target_env = "gnu"))]
+mod tests {
+    use super::*;
+    use crate::dep::transaction::fixture::FakeRunner;
+    use crate::dep::transaction::plan_uninstall;
+
+    #[test]
+    fn test_install_into_fake_root() {
+        let runtime = tempfile::tempdir().unwrap();
+        let libedgetpu_dir = runtime.path().join("libedgetpu");
+        std::fs::create_dir_all(&libedgetpu_dir).unwrap();
+        // a small library of the host stands in for the runtime
+        let machine = crate::dep::dependency::SharedObject::read(std::env::current_exe().unwrap())
+            .unwrap()
+            .machine;
+        let libgcc = DependencyCheck::new()
+            .resolve("libgcc_s.so.1", machine)
+            .unwrap();
+        for frequency in [Frequency::Reduced, Frequency::Max] {
+            let dir = edgetpu_path_of(&libedgetpu_dir, frequency.variant_name());
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::copy(&libgcc, dir.join("libedgetpu.so.1.0")).unwrap();
+        }
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("sbin")).unwrap();
+        std::fs::write(root.path().join("sbin/apk"), "").unwrap();
+        let options = InstallOptions {
+            root: root.path().to_owned(),
+            install_packages: true,
+            ..Default::default()
+        };
+        let runner = FakeRunner::default();
+
+        // nothing of the host is in the fake root
+        let plan = plan_install(runtime.path(), Frequency::Max, &options, &runner).unwrap();
+        assert!(matches!(
+            &plan.steps()[0],
+            Step::InstallPackages { manager: PackageManager::Apk, packages }
+                if packages.contains(&"gcompat".to_owned())
+        ));
+        let manifest = plan.apply(&runner).unwrap();
+        assert!(manifest.packages.contains(&"gcompat".to_owned()));
+        let library = install_path_of().join("libedgetpu.so.1.0");
+        assert_eq!(
+            std::fs::read(root.path().join(library.strip_prefix("/").unwrap())).unwrap(),
+            std::fs::read(&libgcc).unwrap()
+        );
+        assert_eq!(manifest.files.len(), 4);
+        let rules = root.path().join(UDEV_RULES_PATH.trim_start_matches('/'));
+        assert_eq!(
+            std::fs::read_to_string(&rules).unwrap(),
+            UdevRules::new().render()
+        );
+        assert!(runner
+            .commands
+            .borrow()
+            .contains(&format!("ldconfig -r {}", root.path().display())));
+
+        plan_uninstall(root.path()).unwrap().apply(&runner).unwrap();
+        assert!(!root.path().join("usr").exists());
+        assert!(!rules.exists());
+    }
+}
@@ -1,4 +1,7 @@
+pub mod dependency;
 pub mod download;
 pub mod install;
+pub mod transaction;
+pub mod udev;
 pub mod uninstall;
 pub mod util;
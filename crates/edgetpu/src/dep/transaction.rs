@@ -0,0 +1,803 @@
+//! Transactional changes of the host by the installer.
+//!
+//! The installer first computes a [`Plan`], which can be printed for a dry run. Applying it
+//! backs up every file it replaces and rolls every step back when one fails, then records
+//! what was done in an [`InstallManifest`] that the uninstaller follows. All paths are
+//! relative to a root prefix, `/` on a real install.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::dep::dependency::PackageManager;
+use crate::dep::download::sha256_of;
+
+/// Directory of the installer state, relative to the root
+pub const STATE_DIR: &str = "var/lib/tpu-container-runtime";
+pub const MANIFEST_FILE: &str = "install-manifest.json";
+/// Directory of the state directory keeping the files replaced by the install
+const ORIGINALS_DIR: &str = "originals";
+/// Directory of the state directory keeping the backups of a running transaction
+const TRANSACTIONS_DIR: &str = "transactions";
+
+#[derive(Debug, thiserror::Error)]
+pub enum InstallError {
+    #[error("I/O error on {path:?}")]
+    Io { source: io::Error, path: PathBuf },
+    #[error("`{command}` failed: {reason}")]
+    Command { command: String, reason: String },
+    #[error("invalid install manifest {path:?}")]
+    Manifest {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+    #[error("nothing is installed, there is no install manifest at {0:?}")]
+    NotInstalled(PathBuf),
+    #[error("the installation failed and was rolled back")]
+    RolledBack(#[source] Box<InstallError>),
+    #[error("the installation failed and was partly rolled back: {}", .problems.join("; "))]
+    RollbackFailed {
+        source: Box<InstallError>,
+        problems: Vec<String>,
+    },
+}
+
+type Result<T> = std::result::Result<T, InstallError>;
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> InstallError + '_ {
+    move |source| InstallError::Io {
+        source,
+        path: path.to_owned(),
+    }
+}
+
+/// Runs the commands of a plan. Fakes stand in for it to test plans against a fake root.
+pub trait CommandRunner {
+    /// Runs the command to completion, failing on a non-zero exit status.
+    fn run(&self, program: &str, args: &[String]) -> std::result::Result<(), String>;
+}
+
+/// Runs the commands on the host.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[String]) -> std::result::Result<(), String> {
+        let output = Command::new(program)
+            .args(args)
+            .output()
+            .map_err(|err| err.to_string())?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(format!("exited with {}: {}", output.status, stderr.trim()))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileKind {
+    Library,
+    UdevRules,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// Installs system packages, which are left on uninstall
+    InstallPackages {
+        manager: PackageManager,
+        packages: Vec<String>,
+    },
+    /// Installs a file, creating its missing parent directories
+    InstallFile {
+        source: PathBuf,
+        destination: PathBuf,
+        mode: u32,
+        kind: FileKind,
+    },
+    /// Installs a file generated by the installer
+    WriteFile {
+        contents: String,
+        destination: PathBuf,
+        mode: u32,
+        kind: FileKind,
+    },
+    /// Removes an installed file, putting back the file it replaced if any
+    RemoveFile {
+        path: PathBuf,
+        original: Option<PathBuf>,
+    },
+    /// Removes a directory created by the install if it is empty
+    RemoveDir(PathBuf),
+    Run {
+        program: String,
+        args: Vec<String>,
+    },
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InstallPackages { manager, packages } => {
+                write!(f, "install packages {} with {manager}", packages.join(" "))
+            }
+            Self::InstallFile {
+                source,
+                destination,
+                mode,
+                ..
+            } => write!(
+                f,
+                "install {} from {} (mode {mode:o})",
+                destination.display(),
+                source.display()
+            ),
+            Self::WriteFile {
+                destination, mode, ..
+            } => write!(f, "write {} (mode {mode:o})", destination.display()),
+            Self::RemoveFile {
+                path,
+                original: Some(original),
+            } => write!(
+                f,
+                "remove {}, restoring the file it replaced from {}",
+                path.display(),
+                original.display()
+            ),
+            Self::RemoveFile {
+                path,
+                original: None,
+            } => write!(f, "remove {}", path.display()),
+            Self::RemoveDir(path) => write!(f, "remove directory {} if empty", path.display()),
+            Self::Run { program, args } => write!(f, "run {}", command_line(program, args)),
+        }
+    }
+}
+
+fn command_line(program: &str, args: &[String]) -> String {
+    std::iter::once(program)
+        .chain(args.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// What the install left on the host.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstallManifest {
+    pub files: Vec<InstalledFile>,
+    /// Directories created by the install
+    pub directories: Vec<PathBuf>,
+    /// Commands refreshing the host after its files change, e.g. `ldconfig`
+    pub commands: Vec<Vec<String>>,
+    pub packages: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstalledFile {
+    pub path: PathBuf,
+    pub sha256: String,
+    pub kind: FileKind,
+    /// Backup of the file the install replaced
+    pub original: Option<PathBuf>,
+}
+
+impl InstallManifest {
+    pub fn path(root: &Path) -> PathBuf {
+        under_root(root, Path::new("/").join(STATE_DIR).join(MANIFEST_FILE))
+    }
+
+    /// Reads the manifest of the install in `root`, `None` when nothing is installed.
+    pub fn load(root: &Path) -> Result<Option<Self>> {
+        let path = Self::path(root);
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(source) => return Err(InstallError::Io { source, path }),
+        };
+        serde_json::from_slice(&content)
+            .map(Some)
+            .map_err(|source| InstallError::Manifest { source, path })
+    }
+
+    fn save(&self, root: &Path) -> Result<()> {
+        let path = Self::path(root);
+        if self.files.is_empty() && self.directories.is_empty() {
+            return match fs::remove_file(&path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(io_error(&path)(err)),
+                _ => Ok(()),
+            };
+        }
+        let parent = path
+            .parent()
+            .expect("the manifest is in the state directory");
+        fs::create_dir_all(parent).map_err(io_error(parent))?;
+        let content = serde_json::to_vec_pretty(self).expect("the manifest serializes");
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, content).map_err(io_error(&tmp))?;
+        fs::rename(&tmp, &path).map_err(io_error(&path))
+    }
+
+    pub fn file(&self, path: &Path) -> Option<&InstalledFile> {
+        self.files.iter().find(|file| file.path == path)
+    }
+}
+
+/// `path` of the host under the root prefix
+pub(crate) fn under_root<P: AsRef<Path>>(root: &Path, path: P) -> PathBuf {
+    let path = path.as_ref();
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Changes of the host, applied all or nothing.
+///
+/// # Example
+///
+/// ```no_run
+/// use edgetpu::dep::transaction::{FileKind, Plan, Step, SystemRunner};
+///
+/// let plan = Plan::new("/")
+///     .with_step(Step::InstallFile {
+///         source: "edgetpu_runtime/libedgetpu/edgetpu-accelerator.rules".into(),
+///         destination: "/etc/udev/rules.d/99-edgetpu-accelerator.rules".into(),
+///         mode: 0o644,
+///         kind: FileKind::UdevRules,
+///     })
+///     .with_step(Step::Run {
+///         program: "udevadm".to_owned(),
+///         args: vec!["control".to_owned(), "--reload-rules".to_owned()],
+///     });
+/// println!("{plan}");
+/// plan.apply(&SystemRunner).unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    root: PathBuf,
+    steps: Vec<Step>,
+}
+
+impl Plan {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            steps: Vec::new(),
+        }
+    }
+
+    pub fn with_step(mut self, step: Step) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// Applies every step, or none when one fails, and returns the updated manifest.
+    pub fn apply(&self, runner: &dyn CommandRunner) -> Result<InstallManifest> {
+        let manifest = InstallManifest::load(&self.root)?.unwrap_or_default();
+        let mut transaction = Transaction::new(&self.root, manifest);
+
+        let mut result = self
+            .steps
+            .iter()
+            .try_for_each(|step| transaction.execute(step, runner));
+        if result.is_ok() {
+            result = transaction.manifest.save(&self.root);
+        }
+        if let Err(err) = result {
+            let problems = transaction.rollback(runner);
+            return Err(if problems.is_empty() {
+                InstallError::RolledBack(Box::new(err))
+            } else {
+                InstallError::RollbackFailed {
+                    source: Box::new(err),
+                    problems,
+                }
+            });
+        }
+
+        transaction.finish();
+        Ok(transaction.manifest)
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "plan for the root {}:", self.root.display())?;
+        if self.steps.is_empty() {
+            write!(f, "\n  nothing to do")?;
+        }
+        for (index, step) in self.steps.iter().enumerate() {
+            write!(f, "\n  {}. {step}", index + 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the contents of an installed file come from
+enum Contents<'a> {
+    Copy(&'a Path),
+    Bytes(&'a [u8]),
+}
+
+/// How to revert a change made by a step
+#[derive(Debug)]
+enum Undo {
+    Remove(PathBuf),
+    Restore { backup: PathBuf, path: PathBuf },
+    Rename { from: PathBuf, to: PathBuf },
+    RemoveDir(PathBuf),
+    CreateDir(PathBuf),
+}
+
+struct Transaction<'a> {
+    root: &'a Path,
+    /// Backups of the files replaced by the transaction, deleted once it succeeds
+    backup_dir: PathBuf,
+    manifest: InstallManifest,
+    undo: Vec<Undo>,
+    /// Commands run, run again once the files are restored on rollback
+    commands: Vec<(String, Vec<String>)>,
+}
+
+impl<'a> Transaction<'a> {
+    fn new(root: &'a Path, manifest: InstallManifest) -> Self {
+        let state_dir = under_root(root, Path::new("/").join(STATE_DIR));
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos())
+            .unwrap_or_default();
+        Self {
+            root,
+            backup_dir: state_dir
+                .join(TRANSACTIONS_DIR)
+                .join(format!("{id}-{}", std::process::id())),
+            manifest,
+            undo: Vec::new(),
+            commands: Vec::new(),
+        }
+    }
+
+    fn execute(&mut self, step: &Step, runner: &dyn CommandRunner) -> Result<()> {
+        log::info!("{step}");
+        match step {
+            Step::InstallPackages { manager, packages } => {
+                for command in manager.install_commands(packages) {
+                    if let Some((program, args)) = command.split_first() {
+                        run(runner, program, args)?;
+                    }
+                }
+                for package in packages {
+                    if !self.manifest.packages.contains(package) {
+                        self.manifest.packages.push(package.clone());
+                    }
+                }
+            }
+            Step::InstallFile {
+                source,
+                destination,
+                mode,
+                kind,
+            } => self.install_file(Contents::Copy(source), destination, *mode, *kind)?,
+            Step::WriteFile {
+                contents,
+                destination,
+                mode,
+                kind,
+            } => self.install_file(
+                Contents::Bytes(contents.as_bytes()),
+                destination,
+                *mode,
+                *kind,
+            )?,
+            Step::RemoveFile { path, original } => self.remove_file(path, original.as_deref())?,
+            Step::RemoveDir(path) => {
+                let target = under_root(self.root, path);
+                match fs::remove_dir(&target) {
+                    Ok(()) => self.undo.push(Undo::CreateDir(target)),
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => log::warn!("leaving {}: {err}", path.display()),
+                }
+                self.manifest.directories.retain(|dir| dir != path);
+            }
+            Step::Run { program, args } => {
+                run(runner, program, args)?;
+                self.commands.push((program.clone(), args.clone()));
+                let command: Vec<String> = std::iter::once(program.clone())
+                    .chain(args.iter().cloned())
+                    .collect();
+                if !self.manifest.commands.contains(&command) {
+                    self.manifest.commands.push(command);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn install_file(
+        &mut self,
+        contents: Contents,
+        destination: &Path,
+        mode: u32,
+        kind: FileKind,
+    ) -> Result<()> {
+        let target = under_root(self.root, destination);
+        self.create_parents(destination)?;
+
+        let previous = self.manifest.file(destination).cloned();
+        let mut original = previous.and_then(|file| file.original);
+        if target.exists() {
+            // a file of a previous install is only kept for the rollback, a foreign one is
+            // kept for the uninstall
+            let backup = if self.manifest.file(destination).is_some() {
+                under_root(&self.backup_dir, destination)
+            } else {
+                let backup = Path::new("/")
+                    .join(STATE_DIR)
+                    .join(ORIGINALS_DIR)
+                    .join(destination.strip_prefix("/").unwrap_or(destination));
+                original = Some(backup.clone());
+                under_root(self.root, backup)
+            };
+            move_file(&target, &backup)?;
+            self.undo.push(Undo::Restore {
+                backup,
+                path: target.clone(),
+            });
+        } else {
+            self.undo.push(Undo::Remove(target.clone()));
+        }
+
+        let tmp = target.with_file_name(format!(
+            ".{}.tmp",
+            target.file_name().unwrap_or_default().to_string_lossy()
+        ));
+        match contents {
+            Contents::Copy(source) => fs::copy(source, &tmp).map(|_| ()).map_err(io_error(source)),
+            Contents::Bytes(bytes) => fs::write(&tmp, bytes).map_err(io_error(&tmp)),
+        }?;
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&tmp, fs::Permissions::from_mode(mode)).map_err(io_error(&tmp))?;
+        }
+        fs::rename(&tmp, &target).map_err(io_error(&target))?;
+
+        let file = InstalledFile {
+            path: destination.to_owned(),
+            sha256: sha256_of(&target).map_err(|err| InstallError::Io {
+                source: io::Error::other(err.to_string()),
+                path: target.clone(),
+            })?,
+            kind,
+            original,
+        };
+        self.manifest.files.retain(|file| file.path != destination);
+        self.manifest.files.push(file);
+        Ok(())
+    }
+
+    fn remove_file(&mut self, path: &Path, original: Option<&Path>) -> Result<()> {
+        let target = under_root(self.root, path);
+        if target.exists() {
+            let backup = under_root(&self.backup_dir, path);
+            move_file(&target, &backup)?;
+            self.undo.push(Undo::Restore {
+                backup,
+                path: target.clone(),
+            });
+        }
+        if let Some(original) = original {
+            let original = under_root(self.root, original);
+            if original.exists() {
+                move_file(&original, &target)?;
+                self.undo.push(Undo::Rename {
+                    from: target,
+                    to: original,
+                });
+            } else {
+                log::warn!("the backup {} is gone", original.display());
+            }
+        }
+        self.manifest.files.retain(|file| file.path != path);
+        Ok(())
+    }
+
+    /// Creates the missing parents of a file of the host, recording them in the manifest
+    fn create_parents(&mut self, path: &Path) -> Result<()> {
+        let mut missing = Vec::new();
+        let mut parent = path.parent();
+        while let Some(dir) = parent {
+            if under_root(self.root, dir).exists() {
+                break;
+            }
+            missing.push(dir.to_owned());
+            parent = dir.parent();
+        }
+        for dir in missing.into_iter().rev() {
+            let target = under_root(self.root, &dir);
+            fs::create_dir(&target).map_err(io_error(&target))?;
+            self.undo.push(Undo::RemoveDir(target));
+            self.manifest.directories.push(dir);
+        }
+        Ok(())
+    }
+
+    /// Reverts every change, returning what could not be.
+    fn rollback(&mut self, runner: &dyn CommandRunner) -> Vec<String> {
+        let mut problems = Vec::new();
+        while let Some(undo) = self.undo.pop() {
+            log::info!("rolling back: {undo:?}");
+            let result = match &undo {
+                Undo::Remove(path) => fs::remove_file(path),
+                Undo::Restore { backup, path } => move_file(backup, path).map_err(io::Error::other),
+                Undo::Rename { from, to } => move_file(from, to).map_err(io::Error::other),
+                Undo::RemoveDir(path) => fs::remove_dir(path),
+                Undo::CreateDir(path) => fs::create_dir(path),
+            };
+            if let Err(err) = result {
+                problems.push(format!("{undo:?}: {err}"));
+            }
+        }
+        for (program, args) in std::mem::take(&mut self.commands) {
+            if let Err(err) = run(runner, &program, &args) {
+                problems.push(err.to_string());
+            }
+        }
+        if problems.is_empty() {
+            let _ = fs::remove_dir_all(&self.backup_dir);
+        }
+        problems
+    }
+
+    fn finish(&self) {
+        if let Err(err) = fs::remove_dir_all(&self.backup_dir) {
+            if err.kind() != io::ErrorKind::NotFound {
+                log::warn!("failed to remove {}: {err}", self.backup_dir.display());
+            }
+        }
+    }
+}
+
+fn run(runner: &dyn CommandRunner, program: &str, args: &[String]) -> Result<()> {
+    runner
+        .run(program, args)
+        .map_err(|reason| InstallError::Command {
+            command: command_line(program, args),
+            reason,
+        })
+}
+
+/// Moves a file, creating the parents of the destination
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).map_err(io_error(parent))?;
+    }
+    fs::rename(from, to).map_err(io_error(from))
+}
+
+/// Plan removing what the manifest of the install in `root` records. Files changed since
+/// the install are left in place.
+pub fn plan_uninstall(root: &Path) -> Result<Plan> {
+    let manifest = InstallManifest::load(root)?
+        .ok_or_else(|| InstallError::NotInstalled(InstallManifest::path(root)))?;
+
+    let mut plan = Plan::new(root);
+    for file in manifest.files.iter().rev() {
+        let path = under_root(root, &file.path);
+        match sha256_of(&path) {
+            Ok(sha256) if sha256 == file.sha256 => {}
+            Ok(_) => {
+                log::warn!(
+                    "{} changed since the install, leaving it",
+                    file.path.display()
+                );
+                continue;
+            }
+            Err(err) => log::warn!("{}: {err}", file.path.display()),
+        }
+        plan = plan.with_step(Step::RemoveFile {
+            path: file.path.clone(),
+            original: file.original.clone(),
+        });
+    }
+    for dir in manifest.directories.iter().rev() {
+        plan = plan.with_step(Step::RemoveDir(dir.clone()));
+    }
+    for command in &manifest.commands {
+        if let Some((program, args)) = command.split_first() {
+            plan = plan.with_step(Step::Run {
+                program: program.clone(),
+                args: args.to_vec(),
+            });
+        }
+    }
+    Ok(plan)
+}
+
+#[cfg(test)]
+pub(crate) mod fixture {
+    use std::cell::RefCell;
+
+    use super::CommandRunner;
+
+    /// Records the commands, failing those starting with `fail`
+    #[derive(Default)]
+    pub struct FakeRunner {
+        pub fail: Option<String>,
+        pub commands: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for FakeRunner {
+        fn run(&self, program: &str, args: &[String]) -> Result<(), String> {
+            let command = super::command_line(program, args);
+            self.commands.borrow_mut().push(command.clone());
+            match &self.fail {
+                Some(prefix) if command.starts_with(prefix.as_str()) => {
+                    Err("exited with exit status: 1".to_owned())
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::FakeRunner;
+    use super::*;
+
+    struct Host {
+        root: tempfile::TempDir,
+        sources: tempfile::TempDir,
+    }
+
+    impl Host {
+        fn new() -> Self {
+            let host = Self {
+                root: tempfile::tempdir().unwrap(),
+                sources: tempfile::tempdir().unwrap(),
+            };
+            fs::create_dir_all(host.root.path().join("etc/udev/rules.d")).unwrap();
+            fs::write(host.sources.path().join("libedgetpu.so.1.0"), "runtime").unwrap();
+            fs::write(host.sources.path().join("edgetpu.rules"), "rules").unwrap();
+            host
+        }
+
+        fn read(&self, path: &str) -> Option<String> {
+            fs::read_to_string(self.root.path().join(path)).ok()
+        }
+
+        fn plan(&self) -> Plan {
+            Plan::new(self.root.path())
+                .with_step(Step::InstallFile {
+                    source: self.sources.path().join("edgetpu.rules"),
+                    destination: "/etc/udev/rules.d/99-edgetpu-accelerator.rules".into(),
+                    mode: 0o644,
+                    kind: FileKind::UdevRules,
+                })
+                .with_step(Step::InstallFile {
+                    source: self.sources.path().join("libedgetpu.so.1.0"),
+                    destination: "/usr/lib/edgetpu/direct/libedgetpu.so.1.0".into(),
+                    mode: 0o644,
+                    kind: FileKind::Library,
+                })
+                .with_step(Step::Run {
+                    program: "ldconfig".to_owned(),
+                    args: Vec::new(),
+                })
+        }
+    }
+
+    #[test]
+    fn test_install_and_uninstall() {
+        let host = Host::new();
+        let rules = "etc/udev/rules.d/99-edgetpu-accelerator.rules";
+        fs::write(host.root.path().join(rules), "older rules").unwrap();
+        let runner = FakeRunner::default();
+
+        let manifest = host.plan().apply(&runner).unwrap();
+        assert_eq!(host.read(rules).as_deref(), Some("rules"));
+        assert_eq!(
+            host.read("usr/lib/edgetpu/direct/libedgetpu.so.1.0")
+                .as_deref(),
+            Some("runtime")
+        );
+        assert_eq!(
+            manifest.directories,
+            vec![
+                PathBuf::from("/usr"),
+                PathBuf::from("/usr/lib"),
+                PathBuf::from("/usr/lib/edgetpu"),
+                PathBuf::from("/usr/lib/edgetpu/direct")
+            ]
+        );
+        assert_eq!(manifest.commands, vec![vec!["ldconfig".to_owned()]]);
+        assert_eq!(
+            InstallManifest::load(host.root.path()).unwrap(),
+            Some(manifest)
+        );
+
+        // installing again keeps the original of the first install
+        host.plan().apply(&runner).unwrap();
+
+        let uninstall = plan_uninstall(host.root.path()).unwrap();
+        assert_eq!(uninstall.steps().len(), 2 + 4 + 1);
+        uninstall.apply(&runner).unwrap();
+        assert_eq!(host.read(rules).as_deref(), Some("older rules"));
+        assert!(!host.root.path().join("usr").exists());
+        assert_eq!(InstallManifest::load(host.root.path()).unwrap(), None);
+        assert_eq!(runner.commands.borrow().len(), 3);
+        assert!(matches!(
+            plan_uninstall(host.root.path()),
+            Err(InstallError::NotInstalled(_))
+        ));
+    }
+
+    #[test]
+    fn test_failed_install_is_rolled_back() {
+        let host = Host::new();
+        let rules = "etc/udev/rules.d/99-edgetpu-accelerator.rules";
+        fs::write(host.root.path().join(rules), "older rules").unwrap();
+        let runner = FakeRunner {
+            fail: Some("ldconfig".to_owned()),
+            ..Default::default()
+        };
+
+        let result = host.plan().apply(&runner);
+        assert!(
+            matches!(result, Err(InstallError::RolledBack(ref err)) if matches!(**err, InstallError::Command { .. })),
+            "{result:?}"
+        );
+        assert_eq!(host.read(rules).as_deref(), Some("older rules"));
+        assert!(!host.root.path().join("usr").exists());
+        assert_eq!(InstallManifest::load(host.root.path()).unwrap(), None);
+        let transactions = host.root.path().join(STATE_DIR).join(TRANSACTIONS_DIR);
+        assert_eq!(
+            fs::read_dir(transactions)
+                .map(|dir| dir.count())
+                .unwrap_or_default(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_uninstall_leaves_changed_files() {
+        let host = Host::new();
+        host.plan().apply(&FakeRunner::default()).unwrap();
+        let library = "usr/lib/edgetpu/direct/libedgetpu.so.1.0";
+        fs::write(host.root.path().join(library), "patched").unwrap();
+
+        plan_uninstall(host.root.path())
+            .unwrap()
+            .apply(&FakeRunner::default())
+            .unwrap();
+        assert_eq!(host.read(library).as_deref(), Some("patched"));
+        let manifest = InstallManifest::load(host.root.path()).unwrap().unwrap();
+        assert_eq!(manifest.files.len(), 1);
+        assert_eq!(manifest.files[0].path, Path::new("/").join(library));
+    }
+
+    #[test]
+    fn test_display_plan() {
+        let plan = Plan::new("/").with_step(Step::Run {
+            program: "udevadm".to_owned(),
+            args: vec!["trigger".to_owned()],
+        });
+        assert_eq!(
+            plan.to_string(),
+            "plan for the root /:\n  1. run udevadm trigger"
+        );
+    }
+}
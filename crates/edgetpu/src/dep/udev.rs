@@ -0,0 +1,251 @@
+//! udev rules giving access to the Edge TPUs.
+//!
+//! The rules cover the Apex PCIe and M.2 accelerators and the Coral USB accelerator under both
+//! of its USB IDs: before its firmware is loaded and after. Every device gets the [`UDEV_TAG`]
+//! tag, which lets the runtime find them in the udev database, and the USB accelerators get a
+//! `/dev/coral/by-serial/<serial>` symlink that survives replugging.
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer};
+
+/// Configuration of the generated rules, the defaults apply when it does not exist
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/tpu-container-runtime/udev.yaml";
+/// Tag of the Edge TPU devices in the udev database
+pub const UDEV_TAG: &str = "coral";
+/// Directory of `/dev` holding the symlinks named after the serial numbers
+pub const SYMLINK_DIR: &str = "coral/by-serial";
+/// Directory of the udev database listing the devices of each tag
+pub const UDEV_TAGS_DIR: &str = "/run/udev/tags";
+/// USB IDs of the Coral USB accelerator, before and after its firmware is loaded
+pub const USB_IDS: [(u16, u16); 2] = [(0x1a6e, 0x089a), (0x18d1, 0x9302)];
+const HEADER: &str = "# Generated by tpu-container-runtime, changes are lost on reinstall.";
+
+#[derive(Debug, thiserror::Error)]
+pub enum UdevError {
+    #[error("failed to read the udev configuration {path:?}")]
+    Read {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("invalid udev configuration {path:?}")]
+    Parse {
+        source: serde_yaml::Error,
+        path: PathBuf,
+    },
+}
+
+/// Owner, permissions and names of the Edge TPU device nodes.
+///
+/// ```yaml
+/// group: coral
+/// mode: "0660"
+/// # /dev/coral/by-serial/<serial> symlinks of the USB accelerators
+/// symlinks: true
+/// # access for the users logged in on the seat, without group membership
+/// uaccess: false
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct UdevRules {
+    #[serde(default = "default_group")]
+    group: String,
+    #[serde(default = "default_mode", deserialize_with = "deserialize_mode")]
+    mode: u32,
+    #[serde(default = "default_true")]
+    symlinks: bool,
+    #[serde(default)]
+    uaccess: bool,
+}
+
+fn default_group() -> String {
+    "plugdev".to_owned()
+}
+
+fn default_mode() -> u32 {
+    0o660
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Reads an octal mode such as `"0660"`
+fn deserialize_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let mode = String::deserialize(deserializer)?;
+    u32::from_str_radix(&mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid mode {mode:?}")))
+}
+
+impl Default for UdevRules {
+    fn default() -> Self {
+        Self {
+            group: default_group(),
+            mode: default_mode(),
+            symlinks: true,
+            uaccess: false,
+        }
+    }
+}
+
+impl UdevRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the configuration file, the default rules when there is none.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, UdevError> {
+        let path = path.as_ref();
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(source) => {
+                return Err(UdevError::Read {
+                    source,
+                    path: path.to_owned(),
+                })
+            }
+        };
+        serde_yaml::from_str(&content).map_err(|source| UdevError::Parse {
+            source,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Sets the group owning the device nodes
+    pub fn with_group<S: Into<String>>(mut self, group: S) -> Self {
+        self.group = group.into();
+        self
+    }
+
+    /// Sets the permissions of the device nodes, e.g. `0o660`
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_symlinks(mut self, symlinks: bool) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// Gives access to the users logged in on the seat through the `uaccess` tag
+    pub fn with_uaccess(mut self, uaccess: bool) -> Self {
+        self.uaccess = uaccess;
+        self
+    }
+
+    /// Renders the rules file.
+    pub fn render(&self) -> String {
+        let mut access = format!(
+            "GROUP=\"{}\", MODE=\"{:04o}\", TAG+=\"{UDEV_TAG}\"",
+            self.group, self.mode
+        );
+        if self.uaccess {
+            access.push_str(", TAG+=\"uaccess\"");
+        }
+
+        let mut rules = format!("{HEADER}\n\n# Coral PCIe and M.2 accelerators\n");
+        let _ = writeln!(rules, "SUBSYSTEM==\"apex\", KERNEL==\"apex_*\", {access}");
+        rules.push_str("\n# Coral USB accelerators, before and after the firmware is loaded\n");
+        for (vendor, product) in USB_IDS {
+            let device = format!(
+                "SUBSYSTEM==\"usb\", ENV{{DEVTYPE}}==\"usb_device\", \
+                 ATTR{{idVendor}}==\"{vendor:04x}\", ATTR{{idProduct}}==\"{product:04x}\""
+            );
+            let _ = writeln!(rules, "{device}, {access}");
+            if self.symlinks {
+                let _ = writeln!(
+                    rules,
+                    "{device}, ATTR{{serial}}==\"?*\", SYMLINK+=\"{SYMLINK_DIR}/$attr{{serial}}\""
+                );
+            }
+        }
+        rules
+    }
+}
+
+/// Device nodes udev tagged with [`UDEV_TAG`], as `/dev/char/<major>:<minor>` paths.
+pub fn tagged_devices<P: AsRef<Path>>(tags_dir: P) -> std::io::Result<Vec<PathBuf>> {
+    let dir = tags_dir.as_ref().join(UDEV_TAG);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut devices = Vec::new();
+    for entry in entries {
+        // character devices are named c<major>:<minor>, other entries have no node
+        let name = entry?.file_name();
+        if let Some(device) = name.to_string_lossy().strip_prefix('c') {
+            devices.push(Path::new("/dev/char").join(device));
+        }
+    }
+    devices.sort();
+    Ok(devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_default() {
+        assert_eq!(
+            UdevRules::new().render(),
+            include_str!("testdata/edgetpu-default.rules")
+        );
+    }
+
+    #[test]
+    fn test_render_config() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("udev.yaml");
+        fs::write(
+            &path,
+            "group: coral\nmode: \"0666\"\nsymlinks: false\nuaccess: true\n",
+        )
+        .unwrap();
+        let rules = UdevRules::load(&path).unwrap();
+        assert_eq!(
+            rules,
+            UdevRules::new()
+                .with_group("coral")
+                .with_mode(0o666)
+                .with_symlinks(false)
+                .with_uaccess(true)
+        );
+        assert_eq!(
+            rules.render(),
+            include_str!("testdata/edgetpu-uaccess.rules")
+        );
+
+        fs::write(&path, "mode: \"0999\"\n").unwrap();
+        assert!(matches!(
+            UdevRules::load(&path),
+            Err(UdevError::Parse { .. })
+        ));
+    }
+
+    #[test]
+    fn test_tagged_devices() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert_eq!(tagged_devices(tmp.path()).unwrap(), Vec::<PathBuf>::new());
+
+        let dir = tmp.path().join(UDEV_TAG);
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["c189:3", "c120:0", "+usb:1-1"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        assert_eq!(
+            tagged_devices(tmp.path()).unwrap(),
+            vec![
+                PathBuf::from("/dev/char/120:0"),
+                PathBuf::from("/dev/char/189:3")
+            ]
+        );
+    }
+}
@@ -1,14 +1,25 @@
-use crate::dep::util::{check_privileges, install_path_of};
+use crate::dep::install::InstallOptions;
+#[cfg(target_os = "linux")]
+use crate::dep::transaction::{plan_uninstall, InstallManifest, SystemRunner};
+use crate::dep::util::check_privileges;
+#[cfg(not(target_os = "linux"))]
+use crate::dep::util::install_path_of;
 use log::info;
+#[cfg(target_os = "linux")]
+use log::warn;
+#[cfg(not(target_os = "linux"))]
 use std::fs;
-use std::path::PathBuf;
+#[cfg(target_os = "windows")]
 use std::process::Command;
 
-pub fn run_uninstall() -> Result<(), Box<dyn std::error::Error>> {
-    if !check_privileges() {
+pub fn run_uninstall(options: &InstallOptions) -> Result<(), Box<dyn std::error::Error>> {
+    if options.privileged() && !check_privileges() {
         return Err("Root privileges are required to uninstall the Edge TPU driver.".into());
     }
 
+    #[cfg(target_os = "linux")]
+    return uninstall_linux(options);
+    #[cfg(not(target_os = "linux"))]
     uninstall_dependencies()
 }
 
@@ -81,29 +92,23 @@ pub fn uninstall_dependencies() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 #[cfg(target_os = "linux")]
-fn uninstall_dependencies() -> Result<(), Box<dyn std::error::Error>> {
-    if !Command::new("udevadm").output().is_ok() {
-        return Err("udevadm not found.".into());
+fn uninstall_linux(options: &InstallOptions) -> Result<(), Box<dyn std::error::Error>> {
+    // the uninstall follows the manifest of the install instead of guessing
+    let plan = plan_uninstall(&options.root)?;
+    if options.dry_run {
+        println!("{plan}");
+        return Ok(());
     }
 
-    let rules_file = PathBuf::from("/etc/udev/rules.d/99-edgetpu-accelerator.rules");
-    if rules_file.exists() {
-        info!("Registering edgetpu device driver...");
-        fs::remove_file(&rules_file)?;
-        Command::new("udevadm")
-            .arg("control")
-            .arg("--reload-rules")
-            .status()?;
-        Command::new("udevadm").arg("trigger").status()?;
+    plan.apply(&SystemRunner)?;
+    if let Some(manifest) = InstallManifest::load(&options.root)? {
+        for file in manifest.files {
+            warn!(
+                "{} changed since the install and was kept",
+                file.path.display()
+            );
+        }
     }
-
-    let libedgetpu_dst = install_path_of().join("libedgetpu.so.1.0");
-    if libedgetpu_dst.exists() {
-        info!("Uninstalling Edge TPU runtime library ...");
-        fs::remove_file(&libedgetpu_dst)?;
-        Command::new("ldconfig").status()?;
-    }
-
     info!("Uninstall complete!");
 
     Ok(())
@@ -0,0 +1,289 @@
+//! Edge TPU discovery straight from sysfs.
+//!
+//! Unlike `libedgetpu::driver::EdgeTpuDeviceManager::enumerate_devices`, this does not go
+//! through the libedgetpu singleton, so it works on hosts (and CI machines) where the runtime
+//! library is not installed. PCIe/M.2 accelerators are found under `/sys/class/apex`, USB
+//! accelerators under `/sys/bus/usb/devices` by their Coral vendor/product ids.
+mod pci;
+mod usb;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub use libedgetpu::driver::{DeviceRecord, DeviceType};
+pub use usb::{CoralUsbId, CORAL_USB_IDS};
+
+pub const DEFAULT_SYSFS_ROOT: &str = "/sys";
+pub const DEFAULT_DEV_ROOT: &str = "/dev";
+
+#[derive(Debug, thiserror::Error)]
+pub enum DiscoveryError {
+    #[error("failed to read {path:?}")]
+    Read {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("invalid device number {value:?} in {path:?}")]
+    InvalidDevNumber { value: String, path: PathBuf },
+    #[error("invalid {name} {value:?} in {path:?}")]
+    InvalidAttribute {
+        name: &'static str,
+        value: String,
+        path: PathBuf,
+    },
+}
+
+type Result<T> = std::result::Result<T, DiscoveryError>;
+
+/// An Edge TPU found on the host.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EdgeTpuDevice {
+    /// The record libedgetpu would report for this device. For PCIe parts the path is the
+    /// device node (`/dev/apex_0`), for USB parts it is the sysfs directory of the device.
+    pub record: DeviceRecord,
+    pub major: u32,
+    pub minor: u32,
+    /// Device node to expose to a container.
+    pub dev_node: PathBuf,
+    /// PCI address (`0000:01:00.0`) or USB port path (`2-1.4`).
+    pub bus_path: String,
+    pub serial: Option<String>,
+    /// Vendor/product id the USB accelerator currently enumerates with.
+    pub usb_id: Option<CoralUsbId>,
+}
+
+impl EdgeTpuDevice {
+    pub fn device_type(&self) -> DeviceType {
+        self.record.device_type
+    }
+
+    /// Short, stable name of the device: `apex_0` for PCIe parts, `usb-2-1.4` for USB ones.
+    pub fn name(&self) -> String {
+        match self.record.device_type {
+            DeviceType::ApexPCI => self
+                .dev_node
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| self.bus_path.clone()),
+            DeviceType::ApexUSB => format!("usb-{}", self.bus_path),
+        }
+    }
+}
+
+/// Scans sysfs for Edge TPUs.
+///
+/// # Example
+///
+/// ```no_run
+/// use edgetpu::discovery::Discovery;
+///
+/// let devices = Discovery::new()
+///     .with_sysfs_root("/sys")
+///     .discover()
+///     .expect("failed to discover Edge TPUs");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Discovery {
+    sysfs_root: PathBuf,
+    dev_root: PathBuf,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self {
+            sysfs_root: PathBuf::from(DEFAULT_SYSFS_ROOT),
+            dev_root: PathBuf::from(DEFAULT_DEV_ROOT),
+        }
+    }
+}
+
+impl Discovery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the directory sysfs is mounted on
+    pub fn with_sysfs_root<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.sysfs_root = path.into();
+        self
+    }
+
+    /// Sets the directory device nodes are created in
+    pub fn with_dev_root<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.dev_root = path.into();
+        self
+    }
+
+    pub fn sysfs_root(&self) -> &Path {
+        &self.sysfs_root
+    }
+
+    pub fn dev_root(&self) -> &Path {
+        &self.dev_root
+    }
+
+    /// Returns all Edge TPUs, PCIe parts first, each group in a stable order.
+    pub fn discover(&self) -> Result<Vec<EdgeTpuDevice>> {
+        let mut devices = self.pci_devices()?;
+        devices.extend(self.usb_devices()?);
+        Ok(devices)
+    }
+
+    /// Returns the PCIe/M.2 accelerators bound to the apex driver.
+    pub fn pci_devices(&self) -> Result<Vec<EdgeTpuDevice>> {
+        pci::discover(&self.sysfs_root, &self.dev_root)
+    }
+
+    /// Returns the USB accelerators, whether or not their firmware has been loaded.
+    pub fn usb_devices(&self) -> Result<Vec<EdgeTpuDevice>> {
+        usb::discover(&self.sysfs_root, &self.dev_root)
+    }
+}
+
+fn read_attribute(path: &Path) -> Result<String> {
+    fs::read_to_string(path)
+        .map(|content| content.trim().to_owned())
+        .map_err(|err| DiscoveryError::Read {
+            source: err,
+            path: path.to_owned(),
+        })
+}
+
+fn read_optional_attribute(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content.trim().to_owned())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(DiscoveryError::Read {
+            source: err,
+            path: path.to_owned(),
+        }),
+    }
+}
+
+/// Parses the `major:minor` content of a sysfs `dev` attribute.
+fn read_dev_number(path: &Path) -> Result<(u32, u32)> {
+    let value = read_attribute(path)?;
+    value
+        .split_once(':')
+        .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)))
+        .ok_or_else(|| DiscoveryError::InvalidDevNumber {
+            value: value.clone(),
+            path: path.to_owned(),
+        })
+}
+
+/// Lists the entries of a sysfs directory, treating a missing directory as empty.
+fn list_dir(path: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(DiscoveryError::Read {
+                source: err,
+                path: path.to_owned(),
+            })
+        }
+    };
+
+    let mut paths = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|err| DiscoveryError::Read {
+            source: err,
+            path: path.to_owned(),
+        })?;
+        paths.push(entry.path());
+    }
+    Ok(paths)
+}
+
+#[cfg(any(test, feature = "test-util"))]
+pub mod fixture {
+    //! Helpers to lay out a fake sysfs tree.
+    use std::fs;
+    use std::path::Path;
+
+    pub fn write(root: &Path, relative: &str, content: &str) {
+        let path = root.join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    pub fn add_apex(root: &Path, index: u32, pci_address: &str) {
+        let dir = format!("class/apex/apex_{index}");
+        write(root, &format!("{dir}/dev"), &format!("120:{index}\n"));
+        write(root, &format!("{dir}/status"), "ALIVE\n");
+        let pci_dir = root.join("bus/pci/devices").join(pci_address);
+        fs::create_dir_all(&pci_dir).unwrap();
+        std::os::unix::fs::symlink(&pci_dir, root.join(&dir).join("device")).unwrap();
+    }
+
+    /// Sets the NUMA node of the PCI function of `apex_<index>`
+    pub fn set_numa_node(root: &Path, index: u32, numa_node: i64) {
+        let path = format!("class/apex/apex_{index}/device/numa_node");
+        write(root, &path, &format!("{numa_node}\n"));
+    }
+
+    pub fn add_usb(root: &Path, port: &str, id: (&str, &str), bus: u32, dev: u32, serial: &str) {
+        let dir = format!("bus/usb/devices/{port}");
+        write(root, &format!("{dir}/idVendor"), &format!("{}\n", id.0));
+        write(root, &format!("{dir}/idProduct"), &format!("{}\n", id.1));
+        write(root, &format!("{dir}/busnum"), &format!("{bus}\n"));
+        write(root, &format!("{dir}/devnum"), &format!("{dev}\n"));
+        write(
+            root,
+            &format!("{dir}/dev"),
+            &format!("189:{}\n", (bus - 1) * 128 + dev - 1),
+        );
+        if !serial.is_empty() {
+            write(root, &format!("{dir}/serial"), &format!("{serial}\n"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::*;
+    use super::*;
+
+    #[test]
+    fn test_discover_empty_tree() {
+        let tmp = tempfile::tempdir().unwrap();
+        let devices = Discovery::new()
+            .with_sysfs_root(tmp.path())
+            .discover()
+            .unwrap();
+        assert!(devices.is_empty());
+    }
+
+    #[test]
+    fn test_discover_pci_before_usb() {
+        let tmp = tempfile::tempdir().unwrap();
+        add_usb(tmp.path(), "2-1", ("18d1", "9302"), 2, 3, "abc");
+        add_apex(tmp.path(), 0, "0000:01:00.0");
+
+        let devices = Discovery::new()
+            .with_sysfs_root(tmp.path())
+            .with_dev_root("/dev")
+            .discover()
+            .unwrap();
+
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].device_type(), DeviceType::ApexPCI);
+        assert_eq!(devices[0].name(), "apex_0");
+        assert_eq!(devices[1].device_type(), DeviceType::ApexUSB);
+        assert_eq!(devices[1].name(), "usb-2-1");
+    }
+
+    #[test]
+    fn test_read_dev_number() {
+        let tmp = tempfile::tempdir().unwrap();
+        write(tmp.path(), "dev", "120:3\n");
+        assert_eq!(read_dev_number(&tmp.path().join("dev")).unwrap(), (120, 3));
+
+        write(tmp.path(), "dev", "garbage\n");
+        assert!(matches!(
+            read_dev_number(&tmp.path().join("dev")),
+            Err(DiscoveryError::InvalidDevNumber { .. })
+        ));
+    }
+}
@@ -0,0 +1,89 @@
+use std::fs;
+use std::path::Path;
+
+use super::{list_dir, read_dev_number, DeviceRecord, DeviceType, EdgeTpuDevice, Result};
+
+/// Finds the accelerators bound to the apex driver through `/sys/class/apex/apex_N`.
+pub(super) fn discover(sysfs_root: &Path, dev_root: &Path) -> Result<Vec<EdgeTpuDevice>> {
+    let mut devices = Vec::new();
+    for class_dir in list_dir(&sysfs_root.join("class/apex"))? {
+        let name = match class_dir.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        let index = match apex_index(&name) {
+            Some(index) => index,
+            None => continue,
+        };
+
+        let (major, minor) = read_dev_number(&class_dir.join("dev"))?;
+        // `device` links to the PCI function the apex driver is bound to.
+        let bus_path = fs::read_link(class_dir.join("device"))
+            .ok()
+            .and_then(|target| target.file_name().map(|f| f.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| name.clone());
+        let dev_node = dev_root.join(&name);
+
+        devices.push((
+            index,
+            EdgeTpuDevice {
+                record: DeviceRecord {
+                    device_type: DeviceType::ApexPCI,
+                    path: dev_node.to_string_lossy().into_owned(),
+                },
+                major,
+                minor,
+                dev_node,
+                bus_path,
+                // PCIe and M.2 modules do not expose a serial number.
+                serial: None,
+                usb_id: None,
+            },
+        ));
+    }
+
+    devices.sort_by_key(|(index, _)| *index);
+    Ok(devices.into_iter().map(|(_, device)| device).collect())
+}
+
+fn apex_index(name: &str) -> Option<u32> {
+    name.strip_prefix("apex_")?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::super::fixture::*;
+    use super::*;
+
+    #[test]
+    fn test_discover_apex() {
+        let tmp = tempfile::tempdir().unwrap();
+        add_apex(tmp.path(), 10, "0000:03:00.0");
+        add_apex(tmp.path(), 1, "0000:02:00.0");
+        add_apex(tmp.path(), 0, "0000:01:00.0");
+        // unrelated entries in the class directory are ignored
+        write(tmp.path(), "class/apex/not-an-apex/dev", "1:1");
+
+        let devices = discover(tmp.path(), Path::new("/dev")).unwrap();
+        let names: Vec<_> = devices.iter().map(|d| d.name()).collect();
+        assert_eq!(names, vec!["apex_0", "apex_1", "apex_10"]);
+
+        let apex_1 = &devices[1];
+        assert_eq!(apex_1.record.device_type, DeviceType::ApexPCI);
+        assert_eq!(apex_1.record.path, "/dev/apex_1");
+        assert_eq!(apex_1.dev_node, PathBuf::from("/dev/apex_1"));
+        assert_eq!((apex_1.major, apex_1.minor), (120, 1));
+        assert_eq!(apex_1.bus_path, "0000:02:00.0");
+        assert_eq!(apex_1.serial, None);
+    }
+
+    #[test]
+    fn test_apex_index() {
+        assert_eq!(apex_index("apex_0"), Some(0));
+        assert_eq!(apex_index("apex_12"), Some(12));
+        assert_eq!(apex_index("apex_"), None);
+        assert_eq!(apex_index("gasket"), None);
+    }
+}
@@ -0,0 +1,197 @@
+use std::fmt;
+use std::path::Path;
+
+use super::{
+    list_dir, read_attribute, read_dev_number, read_optional_attribute, DeviceRecord, DeviceType,
+    DiscoveryError, EdgeTpuDevice, Result,
+};
+
+/// USB vendor/product id pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CoralUsbId {
+    pub vendor: u16,
+    pub product: u16,
+}
+
+impl CoralUsbId {
+    /// Identity of a Coral USB Accelerator before libedgetpu has uploaded its firmware.
+    pub const BOOTLOADER: CoralUsbId = CoralUsbId {
+        vendor: 0x1a6e,
+        product: 0x089a,
+    };
+    /// Identity of a Coral USB Accelerator running the Edge TPU firmware.
+    pub const RUNTIME: CoralUsbId = CoralUsbId {
+        vendor: 0x18d1,
+        product: 0x9302,
+    };
+
+    /// Whether the firmware has been loaded onto the device.
+    pub fn firmware_loaded(&self) -> bool {
+        *self == Self::RUNTIME
+    }
+}
+
+impl fmt::Display for CoralUsbId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vendor, self.product)
+    }
+}
+
+/// Every identity a Coral USB Accelerator can enumerate with.
+pub const CORAL_USB_IDS: [CoralUsbId; 2] = [CoralUsbId::BOOTLOADER, CoralUsbId::RUNTIME];
+
+/// Finds Coral USB Accelerators in `/sys/bus/usb/devices`. Devices whose attributes cannot
+/// be read, e.g. one being unplugged, are skipped.
+pub(super) fn discover(sysfs_root: &Path, dev_root: &Path) -> Result<Vec<EdgeTpuDevice>> {
+    let mut devices = Vec::new();
+    for device_dir in list_dir(&sysfs_root.join("bus/usb/devices"))? {
+        let port = match device_dir.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        // Interfaces (`2-1:1.0`) and root hubs (`usb2`) are listed next to the devices.
+        if port.contains(':') || port.starts_with("usb") {
+            continue;
+        }
+
+        match read_device(&device_dir, port, dev_root) {
+            Ok(Some(device)) => devices.push(device),
+            Ok(None) => {}
+            Err(err) => log::warn!("skipping USB device {}: {err}", device_dir.display()),
+        }
+    }
+
+    devices.sort_by(|a, b| a.bus_path.cmp(&b.bus_path));
+    Ok(devices)
+}
+
+/// Reads the USB device plugged in `port`, `None` if it is not a Coral USB Accelerator.
+fn read_device(device_dir: &Path, port: String, dev_root: &Path) -> Result<Option<EdgeTpuDevice>> {
+    let usb_id = match read_usb_id(device_dir)? {
+        Some(id) if CORAL_USB_IDS.contains(&id) => id,
+        _ => return Ok(None),
+    };
+
+    let busnum = read_number(&device_dir.join("busnum"), "busnum")?;
+    let devnum = read_number(&device_dir.join("devnum"), "devnum")?;
+    let (major, minor) = read_dev_number(&device_dir.join("dev"))?;
+    let serial = read_optional_attribute(&device_dir.join("serial"))?.filter(|s| !s.is_empty());
+
+    Ok(Some(EdgeTpuDevice {
+        record: DeviceRecord {
+            device_type: DeviceType::ApexUSB,
+            // libedgetpu identifies USB accelerators by their sysfs directory.
+            path: device_dir.to_string_lossy().into_owned(),
+        },
+        major,
+        minor,
+        dev_node: dev_root
+            .join("bus/usb")
+            .join(format!("{busnum:03}"))
+            .join(format!("{devnum:03}")),
+        bus_path: port,
+        serial,
+        usb_id: Some(usb_id),
+    }))
+}
+
+fn read_usb_id(device_dir: &Path) -> Result<Option<CoralUsbId>> {
+    let vendor = match read_optional_attribute(&device_dir.join("idVendor"))? {
+        Some(vendor) => vendor,
+        None => return Ok(None),
+    };
+    let product = read_attribute(&device_dir.join("idProduct"))?;
+
+    let parse = |name: &'static str, value: &str, file: &str| {
+        u16::from_str_radix(value, 16).map_err(|_| DiscoveryError::InvalidAttribute {
+            name,
+            value: value.to_owned(),
+            path: device_dir.join(file),
+        })
+    };
+
+    Ok(Some(CoralUsbId {
+        vendor: parse("idVendor", &vendor, "idVendor")?,
+        product: parse("idProduct", &product, "idProduct")?,
+    }))
+}
+
+fn read_number(path: &Path, name: &'static str) -> Result<u32> {
+    let value = read_attribute(path)?;
+    value.parse().map_err(|_| DiscoveryError::InvalidAttribute {
+        name,
+        value,
+        path: path.to_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::super::fixture::*;
+    use super::*;
+
+    #[test]
+    fn test_discover_both_identities() {
+        let tmp = tempfile::tempdir().unwrap();
+        add_usb(tmp.path(), "2-1", ("18d1", "9302"), 2, 5, "0123abcd");
+        add_usb(tmp.path(), "1-4.2", ("1a6e", "089a"), 1, 9, "");
+        // a keyboard and the interfaces/root hubs are not Edge TPUs
+        add_usb(tmp.path(), "1-3", ("046d", "c31c"), 1, 2, "kbd");
+        write(tmp.path(), "bus/usb/devices/2-1:1.0/bInterfaceClass", "ff");
+        write(tmp.path(), "bus/usb/devices/usb2/idVendor", "1d6b");
+
+        let devices = discover(tmp.path(), Path::new("/dev")).unwrap();
+        assert_eq!(devices.len(), 2);
+
+        let bootloader = &devices[0];
+        assert_eq!(bootloader.bus_path, "1-4.2");
+        assert_eq!(bootloader.usb_id, Some(CoralUsbId::BOOTLOADER));
+        assert!(!bootloader.usb_id.unwrap().firmware_loaded());
+        assert_eq!(bootloader.serial, None);
+        assert_eq!(bootloader.dev_node, PathBuf::from("/dev/bus/usb/001/009"));
+
+        let runtime = &devices[1];
+        assert_eq!(runtime.record.device_type, DeviceType::ApexUSB);
+        assert_eq!(
+            runtime.record.path,
+            tmp.path().join("bus/usb/devices/2-1").to_string_lossy()
+        );
+        assert_eq!(runtime.usb_id, Some(CoralUsbId::RUNTIME));
+        assert_eq!(runtime.serial.as_deref(), Some("0123abcd"));
+        assert_eq!((runtime.major, runtime.minor), (189, 132));
+        assert_eq!(runtime.dev_node, PathBuf::from("/dev/bus/usb/002/005"));
+    }
+
+    #[test]
+    fn test_invalid_devices_are_skipped() {
+        let tmp = tempfile::tempdir().unwrap();
+        add_usb(tmp.path(), "2-1", ("18d1", "9302"), 2, 5, "0123abcd");
+        // a device with an odd identity, and a hub being unplugged
+        write(tmp.path(), "bus/usb/devices/1-1/idVendor", "zzzz");
+        write(tmp.path(), "bus/usb/devices/1-1/idProduct", "089a");
+        write(tmp.path(), "bus/usb/devices/1-2/idVendor", "05e3");
+        // an accelerator without its device number
+        add_usb(tmp.path(), "2-2", ("1a6e", "089a"), 2, 6, "");
+        fs::remove_file(tmp.path().join("bus/usb/devices/2-2/dev")).unwrap();
+
+        let devices = discover(tmp.path(), Path::new("/dev")).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].bus_path, "2-1");
+        assert!(matches!(
+            read_usb_id(&tmp.path().join("bus/usb/devices/1-1")),
+            Err(DiscoveryError::InvalidAttribute {
+                name: "idVendor",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_usb_id_display() {
+        assert_eq!(CoralUsbId::BOOTLOADER.to_string(), "1a6e:089a");
+        assert_eq!(CoralUsbId::RUNTIME.to_string(), "18d1:9302");
+    }
+}
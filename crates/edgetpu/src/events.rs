@@ -0,0 +1,245 @@
+//! Events the runtime records about the Edge TPUs.
+//!
+//! Events are appended as JSON lines to a log kept in the runtime root, next to the lease
+//! ledger, so that operators and monitoring tools can follow them. They have the shape of the
+//! runc events, `{"type": ..., "id": ..., "data": ...}`, where the id is the name of the device
+//! or the container the event is about.
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Name of the event log in the runtime root
+pub const EVENT_LOG_FILE: &str = "edgetpu-events.jsonl";
+/// Size from which the log is rotated to `<log>.1`, replacing the previous rotation
+const MAX_LOG_SIZE: u64 = 1 << 20;
+
+#[derive(Debug, thiserror::Error)]
+#[error("failed to access the event log {path:?}")]
+pub struct EventError {
+    source: std::io::Error,
+    path: PathBuf,
+}
+
+/// An event about a device or a container.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Name of the device or id of the container
+    pub id: String,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub data: serde_json::Value,
+}
+
+impl Event {
+    /// Creates an event happening now.
+    pub fn new<S: Into<String>, I: Into<String>>(kind: S, id: I, data: serde_json::Value) -> Self {
+        Self {
+            kind: kind.into(),
+            id: id.into(),
+            timestamp: now(),
+            data,
+        }
+    }
+}
+
+/// Seconds since the Unix epoch
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// The event log of a runtime root.
+///
+/// # Example
+///
+/// ```no_run
+/// use edgetpu::events::{Event, EventLog};
+///
+/// let log = EventLog::new("/run/youki");
+/// log.emit(&Event::new("edgetpu-health", "apex_0", serde_json::json!({"to": "healthy"})))
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    path: PathBuf,
+}
+
+impl EventLog {
+    /// Creates the log kept in the runtime root
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            path: root.as_ref().join(EVENT_LOG_FILE),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends the event to the log.
+    pub fn emit(&self, event: &Event) -> Result<(), EventError> {
+        log::info!("{} event of {}: {}", event.kind, event.id, event.data);
+        self.rotate().map_err(|source| self.error(source))?;
+
+        let mut line = serde_json::to_vec(event).expect("events are valid JSON");
+        line.push(b'\n');
+        // a single write of an O_APPEND file is not interleaved with other writers
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&line))
+            .map_err(|source| self.error(source))
+    }
+
+    /// Returns the events of the log, oldest first. Malformed lines are skipped.
+    pub fn read(&self) -> Result<Vec<Event>, EventError> {
+        self.read_since(0).map(|(events, _)| events)
+    }
+
+    /// Returns the events appended after the first `offset` bytes of the log, and the offset
+    /// to read the next ones from. Reading starts over when the log was rotated meanwhile.
+    pub fn read_since(&self, offset: u64) -> Result<(Vec<Event>, u64), EventError> {
+        let mut file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+            Err(source) => return Err(self.error(source)),
+        };
+        let len = file.metadata().map_err(|source| self.error(source))?.len();
+        let offset = if len < offset { 0 } else { offset };
+        let mut content = Vec::new();
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_to_end(&mut content))
+            .map_err(|source| self.error(source))?;
+        // a line still being written is read next time
+        let complete = content
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |end| end + 1);
+
+        let mut events = Vec::new();
+        for line in content[..complete].split(|byte| *byte == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_slice(line) {
+                Ok(event) => events.push(event),
+                Err(err) => log::warn!(
+                    "skipping malformed event {:?}: {err}",
+                    String::from_utf8_lossy(line)
+                ),
+            }
+        }
+        Ok((events, offset + complete as u64))
+    }
+
+    fn rotate(&self) -> std::io::Result<()> {
+        match fs::metadata(&self.path) {
+            Ok(metadata) if metadata.len() >= MAX_LOG_SIZE => {
+                let mut rotated = self.path.clone().into_os_string();
+                rotated.push(".1");
+                fs::rename(&self.path, rotated)
+            }
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn error(&self, source: std::io::Error) -> EventError {
+        EventError {
+            source,
+            path: self.path.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_emit_and_read() {
+        let root = tempfile::tempdir().unwrap();
+        let log = EventLog::new(root.path());
+        assert!(log.read().unwrap().is_empty());
+
+        let first = Event::new("edgetpu-health", "apex_0", json!({"to": "failing"}));
+        let second = Event::new("edgetpu-health", "apex_0", serde_json::Value::Null);
+        log.emit(&first).unwrap();
+        log.emit(&second).unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(log.path())
+            .unwrap()
+            .write_all(b"not json\n")
+            .unwrap();
+        assert_eq!(log.read().unwrap(), vec![first, second]);
+
+        // one compact line per event, without data when there is none
+        let content = fs::read_to_string(log.path()).unwrap();
+        assert!(content
+            .lines()
+            .next()
+            .unwrap()
+            .contains(r#""data":{"to":"failing"}"#));
+        assert!(!content.lines().nth(1).unwrap().contains("data"));
+    }
+
+    #[test]
+    fn test_read_since() {
+        let root = tempfile::tempdir().unwrap();
+        let log = EventLog::new(root.path());
+        assert_eq!(log.read_since(0).unwrap(), (vec![], 0));
+
+        let first = Event::new("edgetpu-reset", "apex_0", json!({}));
+        log.emit(&first).unwrap();
+        let (events, offset) = log.read_since(0).unwrap();
+        assert_eq!(events, vec![first]);
+        assert_eq!(log.read_since(offset).unwrap(), (vec![], offset));
+
+        let second = Event::new("edgetpu-thermal", "apex_0", json!({"level": "warning"}));
+        log.emit(&second).unwrap();
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(log.path())
+            .unwrap();
+        file.write_all(br#"{"type":"edgetpu-health","#).unwrap();
+        let (events, next) = log.read_since(offset).unwrap();
+        assert_eq!(events, vec![second]);
+
+        // the partial line is read once complete
+        file.write_all(b"\"id\":\"apex_0\",\"timestamp\":1}\n")
+            .unwrap();
+        let (events, _) = log.read_since(next).unwrap();
+        assert_eq!(events[0].kind, "edgetpu-health");
+
+        // the log was rotated
+        fs::write(log.path(), "").unwrap();
+        let third = Event::new("edgetpu-reset", "apex_1", json!({}));
+        log.emit(&third).unwrap();
+        assert_eq!(log.read_since(next).unwrap().0, vec![third]);
+    }
+
+    #[test]
+    fn test_rotate() {
+        let root = tempfile::tempdir().unwrap();
+        let log = EventLog::new(root.path());
+        fs::write(log.path(), vec![b'x'; MAX_LOG_SIZE as usize]).unwrap();
+
+        let event = Event::new("edgetpu-health", "usb-2-1", json!({}));
+        log.emit(&event).unwrap();
+        assert_eq!(log.read().unwrap(), vec![event]);
+        assert!(root.path().join(format!("{EVENT_LOG_FILE}.1")).exists());
+    }
+}
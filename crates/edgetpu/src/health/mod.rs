@@ -0,0 +1,508 @@
+//! Health of the Edge TPUs, probed periodically.
+//!
+//! The [`HealthMonitor`] probes every device the host has or had: whether it is still
+//! present, the status its driver reports in sysfs, whether the apex reached its hardware
+//! thermal shutdown threshold and whether libedgetpu deems it ready. The outcomes go to the
+//! [`HealthLedger`] of the runtime root. After enough failures in a row a device is
+//! quarantined, and the [`crate::injection::EdgeTpuInjector`] refuses to give it to new
+//! containers until it passes enough probes in a row or an operator clears it. Every change
+//! of status is recorded in the [`EventLog`].
+mod state;
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use libcontainer::lease::{LeaseError, LeaseLedger};
+use libedgetpu::backend::EdgeTpuBackend;
+use libedgetpu::driver::EdgeTpuDeviceManager;
+use serde_json::json;
+
+pub use state::{DeviceHealth, HealthLedger, HealthStatus};
+
+use crate::discovery::{DeviceType, Discovery, DiscoveryError, EdgeTpuDevice};
+use crate::events::{Event, EventError, EventLog};
+use crate::thermal::ApexTelemetry;
+
+/// Type of the events of [`Transition`]s
+pub const HEALTH_EVENT: &str = "edgetpu-health";
+/// Value of the apex `status` attribute of a working accelerator
+const APEX_ALIVE: &str = "ALIVE";
+
+#[derive(Debug, thiserror::Error)]
+pub enum HealthError {
+    #[error("failed to lock the health ledger {path:?}")]
+    Lock { source: nix::Error, path: PathBuf },
+    #[error("failed to access the health ledger {path:?}")]
+    Io {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("corrupted health ledger {path:?}")]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+    #[error(transparent)]
+    Discovery(#[from] DiscoveryError),
+    #[error(transparent)]
+    Lease(#[from] LeaseError),
+    #[error(transparent)]
+    Event(#[from] EventError),
+}
+
+type Result<T> = std::result::Result<T, HealthError>;
+
+/// What a probe checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probe {
+    /// The device is still on the bus
+    Presence,
+    /// The apex driver reports the device `ALIVE`
+    Sysfs,
+    /// The apex is below its hardware thermal shutdown threshold
+    Thermal,
+    /// libedgetpu opens the device and reports it ready
+    Ready,
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let probe = match self {
+            Self::Presence => "presence",
+            Self::Sysfs => "sysfs status",
+            Self::Thermal => "thermal shutdown",
+            Self::Ready => "readiness",
+        };
+        f.write_str(probe)
+    }
+}
+
+/// A change of the health status of a device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transition {
+    pub device: String,
+    pub from: HealthStatus,
+    pub to: HealthStatus,
+    /// Why the device became unhealthy, or why it was cleared
+    pub reason: Option<String>,
+}
+
+impl Transition {
+    pub fn to_event(&self) -> Event {
+        let mut data = json!({"from": self.from, "to": self.to});
+        if let Some(reason) = &self.reason {
+            data["reason"] = json!(reason);
+        }
+        Event::new(HEALTH_EVENT, &self.device, data)
+    }
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is {} (was {})", self.device, self.to, self.from)?;
+        if let Some(reason) = &self.reason {
+            write!(f, ": {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Probes the Edge TPUs and records their health.
+///
+/// # Example
+///
+/// ```no_run
+/// use edgetpu::events::EventLog;
+/// use edgetpu::health::{HealthLedger, HealthMonitor};
+///
+/// let monitor = HealthMonitor::new(HealthLedger::new("/run/youki"))
+///     .with_events(EventLog::new("/run/youki"))
+///     .with_quarantine_after(3);
+/// for transition in monitor.probe_all().unwrap() {
+///     println!("{transition}");
+/// }
+/// ```
+#[derive(Clone)]
+pub struct HealthMonitor {
+    ledger: HealthLedger,
+    discovery: Discovery,
+    /// Backend opening the devices, the libedgetpu singleton if unset
+    backend: Option<Arc<dyn EdgeTpuBackend>>,
+    /// Leases of the devices, leased devices are not opened
+    leases: Option<LeaseLedger>,
+    events: Option<EventLog>,
+    quarantine_after: u32,
+    recover_after: u32,
+}
+
+impl HealthMonitor {
+    pub fn new(ledger: HealthLedger) -> Self {
+        Self {
+            ledger,
+            discovery: Discovery::default(),
+            backend: None,
+            leases: None,
+            events: None,
+            quarantine_after: 3,
+            recover_after: 3,
+        }
+    }
+
+    /// Sets how Edge TPUs are discovered on the host
+    pub fn with_discovery(mut self, discovery: Discovery) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    /// Sets the backend the readiness probe opens the devices with
+    pub fn with_backend(mut self, backend: impl EdgeTpuBackend + 'static) -> Self {
+        self.backend = Some(Arc::new(backend));
+        self
+    }
+
+    /// Skips the readiness probe of the devices leased in the ledger, a container may be
+    /// using them
+    pub fn with_leases(mut self, leases: LeaseLedger) -> Self {
+        self.leases = Some(leases);
+        self
+    }
+
+    /// Records the transitions in the event log
+    pub fn with_events(mut self, events: EventLog) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Sets how many probes in a row must fail to quarantine a device
+    pub fn with_quarantine_after(mut self, failures: u32) -> Self {
+        self.quarantine_after = failures.max(1);
+        self
+    }
+
+    /// Sets how many probes in a row a quarantined device must pass to recover
+    pub fn with_recover_after(mut self, successes: u32) -> Self {
+        self.recover_after = successes.max(1);
+        self
+    }
+
+    pub fn ledger(&self) -> &HealthLedger {
+        &self.ledger
+    }
+
+    /// Probes every device once, records the outcomes and returns the transitions.
+    pub fn probe_all(&self) -> Result<Vec<Transition>> {
+        let devices = self.discovery.discover()?;
+        let leased: BTreeSet<String> = match &self.leases {
+            Some(leases) => leases.leases()?.into_iter().map(|l| l.device).collect(),
+            None => BTreeSet::new(),
+        };
+
+        // probe before taking the lock, opening a device takes a while
+        let mut outcomes: Vec<(String, std::result::Result<(), String>)> = devices
+            .iter()
+            .map(|device| {
+                let name = device.name();
+                let outcome = self.probe(device, !leased.contains(&name));
+                (name, outcome)
+            })
+            .collect();
+
+        let transitions = self.ledger.update(|health| {
+            for name in health.keys() {
+                if !outcomes.iter().any(|(probed, _)| probed == name) {
+                    outcomes.push((name.clone(), Err(format!("{}: not found", Probe::Presence))));
+                }
+            }
+
+            let mut transitions = Vec::new();
+            for (name, outcome) in outcomes {
+                let reason = outcome.as_ref().err().cloned();
+                let device = health.entry(name.clone()).or_default();
+                let from = device.status;
+                if let Some(to) = device.record(outcome, self.quarantine_after, self.recover_after)
+                {
+                    transitions.push(Transition {
+                        device: name,
+                        from,
+                        to,
+                        reason,
+                    });
+                }
+            }
+            Ok(transitions)
+        })?;
+
+        for transition in &transitions {
+            log::warn!("Edge TPU {transition}");
+            if let Some(events) = &self.events {
+                events.emit(&transition.to_event())?;
+            }
+        }
+        Ok(transitions)
+    }
+
+    /// Runs the probes of the device, `Err` with the failures.
+    fn probe(&self, device: &EdgeTpuDevice, open: bool) -> std::result::Result<(), String> {
+        let mut failures = Vec::new();
+        if device.device_type() == DeviceType::ApexPCI {
+            if let Err(reason) = self.probe_sysfs(device) {
+                failures.push(format!("{}: {reason}", Probe::Sysfs));
+            }
+            if let Err(reason) = self.probe_thermal(device) {
+                failures.push(format!("{}: {reason}", Probe::Thermal));
+            }
+        }
+        if open {
+            if let Err(reason) = self.probe_ready(device) {
+                failures.push(format!("{}: {reason}", Probe::Ready));
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures.join(", "))
+        }
+    }
+
+    fn apex_attribute(&self, device: &EdgeTpuDevice, name: &str) -> Option<String> {
+        let path = self
+            .discovery
+            .sysfs_root()
+            .join("class/apex")
+            .join(device.name())
+            .join(name);
+        fs::read_to_string(path)
+            .ok()
+            .map(|value| value.trim().to_owned())
+    }
+
+    fn probe_sysfs(&self, device: &EdgeTpuDevice) -> std::result::Result<(), String> {
+        match self.apex_attribute(device, "status") {
+            // older drivers do not have the attribute
+            None => Ok(()),
+            Some(status) if status == APEX_ALIVE => Ok(()),
+            Some(status) => Err(format!("status is {status}")),
+        }
+    }
+
+    /// The apex shuts itself down once `temp` reaches `hw_temp_warn2`, if enabled
+    fn probe_thermal(&self, device: &EdgeTpuDevice) -> std::result::Result<(), String> {
+        match ApexTelemetry::read(self.discovery.sysfs_root(), &device.name()) {
+            Some(ApexTelemetry {
+                temperature,
+                hw_temp_warn2: Some(shutdown),
+                ..
+            }) if temperature >= shutdown => Err(format!(
+                "{temperature}°C reached the shutdown threshold of {shutdown}°C"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn probe_ready(&self, device: &EdgeTpuDevice) -> std::result::Result<(), String> {
+        let singleton;
+        let backend: &dyn EdgeTpuBackend = match &self.backend {
+            Some(backend) => backend.as_ref(),
+            None => match EdgeTpuDeviceManager::get_singleton() {
+                Some(manager) => {
+                    singleton = manager;
+                    &singleton
+                }
+                None => {
+                    log::debug!("libedgetpu could not be loaded, skipping the readiness probe");
+                    return Ok(());
+                }
+            },
+        };
+        let context = backend
+            .open_device_path(device.device_type(), &device.record.path)
+            .map_err(|err| err.to_string())?;
+        if context.is_ready() {
+            Ok(())
+        } else {
+            Err("the device is not ready".to_owned())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libedgetpu::backend::MockBackend;
+
+    use super::*;
+    use crate::discovery::fixture::{add_apex, add_usb, write};
+
+    struct Host {
+        sysfs: tempfile::TempDir,
+        root: tempfile::TempDir,
+        backend: MockBackend,
+    }
+
+    impl Host {
+        fn new() -> Self {
+            let host = Self {
+                sysfs: tempfile::tempdir().unwrap(),
+                root: tempfile::tempdir().unwrap(),
+                backend: MockBackend::new().with_pci_devices(1),
+            };
+            add_apex(host.sysfs.path(), 0, "0000:01:00.0");
+            add_usb(host.sysfs.path(), "2-1", ("18d1", "9302"), 2, 3, "f00d");
+            host.backend.add_device(
+                DeviceType::ApexUSB,
+                &host
+                    .sysfs
+                    .path()
+                    .join("bus/usb/devices/2-1")
+                    .to_string_lossy(),
+            );
+            host
+        }
+
+        fn monitor(&self) -> HealthMonitor {
+            HealthMonitor::new(HealthLedger::new(self.root.path()))
+                .with_discovery(
+                    Discovery::new()
+                        .with_sysfs_root(self.sysfs.path())
+                        .with_dev_root("/dev"),
+                )
+                .with_backend(self.backend.clone())
+                .with_events(EventLog::new(self.root.path()))
+                .with_quarantine_after(2)
+                .with_recover_after(2)
+        }
+    }
+
+    fn statuses(transitions: &[Transition]) -> Vec<(&str, HealthStatus)> {
+        transitions
+            .iter()
+            .map(|t| (t.device.as_str(), t.to))
+            .collect()
+    }
+
+    #[test]
+    fn test_quarantine_and_recover() {
+        let host = Host::new();
+        let monitor = host.monitor();
+        assert!(monitor.probe_all().unwrap().is_empty());
+
+        write(host.sysfs.path(), "class/apex/apex_0/status", "DEAD\n");
+        let transitions = monitor.probe_all().unwrap();
+        assert_eq!(
+            statuses(&transitions),
+            vec![("apex_0", HealthStatus::Failing)]
+        );
+        assert_eq!(
+            transitions[0].reason.as_deref(),
+            Some("sysfs status: status is DEAD")
+        );
+        assert_eq!(
+            statuses(&monitor.probe_all().unwrap()),
+            vec![("apex_0", HealthStatus::Quarantined)]
+        );
+        assert_eq!(monitor.ledger().quarantined().unwrap(), vec!["apex_0"]);
+
+        write(host.sysfs.path(), "class/apex/apex_0/status", "ALIVE\n");
+        assert!(monitor.probe_all().unwrap().is_empty());
+        assert_eq!(
+            statuses(&monitor.probe_all().unwrap()),
+            vec![("apex_0", HealthStatus::Healthy)]
+        );
+
+        let events = EventLog::new(host.root.path()).read().unwrap();
+        let kinds: Vec<_> = events
+            .iter()
+            .map(|e| {
+                (
+                    e.kind.as_str(),
+                    e.id.as_str(),
+                    e.data["to"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (HEALTH_EVENT, "apex_0", "failing"),
+                (HEALTH_EVENT, "apex_0", "quarantined"),
+                (HEALTH_EVENT, "apex_0", "healthy"),
+            ]
+        );
+        assert_eq!(events[0].data["reason"], "sysfs status: status is DEAD");
+    }
+
+    #[test]
+    fn test_probes() {
+        let host = Host::new();
+        let monitor = host.monitor().with_quarantine_after(1);
+        monitor.probe_all().unwrap();
+
+        // the hardware thermal shutdown only counts when enabled
+        write(host.sysfs.path(), "class/apex/apex_0/temp", "104500\n");
+        write(
+            host.sysfs.path(),
+            "class/apex/apex_0/hw_temp_warn2",
+            "100000\n",
+        );
+        write(
+            host.sysfs.path(),
+            "class/apex/apex_0/hw_temp_warn2_en",
+            "0\n",
+        );
+        assert!(monitor.probe_all().unwrap().is_empty());
+        write(
+            host.sysfs.path(),
+            "class/apex/apex_0/hw_temp_warn2_en",
+            "1\n",
+        );
+        let transitions = monitor.probe_all().unwrap();
+        assert_eq!(
+            transitions[0].reason.as_deref(),
+            Some("thermal shutdown: 104.5°C reached the shutdown threshold of 100°C")
+        );
+
+        // an unplugged USB accelerator is no longer found
+        fs::remove_dir_all(host.sysfs.path().join("bus/usb/devices/2-1")).unwrap();
+        let transitions = monitor.probe_all().unwrap();
+        assert_eq!(
+            statuses(&transitions),
+            vec![("usb-2-1", HealthStatus::Quarantined)]
+        );
+        assert_eq!(
+            transitions[0].reason.as_deref(),
+            Some("presence: not found")
+        );
+    }
+
+    #[test]
+    fn test_readiness_probe() {
+        let host = Host::new();
+        let monitor = host.monitor().with_quarantine_after(1);
+        monitor.probe_all().unwrap();
+        let opened = host.backend.opened();
+
+        host.backend.fail_open("/dev/apex_0", true);
+        let transitions = monitor.probe_all().unwrap();
+        assert_eq!(
+            statuses(&transitions),
+            vec![("apex_0", HealthStatus::Quarantined)]
+        );
+        assert!(transitions[0]
+            .reason
+            .as_deref()
+            .unwrap()
+            .starts_with("readiness: failed to open /dev/apex_0"));
+        assert_eq!(host.backend.opened(), opened + 1);
+
+        // a device leased to a container is not opened
+        let leases = LeaseLedger::new(host.root.path());
+        fs::create_dir(host.root.path().join("holder")).unwrap();
+        leases.acquire("holder", &["usb-2-1"], false).unwrap();
+        let monitor = monitor.with_leases(leases);
+        monitor.probe_all().unwrap();
+        assert_eq!(host.backend.opened(), opened + 1);
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use nix::fcntl::{Flock, FlockArg};
+use serde::{Deserialize, Serialize};
+
+use super::{HealthError, Result, Transition};
+use crate::events::now;
+
+const HEALTH_FILE: &str = "device-health.json";
+const LOCK_FILE: &str = "device-health.lock";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HealthStatus {
+    Healthy,
+    /// The last probes failed, but not enough of them to quarantine the device
+    Failing,
+    /// Too many probes failed in a row, the device is not given to new containers
+    Quarantined,
+}
+
+impl fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            Self::Healthy => "healthy",
+            Self::Failing => "failing",
+            Self::Quarantined => "quarantined",
+        };
+        f.write_str(status)
+    }
+}
+
+/// Probe history of a device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceHealth {
+    pub status: HealthStatus,
+    /// Probes failed in a row
+    pub failures: u32,
+    /// Probes passed in a row
+    pub successes: u32,
+    /// Why the last failed probe failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_failure: Option<String>,
+    /// Seconds since the Unix epoch of the last probe
+    pub last_probe: u64,
+}
+
+impl Default for DeviceHealth {
+    fn default() -> Self {
+        Self {
+            status: HealthStatus::Healthy,
+            failures: 0,
+            successes: 0,
+            last_failure: None,
+            last_probe: 0,
+        }
+    }
+}
+
+impl DeviceHealth {
+    /// Records the outcome of a probe, `Err` with the reason of a failure, and returns the
+    /// new status if it changed.
+    ///
+    /// `quarantine_after` failures in a row quarantine the device, and a quarantined device
+    /// recovers after `recover_after` successes in a row.
+    pub fn record(
+        &mut self,
+        outcome: std::result::Result<(), String>,
+        quarantine_after: u32,
+        recover_after: u32,
+    ) -> Option<HealthStatus> {
+        let previous = self.status;
+        self.last_probe = now();
+        match outcome {
+            Ok(()) => {
+                self.failures = 0;
+                self.successes = self.successes.saturating_add(1);
+                if previous != HealthStatus::Quarantined || self.successes >= recover_after {
+                    self.status = HealthStatus::Healthy;
+                }
+            }
+            Err(reason) => {
+                self.successes = 0;
+                self.failures = self.failures.saturating_add(1);
+                self.last_failure = Some(reason);
+                self.status = if self.failures >= quarantine_after {
+                    HealthStatus::Quarantined
+                } else if previous == HealthStatus::Quarantined {
+                    previous
+                } else {
+                    HealthStatus::Failing
+                };
+            }
+        }
+        (self.status != previous).then_some(self.status)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HealthFile {
+    devices: BTreeMap<String, DeviceHealth>,
+}
+
+/// The health of the Edge TPUs, kept in the runtime root.
+///
+/// Like the lease ledger, the file is guarded by an exclusive `flock(2)` on a lock file, so
+/// the health monitor and the runtime invocations creating containers see each other's
+/// changes.
+///
+/// # Example
+///
+/// ```no_run
+/// use edgetpu::health::HealthLedger;
+///
+/// let ledger = HealthLedger::new("/run/youki");
+/// for device in ledger.quarantined().unwrap() {
+///     println!("{device} is quarantined");
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct HealthLedger {
+    root: PathBuf,
+}
+
+impl HealthLedger {
+    /// Creates a ledger kept in the runtime root, where the container state directories are
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the health of every probed device, by name.
+    pub fn devices(&self) -> Result<BTreeMap<String, DeviceHealth>> {
+        if !self.health_path().exists() {
+            return Ok(BTreeMap::new());
+        }
+        let _lock = self.lock()?;
+        Ok(self.load()?.devices)
+    }
+
+    /// Names of the quarantined devices.
+    pub fn quarantined(&self) -> Result<Vec<String>> {
+        Ok(self
+            .devices()?
+            .into_iter()
+            .filter(|(_, health)| health.status == HealthStatus::Quarantined)
+            .map(|(device, _)| device)
+            .collect())
+    }
+
+    /// Marks the device healthy again, e.g. once an operator fixed it, and returns the
+    /// transition if it was not healthy.
+    pub fn clear(&self, device: &str) -> Result<Option<Transition>> {
+        self.update(|devices| {
+            let Some(health) = devices.get_mut(device) else {
+                return Ok(None);
+            };
+            let from = health.status;
+            *health = DeviceHealth {
+                last_probe: health.last_probe,
+                ..Default::default()
+            };
+            Ok((from != HealthStatus::Healthy).then(|| Transition {
+                device: device.to_owned(),
+                from,
+                to: HealthStatus::Healthy,
+                reason: Some("cleared by the operator".to_owned()),
+            }))
+        })
+    }
+
+    /// Runs `f` on the devices with the lock held, and saves them if `f` succeeds.
+    pub(super) fn update<T>(
+        &self,
+        f: impl FnOnce(&mut BTreeMap<String, DeviceHealth>) -> Result<T>,
+    ) -> Result<T> {
+        let _lock = self.lock()?;
+        let mut file = self.load()?;
+        let result = f(&mut file.devices)?;
+        self.save(&file)?;
+        Ok(result)
+    }
+
+    fn lock(&self) -> Result<Flock<File>> {
+        let path = self.root.join(LOCK_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|err| HealthError::Io {
+                source: err,
+                path: path.clone(),
+            })?;
+        Flock::lock(file, FlockArg::LockExclusive)
+            .map_err(|(_, err)| HealthError::Lock { source: err, path })
+    }
+
+    fn load(&self) -> Result<HealthFile> {
+        let path = self.health_path();
+        match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .map_err(|err| HealthError::Parse { source: err, path }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(HealthFile::default()),
+            Err(err) => Err(HealthError::Io { source: err, path }),
+        }
+    }
+
+    fn save(&self, file: &HealthFile) -> Result<()> {
+        let path = self.health_path();
+        let tmp = path.with_extension("json.tmp");
+        let content = serde_json::to_vec(file).map_err(|err| HealthError::Parse {
+            source: err,
+            path: path.clone(),
+        })?;
+        fs::write(&tmp, content)
+            .and_then(|_| fs::rename(&tmp, &path))
+            .map_err(|err| HealthError::Io { source: err, path })
+    }
+
+    fn health_path(&self) -> PathBuf {
+        self.root.join(HEALTH_FILE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut health = DeviceHealth::default();
+        let fail = || Err("status is DEAD".to_owned());
+
+        assert_eq!(health.record(Ok(()), 3, 2), None);
+        assert_eq!(health.record(fail(), 3, 2), Some(HealthStatus::Failing));
+        assert_eq!(health.record(fail(), 3, 2), None);
+        assert_eq!(health.record(fail(), 3, 2), Some(HealthStatus::Quarantined));
+        assert_eq!(health.last_failure.as_deref(), Some("status is DEAD"));
+
+        // a quarantined device needs several successes in a row to recover
+        assert_eq!(health.record(Ok(()), 3, 2), None);
+        assert_eq!(health.record(fail(), 3, 2), None);
+        assert_eq!(health.record(Ok(()), 3, 2), None);
+        assert_eq!(health.record(Ok(()), 3, 2), Some(HealthStatus::Healthy));
+
+        // a failing device recovers at once
+        assert_eq!(health.record(fail(), 3, 2), Some(HealthStatus::Failing));
+        assert_eq!(health.record(Ok(()), 3, 2), Some(HealthStatus::Healthy));
+    }
+
+    #[test]
+    fn test_clear() {
+        let root = tempfile::tempdir().unwrap();
+        let ledger = HealthLedger::new(root.path());
+        assert!(ledger.devices().unwrap().is_empty());
+        assert_eq!(ledger.clear("apex_0").unwrap(), None);
+
+        ledger
+            .update(|devices| {
+                for device in ["apex_0", "apex_1"] {
+                    let health = devices.entry(device.to_owned()).or_default();
+                    health.record(Err("gone".to_owned()), 1, 1);
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(ledger.quarantined().unwrap(), vec!["apex_0", "apex_1"]);
+
+        let transition = ledger.clear("apex_1").unwrap().unwrap();
+        assert_eq!(
+            (transition.from, transition.to),
+            (HealthStatus::Quarantined, HealthStatus::Healthy)
+        );
+        assert_eq!(ledger.quarantined().unwrap(), vec!["apex_0"]);
+        assert_eq!(ledger.devices().unwrap()["apex_1"].failures, 0);
+    }
+}
@@ -0,0 +1,303 @@
+//! The processes holding an Edge TPU open.
+//!
+//! Every `/proc/<pid>/fd` entry is compared with the device node, by path and, since a
+//! containerized process sees the node under its own mount namespace, by device number. The
+//! processes are attributed to containers through their cgroup.
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::discovery::EdgeTpuDevice;
+
+pub const DEFAULT_PROCFS_ROOT: &str = "/proc";
+
+#[derive(Debug, thiserror::Error)]
+#[error("failed to read {path:?}")]
+pub struct HolderError {
+    source: std::io::Error,
+    path: PathBuf,
+}
+
+/// A process with an Edge TPU open.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Holder {
+    pub pid: i32,
+    /// Name of the executable, from `/proc/<pid>/comm`
+    pub command: String,
+    /// File descriptors referring to the device
+    pub fds: Vec<i32>,
+    /// Unified cgroup of the process, or its first one on cgroup v1
+    pub cgroup: Option<String>,
+    /// Id of the container the process runs in
+    pub container: Option<String>,
+}
+
+/// Finds the [`Holder`]s of a device.
+///
+/// # Example
+///
+/// ```no_run
+/// use edgetpu::discovery::Discovery;
+/// use edgetpu::holders::HolderScanner;
+///
+/// let scanner = HolderScanner::new().with_container("inference", "/youki/inference");
+/// for device in Discovery::new().discover().unwrap() {
+///     for holder in scanner.scan(&device).unwrap() {
+///         println!("{} is held by {} ({})", device.name(), holder.pid, holder.command);
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct HolderScanner {
+    procfs_root: PathBuf,
+    /// Containers by id with their cgroup path
+    containers: Vec<(String, PathBuf)>,
+}
+
+impl Default for HolderScanner {
+    fn default() -> Self {
+        Self {
+            procfs_root: PathBuf::from(DEFAULT_PROCFS_ROOT),
+            containers: Vec::new(),
+        }
+    }
+}
+
+impl HolderScanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the directory procfs is mounted on
+    pub fn with_procfs_root<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.procfs_root = path.into();
+        self
+    }
+
+    /// Attributes the processes of the cgroup to the container. The cgroup path is the one
+    /// of the container config, `/youki/<id>` or `<slice>:<prefix>:<name>` with systemd.
+    pub fn with_container<S: Into<String>, P: Into<PathBuf>>(
+        mut self,
+        container_id: S,
+        cgroup_path: P,
+    ) -> Self {
+        self.containers
+            .push((container_id.into(), cgroup_path.into()));
+        self
+    }
+
+    pub fn procfs_root(&self) -> &Path {
+        &self.procfs_root
+    }
+
+    /// Returns the processes holding the device open, by pid. Processes that exit during
+    /// the scan or that the caller may not inspect are skipped.
+    pub fn scan(&self, device: &EdgeTpuDevice) -> Result<Vec<Holder>, HolderError> {
+        let entries = fs::read_dir(&self.procfs_root).map_err(|source| HolderError {
+            source,
+            path: self.procfs_root.clone(),
+        })?;
+        let mut holders = Vec::new();
+        for entry in entries.flatten() {
+            let Some(pid) = entry.file_name().to_str().and_then(|pid| pid.parse().ok()) else {
+                continue;
+            };
+            let process_dir = entry.path();
+            let fds = match open_fds(&process_dir, device) {
+                Ok(fds) if !fds.is_empty() => fds,
+                Ok(_) => continue,
+                Err(err) => {
+                    log::debug!("skipping process {pid}: {err}");
+                    continue;
+                }
+            };
+
+            let cgroups = fs::read_to_string(process_dir.join("cgroup")).unwrap_or_default();
+            let cgroups: Vec<(&str, &str)> = cgroups
+                .lines()
+                .filter_map(|line| {
+                    let (_, rest) = line.split_once(':')?;
+                    rest.split_once(':')
+                })
+                .collect();
+            let container = self
+                .containers
+                .iter()
+                .find(|(_, cgroup_path)| {
+                    cgroups.iter().any(|(_, path)| in_cgroup(path, cgroup_path))
+                })
+                .map(|(id, _)| id.clone());
+            let cgroup = cgroups
+                .iter()
+                .find(|(controllers, _)| controllers.is_empty())
+                .or(cgroups.first())
+                .map(|(_, path)| (*path).to_owned());
+
+            holders.push(Holder {
+                pid,
+                command: fs::read_to_string(process_dir.join("comm"))
+                    .map(|comm| comm.trim_end().to_owned())
+                    .unwrap_or_default(),
+                fds,
+                cgroup,
+                container,
+            });
+        }
+        holders.sort_by_key(|holder| holder.pid);
+        Ok(holders)
+    }
+}
+
+/// The file descriptors of the process referring to the device.
+fn open_fds(process_dir: &Path, device: &EdgeTpuDevice) -> std::io::Result<Vec<i32>> {
+    let rdev = nix::sys::stat::makedev(device.major.into(), device.minor.into());
+    let mut fds = Vec::new();
+    for entry in fs::read_dir(process_dir.join("fd"))?.flatten() {
+        let Some(fd) = entry.file_name().to_str().and_then(|fd| fd.parse().ok()) else {
+            continue;
+        };
+        let path = entry.path();
+        let same_path = fs::read_link(&path).is_ok_and(|target| target == device.dev_node);
+        let same_device = fs::metadata(&path)
+            .is_ok_and(|metadata| metadata.file_type().is_char_device() && metadata.rdev() == rdev);
+        if same_path || same_device {
+            fds.push(fd);
+        }
+    }
+    fds.sort_unstable();
+    Ok(fds)
+}
+
+/// Whether the process cgroup `path` is the container cgroup or below it.
+fn in_cgroup(path: &str, cgroup_path: &Path) -> bool {
+    let path = Path::new(path);
+    if path.starts_with(Path::new("/").join(cgroup_path)) {
+        return true;
+    }
+    // systemd runs the container in the `<prefix>-<name>.scope` unit of the slice
+    let cgroup_path = cgroup_path.to_string_lossy();
+    match cgroup_path.split(':').collect::<Vec<_>>()[..] {
+        [_, prefix, name] => {
+            let scope = format!("{prefix}-{name}.scope");
+            path.components()
+                .any(|component| component.as_os_str() == scope.as_str())
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod fixture {
+    //! Helpers to lay out a fake procfs tree.
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+
+    /// Adds the process `pid` in the cgroup `cgroup` with the files `fds` open
+    pub fn add_process(root: &Path, pid: i32, command: &str, cgroup: &str, fds: &[(i32, &Path)]) {
+        let dir = root.join(pid.to_string());
+        fs::create_dir_all(dir.join("fd")).unwrap();
+        fs::write(dir.join("comm"), format!("{command}\n")).unwrap();
+        fs::write(dir.join("cgroup"), format!("0::{cgroup}\n")).unwrap();
+        for (fd, target) in fds {
+            symlink(target, dir.join("fd").join(fd.to_string())).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::add_process;
+    use super::*;
+    use crate::discovery::fixture::{add_apex, add_usb};
+    use crate::discovery::Discovery;
+
+    #[test]
+    fn test_scan() {
+        let tmp = tempfile::tempdir().unwrap();
+        let sysfs = tmp.path().join("sys");
+        let procfs = tmp.path().join("proc");
+        add_apex(&sysfs, 0, "0000:01:00.0");
+        add_apex(&sysfs, 1, "0000:02:00.0");
+        add_usb(&sysfs, "2-1", ("18d1", "9302"), 2, 3, "f00d");
+        let devices = Discovery::new()
+            .with_sysfs_root(&sysfs)
+            .with_dev_root("/dev")
+            .discover()
+            .unwrap();
+
+        let apex_0 = Path::new("/dev/apex_0");
+        let usb = Path::new("/dev/bus/usb/002/003");
+        add_process(
+            &procfs,
+            42,
+            "classify",
+            "/youki/inference",
+            &[(0, Path::new("/dev/null")), (5, apex_0), (7, apex_0)],
+        );
+        add_process(
+            &procfs,
+            7,
+            "detect",
+            "/system.slice/youki-camera.scope",
+            &[(3, usb)],
+        );
+        add_process(&procfs, 100, "python3", "/user.slice", &[(3, apex_0)]);
+        // neither a process nor readable
+        fs::create_dir_all(procfs.join("self")).unwrap();
+        fs::create_dir_all(procfs.join("1")).unwrap();
+
+        let scanner = HolderScanner::new()
+            .with_procfs_root(&procfs)
+            .with_container("inference", "/youki/inference")
+            .with_container("camera", ":youki:camera");
+
+        let holders = scanner.scan(&devices[0]).unwrap();
+        assert_eq!(
+            holders,
+            vec![
+                Holder {
+                    pid: 42,
+                    command: "classify".to_owned(),
+                    fds: vec![5, 7],
+                    cgroup: Some("/youki/inference".to_owned()),
+                    container: Some("inference".to_owned()),
+                },
+                Holder {
+                    pid: 100,
+                    command: "python3".to_owned(),
+                    fds: vec![3],
+                    cgroup: Some("/user.slice".to_owned()),
+                    container: None,
+                },
+            ]
+        );
+        assert!(scanner.scan(&devices[1]).unwrap().is_empty());
+
+        let holders = scanner.scan(&devices[2]).unwrap();
+        let holders: Vec<_> = holders
+            .iter()
+            .map(|h| (h.pid, h.container.as_deref()))
+            .collect();
+        assert_eq!(holders, vec![(7, Some("camera"))]);
+    }
+
+    #[test]
+    fn test_in_cgroup() {
+        assert!(in_cgroup("/youki/abc", Path::new("/youki/abc")));
+        assert!(in_cgroup("/youki/abc/sub", Path::new("youki/abc")));
+        assert!(!in_cgroup("/youki/abcd", Path::new("/youki/abc")));
+        assert!(in_cgroup("/:youki:abc", Path::new(":youki:abc")));
+        assert!(in_cgroup(
+            "/machine.slice/youki-abc.scope",
+            Path::new("machine.slice:youki:abc")
+        ));
+        assert!(!in_cgroup(
+            "/machine.slice/youki-abcd.scope",
+            Path::new("machine.slice:youki:abc")
+        ));
+    }
+}
@@ -1,7 +1,60 @@
-use oci_spec::mount::Mount;
+use libcontainer::oci_spec::runtime::{Hook, Mount, Spec};
 
+use crate::injection::edits;
+
+/// What a container needs to use the host Edge TPU runtime: the process environment, the
+/// library mounts and the hooks preparing the root filesystem.
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct EdgeTPU {
-    env: Map<str, str>,
+    env: Vec<(String, String)>,
     mounts: Vec<Mount>,
+    /// `createContainer` hooks, run once the mounts are in place
+    hooks: Vec<Hook>,
 }
 
+impl EdgeTPU {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_env<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn with_mount(mut self, mount: Mount) -> Self {
+        self.mounts.push(mount);
+        self
+    }
+
+    pub fn with_hook(mut self, hook: Hook) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    pub fn env(&self) -> &[(String, String)] {
+        &self.env
+    }
+
+    pub fn mounts(&self) -> &[Mount] {
+        &self.mounts
+    }
+
+    pub fn hooks(&self) -> &[Hook] {
+        &self.hooks
+    }
+
+    /// Adds everything to the spec, replacing the variables and the mounts it already has
+    /// with the same names and destinations.
+    pub fn apply(&self, spec: &mut Spec) {
+        for (key, value) in &self.env {
+            edits::set_env(spec, key, value);
+        }
+        for mount in &self.mounts {
+            edits::add_mount(spec, mount.clone());
+        }
+        for hook in &self.hooks {
+            edits::add_create_container_hook(spec, hook.clone());
+        }
+    }
+}
@@ -0,0 +1,326 @@
+//! Clock frequency of the Edge TPU runtime given to a container.
+//!
+//! The runtime comes in two builds: the reduced frequency one ("throttled") and the maximum
+//! frequency one ("direct"), which runs USB accelerators faster and hotter. Both are installed
+//! side by side under [`VARIANTS_DIR`] of the library directory, and a container picks one
+//! with the [`FREQUENCY_ANNOTATION`] annotation. The [`FrequencyPolicy`] of the node caps what
+//! containers may pick, for example no maximum frequency once the thermal sensors run hot.
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use libcontainer::oci_spec::runtime::Spec;
+use serde::{Deserialize, Serialize};
+
+use crate::injection::InjectionError;
+
+/// Annotation choosing the [`Frequency`] of the container
+pub const FREQUENCY_ANNOTATION: &str = "edgetpu.coral.ai/frequency";
+/// Variable of the container process telling the frequency of the injected runtime
+pub const FREQUENCY_ENV: &str = "EDGETPU_FREQUENCY";
+/// Directory of the library directory holding a subdirectory per runtime build
+pub const VARIANTS_DIR: &str = "edgetpu";
+/// Node policy read by the runtime, the default policy applies when it does not exist
+pub const DEFAULT_POLICY_PATH: &str = "/etc/tpu-container-runtime/frequency.yaml";
+
+#[derive(Debug, thiserror::Error)]
+pub enum PolicyError {
+    #[error("failed to read the frequency policy {path:?}")]
+    Read {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("invalid frequency policy {path:?}")]
+    Parse {
+        source: serde_yaml::Error,
+        path: PathBuf,
+    },
+}
+
+/// Clock frequency of the Edge TPU, ordered from the coolest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Frequency {
+    Reduced,
+    Max,
+}
+
+impl Frequency {
+    /// Reads the frequency the container asks for, if any.
+    pub fn from_spec(spec: &Spec) -> Result<Option<Self>, InjectionError> {
+        spec.annotations()
+            .as_ref()
+            .and_then(|annotations| annotations.get(FREQUENCY_ANNOTATION))
+            .map(|value| value.parse())
+            .transpose()
+    }
+
+    /// Directory of the build in the runtime archive and under [`VARIANTS_DIR`]
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            Self::Reduced => "throttled",
+            Self::Max => "direct",
+        }
+    }
+
+    /// Directory the build is installed in
+    pub fn variant_dir(&self, library_dir: &Path) -> PathBuf {
+        library_dir.join(VARIANTS_DIR).join(self.variant_name())
+    }
+}
+
+impl FromStr for Frequency {
+    type Err = InjectionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "reduced" => Ok(Self::Reduced),
+            "max" => Ok(Self::Max),
+            other => Err(InjectionError::Frequency(other.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Frequency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Reduced => write!(f, "reduced"),
+            Self::Max => write!(f, "max"),
+        }
+    }
+}
+
+/// Highest frequency containers of the node may run at.
+///
+/// ```yaml
+/// maxFrequency: max
+/// thermal:
+///   # degrees Celsius from which containers get the reduced frequency
+///   maxTemperature: 75
+///   # degrees Celsius from which the containers of an Edge TPU are paused
+///   criticalTemperature: 90
+///   # degrees Celsius below which they are resumed, maxTemperature by default
+///   resumeTemperature: 80
+/// ```
+///
+/// The pause and the resume are left to the [`ThermalGovernor`](crate::thermal::ThermalGovernor).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct FrequencyPolicy {
+    #[serde(default = "max_frequency")]
+    max_frequency: Frequency,
+    #[serde(default)]
+    thermal: Option<ThermalLimit>,
+    #[serde(skip, default = "default_sysfs_root")]
+    sysfs_root: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ThermalLimit {
+    /// Temperature in degrees Celsius
+    pub max_temperature: f64,
+    /// Temperature in degrees Celsius from which the containers of an Edge TPU are paused
+    #[serde(default)]
+    pub critical_temperature: Option<f64>,
+    /// Temperature in degrees Celsius below which the paused containers are resumed
+    #[serde(default)]
+    pub resume_temperature: Option<f64>,
+}
+
+impl ThermalLimit {
+    pub fn new(max_temperature: f64) -> Self {
+        Self {
+            max_temperature,
+            critical_temperature: None,
+            resume_temperature: None,
+        }
+    }
+
+    /// Pauses the containers of an Edge TPU from `critical` °C until it cools down below
+    /// `resume` °C
+    pub fn with_critical_temperature(mut self, critical: f64, resume: Option<f64>) -> Self {
+        self.critical_temperature = Some(critical);
+        self.resume_temperature = resume;
+        self
+    }
+
+    /// Temperature below which the paused containers are resumed
+    pub fn resume_temperature(&self) -> f64 {
+        self.resume_temperature.unwrap_or(self.max_temperature)
+    }
+}
+
+fn max_frequency() -> Frequency {
+    Frequency::Max
+}
+
+fn default_sysfs_root() -> PathBuf {
+    PathBuf::from("/sys")
+}
+
+impl Default for FrequencyPolicy {
+    fn default() -> Self {
+        Self {
+            max_frequency: max_frequency(),
+            thermal: None,
+            sysfs_root: default_sysfs_root(),
+        }
+    }
+}
+
+impl FrequencyPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the policy file, the default policy when there is none.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, PolicyError> {
+        let path = path.as_ref();
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(source) => {
+                return Err(PolicyError::Read {
+                    source,
+                    path: path.to_owned(),
+                })
+            }
+        };
+        serde_yaml::from_str(&content).map_err(|source| PolicyError::Parse {
+            source,
+            path: path.to_owned(),
+        })
+    }
+
+    pub fn with_max_frequency(mut self, frequency: Frequency) -> Self {
+        self.max_frequency = frequency;
+        self
+    }
+
+    /// Caps the frequency at reduced while a sensor reaches `max_temperature` °C
+    pub fn with_thermal_limit(mut self, max_temperature: f64) -> Self {
+        self.thermal = Some(ThermalLimit::new(max_temperature));
+        self
+    }
+
+    pub fn thermal(&self) -> Option<&ThermalLimit> {
+        self.thermal.as_ref()
+    }
+
+    /// Sets the directory sysfs is mounted on
+    pub fn with_sysfs_root<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.sysfs_root = path.into();
+        self
+    }
+
+    /// Highest frequency allowed right now.
+    pub fn cap(&self) -> Frequency {
+        if self.max_frequency == Frequency::Reduced {
+            return Frequency::Reduced;
+        }
+        let Some(thermal) = &self.thermal else {
+            return self.max_frequency;
+        };
+
+        let hottest = self
+            .temperatures()
+            .into_iter()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+        match hottest {
+            Some((sensor, celsius)) if celsius >= thermal.max_temperature => {
+                log::info!(
+                    "{} reports {celsius}°C, the frequency is capped at reduced",
+                    sensor.display()
+                );
+                Frequency::Reduced
+            }
+            _ => self.max_frequency,
+        }
+    }
+
+    /// Temperatures in degrees Celsius of the thermal zones and of the PCIe Edge TPUs
+    fn temperatures(&self) -> Vec<(PathBuf, f64)> {
+        let root = glob::Pattern::escape(&self.sysfs_root.to_string_lossy());
+        let mut temperatures = Vec::new();
+        for sensors in ["class/thermal/thermal_zone*/temp", "class/apex/*/temp"] {
+            let Ok(paths) = glob::glob(&format!("{root}/{sensors}")) else {
+                continue;
+            };
+            for path in paths.flatten() {
+                // millidegrees Celsius, unreadable while a zone is disabled
+                match fs::read_to_string(&path).map(|value| value.trim().parse::<i64>()) {
+                    Ok(Ok(millidegrees)) => temperatures.push((path, millidegrees as f64 / 1000.0)),
+                    _ => log::debug!("skipping unreadable sensor {}", path.display()),
+                }
+            }
+        }
+        temperatures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add_sensor(sysfs: &Path, sensor: &str, millidegrees: i64) {
+        let dir = sysfs.join(sensor);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("temp"), format!("{millidegrees}\n")).unwrap();
+    }
+
+    #[test]
+    fn test_load_policy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("frequency.yaml");
+        assert_eq!(
+            FrequencyPolicy::load(&path).unwrap(),
+            FrequencyPolicy::new()
+        );
+
+        fs::write(&path, "maxFrequency: max\nthermal:\n  maxTemperature: 75\n").unwrap();
+        assert_eq!(
+            FrequencyPolicy::load(&path).unwrap(),
+            FrequencyPolicy::new().with_thermal_limit(75.0)
+        );
+
+        fs::write(
+            &path,
+            "thermal:\n  maxTemperature: 75\n  criticalTemperature: 90\n",
+        )
+        .unwrap();
+        let policy = FrequencyPolicy::load(&path).unwrap();
+        let thermal = policy.thermal().unwrap();
+        assert_eq!(thermal.critical_temperature, Some(90.0));
+        assert_eq!(thermal.resume_temperature(), 75.0);
+
+        fs::write(&path, "maxFrequency: turbo\n").unwrap();
+        assert!(matches!(
+            FrequencyPolicy::load(&path),
+            Err(PolicyError::Parse { .. })
+        ));
+    }
+
+    #[test]
+    fn test_thermal_cap() {
+        let sysfs = tempfile::tempdir().unwrap();
+        add_sensor(sysfs.path(), "class/thermal/thermal_zone0", 48000);
+        add_sensor(sysfs.path(), "class/apex/apex_0", 61500);
+        let policy = FrequencyPolicy::new()
+            .with_sysfs_root(sysfs.path())
+            .with_thermal_limit(70.0);
+        assert_eq!(policy.cap(), Frequency::Max);
+
+        add_sensor(sysfs.path(), "class/thermal/thermal_zone1", 70000);
+        assert_eq!(policy.cap(), Frequency::Reduced);
+
+        assert_eq!(
+            FrequencyPolicy::new()
+                .with_sysfs_root(sysfs.path())
+                .with_max_frequency(Frequency::Reduced)
+                .cap(),
+            Frequency::Reduced
+        );
+    }
+}
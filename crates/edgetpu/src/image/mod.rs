@@ -1 +1,495 @@
+//! The host Edge TPU runtime, given to containers in place of the one of their image.
+//!
+//! Images that install `libedgetpu1-std` drift from the driver of the host. Instead, the
+//! host libedgetpu and its libusb are bind mounted read-only into the library directory of
+//! the container, and a `createContainer` hook runs the host `ldconfig` on the root
+//! filesystem so that the container links against them. On hosts without `ldconfig`, the
+//! libraries are mounted under their sonames in [`PRIVATE_LIBRARY_DIR`], which is prepended
+//! to `LD_LIBRARY_PATH`.
+//!
+//! A container keeps the runtime of its image with the [`LIBRARIES_ANNOTATION`] annotation
+//! set to `image`, and picks the clock frequency of the host runtime as described in
+//! [`frequency`].
+mod edgetpu_image;
+pub mod frequency;
 
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use libcontainer::oci_spec::runtime::{HookBuilder, Spec};
+
+pub use edgetpu_image::EdgeTPU;
+use frequency::{Frequency, FrequencyPolicy, FREQUENCY_ANNOTATION, FREQUENCY_ENV};
+
+use crate::dep::util::install_path_of;
+use crate::injection::{edits, InjectionError};
+
+/// Annotation choosing the [`LibrarySource`] of the container
+pub const LIBRARIES_ANNOTATION: &str = "edgetpu.coral.ai/libraries";
+/// File the Edge TPU runtime is installed as
+pub const LIBEDGETPU_FILE: &str = "libedgetpu.so.1.0";
+pub const LIBEDGETPU_SONAME: &str = "libedgetpu.so.1";
+pub const LIBUSB_SONAME: &str = "libusb-1.0.so.0";
+/// Directory of the container the libraries are mounted in when the host has no `ldconfig`
+pub const PRIVATE_LIBRARY_DIR: &str = "/usr/lib/edgetpu";
+
+const LDCONFIG_PATHS: [&str; 2] = ["/sbin/ldconfig", "/usr/sbin/ldconfig"];
+const LD_LIBRARY_PATH: &str = "LD_LIBRARY_PATH";
+
+/// Where the Edge TPU runtime of a container comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LibrarySource {
+    /// The runtime installed on the host
+    #[default]
+    Host,
+    /// The runtime installed in the image
+    Image,
+}
+
+impl LibrarySource {
+    /// Reads the source of the container from its annotations.
+    pub fn from_spec(spec: &Spec) -> Result<Self, InjectionError> {
+        spec.annotations()
+            .as_ref()
+            .and_then(|annotations| annotations.get(LIBRARIES_ANNOTATION))
+            .map_or(Ok(Self::default()), |value| value.parse())
+    }
+}
+
+impl FromStr for LibrarySource {
+    type Err = InjectionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "host" => Ok(Self::Host),
+            "image" => Ok(Self::Image),
+            other => Err(InjectionError::LibrarySource(other.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for LibrarySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Host => write!(f, "host"),
+            Self::Image => write!(f, "image"),
+        }
+    }
+}
+
+/// Locates the host Edge TPU runtime and builds the [`EdgeTPU`] edits giving it to a
+/// container.
+///
+/// # Example
+///
+/// ```no_run
+/// use edgetpu::image::HostLibraries;
+/// use libcontainer::oci_spec::runtime::Spec;
+///
+/// let mut spec = Spec::load("config.json").unwrap();
+/// if let Some(edgetpu) = HostLibraries::new().edits(&spec).unwrap() {
+///     edgetpu.apply(&mut spec);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct HostLibraries {
+    library_dir: PathBuf,
+    container_library_dir: PathBuf,
+    ldconfig: Option<PathBuf>,
+    frequency_policy: FrequencyPolicy,
+}
+
+impl Default for HostLibraries {
+    fn default() -> Self {
+        Self {
+            library_dir: install_path_of(),
+            container_library_dir: install_path_of(),
+            ldconfig: LDCONFIG_PATHS
+                .iter()
+                .map(PathBuf::from)
+                .find(|path| path.exists()),
+            frequency_policy: FrequencyPolicy::default(),
+        }
+    }
+}
+
+impl HostLibraries {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the host directory libedgetpu is installed in
+    pub fn with_library_dir<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.library_dir = path.into();
+        self
+    }
+
+    /// Sets the directory of the container the libraries are mounted in
+    pub fn with_container_library_dir<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.container_library_dir = path.into();
+        self
+    }
+
+    /// Sets the host `ldconfig` refreshing the ld cache of the containers
+    pub fn with_ldconfig<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.ldconfig = Some(path.into());
+        self
+    }
+
+    /// Mounts the libraries in [`PRIVATE_LIBRARY_DIR`] instead of refreshing the ld cache
+    pub fn without_ldconfig(mut self) -> Self {
+        self.ldconfig = None;
+        self
+    }
+
+    /// Sets the policy capping the frequency of the runtime
+    pub fn with_frequency_policy(mut self, policy: FrequencyPolicy) -> Self {
+        self.frequency_policy = policy;
+        self
+    }
+
+    pub fn library_dir(&self) -> &Path {
+        &self.library_dir
+    }
+
+    /// Returns the edits giving the host runtime to the container, `None` when libedgetpu is
+    /// not installed on the host.
+    pub fn edits(&self, spec: &Spec) -> Result<Option<EdgeTPU>, InjectionError> {
+        let Some((libedgetpu, frequency)) = self.libedgetpu(self.frequency(spec)?)? else {
+            return Ok(None);
+        };
+        let libusb = self.host_file(LIBUSB_SONAME)?;
+        if libusb.is_none() {
+            log::warn!(
+                "{LIBUSB_SONAME} was not found next to libedgetpu in {}, the container must provide it",
+                self.library_dir.display()
+            );
+        }
+
+        let rootfs = spec.root().as_ref().map(|root| root.path());
+        let edgetpu = match (&self.ldconfig, rootfs) {
+            (Some(ldconfig), Some(rootfs)) => {
+                // mounted under the file names, ldconfig links the sonames to them
+                let mut edgetpu = EdgeTPU::new();
+                for library in [Some(&libedgetpu), libusb.as_ref()].into_iter().flatten() {
+                    let name = library
+                        .file_name()
+                        .expect("canonical paths have a file name");
+                    edgetpu = edgetpu.with_mount(edits::readonly_bind(
+                        library,
+                        &self.container_library_dir.join(name),
+                    )?);
+                }
+                let hook = HookBuilder::default()
+                    .path(ldconfig)
+                    .args(vec![
+                        "ldconfig".to_owned(),
+                        "-r".to_owned(),
+                        rootfs.to_string_lossy().into_owned(),
+                        self.container_library_dir.to_string_lossy().into_owned(),
+                    ])
+                    .build()?;
+                edgetpu.with_hook(hook)
+            }
+            _ => {
+                let dir = Path::new(PRIVATE_LIBRARY_DIR);
+                let mut edgetpu = EdgeTPU::new().with_mount(edits::readonly_bind(
+                    &libedgetpu,
+                    &dir.join(LIBEDGETPU_SONAME),
+                )?);
+                if let Some(libusb) = &libusb {
+                    edgetpu =
+                        edgetpu.with_mount(edits::readonly_bind(libusb, &dir.join(LIBUSB_SONAME))?);
+                }
+                let search_path = match process_env(spec, LD_LIBRARY_PATH) {
+                    Some(previous) if previous.split(':').any(|dir| dir == PRIVATE_LIBRARY_DIR) => {
+                        previous.to_owned()
+                    }
+                    Some(previous) if !previous.is_empty() => {
+                        format!("{PRIVATE_LIBRARY_DIR}:{previous}")
+                    }
+                    _ => PRIVATE_LIBRARY_DIR.to_owned(),
+                };
+                edgetpu.with_env(LD_LIBRARY_PATH, search_path)
+            }
+        };
+
+        Ok(Some(match frequency {
+            Some(frequency) => edgetpu.with_env(FREQUENCY_ENV, frequency.to_string()),
+            None => edgetpu,
+        }))
+    }
+
+    /// The frequency of the runtime given to the container and whether the container asked
+    /// for it, `None` for the runtime installed as the host default.
+    fn frequency(&self, spec: &Spec) -> Result<Option<(Frequency, bool)>, InjectionError> {
+        let requested = Frequency::from_spec(spec)?;
+        let cap = self.frequency_policy.cap();
+        Ok(match requested {
+            Some(frequency) if frequency > cap => {
+                log::warn!(
+                    "{FREQUENCY_ANNOTATION}={frequency} is capped at {cap} by the node policy"
+                );
+                Some((cap, true))
+            }
+            Some(frequency) => Some((frequency, true)),
+            // the default runtime may be the max frequency build
+            None if cap < Frequency::Max => Some((cap, false)),
+            None => None,
+        })
+    }
+
+    /// The host runtime of the frequency, and the frequency unless it is the default runtime
+    #[allow(clippy::type_complexity)]
+    fn libedgetpu(
+        &self,
+        frequency: Option<(Frequency, bool)>,
+    ) -> Result<Option<(PathBuf, Option<Frequency>)>, InjectionError> {
+        let default = || -> Result<_, InjectionError> {
+            Ok(self.host_file(LIBEDGETPU_FILE)?.map(|path| (path, None)))
+        };
+        let Some((frequency, requested)) = frequency else {
+            return default();
+        };
+        let path = frequency
+            .variant_dir(&self.library_dir)
+            .join(LIBEDGETPU_FILE);
+        if path.exists() {
+            return match path.canonicalize() {
+                Ok(library) => Ok(Some((library, Some(frequency)))),
+                Err(source) => Err(InjectionError::Library { source, path }),
+            };
+        }
+        if requested {
+            return Err(InjectionError::FrequencyUnavailable {
+                frequency,
+                path: path.parent().unwrap_or(&path).to_owned(),
+            });
+        }
+        log::warn!(
+            "the {frequency} frequency runtime is not installed in {}, using the default one",
+            self.library_dir.display()
+        );
+        default()
+    }
+
+    /// The file a library of the host resolves to. Libraries of the base system may be in
+    /// `/lib` rather than `/usr/lib` on hosts without merged `/usr`.
+    fn host_file(&self, name: &str) -> Result<Option<PathBuf>, InjectionError> {
+        let mut candidates = vec![self.library_dir.join(name)];
+        if let Ok(relative) = self.library_dir.strip_prefix("/usr") {
+            candidates.push(Path::new("/").join(relative).join(name));
+        }
+
+        match candidates.into_iter().find(|path| path.exists()) {
+            Some(path) => path
+                .canonicalize()
+                .map(Some)
+                .map_err(|source| InjectionError::Library { source, path }),
+            None => Ok(None),
+        }
+    }
+}
+
+fn process_env<'a>(spec: &'a Spec, key: &str) -> Option<&'a str> {
+    let prefix = format!("{key}=");
+    spec.process()
+        .as_ref()?
+        .env()
+        .as_ref()?
+        .iter()
+        .find_map(|entry| entry.strip_prefix(&prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    use libcontainer::oci_spec::runtime::RootBuilder;
+
+    use super::*;
+
+    const CONTAINER_LIBRARY_DIR: &str = "/usr/lib/x86_64-linux-gnu";
+
+    fn host_libraries(libs: &Path) -> HostLibraries {
+        fs::write(libs.join(LIBEDGETPU_FILE), "").unwrap();
+        symlink(LIBEDGETPU_FILE, libs.join(LIBEDGETPU_SONAME)).unwrap();
+        fs::write(libs.join("libusb-1.0.so.0.3.0"), "").unwrap();
+        symlink("libusb-1.0.so.0.3.0", libs.join(LIBUSB_SONAME)).unwrap();
+        HostLibraries::new()
+            .with_library_dir(libs)
+            .with_container_library_dir(CONTAINER_LIBRARY_DIR)
+    }
+
+    fn mounts(edgetpu: &EdgeTPU) -> Vec<(PathBuf, PathBuf)> {
+        edgetpu
+            .mounts()
+            .iter()
+            .map(|mount| (mount.source().clone().unwrap(), mount.destination().clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_refresh_ld_cache() {
+        let libs = tempfile::tempdir().unwrap();
+        let libs_path = libs.path().canonicalize().unwrap();
+        let libraries = host_libraries(&libs_path).with_ldconfig("/sbin/ldconfig");
+        let mut spec = Spec::default();
+        spec.set_root(Some(
+            RootBuilder::default()
+                .path("/run/bundle/rootfs")
+                .build()
+                .unwrap(),
+        ));
+
+        let edgetpu = libraries.edits(&spec).unwrap().unwrap();
+        assert_eq!(
+            mounts(&edgetpu),
+            vec![
+                (
+                    libs_path.join(LIBEDGETPU_FILE),
+                    Path::new(CONTAINER_LIBRARY_DIR).join(LIBEDGETPU_FILE)
+                ),
+                (
+                    libs_path.join("libusb-1.0.so.0.3.0"),
+                    Path::new(CONTAINER_LIBRARY_DIR).join("libusb-1.0.so.0.3.0")
+                ),
+            ]
+        );
+        assert!(edgetpu.env().is_empty());
+        assert_eq!(edgetpu.hooks().len(), 1);
+        assert_eq!(
+            edgetpu.hooks()[0].args().as_ref().unwrap(),
+            &[
+                "ldconfig",
+                "-r",
+                "/run/bundle/rootfs",
+                CONTAINER_LIBRARY_DIR
+            ]
+        );
+
+        edgetpu.apply(&mut spec);
+        edgetpu.apply(&mut spec);
+        let hooks = spec.hooks().as_ref().unwrap();
+        assert_eq!(hooks.create_container().as_ref().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_private_library_dir_without_ldconfig() {
+        let libs = tempfile::tempdir().unwrap();
+        let libs_path = libs.path().canonicalize().unwrap();
+        let libraries = host_libraries(&libs_path).without_ldconfig();
+        let mut spec = Spec::default();
+        spec.process_mut()
+            .as_mut()
+            .unwrap()
+            .set_env(Some(vec!["LD_LIBRARY_PATH=/opt/lib".to_owned()]));
+
+        let edgetpu = libraries.edits(&spec).unwrap().unwrap();
+        let dir = Path::new(PRIVATE_LIBRARY_DIR);
+        assert_eq!(
+            mounts(&edgetpu),
+            vec![
+                (libs_path.join(LIBEDGETPU_FILE), dir.join(LIBEDGETPU_SONAME)),
+                (
+                    libs_path.join("libusb-1.0.so.0.3.0"),
+                    dir.join(LIBUSB_SONAME)
+                ),
+            ]
+        );
+        assert!(edgetpu.hooks().is_empty());
+
+        edgetpu.apply(&mut spec);
+        let env = spec.process().as_ref().unwrap().env().clone().unwrap();
+        assert!(env.contains(&"LD_LIBRARY_PATH=/usr/lib/edgetpu:/opt/lib".to_owned()));
+        assert!(!env.contains(&"LD_LIBRARY_PATH=/opt/lib".to_owned()));
+    }
+
+    #[test]
+    fn test_frequency() {
+        let libs = tempfile::tempdir().unwrap();
+        let libs_path = libs.path().canonicalize().unwrap();
+        let libraries = host_libraries(&libs_path).without_ldconfig();
+        let mut spec = Spec::default();
+        let libedgetpu = |edgetpu: &EdgeTPU| edgetpu.mounts()[0].source().clone().unwrap();
+        let request = |spec: &mut Spec, frequency: &str| {
+            spec.set_annotations(Some(HashMap::from([(
+                FREQUENCY_ANNOTATION.to_owned(),
+                frequency.to_owned(),
+            )])));
+        };
+
+        // only the default runtime is installed
+        request(&mut spec, "max");
+        assert!(matches!(
+            libraries.edits(&spec),
+            Err(InjectionError::FrequencyUnavailable {
+                frequency: Frequency::Max,
+                ..
+            })
+        ));
+
+        for frequency in [Frequency::Reduced, Frequency::Max] {
+            let dir = frequency.variant_dir(&libs_path);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(LIBEDGETPU_FILE), "").unwrap();
+        }
+        let edgetpu = libraries.edits(&spec).unwrap().unwrap();
+        assert_eq!(
+            libedgetpu(&edgetpu),
+            libs_path.join("edgetpu/direct").join(LIBEDGETPU_FILE)
+        );
+        assert!(edgetpu
+            .env()
+            .contains(&(FREQUENCY_ENV.to_owned(), "max".to_owned())));
+
+        // the node policy wins, also over the default runtime
+        let capped = libraries
+            .with_frequency_policy(FrequencyPolicy::new().with_max_frequency(Frequency::Reduced));
+        for annotations in [Some("max"), None] {
+            match annotations {
+                Some(frequency) => request(&mut spec, frequency),
+                None => {
+                    spec.set_annotations(None);
+                }
+            }
+            assert_eq!(
+                libedgetpu(&capped.edits(&spec).unwrap().unwrap()),
+                libs_path.join("edgetpu/throttled").join(LIBEDGETPU_FILE)
+            );
+        }
+
+        request(&mut spec, "turbo");
+        assert!(matches!(
+            capped.edits(&spec),
+            Err(InjectionError::Frequency(_))
+        ));
+    }
+
+    #[test]
+    fn test_no_host_runtime() {
+        let libs = tempfile::tempdir().unwrap();
+        let libraries = HostLibraries::new().with_library_dir(libs.path());
+        assert_eq!(libraries.edits(&Spec::default()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_library_source() {
+        let mut spec = Spec::default();
+        assert_eq!(
+            LibrarySource::from_spec(&spec).unwrap(),
+            LibrarySource::Host
+        );
+
+        for (value, expected) in [("image", Some(LibrarySource::Image)), ("bundle", None)] {
+            spec.set_annotations(Some(HashMap::from([(
+                LIBRARIES_ANNOTATION.to_owned(),
+                value.to_owned(),
+            )])));
+            assert_eq!(LibrarySource::from_spec(&spec).ok(), expected);
+        }
+    }
+}
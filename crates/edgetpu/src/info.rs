@@ -0,0 +1,356 @@
+//! What the host provides for the Edge TPUs: the devices, the kernel driver, the runtime
+//! library and the udev rules, as reported by `tpu-container-runtime info`.
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use libedgetpu::backend::EdgeTpuBackend;
+use libedgetpu::driver::EdgeTpuDeviceManager;
+use nix::unistd::{access, AccessFlags, Gid, Group, Uid, User};
+use serde::Serialize;
+
+use crate::dep::install::UDEV_RULES_PATH;
+use crate::dep::util::install_path_of;
+use crate::discovery::{DeviceType, Discovery, EdgeTpuDevice};
+use crate::image::frequency::Frequency;
+use crate::image::{LIBEDGETPU_FILE, LIBEDGETPU_SONAME};
+
+/// Kernel modules of the PCIe/M.2 accelerators
+pub const KERNEL_MODULES: [&str; 2] = ["gasket", "apex"];
+
+/// The Edge TPU part of the host report.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EdgeTpuInfo {
+    pub devices: Vec<DeviceInfo>,
+    /// Why the devices could not be listed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discovery_error: Option<String>,
+    pub modules: Vec<ModuleInfo>,
+    /// The default runtime library, `None` when it is not installed
+    pub library: Option<LibraryInfo>,
+    pub udev_rules: UdevRulesInfo,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfo {
+    pub name: String,
+    /// `pci` or `usb`
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// Device node
+    pub path: PathBuf,
+    pub serial: Option<String>,
+    /// PCI address or USB port path
+    pub bus: String,
+    /// The device node as the current user sees it, `None` when it does not exist
+    pub node: Option<NodeAccess>,
+}
+
+impl DeviceInfo {
+    pub fn new(device: &EdgeTpuDevice) -> Self {
+        Self {
+            name: device.name(),
+            kind: match device.device_type() {
+                DeviceType::ApexPCI => "pci",
+                DeviceType::ApexUSB => "usb",
+            },
+            path: device.dev_node.clone(),
+            serial: device.serial.clone(),
+            bus: device.bus_path.clone(),
+            node: node_access(&device.dev_node),
+        }
+    }
+}
+
+/// Permissions of a device node.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeAccess {
+    /// `ls -l` style mode, `crw-rw----`
+    pub mode: String,
+    pub owner: String,
+    pub group: String,
+    /// Whether the current user may open the node for reading
+    pub readable: bool,
+    /// Whether the current user may open the node for writing
+    pub writable: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModuleInfo {
+    pub name: &'static str,
+    pub loaded: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryInfo {
+    pub path: PathBuf,
+    /// Version reported by the runtime, `None` when it could not be loaded
+    pub version: Option<String>,
+    /// The build the default library is, `None` when it matches none of the installed builds
+    pub frequency: Option<Frequency>,
+    /// Builds containers can pick with the frequency annotation
+    pub variants: Vec<Frequency>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UdevRulesInfo {
+    pub path: PathBuf,
+    pub present: bool,
+}
+
+/// Collects the [`EdgeTpuInfo`] of the host.
+///
+/// # Example
+///
+/// ```no_run
+/// use edgetpu::info::HostInfo;
+///
+/// let info = HostInfo::new().collect();
+/// for device in &info.devices {
+///     println!("{} {}", device.name, device.path.display());
+/// }
+/// ```
+#[derive(Clone)]
+pub struct HostInfo {
+    discovery: Discovery,
+    library_dir: PathBuf,
+    udev_rules: PathBuf,
+    /// Backend reporting the runtime version, the libedgetpu singleton if unset
+    backend: Option<Arc<dyn EdgeTpuBackend>>,
+}
+
+impl Default for HostInfo {
+    fn default() -> Self {
+        Self {
+            discovery: Discovery::new(),
+            library_dir: install_path_of(),
+            udev_rules: PathBuf::from(UDEV_RULES_PATH),
+            backend: None,
+        }
+    }
+}
+
+impl HostInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how the devices and the kernel modules are found
+    pub fn with_discovery(mut self, discovery: Discovery) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    /// Sets the host directory libedgetpu is installed in
+    pub fn with_library_dir<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.library_dir = path.into();
+        self
+    }
+
+    /// Sets the path of the udev rules of the Edge TPUs
+    pub fn with_udev_rules<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.udev_rules = path.into();
+        self
+    }
+
+    /// Sets the backend reporting the runtime version
+    pub fn with_backend(mut self, backend: impl EdgeTpuBackend + 'static) -> Self {
+        self.backend = Some(Arc::new(backend));
+        self
+    }
+
+    /// Collects the report. Whatever cannot be read is reported as missing.
+    pub fn collect(&self) -> EdgeTpuInfo {
+        let (devices, discovery_error) = match self.discovery.discover() {
+            Ok(devices) => (devices.iter().map(DeviceInfo::new).collect(), None),
+            Err(err) => (Vec::new(), Some(err.to_string())),
+        };
+        let modules = KERNEL_MODULES
+            .into_iter()
+            .map(|name| ModuleInfo {
+                name,
+                loaded: self
+                    .discovery
+                    .sysfs_root()
+                    .join("module")
+                    .join(name)
+                    .exists(),
+            })
+            .collect();
+        EdgeTpuInfo {
+            devices,
+            discovery_error,
+            modules,
+            library: self.library(),
+            udev_rules: UdevRulesInfo {
+                path: self.udev_rules.clone(),
+                present: self.udev_rules.exists(),
+            },
+        }
+    }
+
+    fn library(&self) -> Option<LibraryInfo> {
+        let path = fs::canonicalize(self.library_dir.join(LIBEDGETPU_SONAME)).ok()?;
+        let content = fs::read(&path).ok();
+        let mut frequency = None;
+        let mut variants = Vec::new();
+        for variant in [Frequency::Reduced, Frequency::Max] {
+            let Ok(build) = fs::read(variant.variant_dir(&self.library_dir).join(LIBEDGETPU_FILE))
+            else {
+                continue;
+            };
+            if content.as_ref() == Some(&build) {
+                frequency = Some(variant);
+            }
+            variants.push(variant);
+        }
+        Some(LibraryInfo {
+            path,
+            version: self.runtime_version(),
+            frequency,
+            variants,
+        })
+    }
+
+    fn runtime_version(&self) -> Option<String> {
+        let version = match &self.backend {
+            Some(backend) => backend.runtime_version(),
+            None => EdgeTpuDeviceManager::get_singleton()?.runtime_version(),
+        };
+        version
+            .map_err(|err| log::debug!("failed to get the runtime version: {err}"))
+            .ok()
+    }
+}
+
+fn node_access(path: &Path) -> Option<NodeAccess> {
+    let metadata = fs::metadata(path).ok()?;
+    let owner = User::from_uid(Uid::from_raw(metadata.uid()))
+        .ok()
+        .flatten()
+        .map(|user| user.name)
+        .unwrap_or_else(|| metadata.uid().to_string());
+    let group = Group::from_gid(Gid::from_raw(metadata.gid()))
+        .ok()
+        .flatten()
+        .map(|group| group.name)
+        .unwrap_or_else(|| metadata.gid().to_string());
+    Some(NodeAccess {
+        mode: mode_string(&metadata),
+        owner,
+        group,
+        readable: access(path, AccessFlags::R_OK).is_ok(),
+        writable: access(path, AccessFlags::W_OK).is_ok(),
+    })
+}
+
+/// Formats the mode like `ls -l` does
+fn mode_string(metadata: &fs::Metadata) -> String {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_char_device() {
+        'c'
+    } else if file_type.is_block_device() {
+        'b'
+    } else if file_type.is_dir() {
+        'd'
+    } else {
+        '-'
+    };
+    let mode = metadata.mode();
+    let mut string = String::from(kind);
+    for (bit, flag) in "rwxrwxrwx".chars().enumerate() {
+        string.push(if mode & (0o400 >> bit) != 0 {
+            flag
+        } else {
+            '-'
+        });
+    }
+    string
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    use libedgetpu::backend::MockBackend;
+
+    use super::*;
+    use crate::discovery::fixture::{add_apex, add_usb, write};
+
+    #[test]
+    fn test_collect() {
+        let tmp = tempfile::tempdir().unwrap();
+        let sysfs = tmp.path().join("sys");
+        let dev = tmp.path().join("dev");
+        let libs = tmp.path().join("lib");
+        add_apex(&sysfs, 0, "0000:01:00.0");
+        add_usb(&sysfs, "2-1", ("18d1", "9302"), 2, 3, "f00d");
+        write(&sysfs, "module/gasket/refcnt", "1\n");
+        write(&dev, "apex_0", "");
+        fs::set_permissions(dev.join("apex_0"), fs::Permissions::from_mode(0o640)).unwrap();
+        for (variant, content) in [(Frequency::Reduced, "reduced"), (Frequency::Max, "max")] {
+            let dir = variant.variant_dir(&libs);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(LIBEDGETPU_FILE), content).unwrap();
+        }
+        fs::write(libs.join(LIBEDGETPU_FILE), "max").unwrap();
+        symlink(LIBEDGETPU_FILE, libs.join(LIBEDGETPU_SONAME)).unwrap();
+
+        let info = HostInfo::new()
+            .with_discovery(Discovery::new().with_sysfs_root(&sysfs).with_dev_root(&dev))
+            .with_library_dir(&libs)
+            .with_udev_rules(tmp.path().join("99-edgetpu-accelerator.rules"))
+            .with_backend(MockBackend::new().with_runtime_version("RuntimeVersion(14)"))
+            .collect();
+
+        let devices: Vec<_> = info
+            .devices
+            .iter()
+            .map(|d| (d.name.as_str(), d.kind, d.bus.as_str(), d.serial.as_deref()))
+            .collect();
+        assert_eq!(
+            devices,
+            vec![
+                ("apex_0", "pci", "0000:01:00.0", None),
+                ("usb-2-1", "usb", "2-1", Some("f00d")),
+            ]
+        );
+        let node = info.devices[0].node.as_ref().unwrap();
+        assert_eq!(node.mode, "-rw-r-----");
+        assert!(node.readable);
+        // the USB node was not created
+        assert_eq!(info.devices[1].node, None);
+
+        let modules: Vec<_> = info.modules.iter().map(|m| (m.name, m.loaded)).collect();
+        assert_eq!(modules, vec![("gasket", true), ("apex", false)]);
+
+        let library = info.library.unwrap();
+        assert_eq!(library.version.as_deref(), Some("RuntimeVersion(14)"));
+        assert_eq!(library.frequency, Some(Frequency::Max));
+        assert_eq!(library.variants, vec![Frequency::Reduced, Frequency::Max]);
+        assert!(!info.udev_rules.present);
+    }
+
+    #[test]
+    fn test_missing_library() {
+        let tmp = tempfile::tempdir().unwrap();
+        let info = HostInfo::new()
+            .with_discovery(Discovery::new().with_sysfs_root(tmp.path()))
+            .with_library_dir(tmp.path())
+            .with_backend(MockBackend::new())
+            .collect();
+        assert!(info.devices.is_empty());
+        assert_eq!(info.library, None);
+
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["modules"][0]["loaded"], false);
+        assert!(json.get("discoveryError").is_none());
+    }
+}
@@ -0,0 +1,193 @@
+//! Small helpers that add entries to an OCI spec without duplicating existing ones.
+use std::path::{Path, PathBuf};
+
+use libcontainer::oci_spec::runtime::{
+    Hook, Hooks, Linux, LinuxBuilder, LinuxDevice, LinuxDeviceBuilder, LinuxDeviceCgroup,
+    LinuxDeviceCgroupBuilder, LinuxDeviceType, LinuxResources, Mount, MountBuilder, Spec,
+};
+
+use super::InjectionError;
+use crate::discovery::EdgeTpuDevice;
+
+/// Mode of the device nodes created in the container. Access is restricted by the device
+/// cgroup, so every user of the container may open the nodes it was given.
+const DEVICE_FILE_MODE: u32 = 0o666;
+
+/// Path of a host device node inside the container, i.e. the same path under `/dev`
+/// whatever the host device root is.
+pub fn container_dev_path(device: &EdgeTpuDevice, dev_root: &Path) -> PathBuf {
+    match device.dev_node.strip_prefix(dev_root) {
+        Ok(relative) => Path::new("/dev").join(relative),
+        Err(_) => device.dev_node.clone(),
+    }
+}
+
+/// Adds the device node to `linux.devices`, replacing any entry with the same path.
+pub fn add_device(
+    spec: &mut Spec,
+    path: &Path,
+    major: i64,
+    minor: i64,
+) -> Result<(), InjectionError> {
+    let device = LinuxDeviceBuilder::default()
+        .path(path)
+        .typ(LinuxDeviceType::C)
+        .major(major)
+        .minor(minor)
+        .file_mode(DEVICE_FILE_MODE)
+        .uid(0u32)
+        .gid(0u32)
+        .build()?;
+
+    let devices: &mut Vec<LinuxDevice> =
+        linux_mut(spec)?.devices_mut().get_or_insert_with(Vec::new);
+    devices.retain(|d| d.path() != path);
+    devices.push(device);
+    Ok(())
+}
+
+/// Adds a rule allowing read, write and mknod of the character device to the device cgroup.
+pub fn allow_device(spec: &mut Spec, major: i64, minor: i64) -> Result<(), InjectionError> {
+    let rule = LinuxDeviceCgroupBuilder::default()
+        .allow(true)
+        .typ(LinuxDeviceType::C)
+        .major(major)
+        .minor(minor)
+        .access("rwm")
+        .build()?;
+
+    let resources = linux_mut(spec)?
+        .resources_mut()
+        .get_or_insert_with(LinuxResources::default);
+    let rules: &mut Vec<LinuxDeviceCgroup> = resources.devices_mut().get_or_insert_with(Vec::new);
+    if !rules.contains(&rule) {
+        rules.push(rule);
+    }
+    Ok(())
+}
+
+/// Adds a read-only bind mount of a host file, replacing any mount on the same destination.
+pub fn add_readonly_bind(
+    spec: &mut Spec,
+    source: &Path,
+    destination: &Path,
+) -> Result<(), InjectionError> {
+    add_mount(spec, readonly_bind(source, destination)?);
+    Ok(())
+}
+
+/// Read-only bind mount of a host file.
+pub fn readonly_bind(source: &Path, destination: &Path) -> Result<Mount, InjectionError> {
+    Ok(MountBuilder::default()
+        .destination(destination)
+        .typ("bind")
+        .source(source)
+        .options(vec![
+            "rbind".to_owned(),
+            "ro".to_owned(),
+            "nosuid".to_owned(),
+            "nodev".to_owned(),
+        ])
+        .build()?)
+}
+
+/// Adds the mount, replacing any mount on the same destination.
+pub fn add_mount(spec: &mut Spec, mount: Mount) {
+    let mounts: &mut Vec<Mount> = spec.mounts_mut().get_or_insert_with(Vec::new);
+    mounts.retain(|m| m.destination() != mount.destination());
+    mounts.push(mount);
+}
+
+/// Sets a variable of the process environment, replacing any previous value.
+pub fn set_env(spec: &mut Spec, key: &str, value: &str) {
+    let Some(process) = spec.process_mut() else {
+        return;
+    };
+    let prefix = format!("{key}=");
+    let env = process.env_mut().get_or_insert_with(Vec::new);
+    env.retain(|entry| !entry.starts_with(&prefix));
+    env.push(format!("{prefix}{value}"));
+}
+
+/// Adds a `createContainer` hook unless the same hook is already there.
+pub fn add_create_container_hook(spec: &mut Spec, hook: Hook) {
+    let hooks = spec.hooks_mut().get_or_insert_with(Hooks::default);
+    let list = hooks.create_container_mut().get_or_insert_with(Vec::new);
+    if !list.contains(&hook) {
+        list.push(hook);
+    }
+}
+
+fn linux_mut(spec: &mut Spec) -> Result<&mut Linux, InjectionError> {
+    if spec.linux().is_none() {
+        spec.set_linux(Some(LinuxBuilder::default().build()?));
+    }
+    Ok(spec.linux_mut().as_mut().expect("linux was just set"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_device_replaces_same_path() {
+        let mut spec = Spec::default();
+        add_device(&mut spec, Path::new("/dev/apex_0"), 120, 0).unwrap();
+        add_device(&mut spec, Path::new("/dev/apex_0"), 120, 5).unwrap();
+        add_device(&mut spec, Path::new("/dev/apex_1"), 120, 1).unwrap();
+
+        let devices = spec.linux().as_ref().unwrap().devices().clone().unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].path(), Path::new("/dev/apex_0"));
+        assert_eq!(devices[0].minor(), 5);
+        assert_eq!(devices[0].file_mode(), Some(DEVICE_FILE_MODE));
+    }
+
+    #[test]
+    fn test_allow_device_is_idempotent() {
+        let mut spec = Spec::default();
+        let before = spec
+            .linux()
+            .as_ref()
+            .and_then(|l| l.resources().as_ref())
+            .and_then(|r| r.devices().as_ref())
+            .map(|d| d.len())
+            .unwrap_or_default();
+
+        allow_device(&mut spec, 120, 0).unwrap();
+        allow_device(&mut spec, 120, 0).unwrap();
+
+        let rules = spec
+            .linux()
+            .as_ref()
+            .unwrap()
+            .resources()
+            .as_ref()
+            .unwrap()
+            .devices()
+            .clone()
+            .unwrap();
+        assert_eq!(rules.len(), before + 1);
+        let rule = rules.last().unwrap();
+        assert!(rule.allow());
+        assert_eq!((rule.major(), rule.minor()), (Some(120), Some(0)));
+        assert_eq!(rule.access().as_deref(), Some("rwm"));
+    }
+
+    #[test]
+    fn test_add_readonly_bind() {
+        let mut spec = Spec::default();
+        let lib = Path::new("/usr/lib/libedgetpu.so.1");
+        add_readonly_bind(&mut spec, lib, lib).unwrap();
+        add_readonly_bind(&mut spec, lib, lib).unwrap();
+
+        let mounts = spec.mounts().clone().unwrap();
+        let injected: Vec<_> = mounts.iter().filter(|m| m.destination() == lib).collect();
+        assert_eq!(injected.len(), 1);
+        assert!(injected[0]
+            .options()
+            .as_ref()
+            .unwrap()
+            .contains(&"ro".to_owned()));
+    }
+}
@@ -0,0 +1,383 @@
+//! Injection of Edge TPUs into the runtime spec of containers that ask for them.
+//!
+//! A container asks for accelerators with the [`DEVICES_ANNOTATION`] annotation or the
+//! [`VISIBLE_DEVICES_ENV`] environment variable. The injector resolves the request against
+//! the TPUs discovered on the host and rewrites the spec so that an unprivileged container
+//! gets exactly those devices: the device nodes, the matching device cgroup rules and
+//! read-only bind mounts of the host libedgetpu, see [`crate::image`].
+//!
+//! When the injector is given a [`LeaseLedger`], the TPUs are leased to the container for its
+//! whole lifetime, and a TPU leased to another container cannot be injected. The
+//! [`SHARED_ANNOTATION`] annotation asks for a shared lease instead. When it is given a
+//! [`HealthLedger`], it refuses the TPUs the health monitor quarantined.
+pub mod edits;
+mod request;
+
+use std::path::PathBuf;
+
+use libcontainer::lease::{LeaseError, LeaseLedger};
+use libcontainer::oci_spec::runtime::Spec;
+use libcontainer::oci_spec::OciSpecError;
+use libcontainer::spec_modifier::{SpecModifier, SpecModifierError};
+
+pub use request::{
+    DeviceRequest, DeviceSelector, DEVICES_ANNOTATION, SHARED_ANNOTATION, VISIBLE_DEVICES_ENV,
+};
+
+use crate::discovery::{Discovery, DiscoveryError, EdgeTpuDevice};
+use crate::health::{HealthError, HealthLedger};
+use crate::image::frequency::{Frequency, FREQUENCY_ANNOTATION};
+use crate::image::{HostLibraries, LibrarySource, LIBRARIES_ANNOTATION};
+
+/// Files of the host runtime library mounted by the CDI specs.
+pub const LIBEDGETPU_FILES: [&str; 2] = ["libedgetpu.so.1.0", "libedgetpu.so.1"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum InjectionError {
+    #[error("invalid Edge TPU selector {0:?}")]
+    InvalidSelector(String),
+    #[error("requested Edge TPU {0:?} was not found on this host")]
+    NotFound(String),
+    #[error(transparent)]
+    Discovery(#[from] DiscoveryError),
+    #[error(transparent)]
+    Lease(#[from] LeaseError),
+    #[error("Edge TPU {0} is quarantined after failing its health probes")]
+    Quarantined(String),
+    #[error(transparent)]
+    Health(#[from] HealthError),
+    #[error("failed to build spec entry")]
+    Spec(#[from] OciSpecError),
+    #[error("invalid {LIBRARIES_ANNOTATION} {0:?}, expected host or image")]
+    LibrarySource(String),
+    #[error("invalid {FREQUENCY_ANNOTATION} {0:?}, expected max or reduced")]
+    Frequency(String),
+    #[error("the {frequency} frequency runtime is not installed in {path:?}")]
+    FrequencyUnavailable { frequency: Frequency, path: PathBuf },
+    #[error("failed to resolve the host library {path:?}")]
+    Library {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+}
+
+/// Rewrites the spec of containers that request Edge TPUs.
+///
+/// # Example
+///
+/// ```no_run
+/// use edgetpu::injection::EdgeTpuInjector;
+/// use libcontainer::lease::LeaseLedger;
+/// use libcontainer::oci_spec::runtime::Spec;
+///
+/// let mut spec = Spec::load("config.json").unwrap();
+/// let injected = EdgeTpuInjector::new()
+///     .with_leases(LeaseLedger::new("/run/youki"), "74f1a4cb3801")
+///     .inject(&mut spec)
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct EdgeTpuInjector {
+    discovery: Discovery,
+    libraries: HostLibraries,
+    /// Ledger to lease the TPUs in, and the container they are leased to
+    lease: Option<(LeaseLedger, String)>,
+    /// Health of the TPUs, quarantined TPUs are not injected
+    health: Option<HealthLedger>,
+}
+
+impl EdgeTpuInjector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how Edge TPUs are discovered on the host
+    pub fn with_discovery(mut self, discovery: Discovery) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    /// Sets the host directory libedgetpu is installed in
+    pub fn with_library_dir<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.libraries = self.libraries.with_library_dir(path);
+        self
+    }
+
+    /// Sets how the host runtime is given to the containers
+    pub fn with_host_libraries(mut self, libraries: HostLibraries) -> Self {
+        self.libraries = libraries;
+        self
+    }
+
+    /// Leases the injected TPUs to the container in the ledger
+    pub fn with_leases<S: Into<String>>(mut self, ledger: LeaseLedger, container_id: S) -> Self {
+        self.lease = Some((ledger, container_id.into()));
+        self
+    }
+
+    /// Refuses the TPUs quarantined in the health ledger
+    pub fn with_health(mut self, ledger: HealthLedger) -> Self {
+        self.health = Some(ledger);
+        self
+    }
+
+    pub fn discovery(&self) -> &Discovery {
+        &self.discovery
+    }
+
+    /// Injects the requested Edge TPUs into the spec and returns them. Specs that do not
+    /// request any accelerator are left untouched.
+    pub fn inject(&self, spec: &mut Spec) -> Result<Vec<EdgeTpuDevice>, InjectionError> {
+        let request = match DeviceRequest::from_spec(spec)? {
+            Some(request) if !request.is_empty() => request,
+            _ => return Ok(Vec::new()),
+        };
+
+        let devices = request.resolve(&self.discovery.discover()?)?;
+        if devices.is_empty() {
+            log::warn!("Edge TPUs were requested, but none was found on this host");
+            return Ok(devices);
+        }
+
+        if let Some(health) = &self.health {
+            let quarantined = health.quarantined()?;
+            if let Some(device) = devices.iter().find(|d| quarantined.contains(&d.name())) {
+                return Err(InjectionError::Quarantined(device.name()));
+            }
+        }
+
+        if let Some((ledger, container_id)) = &self.lease {
+            let names: Vec<String> = devices.iter().map(EdgeTpuDevice::name).collect();
+            let names: Vec<&str> = names.iter().map(String::as_str).collect();
+            ledger.acquire(container_id, &names, DeviceRequest::shared_access(spec))?;
+        }
+
+        for device in &devices {
+            let path = edits::container_dev_path(device, self.discovery.dev_root());
+            log::debug!("injecting Edge TPU {} as {}", device.name(), path.display());
+            edits::add_device(spec, &path, device.major.into(), device.minor.into())?;
+            edits::allow_device(spec, device.major.into(), device.minor.into())?;
+        }
+
+        self.inject_library(spec)?;
+
+        Ok(devices)
+    }
+
+    fn inject_library(&self, spec: &mut Spec) -> Result<(), InjectionError> {
+        if LibrarySource::from_spec(spec)? == LibrarySource::Image {
+            return Ok(());
+        }
+
+        match self.libraries.edits(spec)? {
+            Some(edgetpu) => edgetpu.apply(spec),
+            None => log::warn!(
+                "libedgetpu was not found in {}, the container must provide its own",
+                self.libraries.library_dir().display()
+            ),
+        }
+        Ok(())
+    }
+}
+
+impl SpecModifier for EdgeTpuInjector {
+    fn name(&self) -> &str {
+        "edgetpu"
+    }
+
+    fn modify(&self, spec: &mut Spec) -> Result<(), SpecModifierError> {
+        self.inject(spec)
+            .map(|_| ())
+            .map_err(|err| SpecModifierError::Modify {
+                name: self.name().to_owned(),
+                source: Box::new(err),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+
+    use libedgetpu::backend::MockBackend;
+
+    use super::*;
+    use crate::discovery::fixture::{add_apex, add_usb, write};
+    use crate::health::HealthMonitor;
+
+    fn request(spec: &mut Spec, value: &str) {
+        spec.set_annotations(Some(HashMap::from([(
+            DEVICES_ANNOTATION.to_owned(),
+            value.to_owned(),
+        )])));
+    }
+
+    fn injector(sysfs: &Path, libs: &Path) -> EdgeTpuInjector {
+        EdgeTpuInjector::new()
+            .with_discovery(
+                Discovery::new()
+                    .with_sysfs_root(sysfs)
+                    .with_dev_root("/dev"),
+            )
+            .with_host_libraries(
+                HostLibraries::new()
+                    .with_library_dir(libs)
+                    .with_container_library_dir("/usr/lib/x86_64-linux-gnu")
+                    .without_ldconfig(),
+            )
+    }
+
+    #[test]
+    fn test_no_request_leaves_spec_untouched() {
+        let tmp = tempfile::tempdir().unwrap();
+        add_apex(tmp.path(), 0, "0000:01:00.0");
+        let mut spec = Spec::default();
+        let original = spec.clone();
+
+        let injected = injector(tmp.path(), tmp.path()).inject(&mut spec).unwrap();
+        assert!(injected.is_empty());
+        assert_eq!(spec, original);
+    }
+
+    #[test]
+    fn test_inject_requested_devices() {
+        let sysfs = tempfile::tempdir().unwrap();
+        add_apex(sysfs.path(), 0, "0000:01:00.0");
+        add_apex(sysfs.path(), 1, "0000:02:00.0");
+        add_usb(sysfs.path(), "2-1", ("18d1", "9302"), 2, 5, "f00d");
+        let libs = tempfile::tempdir().unwrap();
+        fs::write(libs.path().join("libedgetpu.so.1.0"), "").unwrap();
+
+        let mut spec = Spec::default();
+        request(&mut spec, "1,usb:0");
+        let injected = injector(sysfs.path(), libs.path())
+            .inject(&mut spec)
+            .unwrap();
+        assert_eq!(injected.len(), 2);
+
+        let linux = spec.linux().as_ref().unwrap();
+        let paths: Vec<_> = linux
+            .devices()
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|d| d.path().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(paths, vec!["/dev/apex_1", "/dev/bus/usb/002/005"]);
+
+        let rules = linux
+            .resources()
+            .as_ref()
+            .unwrap()
+            .devices()
+            .clone()
+            .unwrap();
+        assert!(rules
+            .iter()
+            .any(|r| r.allow() && r.major() == Some(120) && r.minor() == Some(1)));
+        assert!(rules
+            .iter()
+            .any(|r| r.allow() && r.major() == Some(189) && r.minor() == Some(132)));
+        assert!(!rules
+            .iter()
+            .any(|r| r.major() == Some(120) && r.minor() == Some(0)));
+
+        let mounts = spec.mounts().as_ref().unwrap();
+        assert!(mounts
+            .iter()
+            .any(|m| m.destination() == Path::new("/usr/lib/edgetpu/libedgetpu.so.1")));
+
+        // the image keeps its own runtime
+        let mut spec = Spec::default();
+        request(&mut spec, "0");
+        spec.annotations_mut()
+            .as_mut()
+            .unwrap()
+            .insert(LIBRARIES_ANNOTATION.to_owned(), "image".to_owned());
+        let mounts_before = spec.mounts().clone();
+        injector(sysfs.path(), libs.path())
+            .inject(&mut spec)
+            .unwrap();
+        assert_eq!(spec.mounts(), &mounts_before);
+    }
+
+    #[test]
+    fn test_missing_device_fails() {
+        let tmp = tempfile::tempdir().unwrap();
+        add_apex(tmp.path(), 0, "0000:01:00.0");
+        let mut spec = Spec::default();
+        request(&mut spec, "usb:0");
+
+        let result = injector(tmp.path(), tmp.path()).modify(&mut spec);
+        assert!(matches!(result, Err(SpecModifierError::Modify { .. })));
+    }
+
+    #[test]
+    fn test_leased_devices() {
+        let sysfs = tempfile::tempdir().unwrap();
+        add_apex(sysfs.path(), 0, "0000:01:00.0");
+        let root = tempfile::tempdir().unwrap();
+        for id in ["first", "second", "third"] {
+            fs::create_dir(root.path().join(id)).unwrap();
+        }
+        let ledger = LeaseLedger::new(root.path());
+        let inject = |id: &str, shared: bool| {
+            let mut spec = Spec::default();
+            request(&mut spec, "apex_0");
+            if shared {
+                spec.annotations_mut()
+                    .as_mut()
+                    .unwrap()
+                    .insert(SHARED_ANNOTATION.to_owned(), "true".to_owned());
+            }
+            injector(sysfs.path(), sysfs.path())
+                .with_leases(ledger.clone(), id)
+                .inject(&mut spec)
+        };
+
+        inject("first", false).unwrap();
+        assert!(matches!(
+            inject("second", false),
+            Err(InjectionError::Lease(LeaseError::Taken { ref holder, .. })) if holder == "first"
+        ));
+
+        ledger.release("first").unwrap();
+        inject("second", true).unwrap();
+        inject("third", true).unwrap();
+        assert_eq!(ledger.leases().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_quarantined_devices() {
+        let sysfs = tempfile::tempdir().unwrap();
+        add_apex(sysfs.path(), 0, "0000:01:00.0");
+        add_apex(sysfs.path(), 1, "0000:02:00.0");
+        write(sysfs.path(), "class/apex/apex_1/status", "DEAD\n");
+        let root = tempfile::tempdir().unwrap();
+        let ledger = HealthLedger::new(root.path());
+        HealthMonitor::new(ledger.clone())
+            .with_discovery(Discovery::new().with_sysfs_root(sysfs.path()))
+            .with_backend(MockBackend::new().with_pci_devices(2))
+            .with_quarantine_after(1)
+            .probe_all()
+            .unwrap();
+
+        let inject = |selector: &str| {
+            let mut spec = Spec::default();
+            request(&mut spec, selector);
+            injector(sysfs.path(), sysfs.path())
+                .with_health(ledger.clone())
+                .inject(&mut spec)
+        };
+        assert_eq!(inject("0").unwrap().len(), 1);
+        assert!(matches!(
+            inject("all"),
+            Err(InjectionError::Quarantined(ref device)) if device == "apex_1"
+        ));
+
+        ledger.clear("apex_1").unwrap();
+        assert_eq!(inject("all").unwrap().len(), 2);
+    }
+}
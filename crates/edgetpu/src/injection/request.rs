@@ -0,0 +1,290 @@
+use std::fmt;
+use std::str::FromStr;
+
+use libcontainer::oci_spec::runtime::Spec;
+
+use super::InjectionError;
+use crate::discovery::{DeviceType, EdgeTpuDevice};
+
+/// Environment variable of the container process that requests Edge TPUs.
+pub const VISIBLE_DEVICES_ENV: &str = "EDGETPU_VISIBLE_DEVICES";
+/// Annotation that requests Edge TPUs. Takes precedence over [`VISIBLE_DEVICES_ENV`].
+pub const DEVICES_ANNOTATION: &str = "edgetpu.coral.ai/devices";
+/// Annotation that opts into sharing the requested Edge TPUs with other containers when set
+/// to `true`. By default a container gets exclusive access to its TPUs.
+pub const SHARED_ANNOTATION: &str = "edgetpu.coral.ai/shared";
+
+/// One entry of a device request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// Every Edge TPU on the host
+    All,
+    /// The n-th Edge TPU in discovery order
+    Index(usize),
+    /// The n-th PCIe/M.2 Edge TPU
+    Pci(usize),
+    /// The n-th USB Edge TPU
+    Usb(usize),
+    /// A device name (`apex_0`, `usb-2-1`) or a serial number
+    Name(String),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = InjectionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InjectionError::InvalidSelector(s.to_owned());
+        let selector = match s.split_once(':') {
+            Some(("pci", index)) => Self::Pci(index.parse().map_err(|_| invalid())?),
+            Some(("usb", index)) => Self::Usb(index.parse().map_err(|_| invalid())?),
+            Some(_) => return Err(invalid()),
+            None if s == "all" => Self::All,
+            None if s.chars().all(|c| c.is_ascii_digit()) => {
+                Self::Index(s.parse().map_err(|_| invalid())?)
+            }
+            None => Self::Name(s.to_owned()),
+        };
+        Ok(selector)
+    }
+}
+
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::All => write!(f, "all"),
+            Self::Index(index) => write!(f, "{index}"),
+            Self::Pci(index) => write!(f, "pci:{index}"),
+            Self::Usb(index) => write!(f, "usb:{index}"),
+            Self::Name(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// The Edge TPUs a container asked for.
+///
+/// A request is a comma separated list of selectors: `all`, an index (`0,1`), an index among
+/// one kind of device (`pci:0`, `usb:0`), or a device name or serial number. `none` and
+/// `void` request no device at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceRequest {
+    selectors: Vec<DeviceSelector>,
+}
+
+impl FromStr for DeviceRequest {
+    type Err = InjectionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() || s == "none" || s == "void" {
+            return Ok(Self {
+                selectors: Vec::new(),
+            });
+        }
+
+        let selectors = s
+            .split(',')
+            .map(|selector| selector.trim().parse())
+            .collect::<Result<_, _>>()?;
+        Ok(Self { selectors })
+    }
+}
+
+impl DeviceRequest {
+    /// Reads the request from the annotations or the process environment of the spec.
+    /// Returns `None` when the container did not ask for Edge TPUs.
+    pub fn from_spec(spec: &Spec) -> Result<Option<Self>, InjectionError> {
+        if let Some(value) = spec
+            .annotations()
+            .as_ref()
+            .and_then(|annotations| annotations.get(DEVICES_ANNOTATION))
+        {
+            return value.parse().map(Some);
+        }
+
+        let prefix = format!("{VISIBLE_DEVICES_ENV}=");
+        let value = spec
+            .process()
+            .as_ref()
+            .and_then(|process| process.env().as_ref())
+            .and_then(|env| env.iter().rev().find_map(|e| e.strip_prefix(&prefix)));
+        value.map(str::parse).transpose()
+    }
+
+    /// Whether the container accepts to share its Edge TPUs with other containers.
+    pub fn shared_access(spec: &Spec) -> bool {
+        spec.annotations()
+            .as_ref()
+            .and_then(|annotations| annotations.get(SHARED_ANNOTATION))
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"))
+    }
+
+    pub fn selectors(&self) -> &[DeviceSelector] {
+        &self.selectors
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.selectors.is_empty()
+    }
+
+    /// Picks the requested devices out of the discovered ones, in request order and
+    /// without duplicates.
+    pub fn resolve(&self, devices: &[EdgeTpuDevice]) -> Result<Vec<EdgeTpuDevice>, InjectionError> {
+        let mut selected: Vec<&EdgeTpuDevice> = Vec::new();
+        for selector in &self.selectors {
+            let matched: Vec<&EdgeTpuDevice> = match selector {
+                DeviceSelector::All => devices.iter().collect(),
+                DeviceSelector::Index(index) => devices.get(*index).into_iter().collect(),
+                DeviceSelector::Pci(index) => nth_of_type(devices, DeviceType::ApexPCI, *index),
+                DeviceSelector::Usb(index) => nth_of_type(devices, DeviceType::ApexUSB, *index),
+                DeviceSelector::Name(name) => devices
+                    .iter()
+                    .filter(|d| d.name() == *name || d.serial.as_deref() == Some(name.as_str()))
+                    .collect(),
+            };
+
+            if matched.is_empty() && *selector != DeviceSelector::All {
+                return Err(InjectionError::NotFound(selector.to_string()));
+            }
+
+            for device in matched {
+                if !selected.iter().any(|s| s.dev_node == device.dev_node) {
+                    selected.push(device);
+                }
+            }
+        }
+
+        Ok(selected.into_iter().cloned().collect())
+    }
+}
+
+fn nth_of_type(
+    devices: &[EdgeTpuDevice],
+    device_type: DeviceType,
+    index: usize,
+) -> Vec<&EdgeTpuDevice> {
+    devices
+        .iter()
+        .filter(|d| d.device_type() == device_type)
+        .nth(index)
+        .into_iter()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use libcontainer::oci_spec::runtime::ProcessBuilder;
+
+    use super::*;
+    use crate::discovery::DeviceRecord;
+
+    fn device(device_type: DeviceType, node: &str, serial: Option<&str>) -> EdgeTpuDevice {
+        EdgeTpuDevice {
+            record: DeviceRecord {
+                device_type,
+                path: node.to_owned(),
+            },
+            major: 120,
+            minor: 0,
+            dev_node: PathBuf::from(node),
+            bus_path: node.rsplit('/').next().unwrap().to_owned(),
+            serial: serial.map(str::to_owned),
+            usb_id: None,
+        }
+    }
+
+    fn devices() -> Vec<EdgeTpuDevice> {
+        vec![
+            device(DeviceType::ApexPCI, "/dev/apex_0", None),
+            device(DeviceType::ApexPCI, "/dev/apex_1", None),
+            device(DeviceType::ApexUSB, "/dev/bus/usb/002/003", Some("f00d")),
+        ]
+    }
+
+    fn nodes(devices: &[EdgeTpuDevice]) -> Vec<String> {
+        devices
+            .iter()
+            .map(|d| d.dev_node.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_selectors() {
+        let request: DeviceRequest = "all, 1,pci:0,usb:2,apex_3".parse().unwrap();
+        assert_eq!(
+            request.selectors(),
+            &[
+                DeviceSelector::All,
+                DeviceSelector::Index(1),
+                DeviceSelector::Pci(0),
+                DeviceSelector::Usb(2),
+                DeviceSelector::Name("apex_3".to_owned()),
+            ]
+        );
+
+        assert!("none".parse::<DeviceRequest>().unwrap().is_empty());
+        assert!("void".parse::<DeviceRequest>().unwrap().is_empty());
+        assert!("".parse::<DeviceRequest>().unwrap().is_empty());
+        assert!("usb:x".parse::<DeviceRequest>().is_err());
+        assert!("gpu:0".parse::<DeviceRequest>().is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let devices = devices();
+        let resolve = |request: &str| {
+            request
+                .parse::<DeviceRequest>()
+                .unwrap()
+                .resolve(&devices)
+                .map(|d| nodes(&d))
+        };
+
+        assert_eq!(resolve("all").unwrap().len(), 3);
+        assert_eq!(resolve("1").unwrap(), vec!["/dev/apex_1"]);
+        assert_eq!(resolve("usb:0").unwrap(), vec!["/dev/bus/usb/002/003"]);
+        assert_eq!(resolve("f00d").unwrap(), vec!["/dev/bus/usb/002/003"]);
+        assert_eq!(
+            resolve("pci:1,apex_0,1").unwrap(),
+            vec!["/dev/apex_1", "/dev/apex_0"]
+        );
+        assert!(matches!(resolve("usb:1"), Err(InjectionError::NotFound(s)) if s == "usb:1"));
+        assert!(matches!(resolve("7"), Err(InjectionError::NotFound(_))));
+        // `all` on a host without TPUs is not an error, the container simply gets none
+        assert!(resolve("all").is_ok());
+        assert!("all"
+            .parse::<DeviceRequest>()
+            .unwrap()
+            .resolve(&[])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_request_from_spec() {
+        let mut spec = Spec::default();
+        assert_eq!(DeviceRequest::from_spec(&spec).unwrap(), None);
+
+        let process = ProcessBuilder::default()
+            .env(vec![
+                "PATH=/bin".to_owned(),
+                format!("{VISIBLE_DEVICES_ENV}=0"),
+                format!("{VISIBLE_DEVICES_ENV}=usb:0"),
+            ])
+            .build()
+            .unwrap();
+        spec.set_process(Some(process));
+        let request = DeviceRequest::from_spec(&spec).unwrap().unwrap();
+        assert_eq!(request.selectors(), &[DeviceSelector::Usb(0)]);
+
+        // the annotation wins over the environment
+        spec.set_annotations(Some(HashMap::from([(
+            DEVICES_ANNOTATION.to_owned(),
+            "all".to_owned(),
+        )])));
+        let request = DeviceRequest::from_spec(&spec).unwrap().unwrap();
+        assert_eq!(request.selectors(), &[DeviceSelector::All]);
+    }
+}
@@ -1,3 +1,14 @@
 pub mod dep;
 pub mod cdi;
+pub mod discovery;
+pub mod events;
+pub mod health;
+pub mod holders;
 pub mod image;
+pub mod info;
+pub mod injection;
+pub mod metrics;
+pub mod model;
+pub mod reset;
+pub mod thermal;
+pub mod uevent;
@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use libcontainer::lease::LeaseLedger;
+
+use super::{Collector, MetricFamily};
+use crate::discovery::Discovery;
+use crate::events::EventLog;
+use crate::health::{HealthLedger, HealthStatus};
+use crate::info::DeviceInfo;
+use crate::reset::RESET_EVENT;
+use crate::thermal::ApexTelemetry;
+use crate::uevent::REENUMERATION_EVENT;
+
+const STATUSES: [HealthStatus; 3] = [
+    HealthStatus::Healthy,
+    HealthStatus::Failing,
+    HealthStatus::Quarantined,
+];
+
+/// Collects the metrics of the Edge TPUs of the host, labelled by device name (`apex_0`,
+/// `usb-2-1.4`):
+///
+/// | Metric | Type | Labels | Description |
+/// |---|---|---|---|
+/// | `tpu_runtime_device_info` | gauge | `device`, `type`, `path`, `bus`, `serial` | Always 1 |
+/// | `tpu_runtime_device_temperature_celsius` | gauge | `device` | Temperature of an apex |
+/// | `tpu_runtime_device_trip_point_celsius` | gauge | `device`, `trip` | Throttling temperatures of an apex |
+/// | `tpu_runtime_device_health_status` | gauge | `device`, `status` | 1 for the current status of a probed device, 0 for the others |
+/// | `tpu_runtime_device_health_failures` | gauge | `device` | Probes failed in a row |
+/// | `tpu_runtime_device_holder_info` | gauge | `device`, `container`, `shared` | 1 for each container leasing the device |
+/// | `tpu_runtime_device_reenumerations_total` | counter | `device` | Resets and re-enumerations recorded in the event log |
+///
+/// # Example
+///
+/// ```no_run
+/// use edgetpu::metrics::{DeviceCollector, MetricsServer};
+///
+/// let mut server = MetricsServer::bind_tcp("127.0.0.1:9400")
+///     .unwrap()
+///     .with_collector(DeviceCollector::new("/run/youki"));
+/// server.serve().unwrap();
+/// ```
+pub struct DeviceCollector {
+    discovery: Discovery,
+    health: HealthLedger,
+    leases: LeaseLedger,
+    events: EventLog,
+    /// End of the event log read so far
+    offset: u64,
+    reenumerations: BTreeMap<String, u64>,
+}
+
+impl DeviceCollector {
+    /// Creates a collector of the devices leased from the runtime root.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        let root = root.into();
+        Self {
+            discovery: Discovery::new(),
+            health: HealthLedger::new(&root),
+            leases: LeaseLedger::new(&root),
+            events: EventLog::new(&root),
+            offset: 0,
+            reenumerations: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_discovery(mut self, discovery: Discovery) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    /// Counts the resets and re-enumerations recorded since the last scrape
+    fn count_reenumerations(&mut self) {
+        match self.events.read_since(self.offset) {
+            Ok((events, offset)) => {
+                self.offset = offset;
+                for event in events {
+                    if event.kind == RESET_EVENT || event.kind == REENUMERATION_EVENT {
+                        *self.reenumerations.entry(event.id).or_default() += 1;
+                    }
+                }
+            }
+            Err(err) => log::warn!("failed to read the Edge TPU events: {err}"),
+        }
+    }
+}
+
+impl Collector for DeviceCollector {
+    fn collect(&mut self) -> Vec<MetricFamily> {
+        let mut info = MetricFamily::gauge("tpu_runtime_device_info", "Edge TPUs of the host");
+        let mut temperature = MetricFamily::gauge(
+            "tpu_runtime_device_temperature_celsius",
+            "Temperature of the Edge TPU",
+        );
+        let mut trip_points = MetricFamily::gauge(
+            "tpu_runtime_device_trip_point_celsius",
+            "Temperatures from which the driver throttles the Edge TPU",
+        );
+        let mut health_status = MetricFamily::gauge(
+            "tpu_runtime_device_health_status",
+            "Health status of the Edge TPU",
+        );
+        let mut health_failures = MetricFamily::gauge(
+            "tpu_runtime_device_health_failures",
+            "Health probes of the Edge TPU failed in a row",
+        );
+        let mut holders = MetricFamily::gauge(
+            "tpu_runtime_device_holder_info",
+            "Containers leasing the Edge TPU",
+        );
+        let mut reenumerations = MetricFamily::counter(
+            "tpu_runtime_device_reenumerations",
+            "Resets and re-enumerations of the Edge TPU",
+        );
+
+        let devices = self.discovery.discover().unwrap_or_else(|err| {
+            log::warn!("failed to discover the Edge TPUs: {err}");
+            Vec::new()
+        });
+        for device in &devices {
+            let device_info = DeviceInfo::new(device);
+            let name = device_info.name.clone();
+            info.push(
+                vec![
+                    ("device", name.clone()),
+                    ("type", device_info.kind.to_owned()),
+                    ("path", device_info.path.display().to_string()),
+                    ("bus", device_info.bus),
+                    ("serial", device_info.serial.unwrap_or_default()),
+                ],
+                1.0,
+            );
+            if let Some(telemetry) = ApexTelemetry::read(self.discovery.sysfs_root(), &name) {
+                temperature.push(vec![("device", name.clone())], telemetry.temperature);
+                for (trip, celsius) in telemetry.trip_points.iter().enumerate() {
+                    trip_points.push(
+                        vec![("device", name.clone()), ("trip", trip.to_string())],
+                        *celsius,
+                    );
+                }
+            }
+            self.reenumerations.entry(name).or_default();
+        }
+
+        let health = self.health.devices().unwrap_or_else(|err| {
+            log::warn!("failed to read the health of the Edge TPUs: {err}");
+            BTreeMap::new()
+        });
+        for (device, health) in health {
+            for status in STATUSES {
+                health_status.push(
+                    vec![("device", device.clone()), ("status", status.to_string())],
+                    if health.status == status { 1.0 } else { 0.0 },
+                );
+            }
+            health_failures.push(vec![("device", device)], health.failures as f64);
+        }
+
+        match self.leases.leases() {
+            Ok(leases) => {
+                for lease in leases {
+                    holders.push(
+                        vec![
+                            ("device", lease.device),
+                            ("container", lease.container_id),
+                            ("shared", lease.shared.to_string()),
+                        ],
+                        1.0,
+                    );
+                }
+            }
+            Err(err) => log::warn!("failed to read the device leases: {err}"),
+        }
+
+        self.count_reenumerations();
+        for (device, count) in &self.reenumerations {
+            reenumerations.push(vec![("device", device.clone())], *count as f64);
+        }
+
+        vec![
+            info,
+            temperature,
+            trip_points,
+            health_status,
+            health_failures,
+            holders,
+            reenumerations,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::discovery::fixture::{add_apex, add_usb};
+    use crate::events::Event;
+    use crate::metrics::{encode, Format};
+    use crate::thermal::fixture::set_temperature;
+
+    #[test]
+    fn test_collect() {
+        let sysfs = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        add_apex(sysfs.path(), 0, "0000:01:00.0");
+        set_temperature(sysfs.path(), 0, 61500);
+        add_usb(sysfs.path(), "2-1", ("18d1", "9302"), 2, 5, "");
+        fs::write(
+            root.path().join("device-health.json"),
+            r#"{"devices":{"apex_0":{"status":"failing","failures":2,"successes":0,"lastProbe":0}}}"#,
+        )
+        .unwrap();
+        fs::create_dir(root.path().join("camera")).unwrap();
+        LeaseLedger::new(root.path())
+            .acquire("camera", &["apex_0"], false)
+            .unwrap();
+        let log = EventLog::new(root.path());
+        let reset = Event::new(RESET_EVENT, "apex_0", serde_json::json!({}));
+        log.emit(&reset).unwrap();
+
+        let mut collector = DeviceCollector::new(root.path())
+            .with_discovery(Discovery::new().with_sysfs_root(sysfs.path()));
+        let text = encode(&collector.collect(), Format::Prometheus);
+        for line in [
+            r#"tpu_runtime_device_info{device="apex_0",type="pci",path="/dev/apex_0",bus="0000:01:00.0",serial=""} 1"#,
+            r#"tpu_runtime_device_info{device="usb-2-1",type="usb",path="/dev/bus/usb/002/005",bus="2-1",serial=""} 1"#,
+            r#"tpu_runtime_device_temperature_celsius{device="apex_0"} 61.5"#,
+            r#"tpu_runtime_device_health_status{device="apex_0",status="healthy"} 0"#,
+            r#"tpu_runtime_device_health_status{device="apex_0",status="failing"} 1"#,
+            r#"tpu_runtime_device_health_failures{device="apex_0"} 2"#,
+            r#"tpu_runtime_device_holder_info{device="apex_0",container="camera",shared="false"} 1"#,
+            r#"tpu_runtime_device_reenumerations_total{device="apex_0"} 1"#,
+            r#"tpu_runtime_device_reenumerations_total{device="usb-2-1"} 0"#,
+        ] {
+            assert!(text.contains(&format!("{line}\n")), "{line} not in\n{text}");
+        }
+
+        // only the events recorded since the previous scrape are counted
+        log.emit(&Event::new(
+            REENUMERATION_EVENT,
+            "usb-2-1",
+            serde_json::json!({}),
+        ))
+        .unwrap();
+        let text = encode(&collector.collect(), Format::Prometheus);
+        assert!(text.contains("tpu_runtime_device_reenumerations_total{device=\"apex_0\"} 1\n"));
+        assert!(text.contains("tpu_runtime_device_reenumerations_total{device=\"usb-2-1\"} 1\n"));
+    }
+}
@@ -0,0 +1,190 @@
+//! Prometheus metrics of the containers and the Edge TPUs of a host.
+//!
+//! [`MetricsServer`] answers `GET /metrics` with the metrics of its [`Collector`]s, in the
+//! Prometheus text format, or in the OpenMetrics one when the scraper asks for it. Every
+//! metric is prefixed with `tpu_runtime_`. The names are stable: a metric may be added, but
+//! none is renamed or changes its labels. [`DeviceCollector`] documents the device metrics.
+mod devices;
+mod server;
+
+use std::fmt::Write;
+
+pub use devices::DeviceCollector;
+pub use server::{MetricsError, MetricsServer};
+
+/// Gathers metrics on every scrape.
+pub trait Collector {
+    fn collect(&mut self) -> Vec<MetricFamily>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricType {
+    Gauge,
+    /// Only increases, its samples are named `<family>_total`
+    Counter,
+}
+
+/// The samples of a metric, by labels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricFamily {
+    pub name: String,
+    pub help: String,
+    pub kind: MetricType,
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub labels: Vec<(&'static str, String)>,
+    pub value: f64,
+}
+
+impl MetricFamily {
+    pub fn gauge<N: Into<String>, H: Into<String>>(name: N, help: H) -> Self {
+        Self::new(name, help, MetricType::Gauge)
+    }
+
+    /// A counter named without its `_total` suffix
+    pub fn counter<N: Into<String>, H: Into<String>>(name: N, help: H) -> Self {
+        Self::new(name, help, MetricType::Counter)
+    }
+
+    fn new<N: Into<String>, H: Into<String>>(name: N, help: H, kind: MetricType) -> Self {
+        Self {
+            name: name.into(),
+            help: help.into(),
+            kind,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, labels: Vec<(&'static str, String)>, value: f64) {
+        self.samples.push(Sample { labels, value });
+    }
+}
+
+/// Exposition format of the metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Prometheus text format 0.0.4
+    Prometheus,
+    /// OpenMetrics 1.0.0
+    OpenMetrics,
+}
+
+impl Format {
+    /// The format a scraper asks for in its `Accept` header
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        match accept {
+            Some(accept) if accept.contains("application/openmetrics-text") => Self::OpenMetrics,
+            _ => Self::Prometheus,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+            Self::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        }
+    }
+}
+
+/// Writes the metric families in the format. Families without samples are left out.
+pub fn encode(families: &[MetricFamily], format: Format) -> String {
+    let mut out = String::new();
+    for family in families.iter().filter(|family| !family.samples.is_empty()) {
+        let (kind, sample_name) = match family.kind {
+            MetricType::Gauge => ("gauge", family.name.clone()),
+            MetricType::Counter => ("counter", format!("{}_total", family.name)),
+        };
+        // OpenMetrics names the counter families without their suffix, Prometheus with it
+        let family_name = match format {
+            Format::OpenMetrics => &family.name,
+            Format::Prometheus => &sample_name,
+        };
+        let help = family.help.replace('\\', r"\\").replace('\n', r"\n");
+        let _ = writeln!(out, "# HELP {family_name} {help}");
+        let _ = writeln!(out, "# TYPE {family_name} {kind}");
+        for sample in &family.samples {
+            out.push_str(&sample_name);
+            if !sample.labels.is_empty() {
+                let labels: Vec<_> = sample
+                    .labels
+                    .iter()
+                    .map(|(name, value)| format!("{name}=\"{}\"", escape_label(value)))
+                    .collect();
+                let _ = write!(out, "{{{}}}", labels.join(","));
+            }
+            let _ = writeln!(out, " {}", format_value(sample.value));
+        }
+    }
+    if format == Format::OpenMetrics {
+        out.push_str("# EOF\n");
+    }
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_owned()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_owned()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn families() -> Vec<MetricFamily> {
+        let mut temperature =
+            MetricFamily::gauge("tpu_runtime_device_temperature_celsius", "Temperature");
+        temperature.push(vec![("device", "apex_0".to_owned())], 61.5);
+        temperature.push(vec![("device", "a\"b\\c\nd".to_owned())], f64::INFINITY);
+        let mut resets = MetricFamily::counter(
+            "tpu_runtime_device_reenumerations",
+            "Re-enumerations\nof the device",
+        );
+        resets.push(vec![], 2.0);
+        let empty = MetricFamily::gauge("tpu_runtime_empty", "Nothing");
+        vec![temperature, resets, empty]
+    }
+
+    #[test]
+    fn test_encode_prometheus() {
+        assert_eq!(
+            encode(&families(), Format::Prometheus),
+            "# HELP tpu_runtime_device_temperature_celsius Temperature\n\
+             # TYPE tpu_runtime_device_temperature_celsius gauge\n\
+             tpu_runtime_device_temperature_celsius{device=\"apex_0\"} 61.5\n\
+             tpu_runtime_device_temperature_celsius{device=\"a\\\"b\\\\c\\nd\"} +Inf\n\
+             # HELP tpu_runtime_device_reenumerations_total Re-enumerations\\nof the device\n\
+             # TYPE tpu_runtime_device_reenumerations_total counter\n\
+             tpu_runtime_device_reenumerations_total 2\n"
+        );
+    }
+
+    #[test]
+    fn test_encode_openmetrics() {
+        let text = encode(&families(), Format::OpenMetrics);
+        assert!(text.contains("# TYPE tpu_runtime_device_reenumerations counter\n"));
+        assert!(text.contains("\ntpu_runtime_device_reenumerations_total 2\n"));
+        assert!(text.ends_with("# EOF\n"));
+
+        assert_eq!(
+            Format::from_accept(Some("application/openmetrics-text;version=1.0.0,*/*;q=0.1")),
+            Format::OpenMetrics
+        );
+        assert_eq!(Format::from_accept(Some("text/plain")), Format::Prometheus);
+        assert_eq!(Format::from_accept(None), Format::Prometheus);
+    }
+}
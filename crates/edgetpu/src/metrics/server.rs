@@ -0,0 +1,409 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use super::{encode, Collector, Format};
+
+/// Largest request accepted, scrapers send a few hundred bytes
+const MAX_REQUEST_SIZE: usize = 8192;
+/// Time given to a client to send its request, and then to read the response
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum MetricsError {
+    #[error("failed to listen on {address}")]
+    Bind { source: io::Error, address: String },
+    #[error("failed to accept a connection")]
+    Accept(#[source] io::Error),
+}
+
+type Result<T> = std::result::Result<T, MetricsError>;
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+/// Serves the metrics of its collectors over HTTP, on a TCP address or a unix socket.
+///
+/// Connections are handled one after the other, each scrape collecting every metric anew. A
+/// client is given a deadline to send its request and another to read the response, so that
+/// a slow one does not hold up the next scrapes.
+pub struct MetricsServer {
+    listener: Listener,
+    collectors: Vec<Box<dyn Collector>>,
+    timeout: Duration,
+}
+
+impl MetricsServer {
+    pub fn bind_tcp<A: ToSocketAddrs + std::fmt::Display>(address: A) -> Result<Self> {
+        let listener = TcpListener::bind(&address).map_err(|source| MetricsError::Bind {
+            source,
+            address: address.to_string(),
+        })?;
+        Ok(Self::new(Listener::Tcp(listener)))
+    }
+
+    /// Listens on a unix socket at `path`, replacing the socket a previous server left.
+    pub fn bind_unix<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let bind_error = |source| MetricsError::Bind {
+            source,
+            address: path.display().to_string(),
+        };
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(bind_error(err)),
+        }
+        let listener = UnixListener::bind(&path).map_err(bind_error)?;
+        Ok(Self::new(Listener::Unix(listener, path)))
+    }
+
+    fn new(listener: Listener) -> Self {
+        Self {
+            listener,
+            collectors: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Sets how long a client is given to send its request, and then to read the response
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_collector(mut self, collector: impl Collector + 'static) -> Self {
+        self.collectors.push(Box::new(collector));
+        self
+    }
+
+    /// The TCP address the server listens on, `None` for a unix socket
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match &self.listener {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            Listener::Unix(..) => None,
+        }
+    }
+
+    /// Serves the connections until accepting one fails.
+    pub fn serve(&mut self) -> Result<()> {
+        loop {
+            self.handle_next()?;
+        }
+    }
+
+    /// Waits for the next connection and answers its request. The errors of the connection
+    /// itself are only logged.
+    pub fn handle_next(&mut self) -> Result<()> {
+        let result = match &self.listener {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept().map_err(MetricsError::Accept)?;
+                self.respond(Deadline::new(stream, self.timeout))
+            }
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().map_err(MetricsError::Accept)?;
+                self.respond(Deadline::new(stream, self.timeout))
+            }
+        };
+        if let Err(err) = result {
+            log::debug!("failed to answer a metrics request: {err}");
+        }
+        Ok(())
+    }
+
+    fn respond<S: TimedStream>(&mut self, mut stream: Deadline<S>) -> io::Result<()> {
+        let request = read_request(&mut stream)?;
+        let response = match request {
+            Some(request) if request.method != "GET" => {
+                Response::text("405 Method Not Allowed", "only GET is supported\n")
+            }
+            Some(request) if request.path == "/metrics" => {
+                let format = Format::from_accept(request.accept.as_deref());
+                let families: Vec<_> = self
+                    .collectors
+                    .iter_mut()
+                    .flat_map(|collector| collector.collect())
+                    .collect();
+                Response {
+                    status: "200 OK",
+                    content_type: format.content_type(),
+                    body: encode(&families, format),
+                }
+            }
+            Some(request) if request.path == "/" => {
+                Response::text("200 OK", "tpu-container-runtime metrics are at /metrics\n")
+            }
+            Some(_) => Response::text("404 Not Found", "not found\n"),
+            None => Response::text("400 Bad Request", "malformed request\n"),
+        };
+        stream.restart(self.timeout);
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            response.status,
+            response.content_type,
+            response.body.len(),
+            response.body
+        )?;
+        stream.flush()
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = &self.listener {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// A stream whose timeouts can be set, for [`Deadline`]
+trait TimedStream: Read + Write {
+    fn set_timeouts(&self, timeout: Duration) -> io::Result<()>;
+}
+
+impl TimedStream for TcpStream {
+    fn set_timeouts(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+impl TimedStream for UnixStream {
+    fn set_timeouts(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+/// A stream whose reads and writes fail once a deadline passed, however slowly the client
+/// trickles its bytes.
+struct Deadline<S> {
+    stream: S,
+    deadline: Instant,
+}
+
+impl<S: TimedStream> Deadline<S> {
+    fn new(stream: S, timeout: Duration) -> Self {
+        Self {
+            stream,
+            deadline: Instant::now() + timeout,
+        }
+    }
+
+    fn restart(&mut self, timeout: Duration) {
+        self.deadline = Instant::now() + timeout;
+    }
+
+    /// Bounds the next operation by the time left
+    fn arm(&self) -> io::Result<()> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the client missed its deadline",
+            ));
+        }
+        self.stream.set_timeouts(left)
+    }
+}
+
+impl<S: TimedStream> Read for Deadline<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.arm()?;
+        self.stream.read(buf)
+    }
+}
+
+impl<S: TimedStream> Write for Deadline<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.arm()?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+struct Request {
+    method: String,
+    /// Without the query string
+    path: String,
+    accept: Option<String>,
+}
+
+/// Reads the request line and the headers, `None` when they are malformed or too long
+fn read_request(stream: &mut impl Read) -> io::Result<Option<Request>> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 1024];
+    while !buffer.windows(4).any(|window| window == b"\r\n\r\n") {
+        if buffer.len() > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+        let read = stream.read(&mut chunk)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+
+    let head = String::from_utf8_lossy(&buffer);
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Ok(None);
+    };
+    let accept = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("accept"))
+        .map(|(_, value)| value.trim().to_owned());
+    Ok(Some(Request {
+        method: method.to_owned(),
+        path: target.split('?').next().unwrap_or(target).to_owned(),
+        accept,
+    }))
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: &str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    use super::*;
+    use crate::metrics::MetricFamily;
+
+    struct Scrapes(u32);
+
+    impl Collector for Scrapes {
+        fn collect(&mut self) -> Vec<MetricFamily> {
+            self.0 += 1;
+            let mut scrapes = MetricFamily::counter("tpu_runtime_test_scrapes", "Scrapes");
+            scrapes.push(vec![], self.0 as f64);
+            vec![scrapes]
+        }
+    }
+
+    fn get(mut stream: impl Read + Write, path: &str, accept: &str) -> String {
+        write!(
+            stream,
+            "GET {path} HTTP/1.1\r\nHost: localhost\r\nAccept: {accept}\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_scrape_over_loopback() {
+        let mut server = MetricsServer::bind_tcp("127.0.0.1:0")
+            .unwrap()
+            .with_collector(Scrapes(0));
+        let address = server.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let scrape = |path, accept| get(TcpStream::connect(address).unwrap(), path, accept);
+            [
+                scrape("/metrics", "text/plain"),
+                scrape(
+                    "/metrics?debug=1",
+                    "application/openmetrics-text; version=1.0.0",
+                ),
+                scrape("/other", "*/*"),
+            ]
+        });
+        for _ in 0..3 {
+            server.handle_next().unwrap();
+        }
+        let [prometheus, openmetrics, other] = client.join().unwrap();
+
+        assert!(prometheus.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(prometheus.contains("Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n"));
+        assert!(prometheus.ends_with("\r\n\r\n# HELP tpu_runtime_test_scrapes_total Scrapes\n# TYPE tpu_runtime_test_scrapes_total counter\ntpu_runtime_test_scrapes_total 1\n"));
+        assert!(openmetrics.contains("Content-Type: application/openmetrics-text;"));
+        assert!(openmetrics.ends_with("tpu_runtime_test_scrapes_total 2\n# EOF\n"));
+        assert!(other.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_slow_client_misses_its_deadline() {
+        let mut server = MetricsServer::bind_tcp("127.0.0.1:0")
+            .unwrap()
+            .with_collector(Scrapes(0))
+            .with_timeout(Duration::from_millis(200));
+        let address = server.local_addr().unwrap();
+        let slow = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            // a byte now and then, never the end of the headers
+            for _ in 0..40 {
+                if stream.write_all(b"X").is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let started = Instant::now();
+        server.handle_next().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        slow.join().unwrap();
+
+        let client = thread::spawn(move || {
+            get(
+                TcpStream::connect(address).unwrap(),
+                "/metrics",
+                "text/plain",
+            )
+        });
+        server.handle_next().unwrap();
+        assert!(client
+            .join()
+            .unwrap()
+            .ends_with("tpu_runtime_test_scrapes_total 1\n"));
+    }
+
+    #[test]
+    fn test_scrape_over_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.sock");
+        // a socket left by a previous server is replaced
+        fs::write(&path, "").unwrap();
+        let mut server = MetricsServer::bind_unix(&path)
+            .unwrap()
+            .with_collector(Scrapes(0));
+        let socket = path.clone();
+        let client =
+            thread::spawn(move || get(UnixStream::connect(socket).unwrap(), "/metrics", ""));
+        server.handle_next().unwrap();
+
+        assert!(client
+            .join()
+            .unwrap()
+            .ends_with("tpu_runtime_test_scrapes_total 1\n"));
+        drop(server);
+        assert!(!path.exists());
+    }
+}
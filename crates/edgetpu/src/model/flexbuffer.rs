@@ -0,0 +1,280 @@
+//! Reader for the FlexBuffers map libedgetpu stores in the custom options of
+//! `edgetpu-custom-op`.
+//!
+//! Only what the map holds is supported: inline and indirect scalars, strings and blobs.
+//! Other values are reported by type so that the caller can skip them.
+
+const TYPE_NULL: u8 = 0;
+const TYPE_INT: u8 = 1;
+const TYPE_UINT: u8 = 2;
+const TYPE_FLOAT: u8 = 3;
+const TYPE_STRING: u8 = 5;
+const TYPE_INDIRECT_INT: u8 = 6;
+const TYPE_INDIRECT_UINT: u8 = 7;
+const TYPE_INDIRECT_FLOAT: u8 = 8;
+const TYPE_MAP: u8 = 9;
+const TYPE_BLOB: u8 = 25;
+const TYPE_BOOL: u8 = 26;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value<'a> {
+    Null,
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Bool(bool),
+    String(&'a [u8]),
+    Blob(&'a [u8]),
+    /// A value of another FlexBuffers type
+    Unsupported(u8),
+}
+
+/// Reads a buffer whose root is a map, returning its entries in key order.
+pub fn read_map(buf: &[u8]) -> Result<Vec<(&str, Value<'_>)>, String> {
+    let reader = Reader { buf };
+    let len = buf.len();
+    if len < 3 {
+        return Err("the buffer is too short".to_owned());
+    }
+    let root_width = buf[len - 1] as usize;
+    let (root_type, width) = unpack(buf[len - 2]);
+    let root = reader.sub(len - 2, root_width)?;
+    if root_type != TYPE_MAP {
+        return Err(format!("the root is of type {root_type}, not a map"));
+    }
+
+    let map = reader.indirect(root, root_width)?;
+    let count = reader.uint(reader.sub(map, width)?, width)? as usize;
+    let keys_field = reader.sub(map, 3 * width)?;
+    let keys = reader.indirect(keys_field, width)?;
+    let keys_width = reader.uint(keys_field + width, width)? as usize;
+    let types = reader.add(map, count, width)?;
+    // the count comes from the buffer, its values and types must fit in it
+    reader.bytes(map, types - map)?;
+    reader.bytes(types, count)?;
+
+    let mut entries = Vec::with_capacity(count);
+    for index in 0..count {
+        let key = reader.indirect(reader.add(keys, index, keys_width)?, keys_width)?;
+        let key = reader.cstr(key)?;
+
+        let value = reader.add(map, index, width)?;
+        let (value_type, value_width) = unpack(reader.bytes(types + index, 1)?[0]);
+        let value = match value_type {
+            TYPE_NULL => Value::Null,
+            TYPE_INT => Value::Int(reader.int(value, width)?),
+            TYPE_UINT => Value::UInt(reader.uint(value, width)?),
+            TYPE_FLOAT => Value::Float(reader.float(value, width)?),
+            TYPE_BOOL => Value::Bool(reader.uint(value, width)? != 0),
+            TYPE_INDIRECT_INT => {
+                Value::Int(reader.int(reader.indirect(value, width)?, value_width)?)
+            }
+            TYPE_INDIRECT_UINT => {
+                Value::UInt(reader.uint(reader.indirect(value, width)?, value_width)?)
+            }
+            TYPE_INDIRECT_FLOAT => {
+                Value::Float(reader.float(reader.indirect(value, width)?, value_width)?)
+            }
+            TYPE_STRING | TYPE_BLOB => {
+                let data = reader.indirect(value, width)?;
+                let size = reader.uint(reader.sub(data, value_width)?, value_width)? as usize;
+                let bytes = reader.bytes(data, size)?;
+                if value_type == TYPE_STRING {
+                    Value::String(bytes)
+                } else {
+                    Value::Blob(bytes)
+                }
+            }
+            other => Value::Unsupported(other),
+        };
+        entries.push((key, value));
+    }
+
+    Ok(entries)
+}
+
+/// Splits a packed type into the type and the byte width of what it points to.
+fn unpack(packed: u8) -> (u8, usize) {
+    (packed >> 2, 1 << (packed & 3))
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, pos: usize, len: usize) -> Result<&'a [u8], String> {
+        pos.checked_add(len)
+            .and_then(|end| self.buf.get(pos..end))
+            .ok_or_else(|| format!("{len} bytes at {pos} are out of bounds"))
+    }
+
+    fn sub(&self, pos: usize, len: usize) -> Result<usize, String> {
+        pos.checked_sub(len)
+            .ok_or_else(|| format!("offset {len} before {pos} is out of bounds"))
+    }
+
+    fn add(&self, pos: usize, index: usize, width: usize) -> Result<usize, String> {
+        index
+            .checked_mul(width)
+            .and_then(|offset| pos.checked_add(offset))
+            .ok_or_else(|| format!("element {index} after {pos} is out of bounds"))
+    }
+
+    fn uint(&self, pos: usize, width: usize) -> Result<u64, String> {
+        let bytes = self.bytes(pos, width)?;
+        Ok(match width {
+            1 => bytes[0] as u64,
+            2 => u16::from_le_bytes(bytes.try_into().unwrap()) as u64,
+            4 => u32::from_le_bytes(bytes.try_into().unwrap()) as u64,
+            8 => u64::from_le_bytes(bytes.try_into().unwrap()),
+            _ => return Err(format!("invalid byte width {width}")),
+        })
+    }
+
+    fn int(&self, pos: usize, width: usize) -> Result<i64, String> {
+        let value = self.uint(pos, width)?;
+        // sign extend
+        let shift = 64 - 8 * width as u32;
+        Ok(((value << shift) as i64) >> shift)
+    }
+
+    fn float(&self, pos: usize, width: usize) -> Result<f64, String> {
+        match width {
+            4 => Ok(f32::from_bits(self.uint(pos, 4)? as u32) as f64),
+            8 => Ok(f64::from_bits(self.uint(pos, 8)?)),
+            _ => Err(format!("invalid float width {width}")),
+        }
+    }
+
+    /// Follows the offset stored at `pos`.
+    fn indirect(&self, pos: usize, width: usize) -> Result<usize, String> {
+        let offset = self.uint(pos, width)?;
+        usize::try_from(offset)
+            .ok()
+            .and_then(|offset| pos.checked_sub(offset))
+            .ok_or_else(|| format!("offset {offset} at {pos} is out of bounds"))
+    }
+
+    fn cstr(&self, pos: usize) -> Result<&'a str, String> {
+        let rest = self.buf.get(pos..).unwrap_or_default();
+        let end = rest
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or_else(|| format!("the key at {pos} is not terminated"))?;
+        std::str::from_utf8(&rest[..end]).map_err(|_| format!("the key at {pos} is not UTF-8"))
+    }
+}
+
+/// Writer for the maps of the tests, with every element 4 bytes wide.
+#[cfg(test)]
+pub(crate) mod fixture {
+    pub enum Entry<'a> {
+        Int(i32),
+        Blob(&'a [u8]),
+    }
+
+    fn align(buf: &mut Vec<u8>) {
+        buf.resize(buf.len().next_multiple_of(4), 0);
+    }
+
+    fn push_u32(buf: &mut Vec<u8>, value: usize) {
+        buf.extend_from_slice(&(value as u32).to_le_bytes());
+    }
+
+    /// Pushes the offset from the pushed element back to `target`
+    fn push_offset(buf: &mut Vec<u8>, target: usize) {
+        let offset = buf.len() - target;
+        push_u32(buf, offset);
+    }
+
+    /// Encodes a map, `entries` must be sorted by key.
+    pub fn map(entries: &[(&str, Entry)]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut keys = Vec::new();
+        for (key, _) in entries {
+            keys.push(buf.len());
+            buf.extend_from_slice(key.as_bytes());
+            buf.push(0);
+        }
+        let mut blobs = Vec::new();
+        for (_, entry) in entries {
+            if let Entry::Blob(data) = entry {
+                align(&mut buf);
+                push_u32(&mut buf, data.len());
+                blobs.push(buf.len());
+                buf.extend_from_slice(data);
+            } else {
+                blobs.push(0);
+            }
+        }
+
+        align(&mut buf);
+        push_u32(&mut buf, entries.len());
+        let keys_vector = buf.len();
+        for key in keys {
+            push_offset(&mut buf, key);
+        }
+
+        push_offset(&mut buf, keys_vector);
+        push_u32(&mut buf, 4);
+        push_u32(&mut buf, entries.len());
+        let map = buf.len();
+        for ((_, entry), blob) in entries.iter().zip(&blobs) {
+            match entry {
+                Entry::Int(value) => buf.extend_from_slice(&value.to_le_bytes()),
+                Entry::Blob(_) => push_offset(&mut buf, *blob),
+            }
+        }
+        for (_, entry) in entries {
+            buf.push(match entry {
+                Entry::Int(_) => super::TYPE_INT << 2 | 2,
+                Entry::Blob(_) => super::TYPE_BLOB << 2 | 2,
+            });
+        }
+
+        align(&mut buf);
+        push_offset(&mut buf, map);
+        buf.push(super::TYPE_MAP << 2 | 2);
+        buf.push(4);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::{map, Entry};
+    use super::*;
+
+    #[test]
+    fn test_read_map() {
+        let buf = map(&[("1", Entry::Int(-3)), ("4", Entry::Blob(b"DWN1 package"))]);
+        assert_eq!(
+            read_map(&buf).unwrap(),
+            vec![("1", Value::Int(-3)), ("4", Value::Blob(b"DWN1 package"))]
+        );
+    }
+
+    #[test]
+    fn test_read_malformed_map() {
+        let buf = map(&[("4", Entry::Blob(b"package"))]);
+        // the blob size points past the end
+        let mut truncated = buf.clone();
+        truncated[4..8].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(read_map(&truncated).is_err());
+        assert!(read_map(&buf[buf.len() - 2..]).is_err());
+
+        // the count of the map is far more than the buffer holds
+        let mut huge = buf.clone();
+        let len = huge.len();
+        let root = u32::from_le_bytes(huge[len - 6..len - 2].try_into().unwrap()) as usize;
+        let map = len - 6 - root;
+        huge[map - 4..map].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_map(&huge).is_err());
+
+        let mut not_a_map = buf;
+        let len = not_a_map.len();
+        not_a_map[len - 2] = TYPE_BLOB << 2 | 2;
+        assert!(read_map(&not_a_map).is_err());
+    }
+}
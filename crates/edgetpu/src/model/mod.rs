@@ -0,0 +1,611 @@
+//! Inspection of models compiled for the Edge TPU.
+//!
+//! The Edge TPU compiler replaces the parts of a TFLite model it maps to the accelerator with
+//! `edgetpu-custom-op` operators. Their custom options embed a DarwiNN package holding the
+//! compiled executables, along with the compiler version and the minimum libedgetpu runtime
+//! version they need. [`ModelInfo`] reads all of this without loading libedgetpu, so that
+//! mismatches can be caught before inference.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use edgetpu::model::ModelInfo;
+//!
+//! let model = ModelInfo::load("model/mobilenet_v2_1.0_224_quant_edgetpu.tflite").unwrap();
+//! for op in &model.edgetpu_ops {
+//!     println!(
+//!         "subgraph {} needs runtime {}",
+//!         op.subgraph, op.min_runtime_version
+//!     );
+//! }
+//! ```
+mod flexbuffer;
+mod schema;
+pub mod validate;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use flatbuffers::{InvalidFlatbuffer, Vector};
+use serde::Serialize;
+
+use flexbuffer::Value;
+use schema::{Executable, Model, MultiExecutable, Package, SubGraph};
+
+/// Custom code of the operators run by the Edge TPU
+pub const EDGETPU_CUSTOM_OP: &str = "edgetpu-custom-op";
+
+/// First line of the pointer files Git LFS leaves in place of models not fetched
+const GIT_LFS_POINTER: &[u8] = b"version https://git-lfs.github.com/spec/";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ModelError {
+    #[error("failed to read {path:?}")]
+    Read {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("the model is a Git LFS pointer, fetch it with `git lfs pull`")]
+    GitLfsPointer,
+    #[error(
+        "not a TFLite model, the {} identifier is missing",
+        schema::TFLITE_IDENTIFIER
+    )]
+    NotTflite,
+    #[error("invalid TFLite model")]
+    Invalid(#[source] InvalidFlatbuffer),
+    #[error("invalid {EDGETPU_CUSTOM_OP} {operator} of subgraph {subgraph}: {reason}")]
+    CustomOp {
+        subgraph: usize,
+        operator: usize,
+        reason: String,
+    },
+    #[error("invalid Edge TPU package in {EDGETPU_CUSTOM_OP} {operator} of subgraph {subgraph}")]
+    Package {
+        source: InvalidFlatbuffer,
+        subgraph: usize,
+        operator: usize,
+    },
+}
+
+type Result<T> = std::result::Result<T, ModelError>;
+
+/// What a compiled model contains.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ModelInfo {
+    /// Version of the TFLite schema
+    pub schema_version: u32,
+    pub description: Option<String>,
+    /// Inputs of the main subgraph
+    pub inputs: Vec<TensorInfo>,
+    /// Outputs of the main subgraph
+    pub outputs: Vec<TensorInfo>,
+    pub edgetpu_ops: Vec<EdgeTpuOp>,
+}
+
+/// An `edgetpu-custom-op` operator.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EdgeTpuOp {
+    pub subgraph: usize,
+    pub subgraph_name: Option<String>,
+    /// Index of the operator in its subgraph
+    pub operator: usize,
+    /// e.g. `16.0.384591198`
+    pub compiler_version: Option<String>,
+    /// Minimum libedgetpu runtime version, as in `RuntimeVersion(14)`
+    pub min_runtime_version: i32,
+    pub model_identifier: Option<String>,
+    /// Token shared by the models whose parameters can be cached together on the chip
+    pub parameter_caching_token: Option<u64>,
+    /// Parameters cached in the on-chip memory
+    pub on_chip_parameter_bytes: u64,
+    /// Parameters streamed from the host memory on every inference
+    pub off_chip_parameter_bytes: u64,
+    pub executables: Vec<ExecutableInfo>,
+    pub inputs: Vec<TensorInfo>,
+    pub outputs: Vec<TensorInfo>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExecutableType {
+    StandAlone,
+    /// Loads the parameters in the on-chip memory
+    ParameterCaching,
+    /// Runs after a parameter caching executable
+    ExecutionOnly,
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExecutableInfo {
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub executable_type: ExecutableType,
+    pub parameter_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TensorInfo {
+    pub name: Option<String>,
+    /// e.g. `uint8`
+    #[serde(rename = "type")]
+    pub tensor_type: String,
+    pub shape: Vec<i32>,
+    pub quantization: Option<Quantization>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Quantization {
+    pub scale: Vec<f32>,
+    pub zero_point: Vec<i64>,
+    /// Dimension of the per-channel parameters
+    pub quantized_dimension: i32,
+}
+
+impl ModelInfo {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path).map_err(|source| ModelError::Read {
+            source,
+            path: path.to_owned(),
+        })?;
+        Self::from_bytes(&data)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.starts_with(GIT_LFS_POINTER) {
+            return Err(ModelError::GitLfsPointer);
+        }
+        if !has_identifier(data, schema::TFLITE_IDENTIFIER) {
+            return Err(ModelError::NotTflite);
+        }
+        let model = flatbuffers::root::<Model>(data).map_err(ModelError::Invalid)?;
+
+        let custom_codes: Vec<Option<&str>> = model
+            .operator_codes()
+            .map(|codes| codes.iter().map(|code| code.custom_code()).collect())
+            .unwrap_or_default();
+        let subgraphs: Vec<SubGraph> = model
+            .subgraphs()
+            .map(|subgraphs| subgraphs.iter().collect())
+            .unwrap_or_default();
+
+        let mut edgetpu_ops = Vec::new();
+        for (subgraph_index, subgraph) in subgraphs.iter().enumerate() {
+            for (operator, op) in subgraph.operators().into_iter().flatten().enumerate() {
+                let code = custom_codes
+                    .get(op.opcode_index() as usize)
+                    .copied()
+                    .flatten();
+                if code != Some(EDGETPU_CUSTOM_OP) {
+                    continue;
+                }
+                let options = custom_options(data, &op).ok_or_else(|| ModelError::CustomOp {
+                    subgraph: subgraph_index,
+                    operator,
+                    reason: "the custom options are missing".to_owned(),
+                })?;
+                let mut edgetpu_op = parse_custom_op(options, subgraph_index, operator)?;
+                edgetpu_op.subgraph_name = subgraph.name().map(str::to_owned);
+                edgetpu_op.inputs = tensors(subgraph, op.inputs());
+                edgetpu_op.outputs = tensors(subgraph, op.outputs());
+                edgetpu_ops.push(edgetpu_op);
+            }
+        }
+
+        let main = subgraphs.first();
+        Ok(ModelInfo {
+            schema_version: model.version(),
+            description: model.description().map(str::to_owned),
+            inputs: main
+                .map(|main| tensors(main, main.inputs()))
+                .unwrap_or_default(),
+            outputs: main
+                .map(|main| tensors(main, main.outputs()))
+                .unwrap_or_default(),
+            edgetpu_ops,
+        })
+    }
+
+    /// Highest runtime version required by the Edge TPU operators
+    pub fn min_runtime_version(&self) -> Option<i32> {
+        self.edgetpu_ops
+            .iter()
+            .map(|op| op.min_runtime_version)
+            .max()
+    }
+}
+
+/// Custom options of an operator, stored in the flatbuffer or, for large models, after it.
+fn custom_options<'a>(data: &'a [u8], op: &schema::Operator<'a>) -> Option<&'a [u8]> {
+    if let Some(options) = op.custom_options() {
+        return Some(options.bytes());
+    }
+    let offset = usize::try_from(op.large_custom_options_offset()).ok()?;
+    let size = usize::try_from(op.large_custom_options_size()).ok()?;
+    if size == 0 {
+        return None;
+    }
+    data.get(offset..offset.checked_add(size)?)
+}
+
+fn parse_custom_op(options: &[u8], subgraph: usize, operator: usize) -> Result<EdgeTpuOp> {
+    let custom_op_error = |reason: String| ModelError::CustomOp {
+        subgraph,
+        operator,
+        reason,
+    };
+    let package_error = |source| ModelError::Package {
+        source,
+        subgraph,
+        operator,
+    };
+
+    // the package is one of the blobs of the custom options map, recognized by its identifier
+    let entries = flexbuffer::read_map(options).map_err(custom_op_error)?;
+    let package_data = entries
+        .into_iter()
+        .find_map(|(_, value)| match value {
+            Value::Blob(data) | Value::String(data)
+                if has_identifier(data, schema::PACKAGE_IDENTIFIER) =>
+            {
+                Some(data)
+            }
+            _ => None,
+        })
+        .ok_or_else(|| custom_op_error("no Edge TPU package was found".to_owned()))?;
+    let package = flatbuffers::root::<Package>(package_data).map_err(package_error)?;
+
+    let mut executables = Vec::new();
+    if let Some(serialized) = package.serialized_multi_executable() {
+        let multi =
+            flatbuffers::root::<MultiExecutable>(serialized.bytes()).map_err(package_error)?;
+        for serialized in multi.serialized_executables().into_iter().flatten() {
+            executables
+                .push(flatbuffers::root::<Executable>(serialized.bytes()).map_err(package_error)?);
+        }
+    }
+
+    let mut op = EdgeTpuOp {
+        subgraph,
+        subgraph_name: None,
+        operator,
+        compiler_version: package.compiler_version().map(str::to_owned),
+        min_runtime_version: package.min_runtime_version(),
+        model_identifier: package.model_identifier().map(str::to_owned),
+        parameter_caching_token: None,
+        on_chip_parameter_bytes: 0,
+        off_chip_parameter_bytes: 0,
+        executables: Vec::new(),
+        inputs: Vec::new(),
+        outputs: Vec::new(),
+    };
+    for executable in executables {
+        let executable_type = match executable.executable_type() {
+            schema::EXECUTABLE_STAND_ALONE => ExecutableType::StandAlone,
+            schema::EXECUTABLE_PARAMETER_CACHING => ExecutableType::ParameterCaching,
+            schema::EXECUTABLE_EXECUTION_ONLY => ExecutableType::ExecutionOnly,
+            _ => ExecutableType::Unknown,
+        };
+        let parameter_bytes = executable
+            .parameters()
+            .map_or(0, |parameters| parameters.len()) as u64;
+        // the parameters loaded by the caching executable stay on the chip, the others are
+        // sent along with every inference
+        if executable_type == ExecutableType::ParameterCaching {
+            op.on_chip_parameter_bytes += parameter_bytes;
+        } else {
+            op.off_chip_parameter_bytes += parameter_bytes;
+        }
+        let token = executable.parameter_caching_token();
+        if token != 0
+            && (op.parameter_caching_token.is_none()
+                || executable_type == ExecutableType::ParameterCaching)
+        {
+            op.parameter_caching_token = Some(token);
+        }
+        op.executables.push(ExecutableInfo {
+            name: executable.name().map(str::to_owned),
+            executable_type,
+            parameter_bytes,
+        });
+    }
+
+    Ok(op)
+}
+
+fn tensors(subgraph: &SubGraph, indices: Option<Vector<i32>>) -> Vec<TensorInfo> {
+    let Some(tensors) = subgraph.tensors() else {
+        return Vec::new();
+    };
+    indices
+        .into_iter()
+        .flatten()
+        // -1 marks an optional input left out
+        .filter_map(|index| usize::try_from(index).ok())
+        .filter(|&index| index < tensors.len())
+        .map(|index| {
+            let tensor = tensors.get(index);
+            TensorInfo {
+                name: tensor.name().map(str::to_owned),
+                tensor_type: tensor_type_name(tensor.tensor_type()),
+                shape: tensor
+                    .shape()
+                    .map(|shape| shape.iter().collect())
+                    .unwrap_or_default(),
+                quantization: tensor.quantization().and_then(|quantization| {
+                    let scale: Vec<f32> = quantization
+                        .scale()
+                        .map(|scale| scale.iter().collect())
+                        .unwrap_or_default();
+                    if scale.is_empty() {
+                        return None;
+                    }
+                    Some(Quantization {
+                        scale,
+                        zero_point: quantization
+                            .zero_point()
+                            .map(|zero_point| zero_point.iter().collect())
+                            .unwrap_or_default(),
+                        quantized_dimension: quantization.quantized_dimension(),
+                    })
+                }),
+            }
+        })
+        .collect()
+}
+
+/// Name of a TFLite `TensorType`
+fn tensor_type_name(tensor_type: i8) -> String {
+    let name = match tensor_type {
+        0 => "float32",
+        1 => "float16",
+        2 => "int32",
+        3 => "uint8",
+        4 => "int64",
+        5 => "string",
+        6 => "bool",
+        7 => "int16",
+        8 => "complex64",
+        9 => "int8",
+        10 => "float64",
+        11 => "complex128",
+        12 => "uint64",
+        13 => "resource",
+        14 => "variant",
+        15 => "uint32",
+        16 => "uint16",
+        17 => "int4",
+        other => return format!("type-{other}"),
+    };
+    name.to_owned()
+}
+
+/// Builder of compiled models for the tests.
+/// Whether `data` is a flatbuffer with the file identifier, without the panic of
+/// `flatbuffers::buffer_has_identifier` on buffers too short to hold one
+fn has_identifier(data: &[u8], identifier: &str) -> bool {
+    data.len() >= flatbuffers::SIZE_UOFFSET + flatbuffers::FILE_IDENTIFIER_LENGTH
+        && flatbuffers::buffer_has_identifier(data, identifier, false)
+}
+
+#[cfg(test)]
+pub(crate) mod fixture {
+    use flatbuffers::{FlatBufferBuilder, WIPOffset};
+
+    use super::flexbuffer::fixture::{map, Entry};
+    use super::schema::*;
+
+    pub const COMPILER_VERSION: &str = "16.0.384591198";
+    pub const CACHING_TOKEN: u64 = 0x1f2e_3d4c_5b6a_7988;
+
+    fn table<T>(
+        builder: &mut FlatBufferBuilder,
+        build: impl FnOnce(&mut FlatBufferBuilder),
+    ) -> WIPOffset<T> {
+        let start = builder.start_table();
+        build(builder);
+        WIPOffset::new(builder.end_table(start).value())
+    }
+
+    fn field(index: u16) -> u16 {
+        4 + 2 * index
+    }
+
+    fn executable(executable_type: i16, parameters: usize) -> Vec<u8> {
+        let mut builder = FlatBufferBuilder::new();
+        let name = builder.create_string("executable");
+        let parameters = builder.create_vector(&vec![0u8; parameters]);
+        let executable: WIPOffset<Executable> = table(&mut builder, |builder| {
+            builder.push_slot_always(field(1), name);
+            builder.push_slot_always(field(6), parameters);
+            builder.push_slot(field(13), executable_type, 0);
+            builder.push_slot(field(14), CACHING_TOKEN, 0);
+        });
+        builder.finish(executable, None);
+        builder.finished_data().to_vec()
+    }
+
+    /// A DarwiNN package with a parameter caching and an execution only executable
+    pub fn package(min_runtime_version: i32, on_chip: usize, off_chip: usize) -> Vec<u8> {
+        let executables = [
+            executable(EXECUTABLE_PARAMETER_CACHING, on_chip),
+            executable(EXECUTABLE_EXECUTION_ONLY, off_chip),
+        ];
+        let mut builder = FlatBufferBuilder::new();
+        let serialized: Vec<_> = executables
+            .iter()
+            .map(|executable| builder.create_vector(executable))
+            .collect();
+        let serialized = builder.create_vector(&serialized);
+        let multi: WIPOffset<MultiExecutable> = table(&mut builder, |builder| {
+            builder.push_slot_always(field(0), serialized);
+        });
+        builder.finish(multi, None);
+        let multi = builder.finished_data().to_vec();
+
+        let mut builder = FlatBufferBuilder::new();
+        let multi = builder.create_vector(&multi);
+        let compiler_version = builder.create_string(COMPILER_VERSION);
+        let package: WIPOffset<Package> = table(&mut builder, |builder| {
+            builder.push_slot(field(0), min_runtime_version, 0);
+            builder.push_slot_always(field(1), multi);
+            builder.push_slot_always(field(4), compiler_version);
+        });
+        builder.finish(package, Some(PACKAGE_IDENTIFIER));
+        builder.finished_data().to_vec()
+    }
+
+    /// A model whose main subgraph is a quantize op followed by an Edge TPU op, with
+    /// `package` in its custom options
+    pub fn model(package: &[u8]) -> Vec<u8> {
+        let options = map(&[("1", Entry::Int(1)), ("4", Entry::Blob(package))]);
+        let mut builder = FlatBufferBuilder::new();
+
+        // builtin QUANTIZE
+        let quantize: WIPOffset<OperatorCode> = table(&mut builder, |builder| {
+            builder.push_slot(field(0), 114i8, 0);
+        });
+        let custom_code = builder.create_string(super::EDGETPU_CUSTOM_OP);
+        let edgetpu_code: WIPOffset<OperatorCode> = table(&mut builder, |builder| {
+            builder.push_slot_always(field(1), custom_code);
+        });
+        let codes = builder.create_vector(&[quantize, edgetpu_code]);
+
+        let mut tensors = Vec::new();
+        for (name, tensor_type, shape, scale, zero_point) in [
+            ("input", 0i8, [1, 224, 224, 3], None, 0i64),
+            ("input_quant", 3, [1, 224, 224, 3], Some(0.0078125f32), 128),
+            ("scores", 3, [1, 1, 1, 965], Some(0.00390625), 0),
+        ] {
+            let name = builder.create_string(name);
+            let shape = builder.create_vector(&shape);
+            let quantization = scale.map(|scale| {
+                let scale = builder.create_vector(&[scale]);
+                let zero_point = builder.create_vector(&[zero_point]);
+                table::<QuantizationParameters>(&mut builder, |builder| {
+                    builder.push_slot_always(field(2), scale);
+                    builder.push_slot_always(field(3), zero_point);
+                })
+            });
+            tensors.push(table::<Tensor>(&mut builder, |builder| {
+                builder.push_slot_always(field(0), shape);
+                builder.push_slot(field(1), tensor_type, 0);
+                builder.push_slot_always(field(3), name);
+                if let Some(quantization) = quantization {
+                    builder.push_slot_always(field(4), quantization);
+                }
+            }));
+        }
+        let tensors = builder.create_vector(&tensors);
+
+        let mut operators = Vec::new();
+        for (opcode_index, input, output, custom_options) in
+            [(0u32, 0, 1, None), (1, 1, 2, Some(&options))]
+        {
+            let inputs = builder.create_vector(&[input]);
+            let outputs = builder.create_vector(&[output]);
+            let custom_options = custom_options.map(|options| builder.create_vector(options));
+            operators.push(table::<Operator>(&mut builder, |builder| {
+                builder.push_slot(field(0), opcode_index, 0);
+                builder.push_slot_always(field(1), inputs);
+                builder.push_slot_always(field(2), outputs);
+                if let Some(custom_options) = custom_options {
+                    builder.push_slot_always(field(5), custom_options);
+                }
+            }));
+        }
+        let operators = builder.create_vector(&operators);
+        let inputs = builder.create_vector(&[0]);
+        let outputs = builder.create_vector(&[2]);
+        let subgraph_name = builder.create_string("main");
+        let subgraph: WIPOffset<SubGraph> = table(&mut builder, |builder| {
+            builder.push_slot_always(field(0), tensors);
+            builder.push_slot_always(field(1), inputs);
+            builder.push_slot_always(field(2), outputs);
+            builder.push_slot_always(field(3), operators);
+            builder.push_slot_always(field(4), subgraph_name);
+        });
+        let subgraphs = builder.create_vector(&[subgraph]);
+
+        let description = builder.create_string("TOCO Converted.");
+        let model: WIPOffset<Model> = table(&mut builder, |builder| {
+            builder.push_slot(field(0), 3u32, 0);
+            builder.push_slot_always(field(1), codes);
+            builder.push_slot_always(field(2), subgraphs);
+            builder.push_slot_always(field(3), description);
+        });
+        builder.finish(model, Some(TFLITE_IDENTIFIER));
+        builder.finished_data().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::{model, package, CACHING_TOKEN, COMPILER_VERSION};
+    use super::*;
+
+    #[test]
+    fn test_inspect_compiled_model() {
+        let info = ModelInfo::from_bytes(&model(&package(14, 4096, 512))).unwrap();
+        assert_eq!(info.schema_version, 3);
+        assert_eq!(info.min_runtime_version(), Some(14));
+        assert_eq!(info.inputs.len(), 1);
+        assert_eq!(info.inputs[0].tensor_type, "float32");
+        assert_eq!(info.outputs[0].shape, vec![1, 1, 1, 965]);
+
+        let [op] = info.edgetpu_ops.as_slice() else {
+            panic!("expected one Edge TPU op: {:?}", info.edgetpu_ops);
+        };
+        assert_eq!((op.subgraph, op.operator), (0, 1));
+        assert_eq!(op.subgraph_name.as_deref(), Some("main"));
+        assert_eq!(op.compiler_version.as_deref(), Some(COMPILER_VERSION));
+        assert_eq!(op.parameter_caching_token, Some(CACHING_TOKEN));
+        assert_eq!(op.on_chip_parameter_bytes, 4096);
+        assert_eq!(op.off_chip_parameter_bytes, 512);
+        assert_eq!(
+            op.executables[0].executable_type,
+            ExecutableType::ParameterCaching
+        );
+        assert_eq!(
+            op.inputs[0].quantization,
+            Some(Quantization {
+                scale: vec![0.0078125],
+                zero_point: vec![128],
+                quantized_dimension: 0,
+            })
+        );
+        assert_eq!(op.outputs[0].name.as_deref(), Some("scores"));
+    }
+
+    #[test]
+    fn test_inspect_invalid_models() {
+        let pointer =
+            b"version https://git-lfs.github.com/spec/v1\noid sha256:5b46\nsize 4298524\n";
+        assert!(matches!(
+            ModelInfo::from_bytes(pointer),
+            Err(ModelError::GitLfsPointer)
+        ));
+        assert!(matches!(
+            ModelInfo::from_bytes(b"\x08\0\0\0ONNXnot a model"),
+            Err(ModelError::NotTflite)
+        ));
+        assert!(matches!(
+            ModelInfo::from_bytes(b"bird"),
+            Err(ModelError::NotTflite)
+        ));
+
+        let mut truncated = model(&package(14, 16, 16));
+        truncated.truncate(40);
+        assert!(ModelInfo::from_bytes(&truncated).is_err());
+
+        // a custom op without package
+        let mut corrupted = package(14, 16, 16);
+        corrupted[4..8].copy_from_slice(b"XXXX");
+        assert!(matches!(
+            ModelInfo::from_bytes(&model(&corrupted)),
+            Err(ModelError::CustomOp { operator: 1, .. })
+        ));
+    }
+}
@@ -0,0 +1,334 @@
+//! Accessors for the parts of the flatbuffers schemas the inspector reads.
+//!
+//! These follow what `flatc --rust` generates for `tensorflow/lite/schema/schema.fbs` and
+//! for the DarwiNN `executable.fbs` of libedgetpu, restricted to the fields used here.
+//! Every accessor is only called on tables checked by their `Verifiable` impl, which visits
+//! exactly the fields exposed.
+use flatbuffers::{
+    Follow, ForwardsUOffset, InvalidFlatbuffer, Table, VOffsetT, Vector, Verifiable, Verifier,
+};
+
+/// Identifier of TFLite models
+pub const TFLITE_IDENTIFIER: &str = "TFL3";
+/// Identifier of the DarwiNN package embedded in `edgetpu-custom-op`
+pub const PACKAGE_IDENTIFIER: &str = "DWN1";
+
+/// Byte offset of the `index`th field in a vtable
+const fn slot(index: VOffsetT) -> VOffsetT {
+    4 + 2 * index
+}
+
+macro_rules! tables {
+    ($($name:ident),* $(,)?) => {
+        $(
+            #[derive(Copy, Clone)]
+            pub struct $name<'a> {
+                table: Table<'a>,
+            }
+
+            impl<'a> Follow<'a> for $name<'a> {
+                type Inner = $name<'a>;
+
+                unsafe fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+                    Self {
+                        table: Table::new(buf, loc),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+tables!(
+    Model,
+    OperatorCode,
+    SubGraph,
+    Tensor,
+    QuantizationParameters,
+    Operator,
+    Package,
+    MultiExecutable,
+    Executable,
+);
+
+type Tables<'a, T> = Vector<'a, ForwardsUOffset<T>>;
+
+/// Reads a field of a verified table.
+macro_rules! field {
+    ($self:ident, $ty:ty, $index:expr) => {
+        // SAFETY: the table was verified, including this field
+        unsafe { $self.table.get::<$ty>(slot($index), None) }
+    };
+    ($self:ident, $ty:ty, $index:expr, $default:expr) => {
+        // SAFETY: the table was verified, including this field
+        unsafe { $self.table.get::<$ty>(slot($index), Some($default)) }.unwrap_or($default)
+    };
+}
+
+impl<'a> Model<'a> {
+    pub fn version(&self) -> u32 {
+        field!(self, u32, 0, 0)
+    }
+
+    pub fn operator_codes(&self) -> Option<Tables<'a, OperatorCode<'a>>> {
+        field!(self, ForwardsUOffset<Tables<'a, OperatorCode<'a>>>, 1)
+    }
+
+    pub fn subgraphs(&self) -> Option<Tables<'a, SubGraph<'a>>> {
+        field!(self, ForwardsUOffset<Tables<'a, SubGraph<'a>>>, 2)
+    }
+
+    pub fn description(&self) -> Option<&'a str> {
+        field!(self, ForwardsUOffset<&str>, 3)
+    }
+}
+
+impl Verifiable for Model<'_> {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<u32>("version", slot(0), false)?
+            .visit_field::<ForwardsUOffset<Tables<OperatorCode>>>("operator_codes", slot(1), false)?
+            .visit_field::<ForwardsUOffset<Tables<SubGraph>>>("subgraphs", slot(2), false)?
+            .visit_field::<ForwardsUOffset<&str>>("description", slot(3), false)?
+            .finish();
+        Ok(())
+    }
+}
+
+impl<'a> OperatorCode<'a> {
+    pub fn custom_code(&self) -> Option<&'a str> {
+        field!(self, ForwardsUOffset<&str>, 1)
+    }
+}
+
+impl Verifiable for OperatorCode<'_> {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<ForwardsUOffset<&str>>("custom_code", slot(1), false)?
+            .finish();
+        Ok(())
+    }
+}
+
+impl<'a> SubGraph<'a> {
+    pub fn tensors(&self) -> Option<Tables<'a, Tensor<'a>>> {
+        field!(self, ForwardsUOffset<Tables<'a, Tensor<'a>>>, 0)
+    }
+
+    pub fn inputs(&self) -> Option<Vector<'a, i32>> {
+        field!(self, ForwardsUOffset<Vector<'a, i32>>, 1)
+    }
+
+    pub fn outputs(&self) -> Option<Vector<'a, i32>> {
+        field!(self, ForwardsUOffset<Vector<'a, i32>>, 2)
+    }
+
+    pub fn operators(&self) -> Option<Tables<'a, Operator<'a>>> {
+        field!(self, ForwardsUOffset<Tables<'a, Operator<'a>>>, 3)
+    }
+
+    pub fn name(&self) -> Option<&'a str> {
+        field!(self, ForwardsUOffset<&str>, 4)
+    }
+}
+
+impl Verifiable for SubGraph<'_> {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<ForwardsUOffset<Tables<Tensor>>>("tensors", slot(0), false)?
+            .visit_field::<ForwardsUOffset<Vector<i32>>>("inputs", slot(1), false)?
+            .visit_field::<ForwardsUOffset<Vector<i32>>>("outputs", slot(2), false)?
+            .visit_field::<ForwardsUOffset<Tables<Operator>>>("operators", slot(3), false)?
+            .visit_field::<ForwardsUOffset<&str>>("name", slot(4), false)?
+            .finish();
+        Ok(())
+    }
+}
+
+impl<'a> Tensor<'a> {
+    pub fn shape(&self) -> Option<Vector<'a, i32>> {
+        field!(self, ForwardsUOffset<Vector<'a, i32>>, 0)
+    }
+
+    /// `TensorType` enum value
+    pub fn tensor_type(&self) -> i8 {
+        field!(self, i8, 1, 0)
+    }
+
+    pub fn name(&self) -> Option<&'a str> {
+        field!(self, ForwardsUOffset<&str>, 3)
+    }
+
+    pub fn quantization(&self) -> Option<QuantizationParameters<'a>> {
+        field!(self, ForwardsUOffset<QuantizationParameters<'a>>, 4)
+    }
+}
+
+impl Verifiable for Tensor<'_> {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<ForwardsUOffset<Vector<i32>>>("shape", slot(0), false)?
+            .visit_field::<i8>("type", slot(1), false)?
+            .visit_field::<ForwardsUOffset<&str>>("name", slot(3), false)?
+            .visit_field::<ForwardsUOffset<QuantizationParameters>>("quantization", slot(4), false)?
+            .finish();
+        Ok(())
+    }
+}
+
+impl<'a> QuantizationParameters<'a> {
+    pub fn scale(&self) -> Option<Vector<'a, f32>> {
+        field!(self, ForwardsUOffset<Vector<'a, f32>>, 2)
+    }
+
+    pub fn zero_point(&self) -> Option<Vector<'a, i64>> {
+        field!(self, ForwardsUOffset<Vector<'a, i64>>, 3)
+    }
+
+    pub fn quantized_dimension(&self) -> i32 {
+        field!(self, i32, 6, 0)
+    }
+}
+
+impl Verifiable for QuantizationParameters<'_> {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<ForwardsUOffset<Vector<f32>>>("scale", slot(2), false)?
+            .visit_field::<ForwardsUOffset<Vector<i64>>>("zero_point", slot(3), false)?
+            .visit_field::<i32>("quantized_dimension", slot(6), false)?
+            .finish();
+        Ok(())
+    }
+}
+
+impl<'a> Operator<'a> {
+    pub fn opcode_index(&self) -> u32 {
+        field!(self, u32, 0, 0)
+    }
+
+    pub fn inputs(&self) -> Option<Vector<'a, i32>> {
+        field!(self, ForwardsUOffset<Vector<'a, i32>>, 1)
+    }
+
+    pub fn outputs(&self) -> Option<Vector<'a, i32>> {
+        field!(self, ForwardsUOffset<Vector<'a, i32>>, 2)
+    }
+
+    pub fn custom_options(&self) -> Option<Vector<'a, u8>> {
+        field!(self, ForwardsUOffset<Vector<'a, u8>>, 5)
+    }
+
+    /// Offset in the file of custom options too large for the flatbuffer
+    pub fn large_custom_options_offset(&self) -> u64 {
+        field!(self, u64, 9, 0)
+    }
+
+    pub fn large_custom_options_size(&self) -> u64 {
+        field!(self, u64, 10, 0)
+    }
+}
+
+impl Verifiable for Operator<'_> {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<u32>("opcode_index", slot(0), false)?
+            .visit_field::<ForwardsUOffset<Vector<i32>>>("inputs", slot(1), false)?
+            .visit_field::<ForwardsUOffset<Vector<i32>>>("outputs", slot(2), false)?
+            .visit_field::<ForwardsUOffset<Vector<u8>>>("custom_options", slot(5), false)?
+            .visit_field::<u64>("large_custom_options_offset", slot(9), false)?
+            .visit_field::<u64>("large_custom_options_size", slot(10), false)?
+            .finish();
+        Ok(())
+    }
+}
+
+impl<'a> Package<'a> {
+    pub fn min_runtime_version(&self) -> i32 {
+        field!(self, i32, 0, 0)
+    }
+
+    /// A serialized `MultiExecutable`
+    pub fn serialized_multi_executable(&self) -> Option<Vector<'a, u8>> {
+        field!(self, ForwardsUOffset<Vector<'a, u8>>, 1)
+    }
+
+    pub fn compiler_version(&self) -> Option<&'a str> {
+        field!(self, ForwardsUOffset<&str>, 4)
+    }
+
+    pub fn model_identifier(&self) -> Option<&'a str> {
+        field!(self, ForwardsUOffset<&str>, 6)
+    }
+}
+
+impl Verifiable for Package<'_> {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<i32>("min_runtime_version", slot(0), false)?
+            .visit_field::<ForwardsUOffset<Vector<u8>>>(
+                "serialized_multi_executable",
+                slot(1),
+                false,
+            )?
+            .visit_field::<ForwardsUOffset<&str>>("compiler_version", slot(4), false)?
+            .visit_field::<ForwardsUOffset<&str>>("model_identifier", slot(6), false)?
+            .finish();
+        Ok(())
+    }
+}
+
+impl<'a> MultiExecutable<'a> {
+    /// Serialized `Executable`s. The schema declares them as strings, they are read as bytes
+    /// since they are not UTF-8.
+    pub fn serialized_executables(&self) -> Option<Tables<'a, Vector<'a, u8>>> {
+        field!(self, ForwardsUOffset<Tables<'a, Vector<'a, u8>>>, 0)
+    }
+}
+
+impl Verifiable for MultiExecutable<'_> {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<ForwardsUOffset<Tables<Vector<u8>>>>(
+                "serialized_executables",
+                slot(0),
+                false,
+            )?
+            .finish();
+        Ok(())
+    }
+}
+
+/// `ExecutableType` of the DarwiNN schema
+pub const EXECUTABLE_STAND_ALONE: i16 = 0;
+pub const EXECUTABLE_PARAMETER_CACHING: i16 = 1;
+pub const EXECUTABLE_EXECUTION_ONLY: i16 = 2;
+
+impl<'a> Executable<'a> {
+    pub fn name(&self) -> Option<&'a str> {
+        field!(self, ForwardsUOffset<&str>, 1)
+    }
+
+    pub fn parameters(&self) -> Option<Vector<'a, u8>> {
+        field!(self, ForwardsUOffset<Vector<'a, u8>>, 6)
+    }
+
+    pub fn executable_type(&self) -> i16 {
+        field!(self, i16, 13, EXECUTABLE_STAND_ALONE)
+    }
+
+    pub fn parameter_caching_token(&self) -> u64 {
+        field!(self, u64, 14, 0)
+    }
+}
+
+impl Verifiable for Executable<'_> {
+    fn run_verifier(v: &mut Verifier, pos: usize) -> Result<(), InvalidFlatbuffer> {
+        v.visit_table(pos)?
+            .visit_field::<ForwardsUOffset<&str>>("name", slot(1), false)?
+            .visit_field::<ForwardsUOffset<Vector<u8>>>("parameters", slot(6), false)?
+            .visit_field::<i16>("type", slot(13), false)?
+            .visit_field::<u64>("parameter_caching_token", slot(14), false)?
+            .finish();
+        Ok(())
+    }
+}
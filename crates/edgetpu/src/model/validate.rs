@@ -0,0 +1,658 @@
+//! Create-time check of the models a container declares against the host.
+//!
+//! A container opts in by listing its models in the [`MODELS_ANNOTATION`] annotation, as
+//! comma separated glob patterns resolved in its root filesystem. Before the container is
+//! created, every model is parsed and checked against the host: the libedgetpu runtime must
+//! be recent enough for the compiled operators, and the container must have been given one
+//! Edge TPU per segment of the pipelined models. By default an incompatible container is not
+//! created, the [`MODEL_POLICY_ANNOTATION`] annotation can relax this to warnings.
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use libcontainer::oci_spec::runtime::Spec;
+use libcontainer::spec_modifier::{SpecModifier, SpecModifierError};
+use libedgetpu::backend::EdgeTpuBackend;
+use libedgetpu::driver::EdgeTpuDeviceManager;
+use nix::fcntl::{open, openat2, OFlag, OpenHow, ResolveFlag};
+use nix::sys::stat::{fstat, Mode, SFlag};
+
+use super::ModelInfo;
+use crate::discovery::{Discovery, DiscoveryError};
+
+/// Annotation listing the models of the container, e.g. `/app/model/*.tflite`
+pub const MODELS_ANNOTATION: &str = "edgetpu.coral.ai/models";
+/// Annotation setting the [`ModelPolicy`] of the container
+pub const MODEL_POLICY_ANNOTATION: &str = "edgetpu.coral.ai/model-policy";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ValidationError {
+    #[error("invalid {MODELS_ANNOTATION} pattern {pattern:?}")]
+    Pattern {
+        source: glob::PatternError,
+        pattern: String,
+    },
+    #[error("invalid {MODEL_POLICY_ANNOTATION} {0:?}, expected strict or permissive")]
+    Policy(String),
+    #[error("the container spec has no root filesystem")]
+    NoRootfs,
+    #[error("failed to resolve the root filesystem {path:?}")]
+    Rootfs {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error(transparent)]
+    Discovery(#[from] DiscoveryError),
+}
+
+type Result<T> = std::result::Result<T, ValidationError>;
+
+/// What to do with a container whose models do not fit the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ModelPolicy {
+    /// Refuse to create the container
+    #[default]
+    Strict,
+    /// Create the container and log the problems
+    Permissive,
+}
+
+impl FromStr for ModelPolicy {
+    type Err = ValidationError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "strict" => Ok(Self::Strict),
+            "permissive" => Ok(Self::Permissive),
+            _ => Err(ValidationError::Policy(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for ModelPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Strict => write!(f, "strict"),
+            Self::Permissive => write!(f, "permissive"),
+        }
+    }
+}
+
+/// Checks the models declared by containers. Run it after the injectors, so that the Edge
+/// TPUs given to the container are in the spec.
+///
+/// # Example
+///
+/// ```no_run
+/// use edgetpu::model::validate::ModelValidator;
+/// use libcontainer::oci_spec::runtime::Spec;
+///
+/// let spec = Spec::load("config.json").unwrap();
+/// for problem in ModelValidator::new().validate(&spec).unwrap() {
+///     eprintln!("{problem}");
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct ModelValidator {
+    discovery: Discovery,
+    /// Backend reporting the host runtime version, the libedgetpu singleton if unset
+    backend: Option<Arc<dyn EdgeTpuBackend>>,
+    policy: ModelPolicy,
+}
+
+impl ModelValidator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how the Edge TPUs given to the container are recognized
+    pub fn with_discovery(mut self, discovery: Discovery) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    /// Sets the backend reporting the host runtime version
+    pub fn with_backend(mut self, backend: impl EdgeTpuBackend + 'static) -> Self {
+        self.backend = Some(Arc::new(backend));
+        self
+    }
+
+    /// Sets the policy of the containers without [`MODEL_POLICY_ANNOTATION`]
+    pub fn with_policy(mut self, policy: ModelPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Returns what prevents the models of the container from running on this host, nothing
+    /// if the container declares no model.
+    pub fn validate(&self, spec: &Spec) -> Result<Vec<String>> {
+        let patterns = match models_annotation(spec) {
+            Some(patterns) => patterns,
+            None => return Ok(Vec::new()),
+        };
+        let rootfs = spec
+            .root()
+            .as_ref()
+            .ok_or(ValidationError::NoRootfs)?
+            .path();
+        let rootfs = rootfs
+            .canonicalize()
+            .map_err(|source| ValidationError::Rootfs {
+                source,
+                path: rootfs.clone(),
+            })?;
+
+        let mut problems = Vec::new();
+        let mut models = Vec::new();
+        for pattern in patterns {
+            let paths = resolve(&rootfs, pattern)?;
+            if paths.is_empty() {
+                problems.push(format!("no model matches {pattern}"));
+            }
+            for path in paths {
+                let in_container = Path::new("/").join(path.strip_prefix(&rootfs).unwrap_or(&path));
+                match ModelInfo::load(&path) {
+                    Ok(model) if model.edgetpu_ops.is_empty() => problems.push(format!(
+                        "{} is not compiled for the Edge TPU",
+                        in_container.display()
+                    )),
+                    Ok(model) => models.push((in_container, model)),
+                    Err(err) => {
+                        problems.push(format!("{}: {}", in_container.display(), error_chain(&err)))
+                    }
+                }
+            }
+        }
+        if models.is_empty() {
+            return Ok(problems);
+        }
+
+        match self.host_runtime_version() {
+            Ok(host_version) => {
+                for (path, model) in &models {
+                    let required = model.min_runtime_version().unwrap_or_default();
+                    if required > host_version {
+                        problems.push(format!(
+                            "{} needs libedgetpu runtime version {required}, the host has {host_version}",
+                            path.display()
+                        ));
+                    }
+                }
+            }
+            Err(reason) => problems.push(format!(
+                "the host libedgetpu runtime version is unknown: {reason}"
+            )),
+        }
+
+        let paths: Vec<&Path> = models.iter().map(|(path, _)| path.as_path()).collect();
+        let (required, reason) = required_tpus(&paths, &mut problems);
+        let allocated = self.allocated_tpus(spec)?;
+        if allocated < required {
+            problems.push(format!(
+                "{reason} needs {required} Edge TPUs, the container was given {allocated}"
+            ));
+        }
+
+        Ok(problems)
+    }
+
+    fn host_runtime_version(&self) -> std::result::Result<i32, String> {
+        let version = match &self.backend {
+            Some(backend) => backend.runtime_version(),
+            None => EdgeTpuDeviceManager::get_singleton()
+                .ok_or("libedgetpu could not be loaded")?
+                .runtime_version(),
+        }
+        .map_err(|err| err.to_string())?;
+        parse_runtime_version(&version)
+            .ok_or_else(|| format!("unexpected version string {version:?}"))
+    }
+
+    /// Number of the host Edge TPUs among the devices of the spec
+    fn allocated_tpus(&self, spec: &Spec) -> Result<usize> {
+        let tpus = self.discovery.discover()?;
+        let devices = spec
+            .linux()
+            .as_ref()
+            .and_then(|linux| linux.devices().as_ref());
+        Ok(devices
+            .into_iter()
+            .flatten()
+            .filter(|device| {
+                tpus.iter().any(|tpu| {
+                    i64::from(tpu.major) == device.major() && i64::from(tpu.minor) == device.minor()
+                })
+            })
+            .count())
+    }
+
+    fn policy(&self, spec: &Spec) -> Result<ModelPolicy> {
+        spec.annotations()
+            .as_ref()
+            .and_then(|annotations| annotations.get(MODEL_POLICY_ANNOTATION))
+            .map_or(Ok(self.policy), |policy| policy.parse())
+    }
+}
+
+impl SpecModifier for ModelValidator {
+    fn name(&self) -> &str {
+        "edgetpu-models"
+    }
+
+    fn modify(&self, spec: &mut Spec) -> std::result::Result<(), SpecModifierError> {
+        let modify_error = |err: ValidationError| SpecModifierError::Modify {
+            name: self.name().to_owned(),
+            source: Box::new(err),
+        };
+        let policy = self.policy(spec).map_err(modify_error)?;
+        let problems = self.validate(spec).map_err(modify_error)?;
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        match policy {
+            ModelPolicy::Strict => Err(SpecModifierError::Incompatible {
+                name: self.name().to_owned(),
+                reasons: problems,
+            }),
+            ModelPolicy::Permissive => {
+                for problem in problems {
+                    log::warn!("{problem}");
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn models_annotation(spec: &Spec) -> Option<Vec<&str>> {
+    let value = spec.annotations().as_ref()?.get(MODELS_ANNOTATION)?;
+    Some(
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+            .collect(),
+    )
+}
+
+/// Files of the root filesystem matching a pattern. The pattern is matched a component at
+/// a time and the symbolic links are resolved in the root filesystem, the way the container
+/// sees them, so that a link never leads to a host file.
+fn resolve(rootfs: &Path, pattern: &str) -> Result<Vec<PathBuf>> {
+    let root = open_root(rootfs).map_err(|source| ValidationError::Rootfs {
+        source: source.into(),
+        path: rootfs.to_owned(),
+    })?;
+
+    let mut candidates = vec![PathBuf::from("/")];
+    for component in pattern.split('/').filter(|component| !component.is_empty()) {
+        if component == "**" {
+            candidates = candidates
+                .iter()
+                .flat_map(|dir| descendants(&root, dir))
+                .collect();
+        } else if component.contains(['*', '?', '[']) {
+            let matcher =
+                glob::Pattern::new(component).map_err(|source| ValidationError::Pattern {
+                    source,
+                    pattern: pattern.to_owned(),
+                })?;
+            candidates = candidates
+                .iter()
+                .flat_map(|dir| {
+                    entries(&root, dir)
+                        .into_iter()
+                        .filter(|(name, _)| matcher.matches(&name.to_string_lossy()))
+                        .map(|(name, _)| dir.join(name))
+                })
+                .collect();
+        } else {
+            for candidate in &mut candidates {
+                candidate.push(component);
+            }
+        }
+    }
+
+    Ok(candidates
+        .iter()
+        .filter_map(|path| resolve_file(&root, path))
+        .collect())
+}
+
+fn open_root(rootfs: &Path) -> nix::Result<OwnedFd> {
+    let fd = open(
+        rootfs,
+        OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    // SAFETY: the descriptor was just opened and is owned by nothing else
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Opens `path` of the root filesystem, resolving its symbolic links in it.
+fn open_in_root(root: &OwnedFd, path: &Path, flags: OFlag) -> nix::Result<OwnedFd> {
+    let how = OpenHow::new()
+        .flags(flags | OFlag::O_CLOEXEC)
+        .resolve(ResolveFlag::RESOLVE_IN_ROOT);
+    let fd = openat2(root.as_raw_fd(), path, how)?;
+    // SAFETY: the descriptor was just opened and is owned by nothing else
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Names of the entries of the directory `dir`, sorted, and whether they are directories.
+/// Nothing if it cannot be read.
+fn entries(root: &OwnedFd, dir: &Path) -> Vec<(OsString, bool)> {
+    let Ok(fd) = open_in_root(root, dir, OFlag::O_RDONLY | OFlag::O_DIRECTORY) else {
+        return Vec::new();
+    };
+    let Ok(entries) = fs::read_dir(format!("/proc/self/fd/{}", fd.as_raw_fd())) else {
+        return Vec::new();
+    };
+    let mut entries: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let is_dir = entry.file_type().is_ok_and(|kind| kind.is_dir());
+            (entry.file_name(), is_dir)
+        })
+        .collect();
+    entries.sort();
+    entries
+}
+
+/// `dir` and its subdirectories, for `**`. Linked directories are not walked into.
+fn descendants(root: &OwnedFd, dir: &Path) -> Vec<PathBuf> {
+    let mut found = vec![dir.to_owned()];
+    for (name, is_dir) in entries(root, dir) {
+        if is_dir {
+            found.extend(descendants(root, &dir.join(name)));
+        }
+    }
+    found
+}
+
+/// Host path of the regular file `path` of the root filesystem.
+fn resolve_file(root: &OwnedFd, path: &Path) -> Option<PathBuf> {
+    let fd = open_in_root(root, path, OFlag::O_PATH).ok()?;
+    let stat = fstat(fd.as_raw_fd()).ok()?;
+    if SFlag::from_bits_truncate(stat.st_mode & SFlag::S_IFMT.bits()) != SFlag::S_IFREG {
+        return None;
+    }
+    fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd())).ok()
+}
+
+/// Parses the version out of `BuildLabel(...), RuntimeVersion(14)`
+pub fn parse_runtime_version(version: &str) -> Option<i32> {
+    let (_, rest) = version.split_once("RuntimeVersion(")?;
+    let (number, _) = rest.split_once(')')?;
+    number.trim().parse().ok()
+}
+
+/// Segment of a pipelined model, named `<model>_segment_<index>_of_<count>_edgetpu.tflite` by
+/// the Edge TPU compiler
+fn segment_of(path: &Path) -> Option<(String, usize, usize)> {
+    let name = path.file_name()?.to_str()?;
+    let (model, rest) = name.rsplit_once("_segment_")?;
+    let (index, rest) = rest.split_once("_of_")?;
+    let (count, _) = rest.split_once('_')?;
+    let model = path.with_file_name(model).to_string_lossy().into_owned();
+    Some((model, index.parse().ok()?, count.parse().ok()?))
+}
+
+/// Number of Edge TPUs the models need, and which model needs them. Models compiled
+/// together share a TPU, while each segment of a pipelined model runs on its own.
+fn required_tpus(paths: &[&Path], problems: &mut Vec<String>) -> (usize, String) {
+    let mut pipelines: Vec<(String, usize, Vec<usize>)> = Vec::new();
+    for path in paths {
+        let Some((model, index, count)) = segment_of(path) else {
+            continue;
+        };
+        match pipelines.iter_mut().find(|(name, _, _)| *name == model) {
+            Some((_, _, indices)) => indices.push(index),
+            None => pipelines.push((model, count, vec![index])),
+        }
+    }
+
+    let mut required = (1, "the models".to_owned());
+    for (model, count, mut indices) in pipelines {
+        indices.sort_unstable();
+        indices.dedup();
+        if indices.len() != count {
+            problems.push(format!(
+                "the pipelined model {model} has {} of its {count} segments",
+                indices.len()
+            ));
+        }
+        if count > required.0 {
+            required = (count, format!("the pipelined model {model}"));
+        }
+    }
+    required
+}
+
+fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(&format!(": {err}"));
+        source = err.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use libcontainer::oci_spec::runtime::RootBuilder;
+    use libedgetpu::backend::MockBackend;
+
+    use super::*;
+    use crate::discovery::fixture::add_apex;
+    use crate::injection::edits;
+    use crate::model::fixture::{model, package};
+
+    struct Host {
+        sysfs: tempfile::TempDir,
+        rootfs: tempfile::TempDir,
+    }
+
+    impl Host {
+        fn new(tpus: u32) -> Self {
+            let host = Self {
+                sysfs: tempfile::tempdir().unwrap(),
+                rootfs: tempfile::tempdir().unwrap(),
+            };
+            for index in 0..tpus {
+                add_apex(
+                    host.sysfs.path(),
+                    index,
+                    &format!("0000:0{}:00.0", index + 1),
+                );
+            }
+            host
+        }
+
+        fn add_model(&self, name: &str, min_runtime_version: i32) {
+            let path = self.rootfs.path().join("app/model").join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, model(&package(min_runtime_version, 64, 64))).unwrap();
+        }
+
+        /// A spec declaring `models` and given the first `tpus` TPUs
+        fn spec(&self, models: &str, tpus: u32) -> Spec {
+            let mut spec = Spec::default();
+            spec.set_root(Some(
+                RootBuilder::default()
+                    .path(self.rootfs.path())
+                    .build()
+                    .unwrap(),
+            ));
+            spec.set_annotations(Some(HashMap::from([(
+                MODELS_ANNOTATION.to_owned(),
+                models.to_owned(),
+            )])));
+            for index in 0..tpus {
+                let path = PathBuf::from(format!("/dev/apex_{index}"));
+                edits::add_device(&mut spec, &path, 120, index.into()).unwrap();
+            }
+            spec
+        }
+
+        fn validator(&self, runtime_version: &str) -> ModelValidator {
+            ModelValidator::new()
+                .with_discovery(Discovery::new().with_sysfs_root(self.sysfs.path()))
+                .with_backend(MockBackend::new().with_runtime_version(runtime_version))
+        }
+    }
+
+    #[test]
+    fn test_no_declared_model() {
+        let host = Host::new(1);
+        let mut spec = host.spec("", 0);
+        spec.set_annotations(None);
+        assert!(host
+            .validator("RuntimeVersion(14)")
+            .validate(&spec)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_runtime_version() {
+        let host = Host::new(1);
+        host.add_model("classify_edgetpu.tflite", 14);
+        let spec = host.spec("/app/model/*.tflite", 1);
+
+        assert!(host
+            .validator("BuildLabel(x), RuntimeVersion(14)")
+            .validate(&spec)
+            .unwrap()
+            .is_empty());
+
+        let problems = host
+            .validator("BuildLabel(x), RuntimeVersion(13)")
+            .validate(&spec)
+            .unwrap();
+        assert_eq!(
+            problems,
+            vec![
+                "/app/model/classify_edgetpu.tflite needs libedgetpu runtime version 14, the host has 13"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pipelined_model_needs_a_tpu_per_segment() {
+        let host = Host::new(3);
+        for index in 0..3 {
+            host.add_model(
+                &format!("inception_segment_{index}_of_3_edgetpu.tflite"),
+                13,
+            );
+        }
+        let validator = host.validator("RuntimeVersion(14)");
+
+        let problems = validator
+            .validate(&host.spec("/app/model/*.tflite", 2))
+            .unwrap();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].ends_with("needs 3 Edge TPUs, the container was given 2"));
+
+        assert!(validator
+            .validate(&host.spec("/app/model/*.tflite", 3))
+            .unwrap()
+            .is_empty());
+
+        let problems = validator
+            .validate(&host.spec("/app/model/inception_segment_0*", 3))
+            .unwrap();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("has 1 of its 3 segments"));
+    }
+
+    #[test]
+    fn test_unusable_models() {
+        let host = Host::new(1);
+        fs::create_dir_all(host.rootfs.path().join("app/model")).unwrap();
+        fs::write(host.rootfs.path().join("app/model/labels.txt"), "bird").unwrap();
+        std::os::unix::fs::symlink("/etc/hostname", host.rootfs.path().join("app/host")).unwrap();
+
+        let problems = host
+            .validator("RuntimeVersion(14)")
+            .validate(&host.spec("/app/model/*.txt, /app/host, /missing/*.tflite", 1))
+            .unwrap();
+        assert_eq!(problems.len(), 3, "{problems:?}");
+        assert!(problems[0].starts_with("/app/model/labels.txt: not a TFLite model"));
+        assert_eq!(problems[1], "no model matches /app/host");
+        assert_eq!(problems[2], "no model matches /missing/*.tflite");
+    }
+
+    #[test]
+    fn test_links_resolve_in_the_rootfs() {
+        let host = Host::new(1);
+        host.add_model("classify_edgetpu.tflite", 14);
+        let rootfs = host.rootfs.path();
+        std::os::unix::fs::symlink(
+            "/app/model/classify_edgetpu.tflite",
+            rootfs.join("app/current.tflite"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("/app/model", rootfs.join("app/latest")).unwrap();
+
+        let problems = host
+            .validator("RuntimeVersion(13)")
+            .validate(&host.spec("/app/current.tflite, /app/latest/*.tflite", 1))
+            .unwrap();
+        let expected = "/app/model/classify_edgetpu.tflite needs libedgetpu runtime version 14, \
+                        the host has 13";
+        assert_eq!(problems, vec![expected, expected]);
+
+        let problems = host
+            .validator("RuntimeVersion(13)")
+            .validate(&host.spec("/**/*_edgetpu.tflite", 1))
+            .unwrap();
+        assert_eq!(problems, vec![expected]);
+    }
+
+    #[test]
+    fn test_policy() {
+        let host = Host::new(1);
+        host.add_model("classify_edgetpu.tflite", 15);
+        let mut spec = host.spec("/app/model/*.tflite", 1);
+        let validator = host.validator("RuntimeVersion(14)");
+
+        assert!(matches!(
+            validator.modify(&mut spec),
+            Err(SpecModifierError::Incompatible { .. })
+        ));
+        assert!(validator
+            .clone()
+            .with_policy(ModelPolicy::Permissive)
+            .modify(&mut spec)
+            .is_ok());
+
+        spec.annotations_mut()
+            .as_mut()
+            .unwrap()
+            .insert(MODEL_POLICY_ANNOTATION.to_owned(), "permissive".to_owned());
+        assert!(validator.modify(&mut spec).is_ok());
+    }
+
+    #[test]
+    fn test_parse_runtime_version() {
+        assert_eq!(
+            parse_runtime_version(
+                "BuildLabel(COMPILER=6.3.0 20170516,DATE=redacted), RuntimeVersion(14)"
+            ),
+            Some(14)
+        );
+        assert_eq!(parse_runtime_version("RuntimeVersion()"), None);
+        assert_eq!(parse_runtime_version("unknown"), None);
+    }
+}
@@ -0,0 +1,249 @@
+//! Resets an Edge TPU by unbinding it from its driver and binding it again through sysfs.
+//!
+//! The apex driver reinitializes a PCIe/M.2 accelerator when it binds to it, and binding a
+//! USB accelerator to the `usb` driver again makes the kernel configure it anew. The device
+//! can come back under another name, e.g. `apex_1` once `apex_0` was taken meanwhile, so it
+//! is found again by its bus path.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::discovery::{DeviceType, Discovery, DiscoveryError, EdgeTpuDevice};
+
+/// Type of the events recorded when an Edge TPU is reset
+pub const RESET_EVENT: &str = "edgetpu-reset";
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Times binding an unbound device is tried before giving up
+const BIND_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ResetError {
+    #[error("{0} is not bound to a driver")]
+    Unbound(String),
+    #[error("failed to write {path:?}")]
+    Write {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    /// The device was unbound and is left without a driver
+    #[error("{device} is left unbound, failed to bind it to {driver:?} again")]
+    Rebind {
+        source: std::io::Error,
+        device: String,
+        driver: PathBuf,
+    },
+    #[error(transparent)]
+    Discovery(#[from] DiscoveryError),
+    #[error("{device} did not come back within {timeout:?}")]
+    Timeout { device: String, timeout: Duration },
+}
+
+/// Resets Edge TPUs.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use edgetpu::discovery::Discovery;
+/// use edgetpu::reset::DeviceReset;
+///
+/// let discovery = Discovery::new();
+/// let device = discovery.discover().unwrap().remove(0);
+/// let device = DeviceReset::new(discovery)
+///     .with_timeout(Duration::from_secs(30))
+///     .reset(&device)
+///     .unwrap();
+/// println!("{} is back", device.name());
+/// ```
+#[derive(Debug, Clone)]
+pub struct DeviceReset {
+    discovery: Discovery,
+    timeout: Duration,
+}
+
+impl DeviceReset {
+    pub fn new(discovery: Discovery) -> Self {
+        Self {
+            discovery,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Sets how long to wait for the device to come back
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Unbinds the device from its driver, binds it again and returns it once it is
+    /// discovered again.
+    pub fn reset(&self, device: &EdgeTpuDevice) -> Result<EdgeTpuDevice, ResetError> {
+        let bus = match device.device_type() {
+            DeviceType::ApexPCI => "bus/pci/devices",
+            DeviceType::ApexUSB => "bus/usb/devices",
+        };
+        let device_dir = self.discovery.sysfs_root().join(bus).join(&device.bus_path);
+        let driver = fs::canonicalize(device_dir.join("driver"))
+            .map_err(|_| ResetError::Unbound(device.name()))?;
+
+        log::info!("resetting {} through {}", device.name(), driver.display());
+        write(&driver.join("unbind"), &device.bus_path)?;
+        let mut attempt = 1;
+        while let Err(source) = fs::write(driver.join("bind"), &device.bus_path) {
+            if attempt == BIND_ATTEMPTS {
+                return Err(ResetError::Rebind {
+                    source,
+                    device: device.name(),
+                    driver,
+                });
+            }
+            log::warn!("failed to bind {} again: {source}", device.name());
+            attempt += 1;
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let found = self.discovery.discover()?.into_iter().find(|found| {
+                found.device_type() == device.device_type() && found.bus_path == device.bus_path
+            });
+            match found {
+                Some(found) if device_dir.join("driver").exists() => return Ok(found),
+                _ if Instant::now() >= deadline => {
+                    return Err(ResetError::Timeout {
+                        device: device.name(),
+                        timeout: self.timeout,
+                    })
+                }
+                _ => thread::sleep(POLL_INTERVAL),
+            }
+        }
+    }
+}
+
+fn write(path: &Path, value: &str) -> Result<(), ResetError> {
+    fs::write(path, value).map_err(|source| ResetError::Write {
+        source,
+        path: path.to_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use crate::discovery::fixture::{add_apex, add_usb, write as write_attribute};
+
+    /// Binds the device in `device_dir` to the driver `driver` of the bus
+    fn bind(root: &Path, device_dir: &str, driver: &str) {
+        write_attribute(root, &format!("{driver}/bind"), "");
+        write_attribute(root, &format!("{driver}/unbind"), "");
+        symlink(root.join(driver), root.join(device_dir).join("driver")).unwrap();
+    }
+
+    #[test]
+    fn test_reset() {
+        let tmp = tempfile::tempdir().unwrap();
+        add_apex(tmp.path(), 0, "0000:01:00.0");
+        add_usb(tmp.path(), "2-1", ("18d1", "9302"), 2, 3, "f00d");
+        bind(
+            tmp.path(),
+            "bus/pci/devices/0000:01:00.0",
+            "bus/pci/drivers/apex",
+        );
+        bind(tmp.path(), "bus/usb/devices/2-1", "bus/usb/drivers/usb");
+        let discovery = Discovery::new().with_sysfs_root(tmp.path());
+        let devices = discovery.discover().unwrap();
+        let reset = DeviceReset::new(discovery);
+
+        for (device, driver) in devices
+            .iter()
+            .zip(["bus/pci/drivers/apex", "bus/usb/drivers/usb"])
+        {
+            assert_eq!(reset.reset(device).unwrap(), *device);
+            for file in ["unbind", "bind"] {
+                let written = fs::read_to_string(tmp.path().join(driver).join(file)).unwrap();
+                assert_eq!(written, device.bus_path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_wait_for_the_device() {
+        let tmp = tempfile::tempdir().unwrap();
+        add_apex(tmp.path(), 0, "0000:01:00.0");
+        bind(
+            tmp.path(),
+            "bus/pci/devices/0000:01:00.0",
+            "bus/pci/drivers/apex",
+        );
+        let discovery = Discovery::new().with_sysfs_root(tmp.path());
+        let device = discovery.discover().unwrap().remove(0);
+        // the driver has not created the class device yet
+        fs::remove_dir_all(tmp.path().join("class/apex/apex_0")).unwrap();
+
+        let reset = DeviceReset::new(discovery);
+        let err = reset
+            .clone()
+            .with_timeout(Duration::from_millis(10))
+            .reset(&device)
+            .unwrap_err();
+        assert!(matches!(err, ResetError::Timeout { .. }), "{err}");
+
+        // it comes back as apex_1
+        let root = tmp.path().to_owned();
+        let driver = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            write_attribute(&root, "class/apex/apex_1/dev", "120:1\n");
+            symlink(
+                root.join("bus/pci/devices/0000:01:00.0"),
+                root.join("class/apex/apex_1/device"),
+            )
+            .unwrap();
+        });
+        let device = reset.reset(&device).unwrap();
+        driver.join().unwrap();
+        assert_eq!(device.name(), "apex_1");
+        assert_eq!(device.bus_path, "0000:01:00.0");
+    }
+
+    #[test]
+    fn test_failed_bind() {
+        let tmp = tempfile::tempdir().unwrap();
+        add_apex(tmp.path(), 0, "0000:01:00.0");
+        bind(
+            tmp.path(),
+            "bus/pci/devices/0000:01:00.0",
+            "bus/pci/drivers/apex",
+        );
+        // a directory cannot be written, even by root
+        let bind_attribute = tmp.path().join("bus/pci/drivers/apex/bind");
+        fs::remove_file(&bind_attribute).unwrap();
+        fs::create_dir(&bind_attribute).unwrap();
+        let discovery = Discovery::new().with_sysfs_root(tmp.path());
+        let device = discovery.discover().unwrap().remove(0);
+
+        let err = DeviceReset::new(discovery).reset(&device).unwrap_err();
+        assert!(
+            matches!(err, ResetError::Rebind { ref device, .. } if device == "apex_0"),
+            "{err}"
+        );
+        let unbound = tmp.path().join("bus/pci/drivers/apex/unbind");
+        assert_eq!(fs::read_to_string(unbound).unwrap(), "0000:01:00.0");
+    }
+
+    #[test]
+    fn test_unbound_device() {
+        let tmp = tempfile::tempdir().unwrap();
+        add_usb(tmp.path(), "2-1", ("1a6e", "089a"), 2, 3, "");
+        let discovery = Discovery::new().with_sysfs_root(tmp.path());
+        let device = discovery.discover().unwrap().remove(0);
+        let err = DeviceReset::new(discovery).reset(&device).unwrap_err();
+        assert!(matches!(err, ResetError::Unbound(name) if name == "usb-2-1"));
+    }
+}
@@ -0,0 +1,506 @@
+//! Thermal throttling of the PCIe/M.2 Edge TPUs.
+//!
+//! The [`ThermalLimit`] of the node policy sets two thresholds. From `maxTemperature`, the
+//! warning level, new containers only get the reduced frequency runtime, which the
+//! [`FrequencyPolicy`](crate::image::frequency::FrequencyPolicy) enforces when they are
+//! created. From `criticalTemperature`, the [`ThermalGovernor`] pauses the containers leasing
+//! the device, and resumes them once it cooled down below `resumeTemperature`. Every change
+//! is recorded as an event.
+mod telemetry;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use libcontainer::container::Container;
+use libcontainer::lease::{LeaseError, LeaseLedger};
+use serde::{Deserialize, Serialize};
+pub use telemetry::ApexTelemetry;
+
+use crate::discovery::{Discovery, DiscoveryError};
+use crate::events::{Event, EventError, EventLog};
+use crate::image::frequency::ThermalLimit;
+
+/// Type of the events of the governor
+pub const THERMAL_EVENT: &str = "edgetpu-thermal";
+const STATE_FILE: &str = "device-thermal.json";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ThermalError {
+    #[error("failed to access the thermal state {path:?}")]
+    Io {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("corrupted thermal state {path:?}")]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+    #[error(transparent)]
+    Discovery(#[from] DiscoveryError),
+    #[error(transparent)]
+    Lease(#[from] LeaseError),
+    #[error(transparent)]
+    Event(#[from] EventError),
+}
+
+type Result<T> = std::result::Result<T, ThermalError>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThermalLevel {
+    #[default]
+    Normal,
+    /// New containers do not get the maximum frequency
+    Warning,
+    /// The containers of the device are paused
+    Critical,
+}
+
+impl fmt::Display for ThermalLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = match self {
+            Self::Normal => "normal",
+            Self::Warning => "warning",
+            Self::Critical => "critical",
+        };
+        f.write_str(level)
+    }
+}
+
+/// Pauses and resumes containers. Fakes stand in for it to test the governor.
+pub trait ContainerControl {
+    fn pause(&self, container_id: &str) -> std::result::Result<(), String>;
+
+    fn resume(&self, container_id: &str) -> std::result::Result<(), String>;
+}
+
+/// Pauses and resumes the containers of a runtime root with the freezer cgroup.
+#[derive(Debug, Clone)]
+pub struct RuntimeContainers {
+    root: PathBuf,
+}
+
+impl RuntimeContainers {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl ContainerControl for RuntimeContainers {
+    fn pause(&self, container_id: &str) -> std::result::Result<(), String> {
+        Container::load(self.root.join(container_id))
+            .and_then(|mut container| container.pause())
+            .map_err(|err| err.to_string())
+    }
+
+    fn resume(&self, container_id: &str) -> std::result::Result<(), String> {
+        Container::load(self.root.join(container_id))
+            .and_then(|mut container| container.resume())
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// What the governor remembers of a device between two steps.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceThermal {
+    pub level: ThermalLevel,
+    /// Containers the governor paused, to be resumed once the device cooled down
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub paused: Vec<String>,
+}
+
+/// Applies the thermal limit to the Edge TPUs, one [`step`](Self::step) at a time.
+///
+/// The state of the devices is kept in the runtime root, so that a restarted governor still
+/// resumes the containers it paused. Only one governor should run per root.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use edgetpu::image::frequency::ThermalLimit;
+/// use edgetpu::thermal::ThermalGovernor;
+///
+/// let limit = ThermalLimit::new(80.0).with_critical_temperature(95.0, Some(85.0));
+/// let governor = ThermalGovernor::new("/run/youki", limit);
+/// loop {
+///     governor.step().unwrap();
+///     std::thread::sleep(Duration::from_secs(5));
+/// }
+/// ```
+pub struct ThermalGovernor {
+    root: PathBuf,
+    limit: ThermalLimit,
+    discovery: Discovery,
+    leases: LeaseLedger,
+    events: EventLog,
+    control: Box<dyn ContainerControl>,
+}
+
+impl ThermalGovernor {
+    /// Creates a governor of the containers of the runtime root
+    pub fn new<P: Into<PathBuf>>(root: P, limit: ThermalLimit) -> Self {
+        let root = root.into();
+        Self {
+            limit,
+            discovery: Discovery::new(),
+            leases: LeaseLedger::new(&root),
+            events: EventLog::new(&root),
+            control: Box::new(RuntimeContainers::new(&root)),
+            root,
+        }
+    }
+
+    pub fn with_discovery(mut self, discovery: Discovery) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    /// Sets how the containers are paused and resumed
+    pub fn with_control(mut self, control: impl ContainerControl + 'static) -> Self {
+        self.control = Box::new(control);
+        self
+    }
+
+    /// Returns the state of the devices, by name.
+    pub fn devices(&self) -> Result<BTreeMap<String, DeviceThermal>> {
+        let path = self.state_path();
+        match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .map_err(|source| ThermalError::Parse { source, path }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(source) => Err(ThermalError::Io { source, path }),
+        }
+    }
+
+    /// Reads the temperature of every apex, pauses or resumes the containers of those
+    /// crossing a threshold and returns the events it recorded.
+    pub fn step(&self) -> Result<Vec<Event>> {
+        let mut devices = self.devices()?;
+        let leases = self.leases.leases()?;
+        let mut events = Vec::new();
+        for device in self.discovery.pci_devices()? {
+            let name = device.name();
+            let Some(telemetry) = ApexTelemetry::read(self.discovery.sysfs_root(), &name) else {
+                continue;
+            };
+            let state = devices.entry(name.clone()).or_default();
+            let previous = state.level;
+            let level = self.level(telemetry.temperature, previous);
+
+            let mut paused = Vec::new();
+            let mut resumed = Vec::new();
+            if level == ThermalLevel::Critical {
+                for lease in leases.iter().filter(|lease| lease.device == name) {
+                    if state.paused.contains(&lease.container_id) {
+                        continue;
+                    }
+                    match self.control.pause(&lease.container_id) {
+                        Ok(()) => {
+                            state.paused.push(lease.container_id.clone());
+                            paused.push(lease.container_id.clone());
+                        }
+                        Err(err) => {
+                            log::warn!("failed to pause container {}: {err}", lease.container_id)
+                        }
+                    }
+                }
+            } else {
+                // the containers that failed to resume are retried at the next step, unless
+                // they were deleted meanwhile
+                state.paused.retain(|container_id| {
+                    let Err(err) = self.control.resume(container_id) else {
+                        resumed.push(container_id.clone());
+                        return false;
+                    };
+                    log::warn!("failed to resume container {container_id}: {err}");
+                    leases
+                        .iter()
+                        .any(|lease| lease.device == name && lease.container_id == *container_id)
+                });
+            }
+            state.level = level;
+
+            if level != previous || !paused.is_empty() || !resumed.is_empty() {
+                let mut data = serde_json::json!({
+                    "level": level,
+                    "previous": previous,
+                    "temperature": telemetry.temperature,
+                });
+                if !paused.is_empty() {
+                    data["paused"] = serde_json::json!(paused);
+                }
+                if !resumed.is_empty() {
+                    data["resumed"] = serde_json::json!(resumed);
+                }
+                let event = Event::new(THERMAL_EVENT, name, data);
+                self.events.emit(&event)?;
+                events.push(event);
+            }
+        }
+        self.save(&devices)?;
+        Ok(events)
+    }
+
+    /// The level of a device at `temperature` °C. A critical device stays critical until it
+    /// cools down below the resume temperature.
+    fn level(&self, temperature: f64, previous: ThermalLevel) -> ThermalLevel {
+        match self.limit.critical_temperature {
+            Some(critical) if temperature >= critical => return ThermalLevel::Critical,
+            Some(_)
+                if previous == ThermalLevel::Critical
+                    && temperature >= self.limit.resume_temperature() =>
+            {
+                return ThermalLevel::Critical
+            }
+            _ => {}
+        }
+        if temperature >= self.limit.max_temperature {
+            ThermalLevel::Warning
+        } else {
+            ThermalLevel::Normal
+        }
+    }
+
+    fn save(&self, devices: &BTreeMap<String, DeviceThermal>) -> Result<()> {
+        let path = self.state_path();
+        let tmp = path.with_extension("json.tmp");
+        let content = serde_json::to_vec(devices).map_err(|source| ThermalError::Parse {
+            source,
+            path: path.clone(),
+        })?;
+        fs::write(&tmp, content)
+            .and_then(|_| fs::rename(&tmp, &path))
+            .map_err(|source| ThermalError::Io { source, path })
+    }
+
+    fn state_path(&self) -> PathBuf {
+        self.root.join(STATE_FILE)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod fixture {
+    use std::cell::RefCell;
+    use std::path::Path;
+    use std::rc::Rc;
+
+    use super::ContainerControl;
+    use crate::discovery::fixture::write;
+
+    /// Sets the temperature of `apex_<index>` in millidegrees Celsius
+    pub fn set_temperature(sysfs: &Path, index: u32, millidegrees: i64) {
+        write(
+            sysfs,
+            &format!("class/apex/apex_{index}/temp"),
+            &format!("{millidegrees}\n"),
+        );
+    }
+
+    /// Records the containers paused and resumed, failing those in `fail`
+    #[derive(Clone, Default)]
+    pub struct FakeControl {
+        pub fail: Vec<String>,
+        pub calls: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl FakeControl {
+        fn call(&self, action: &str, container_id: &str) -> Result<(), String> {
+            if self.fail.iter().any(|id| id == container_id) {
+                return Err("container is stopped".to_owned());
+            }
+            self.calls
+                .borrow_mut()
+                .push(format!("{action} {container_id}"));
+            Ok(())
+        }
+    }
+
+    impl ContainerControl for FakeControl {
+        fn pause(&self, container_id: &str) -> Result<(), String> {
+            self.call("pause", container_id)
+        }
+
+        fn resume(&self, container_id: &str) -> Result<(), String> {
+            self.call("resume", container_id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::{set_temperature, FakeControl};
+    use super::*;
+    use crate::discovery::fixture::add_apex;
+
+    struct Host {
+        sysfs: tempfile::TempDir,
+        root: tempfile::TempDir,
+        control: FakeControl,
+    }
+
+    impl Host {
+        fn new() -> Self {
+            let host = Self {
+                sysfs: tempfile::tempdir().unwrap(),
+                root: tempfile::tempdir().unwrap(),
+                control: FakeControl::default(),
+            };
+            add_apex(host.sysfs.path(), 0, "0000:01:00.0");
+            add_apex(host.sysfs.path(), 1, "0000:02:00.0");
+            let leases = LeaseLedger::new(host.root.path());
+            for container_id in ["inference", "camera", "other"] {
+                std::fs::create_dir(host.root.path().join(container_id)).unwrap();
+            }
+            leases.acquire("inference", &["apex_0"], true).unwrap();
+            leases.acquire("camera", &["apex_0"], true).unwrap();
+            leases.acquire("other", &["apex_1"], false).unwrap();
+            host
+        }
+
+        fn governor(&self) -> ThermalGovernor {
+            let limit = ThermalLimit::new(80.0).with_critical_temperature(95.0, Some(85.0));
+            ThermalGovernor::new(self.root.path(), limit)
+                .with_discovery(Discovery::new().with_sysfs_root(self.sysfs.path()))
+                .with_control(self.control.clone())
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.control.calls.borrow_mut().drain(..).collect()
+        }
+    }
+
+    fn levels(events: &[Event]) -> Vec<(&str, &str)> {
+        events
+            .iter()
+            .map(|event| (event.id.as_str(), event.data["level"].as_str().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_script() {
+        let host = Host::new();
+        let governor = host.governor();
+        set_temperature(host.sysfs.path(), 1, 40_000);
+
+        // millidegrees of apex_0, the level it ends at and the containers paused or resumed
+        let script: [(i64, Option<&str>, &[&str]); 7] = [
+            (60_000, None, &[]),
+            (82_000, Some("warning"), &[]),
+            (
+                96_500,
+                Some("critical"),
+                &["pause inference", "pause camera"],
+            ),
+            // still too hot to resume
+            (88_000, None, &[]),
+            (
+                84_000,
+                Some("warning"),
+                &["resume inference", "resume camera"],
+            ),
+            (
+                97_000,
+                Some("critical"),
+                &["pause inference", "pause camera"],
+            ),
+            (
+                70_000,
+                Some("normal"),
+                &["resume inference", "resume camera"],
+            ),
+        ];
+        for (millidegrees, level, calls) in script {
+            set_temperature(host.sysfs.path(), 0, millidegrees);
+            let events = governor.step().unwrap();
+            let expected: Vec<_> = level.map(|level| ("apex_0", level)).into_iter().collect();
+            assert_eq!(levels(&events), expected, "at {millidegrees}");
+            assert_eq!(host.calls(), calls, "at {millidegrees}");
+        }
+
+        let recorded = EventLog::new(host.root.path()).read().unwrap();
+        assert_eq!(recorded.len(), 5);
+        assert_eq!(
+            recorded[1].data["paused"],
+            serde_json::json!(["inference", "camera"])
+        );
+        assert_eq!(recorded[1].data["temperature"], 96.5);
+        assert_eq!(recorded[2].data["previous"], "critical");
+    }
+
+    #[test]
+    fn test_resume_after_restart() {
+        let host = Host::new();
+        set_temperature(host.sysfs.path(), 1, 99_000);
+        host.governor().step().unwrap();
+        assert_eq!(host.calls(), vec!["pause other"]);
+        assert_eq!(
+            host.governor().devices().unwrap()["apex_1"].paused,
+            vec!["other"]
+        );
+
+        // another governor resumes the containers the first one paused
+        set_temperature(host.sysfs.path(), 1, 50_000);
+        let events = host.governor().step().unwrap();
+        assert_eq!(levels(&events), vec![("apex_1", "normal")]);
+        assert_eq!(host.calls(), vec!["resume other"]);
+    }
+
+    #[test]
+    fn test_pause_failures_are_retried() {
+        let mut host = Host::new();
+        host.control.fail = vec!["camera".to_owned()];
+        set_temperature(host.sysfs.path(), 0, 95_000);
+        let events = host.governor().step().unwrap();
+        assert_eq!(events[0].data["paused"], serde_json::json!(["inference"]));
+
+        host.control.fail.clear();
+        let governor = host.governor();
+        let events = governor.step().unwrap();
+        assert_eq!(levels(&events), vec![("apex_0", "critical")]);
+        assert_eq!(events[0].data["paused"], serde_json::json!(["camera"]));
+        assert!(governor.step().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_resume_failures_are_retried() {
+        let mut host = Host::new();
+        set_temperature(host.sysfs.path(), 0, 96_000);
+        host.governor().step().unwrap();
+        assert_eq!(host.calls(), vec!["pause inference", "pause camera"]);
+
+        host.control.fail = vec!["camera".to_owned()];
+        set_temperature(host.sysfs.path(), 0, 60_000);
+        let events = host.governor().step().unwrap();
+        assert_eq!(events[0].data["resumed"], serde_json::json!(["inference"]));
+        assert_eq!(
+            host.governor().devices().unwrap()["apex_0"].paused,
+            vec!["camera"]
+        );
+
+        host.control.fail.clear();
+        let events = host.governor().step().unwrap();
+        assert_eq!(events[0].data["resumed"], serde_json::json!(["camera"]));
+        assert!(host.governor().devices().unwrap()["apex_0"]
+            .paused
+            .is_empty());
+        assert_eq!(host.calls(), vec!["resume inference", "resume camera"]);
+    }
+
+    #[test]
+    fn test_without_critical_temperature() {
+        let host = Host::new();
+        set_temperature(host.sysfs.path(), 0, 120_000);
+        let governor = ThermalGovernor::new(host.root.path(), ThermalLimit::new(80.0))
+            .with_discovery(Discovery::new().with_sysfs_root(host.sysfs.path()))
+            .with_control(host.control.clone());
+        let events = governor.step().unwrap();
+        assert_eq!(levels(&events), vec![("apex_0", "warning")]);
+        assert!(host.calls().is_empty());
+    }
+}
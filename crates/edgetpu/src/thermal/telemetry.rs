@@ -0,0 +1,101 @@
+use std::fs;
+use std::path::Path;
+
+use serde::Serialize;
+
+/// Temperatures the apex driver reports under `/sys/class/apex/apex_N`, in degrees Celsius.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApexTelemetry {
+    /// `temp`
+    pub temperature: f64,
+    /// `trip_point<N>_temp`, from which the driver throttles the device, by increasing N
+    pub trip_points: Vec<f64>,
+    /// `hw_temp_warn1`, when enabled
+    pub hw_temp_warn1: Option<f64>,
+    /// `hw_temp_warn2`, from which the device shuts itself down, when enabled
+    pub hw_temp_warn2: Option<f64>,
+}
+
+impl ApexTelemetry {
+    /// Reads the telemetry of the apex `name`, `None` when the driver does not report its
+    /// temperature.
+    pub fn read(sysfs_root: &Path, name: &str) -> Option<Self> {
+        let dir = sysfs_root.join("class/apex").join(name);
+        let celsius = |attribute: &str| {
+            fs::read_to_string(dir.join(attribute))
+                .ok()
+                .and_then(|value| value.trim().parse::<i64>().ok())
+                .map(|millidegrees| millidegrees as f64 / 1000.0)
+        };
+        let enabled = |attribute: &str| {
+            fs::read_to_string(dir.join(attribute)).is_ok_and(|value| value.trim() == "1")
+        };
+
+        let mut trip_points: Vec<(u32, f64)> = fs::read_dir(&dir)
+            .ok()?
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name();
+                let index = name
+                    .to_str()?
+                    .strip_prefix("trip_point")?
+                    .strip_suffix("_temp")?
+                    .parse()
+                    .ok()?;
+                Some((index, celsius(name.to_str()?)?))
+            })
+            .collect();
+        trip_points.sort_by_key(|(index, _)| *index);
+
+        Some(Self {
+            temperature: celsius("temp")?,
+            trip_points: trip_points.into_iter().map(|(_, temp)| temp).collect(),
+            hw_temp_warn1: celsius("hw_temp_warn1").filter(|_| enabled("hw_temp_warn1_en")),
+            hw_temp_warn2: celsius("hw_temp_warn2").filter(|_| enabled("hw_temp_warn2_en")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::fixture::{add_apex, write};
+
+    #[test]
+    fn test_read() {
+        let sysfs = tempfile::tempdir().unwrap();
+        add_apex(sysfs.path(), 0, "0000:01:00.0");
+        add_apex(sysfs.path(), 1, "0000:02:00.0");
+        let dir = "class/apex/apex_0";
+        for (attribute, value) in [
+            ("temp", "61500"),
+            ("trip_point10_temp", "95000"),
+            ("trip_point0_temp", "85000"),
+            ("trip_point1_temp", "90000"),
+            ("hw_temp_warn1", "100000"),
+            ("hw_temp_warn1_en", "0"),
+            ("hw_temp_warn2", "105000"),
+            ("hw_temp_warn2_en", "1"),
+        ] {
+            write(
+                sysfs.path(),
+                &format!("{dir}/{attribute}"),
+                &format!("{value}\n"),
+            );
+        }
+
+        assert_eq!(
+            ApexTelemetry::read(sysfs.path(), "apex_0"),
+            Some(ApexTelemetry {
+                temperature: 61.5,
+                trip_points: vec![85.0, 90.0, 95.0],
+                hw_temp_warn1: None,
+                hw_temp_warn2: Some(105.0),
+            })
+        );
+        // older drivers do not report the temperature
+        assert_eq!(ApexTelemetry::read(sysfs.path(), "apex_1"), None);
+        assert_eq!(ApexTelemetry::read(sysfs.path(), "apex_2"), None);
+    }
+}
@@ -0,0 +1,473 @@
+//! Tracking of the USB accelerators of a container across re-enumerations.
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use libcontainer::oci_spec::runtime::{
+    LinuxDeviceCgroup, LinuxDeviceCgroupBuilder, LinuxDeviceType,
+};
+use nix::errno::Errno;
+use nix::fcntl::{open, openat2, OFlag, OpenHow, ResolveFlag};
+use nix::sys::stat::{fstat, makedev, mkdirat, mknodat, Mode, SFlag};
+use nix::unistd::{unlinkat, UnlinkatFlags};
+
+use super::{Result, UEvent, UEventError, REENUMERATION_EVENT};
+use crate::discovery::{CoralUsbId, CORAL_USB_IDS};
+use crate::events::Event;
+
+/// Mode of the nodes created in the container, the same as the injected ones.
+const DEVICE_FILE_MODE: u32 = 0o666;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Tracked {
+    major: u32,
+    minor: u32,
+    dev_path: PathBuf,
+}
+
+/// A followed USB accelerator that came back on its port with another device node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reenumeration {
+    /// USB port path of the device, e.g. `2-1.4`
+    pub port: String,
+    pub previous: (u32, u32),
+    pub previous_path: PathBuf,
+    pub current: (u32, u32),
+    /// Device node of the device inside the container, e.g. `/dev/bus/usb/002/006`
+    pub dev_path: PathBuf,
+    pub usb_id: Option<CoralUsbId>,
+}
+
+impl Reenumeration {
+    /// The event recorded for the container, about the device `usb-<port>`.
+    pub fn to_event(&self, container_id: &str) -> Event {
+        let data = serde_json::json!({
+            "container": container_id,
+            "previous": format!("{}:{}", self.previous.0, self.previous.1),
+            "current": format!("{}:{}", self.current.0, self.current.1),
+            "path": self.dev_path,
+        });
+        Event::new(REENUMERATION_EVENT, format!("usb-{}", self.port), data)
+    }
+
+    /// Creates the new device node under `root`, the root of the container file system as
+    /// seen from the host (`/proc/<pid>/root`), and removes the previous one.
+    ///
+    /// The container controls its file system: the paths are resolved relative to `root`
+    /// without following any symbolic link, so that nothing outside of it is touched.
+    pub fn create_node(&self, root: &Path) -> Result<PathBuf> {
+        let path = under_root(root, &self.dev_path);
+        let io_error = |source: io::Error| UEventError::CreateNode {
+            source,
+            path: path.clone(),
+        };
+
+        let (parent, name) = split(&self.dev_path).map_err(|err| io_error(err.into()))?;
+        let root_dir = open_root(root).map_err(|err| io_error(err.into()))?;
+        let dir = open_dir(&root_dir, parent, true).map_err(|err| io_error(err.into()))?;
+        match unlinkat(Some(dir.as_raw_fd()), name, UnlinkatFlags::NoRemoveDir) {
+            Ok(()) | Err(Errno::ENOENT) => {}
+            Err(err) => return Err(io_error(err.into())),
+        }
+        let (major, minor) = self.current;
+        mknodat(
+            Some(dir.as_raw_fd()),
+            name,
+            SFlag::S_IFCHR,
+            Mode::from_bits_truncate(DEVICE_FILE_MODE),
+            makedev(major.into(), minor.into()),
+        )
+        .map_err(|err| io_error(err.into()))?;
+        // mknod(2) applies the umask
+        set_node_permissions(&dir, name).map_err(io_error)?;
+
+        if self.previous_path != self.dev_path {
+            let previous = under_root(root, &self.previous_path);
+            let removed = split(&self.previous_path).and_then(|(parent, name)| {
+                let dir = open_dir(&root_dir, parent, false)?;
+                unlinkat(Some(dir.as_raw_fd()), name, UnlinkatFlags::NoRemoveDir)
+            });
+            if let Err(err) = removed {
+                log::debug!("failed to remove {}: {err}", previous.display());
+            }
+        }
+        Ok(path)
+    }
+
+    /// Replaces the rules allowing the previous device number by one allowing the new one.
+    pub fn update_rules(&self, rules: &mut Vec<LinuxDeviceCgroup>) -> Result<()> {
+        let (major, minor) = self.previous;
+        rules.retain(|rule| {
+            !(rule.allow()
+                && rule.typ() == Some(LinuxDeviceType::C)
+                && rule.major() == Some(major.into())
+                && rule.minor() == Some(minor.into()))
+        });
+
+        let (major, minor) = self.current;
+        rules.push(
+            LinuxDeviceCgroupBuilder::default()
+                .allow(true)
+                .typ(LinuxDeviceType::C)
+                .major(i64::from(major))
+                .minor(i64::from(minor))
+                .access("rwm")
+                .build()?,
+        );
+        Ok(())
+    }
+}
+
+/// Follows USB accelerators by port through the uevents of the kernel.
+///
+/// # Example
+///
+/// ```no_run
+/// use edgetpu::uevent::{UEventSocket, UsbFollower};
+///
+/// let mut follower = UsbFollower::new();
+/// follower.follow("2-1", (189, 132), "/dev/bus/usb/002/005");
+///
+/// let socket = UEventSocket::open().unwrap();
+/// while let Some(event) = socket.recv().unwrap() {
+///     if let Some(reenumeration) = follower.handle(&event) {
+///         println!("now at {}", reenumeration.dev_path.display());
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct UsbFollower {
+    ports: HashMap<String, Tracked>,
+}
+
+impl UsbFollower {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Follows the device currently plugged in `port`, whose node in the container is
+    /// `dev_path`.
+    pub fn follow<P: Into<PathBuf>>(
+        &mut self,
+        port: &str,
+        (major, minor): (u32, u32),
+        dev_path: P,
+    ) {
+        self.ports.insert(
+            port.to_owned(),
+            Tracked {
+                major,
+                minor,
+                dev_path: dev_path.into(),
+            },
+        );
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ports.is_empty()
+    }
+
+    /// Handles a uevent and returns the re-enumeration it completes, if any.
+    pub fn handle(&mut self, event: &UEvent) -> Option<Reenumeration> {
+        if !event.is_usb_device() {
+            return None;
+        }
+        let port = event.usb_port()?;
+        let tracked = self.ports.get_mut(port)?;
+
+        match event.action.as_str() {
+            "remove" => {
+                log::debug!("USB accelerator on port {port} left the bus");
+                None
+            }
+            "add" => {
+                let usb_id = event.usb_id();
+                if let Some(id) = usb_id.filter(|id| !CORAL_USB_IDS.contains(id)) {
+                    log::warn!(
+                        "device {id} plugged in port {port} is not an Edge TPU, ignoring it"
+                    );
+                    return None;
+                }
+                let current = event.dev_number()?;
+                let devname = event.devname()?;
+                if current == (tracked.major, tracked.minor) {
+                    return None;
+                }
+
+                let reenumeration = Reenumeration {
+                    port: port.to_owned(),
+                    previous: (tracked.major, tracked.minor),
+                    previous_path: tracked.dev_path.clone(),
+                    current,
+                    dev_path: Path::new("/dev").join(devname),
+                    usb_id,
+                };
+                (tracked.major, tracked.minor) = current;
+                tracked.dev_path = reenumeration.dev_path.clone();
+                Some(reenumeration)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn under_root(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Splits the path of a node of the container into its directory and its name.
+fn split(path: &Path) -> nix::Result<(&Path, &OsStr)> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Ok((parent, name)),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+fn open_root(root: &Path) -> nix::Result<OwnedFd> {
+    let fd = open(
+        root,
+        OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    // SAFETY: the descriptor was just opened and is owned by nothing else
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Opens `name` in `dir` without following symbolic links or leaving `dir`.
+fn open_beneath(dir: &OwnedFd, name: &OsStr, flags: OFlag) -> nix::Result<OwnedFd> {
+    let how = OpenHow::new()
+        .flags(flags | OFlag::O_PATH | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC)
+        .resolve(ResolveFlag::RESOLVE_IN_ROOT | ResolveFlag::RESOLVE_NO_SYMLINKS);
+    let fd = openat2(dir.as_raw_fd(), name, how)?;
+    // SAFETY: the descriptor was just opened and is owned by nothing else
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Opens the directory `path` of the container file system, creating the missing
+/// components if `create`.
+fn open_dir(root: &OwnedFd, path: &Path, create: bool) -> nix::Result<OwnedFd> {
+    let mut dir = open_beneath(root, OsStr::new("."), OFlag::O_DIRECTORY)?;
+    for component in path.components() {
+        let name = match component {
+            Component::RootDir | Component::CurDir => continue,
+            Component::Normal(name) => name,
+            Component::ParentDir | Component::Prefix(_) => return Err(Errno::EINVAL),
+        };
+        dir = match open_beneath(&dir, name, OFlag::O_DIRECTORY) {
+            Err(Errno::ENOENT) if create => {
+                match mkdirat(Some(dir.as_raw_fd()), name, Mode::from_bits_truncate(0o755)) {
+                    Ok(()) | Err(Errno::EEXIST) => {}
+                    Err(err) => return Err(err),
+                }
+                open_beneath(&dir, name, OFlag::O_DIRECTORY)?
+            }
+            opened => opened?,
+        };
+    }
+    Ok(dir)
+}
+
+/// Sets the mode of the device node `name` of `dir`, through a descriptor of the node so
+/// that a node replaced meanwhile is not followed.
+fn set_node_permissions(dir: &OwnedFd, name: &OsStr) -> io::Result<()> {
+    let node = open_beneath(dir, name, OFlag::empty())?;
+    let stat = fstat(node.as_raw_fd())?;
+    if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT != SFlag::S_IFCHR {
+        return Err(io::Error::other("the device node was replaced"));
+    }
+    fs::set_permissions(
+        format!("/proc/self/fd/{}", node.as_raw_fd()),
+        fs::Permissions::from_mode(DEVICE_FILE_MODE),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uevent::fixture::message;
+
+    const PORT_DEVPATH: &str = "/devices/pci0000:00/0000:00:14.0/usb2/2-1";
+
+    fn usb_device(action: &str, devpath: &str, product: &str, number: (u32, u32)) -> Vec<u8> {
+        let major = number.0.to_string();
+        let minor = number.1.to_string();
+        let devname = format!("bus/usb/002/{:03}", number.1 - 127);
+        message(
+            action,
+            devpath,
+            &[
+                ("SUBSYSTEM", "usb"),
+                ("MAJOR", &major),
+                ("MINOR", &minor),
+                ("DEVNAME", &devname),
+                ("DEVTYPE", "usb_device"),
+                ("PRODUCT", product),
+                ("TYPE", "0/0/0"),
+            ],
+        )
+    }
+
+    fn usb_interface(action: &str, devpath: &str, product: &str) -> Vec<u8> {
+        message(
+            action,
+            devpath,
+            &[
+                ("SUBSYSTEM", "usb"),
+                ("DEVTYPE", "usb_interface"),
+                ("PRODUCT", product),
+                ("INTERFACE", "255/255/255"),
+            ],
+        )
+    }
+
+    fn replay(follower: &mut UsbFollower, messages: &[Vec<u8>]) -> Vec<Reenumeration> {
+        messages
+            .iter()
+            .map(|message| UEvent::parse(message).unwrap())
+            .filter_map(|event| follower.handle(&event))
+            .collect()
+    }
+
+    #[test]
+    fn test_follow_firmware_load() {
+        let interface = format!("{PORT_DEVPATH}/2-1:1.0");
+        // recorded while libedgetpu loaded the firmware of an accelerator on port 2-1,
+        // with a keyboard plugged in port 2-2 meanwhile
+        let messages = vec![
+            usb_interface("unbind", &interface, "1a6e/89a/100"),
+            usb_interface("remove", &interface, "1a6e/89a/100"),
+            usb_device("unbind", PORT_DEVPATH, "1a6e/89a/100", (189, 132)),
+            usb_device("remove", PORT_DEVPATH, "1a6e/89a/100", (189, 132)),
+            usb_device(
+                "add",
+                "/devices/pci0000:00/0000:00:14.0/usb2/2-2",
+                "46d/c31c/6400",
+                (189, 134),
+            ),
+            usb_device("add", PORT_DEVPATH, "18d1/9302/100", (189, 133)),
+            usb_interface("add", &interface, "18d1/9302/100"),
+            usb_device("bind", PORT_DEVPATH, "18d1/9302/100", (189, 133)),
+        ];
+
+        let mut follower = UsbFollower::new();
+        follower.follow("2-1", (189, 132), "/dev/bus/usb/002/005");
+        let reenumerations = replay(&mut follower, &messages);
+
+        assert_eq!(
+            reenumerations,
+            vec![Reenumeration {
+                port: "2-1".to_owned(),
+                previous: (189, 132),
+                previous_path: PathBuf::from("/dev/bus/usb/002/005"),
+                current: (189, 133),
+                dev_path: PathBuf::from("/dev/bus/usb/002/006"),
+                usb_id: Some(CoralUsbId::RUNTIME),
+            }]
+        );
+
+        // the device is followed under its new number: a reset re-enumerates it again
+        let reset = vec![
+            usb_device("remove", PORT_DEVPATH, "18d1/9302/100", (189, 133)),
+            usb_device("add", PORT_DEVPATH, "18d1/9302/100", (189, 135)),
+        ];
+        let reenumerations = replay(&mut follower, &reset);
+        assert_eq!(reenumerations.len(), 1);
+        assert_eq!(reenumerations[0].previous, (189, 133));
+        assert_eq!(reenumerations[0].current, (189, 135));
+
+        let event = reenumerations[0].to_event("camera");
+        assert_eq!(
+            (event.kind.as_str(), event.id.as_str()),
+            (REENUMERATION_EVENT, "usb-2-1")
+        );
+        assert_eq!(event.data["current"], "189:135");
+    }
+
+    #[test]
+    fn test_other_device_on_port_is_ignored() {
+        let messages = vec![
+            usb_device("remove", PORT_DEVPATH, "1a6e/89a/100", (189, 132)),
+            usb_device("add", PORT_DEVPATH, "46d/c31c/6400", (189, 133)),
+        ];
+        let mut follower = UsbFollower::new();
+        follower.follow("2-1", (189, 132), "/dev/bus/usb/002/005");
+
+        assert!(replay(&mut follower, &messages).is_empty());
+    }
+
+    #[test]
+    fn test_create_node_does_not_follow_symlinks() {
+        let host = tempfile::tempdir().unwrap();
+        fs::create_dir(host.path().join("002")).unwrap();
+        fs::write(host.path().join("002/005"), "host file").unwrap();
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("dev/bus")).unwrap();
+        std::os::unix::fs::symlink(host.path(), root.path().join("dev/bus/usb")).unwrap();
+
+        let mut reenumeration = Reenumeration {
+            port: "2-1".to_owned(),
+            previous: (189, 132),
+            previous_path: PathBuf::from("/dev/bus/usb/002/005"),
+            current: (189, 133),
+            dev_path: PathBuf::from("/dev/bus/usb/002/006"),
+            usb_id: Some(CoralUsbId::RUNTIME),
+        };
+        assert!(matches!(
+            reenumeration.create_node(root.path()),
+            Err(UEventError::CreateNode { .. })
+        ));
+        assert!(!host.path().join("002/006").exists());
+        assert!(host.path().join("002/005").exists());
+
+        // a planted previous node is left alone, the new one is created
+        reenumeration.dev_path = PathBuf::from("/dev/bus/usb-0/002/006");
+        let path = reenumeration.create_node(root.path()).unwrap();
+        assert_eq!(path, root.path().join("dev/bus/usb-0/002/006"));
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, DEVICE_FILE_MODE);
+        assert!(host.path().join("002/005").exists());
+    }
+
+    #[test]
+    fn test_update_rules() {
+        let rule = |allow: bool, major: Option<i64>, minor: Option<i64>| {
+            let mut builder = LinuxDeviceCgroupBuilder::default()
+                .allow(allow)
+                .typ(LinuxDeviceType::C)
+                .access("rwm");
+            if let (Some(major), Some(minor)) = (major, minor) {
+                builder = builder.major(major).minor(minor);
+            }
+            builder.build().unwrap()
+        };
+        let mut rules = vec![
+            rule(false, None, None),
+            rule(true, Some(120), Some(0)),
+            rule(true, Some(189), Some(132)),
+        ];
+
+        Reenumeration {
+            port: "2-1".to_owned(),
+            previous: (189, 132),
+            previous_path: PathBuf::from("/dev/bus/usb/002/005"),
+            current: (189, 133),
+            dev_path: PathBuf::from("/dev/bus/usb/002/006"),
+            usb_id: Some(CoralUsbId::RUNTIME),
+        }
+        .update_rules(&mut rules)
+        .unwrap();
+
+        assert_eq!(
+            rules,
+            vec![
+                rule(false, None, None),
+                rule(true, Some(120), Some(0)),
+                rule(true, Some(189), Some(133)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,267 @@
+//! Kernel uevents of USB devices, received over a netlink socket.
+//!
+//! A Coral USB Accelerator first enumerates with its bootloader identity (`1a6e:089a`).
+//! Once libedgetpu has uploaded the firmware, the device drops off the bus and comes back
+//! as `18d1:9302` with a new device number, so the node a container was given at creation
+//! no longer works. [`UsbFollower`] watches the uevents of the ports leased to a container
+//! and reports the new device node, which the runtime then exposes to the container.
+mod follower;
+
+use std::collections::HashMap;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::sys::socket::{
+    bind, recvfrom, setsockopt, socket, sockopt, AddressFamily, NetlinkAddr, SockFlag,
+    SockProtocol, SockType,
+};
+use nix::sys::time::{TimeVal, TimeValLike};
+
+pub use follower::{Reenumeration, UsbFollower};
+
+use crate::discovery::CoralUsbId;
+
+/// Type of the events recorded when a followed USB accelerator re-enumerates
+pub const REENUMERATION_EVENT: &str = "edgetpu-reenumeration";
+
+/// Multicast group the kernel sends its uevents to.
+const KERNEL_GROUP: u32 = 1;
+/// Uevents are at most a page long.
+const MAX_UEVENT_SIZE: usize = 8192;
+
+#[derive(Debug, thiserror::Error)]
+pub enum UEventError {
+    #[error("failed to {operation} the uevent socket")]
+    Socket {
+        source: nix::Error,
+        operation: &'static str,
+    },
+    #[error("malformed uevent: {0}")]
+    Malformed(String),
+    #[error("failed to create device node {path:?}")]
+    CreateNode {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("failed to build device cgroup rule")]
+    Spec(#[from] libcontainer::oci_spec::OciSpecError),
+}
+
+type Result<T> = std::result::Result<T, UEventError>;
+
+/// A kernel uevent, i.e. the action and the environment the kernel sends to udev.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UEvent {
+    pub action: String,
+    /// Path of the device below `/sys`
+    pub devpath: String,
+    pub env: HashMap<String, String>,
+}
+
+impl UEvent {
+    /// Parses a message of the kernel: an `action@devpath` header followed by `KEY=VALUE`
+    /// pairs, all NUL terminated.
+    pub fn parse(message: &[u8]) -> Result<Self> {
+        let mut fields = message
+            .split(|b| *b == 0)
+            .filter(|field| !field.is_empty())
+            .map(String::from_utf8_lossy);
+
+        let header = fields
+            .next()
+            .ok_or_else(|| UEventError::Malformed("empty message".to_owned()))?;
+        let (action, devpath) = header
+            .split_once('@')
+            .ok_or_else(|| UEventError::Malformed(format!("invalid header {header:?}")))?;
+
+        let mut env = HashMap::new();
+        for field in fields {
+            match field.split_once('=') {
+                Some((key, value)) => {
+                    env.insert(key.to_owned(), value.to_owned());
+                }
+                None => return Err(UEventError::Malformed(format!("invalid field {field:?}"))),
+            }
+        }
+
+        Ok(Self {
+            action: action.to_owned(),
+            devpath: devpath.to_owned(),
+            env,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.env.get(key).map(String::as_str)
+    }
+
+    pub fn subsystem(&self) -> Option<&str> {
+        self.get("SUBSYSTEM")
+    }
+
+    pub fn devtype(&self) -> Option<&str> {
+        self.get("DEVTYPE")
+    }
+
+    /// Path of the device node relative to `/dev`, e.g. `bus/usb/002/006`
+    pub fn devname(&self) -> Option<&str> {
+        self.get("DEVNAME")
+    }
+
+    pub fn dev_number(&self) -> Option<(u32, u32)> {
+        let major = self.get("MAJOR")?.parse().ok()?;
+        let minor = self.get("MINOR")?.parse().ok()?;
+        Some((major, minor))
+    }
+
+    /// Vendor/product id of a USB device, from the `PRODUCT=18d1/9302/100` variable
+    pub fn usb_id(&self) -> Option<CoralUsbId> {
+        let mut parts = self.get("PRODUCT")?.split('/');
+        let vendor = u16::from_str_radix(parts.next()?, 16).ok()?;
+        let product = u16::from_str_radix(parts.next()?, 16).ok()?;
+        Some(CoralUsbId { vendor, product })
+    }
+
+    /// Port path of a USB device (`2-1.4`), the last component of its devpath
+    pub fn usb_port(&self) -> Option<&str> {
+        self.devpath
+            .rsplit('/')
+            .next()
+            .filter(|port| !port.is_empty())
+    }
+
+    /// Whether the event is about a whole USB device rather than one of its interfaces
+    pub fn is_usb_device(&self) -> bool {
+        self.subsystem() == Some("usb") && self.devtype() == Some("usb_device")
+    }
+}
+
+/// Netlink socket receiving the uevents of the kernel.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use edgetpu::uevent::UEventSocket;
+///
+/// let socket = UEventSocket::open().unwrap();
+/// socket.set_timeout(Duration::from_secs(1)).unwrap();
+/// while let Some(event) = socket.recv().unwrap() {
+///     println!("{} {}", event.action, event.devpath);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct UEventSocket {
+    fd: OwnedFd,
+}
+
+impl UEventSocket {
+    pub fn open() -> Result<Self> {
+        let fd = socket(
+            AddressFamily::Netlink,
+            SockType::Datagram,
+            SockFlag::SOCK_CLOEXEC,
+            SockProtocol::NetlinkKObjectUEvent,
+        )
+        .map_err(|err| UEventError::Socket {
+            source: err,
+            operation: "open",
+        })?;
+        bind(fd.as_raw_fd(), &NetlinkAddr::new(0, KERNEL_GROUP)).map_err(|err| {
+            UEventError::Socket {
+                source: err,
+                operation: "bind",
+            }
+        })?;
+        Ok(Self { fd })
+    }
+
+    /// Makes [`recv`](Self::recv) give up after `timeout` without any event.
+    pub fn set_timeout(&self, timeout: Duration) -> Result<()> {
+        let timeout = TimeVal::milliseconds(timeout.as_millis() as i64);
+        setsockopt(&self.fd, sockopt::ReceiveTimeout, &timeout).map_err(|err| UEventError::Socket {
+            source: err,
+            operation: "configure",
+        })
+    }
+
+    /// Waits for the next uevent of the kernel, or returns `None` on timeout. Messages
+    /// that were not sent by the kernel are dropped.
+    pub fn recv(&self) -> Result<Option<UEvent>> {
+        let mut buffer = [0; MAX_UEVENT_SIZE];
+        loop {
+            match recvfrom::<NetlinkAddr>(self.fd.as_raw_fd(), &mut buffer) {
+                Ok((len, Some(sender))) if sender.pid() == 0 => {
+                    return UEvent::parse(&buffer[..len]).map(Some)
+                }
+                Ok(_) => log::debug!("dropping a uevent that was not sent by the kernel"),
+                Err(Errno::EAGAIN) => return Ok(None),
+                Err(Errno::EINTR) => {}
+                Err(err) => {
+                    return Err(UEventError::Socket {
+                        source: err,
+                        operation: "read",
+                    })
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod fixture {
+    /// Encodes a uevent the way the kernel sends it.
+    pub fn message(action: &str, devpath: &str, env: &[(&str, &str)]) -> Vec<u8> {
+        let mut message = format!("{action}@{devpath}\0ACTION={action}\0DEVPATH={devpath}\0");
+        for (key, value) in env {
+            message.push_str(&format!("{key}={value}\0"));
+        }
+        message.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_usb_device_event() {
+        let message = fixture::message(
+            "add",
+            "/devices/pci0000:00/0000:00:14.0/usb2/2-1",
+            &[
+                ("SUBSYSTEM", "usb"),
+                ("MAJOR", "189"),
+                ("MINOR", "133"),
+                ("DEVNAME", "bus/usb/002/006"),
+                ("DEVTYPE", "usb_device"),
+                ("PRODUCT", "18d1/9302/100"),
+                ("SEQNUM", "4211"),
+            ],
+        );
+        let event = UEvent::parse(&message).unwrap();
+
+        assert_eq!(event.action, "add");
+        assert!(event.is_usb_device());
+        assert_eq!(event.usb_port(), Some("2-1"));
+        assert_eq!(event.dev_number(), Some((189, 133)));
+        assert_eq!(event.devname(), Some("bus/usb/002/006"));
+        assert_eq!(event.usb_id(), Some(CoralUsbId::RUNTIME));
+    }
+
+    #[test]
+    fn test_parse_malformed_event() {
+        assert!(matches!(UEvent::parse(b""), Err(UEventError::Malformed(_))));
+        assert!(matches!(
+            UEvent::parse(b"add /devices/usb2\0"),
+            Err(UEventError::Malformed(_))
+        ));
+        assert!(matches!(
+            UEvent::parse(b"add@/devices/usb2\0SUBSYSTEM\0"),
+            Err(UEventError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,279 @@
+//! Notifications of the processes of a cgroup killed by the OOM killer.
+//!
+//! On cgroup v2 the kernel counts the OOM kills in the `oom_kill` entry of `memory.events`,
+//! which is watched with inotify. On cgroup v1 an eventfd registered through
+//! `cgroup.event_control` for `memory.oom_control` is signaled on every OOM.
+use std::fs::{self, File};
+use std::os::fd::{AsFd, AsRawFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::eventfd::{EfdFlags, EventFd};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use nix::unistd::Pid;
+use procfs::process::Process;
+
+use crate::common::{self, CgroupSetup, DEFAULT_CGROUP_ROOT};
+
+const MEMORY_EVENTS: &str = "memory.events";
+const MEMORY_OOM_CONTROL: &str = "memory.oom_control";
+const CGROUP_EVENT_CONTROL: &str = "cgroup.event_control";
+
+#[derive(thiserror::Error, Debug)]
+pub enum OomError {
+    #[error("failed to find the memory cgroup of process {0}")]
+    NoMemoryCgroup(Pid),
+    #[error("failed to read the cgroups of process {pid}: {source}")]
+    Procfs { source: procfs::ProcError, pid: Pid },
+    #[error(transparent)]
+    Setup(#[from] common::GetCgroupSetupError),
+    #[error("failed to access {path}: {source}")]
+    Io {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("failed to {operation}: {source}")]
+    Notify {
+        source: nix::Error,
+        operation: &'static str,
+    },
+    #[error("cgroup v1 support was not enabled during compile time")]
+    V1NotEnabled,
+}
+
+type Result<T> = std::result::Result<T, OomError>;
+
+enum Watch {
+    V1 {
+        event_fd: EventFd,
+        /// Kept open for as long as the eventfd is registered
+        _oom_control: File,
+        memory_dir: PathBuf,
+    },
+    V2 {
+        inotify: Inotify,
+        events: PathBuf,
+        oom_kills: u64,
+    },
+}
+
+/// Waits for the OOM kills of a cgroup.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use libcgroups::oom::OomNotifier;
+/// use nix::unistd::Pid;
+///
+/// let mut notifier = OomNotifier::for_process(Pid::from_raw(4242)).unwrap();
+/// loop {
+///     let kills = notifier.wait(Duration::from_secs(1)).unwrap();
+///     if kills > 0 {
+///         println!("{kills} processes were killed");
+///     }
+/// }
+/// ```
+pub struct OomNotifier {
+    watch: Watch,
+}
+
+impl OomNotifier {
+    /// Watches the memory cgroup of the process, found from `/proc/<pid>/cgroup`.
+    pub fn for_process(pid: Pid) -> Result<Self> {
+        let cgroups = Process::new(pid.as_raw())
+            .and_then(|process| process.cgroups())
+            .map_err(|source| OomError::Procfs { source, pid })?;
+        match common::get_cgroup_setup()? {
+            CgroupSetup::Unified => {
+                let cgroup = cgroups
+                    .into_iter()
+                    .find(|cgroup| cgroup.hierarchy == 0)
+                    .ok_or(OomError::NoMemoryCgroup(pid))?;
+                let path = cgroup.pathname.trim_start_matches('/');
+                Self::unified(&Path::new(DEFAULT_CGROUP_ROOT).join(path))
+            }
+            CgroupSetup::Legacy | CgroupSetup::Hybrid => {
+                let cgroup = cgroups
+                    .into_iter()
+                    .find(|cgroup| cgroup.controllers.iter().any(|c| c == "memory"))
+                    .ok_or(OomError::NoMemoryCgroup(pid))?;
+                Self::legacy(&legacy_memory_dir(&cgroup.pathname, pid)?)
+            }
+        }
+    }
+
+    /// Watches the `memory.events` of a cgroup v2 directory.
+    pub fn unified(cgroup_dir: &Path) -> Result<Self> {
+        let events = cgroup_dir.join(MEMORY_EVENTS);
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK)
+            .map_err(|source| notify_error(source, "create an inotify instance"))?;
+        inotify
+            .add_watch(&events, AddWatchFlags::IN_MODIFY)
+            .map_err(|source| notify_error(source, "watch memory.events"))?;
+        let oom_kills = read_oom_kills(&events)?;
+
+        Ok(Self {
+            watch: Watch::V2 {
+                inotify,
+                events,
+                oom_kills,
+            },
+        })
+    }
+
+    /// Registers an eventfd for the `memory.oom_control` of a cgroup v1 memory directory.
+    pub fn legacy(memory_dir: &Path) -> Result<Self> {
+        let oom_control_path = memory_dir.join(MEMORY_OOM_CONTROL);
+        let oom_control = File::open(&oom_control_path).map_err(|source| OomError::Io {
+            source,
+            path: oom_control_path,
+        })?;
+        let event_fd = EventFd::from_flags(EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)
+            .map_err(|source| notify_error(source, "create an eventfd"))?;
+        let control = memory_dir.join(CGROUP_EVENT_CONTROL);
+        fs::write(
+            &control,
+            format!("{} {}", event_fd.as_raw_fd(), oom_control.as_raw_fd()),
+        )
+        .map_err(|source| OomError::Io {
+            source,
+            path: control,
+        })?;
+
+        Ok(Self {
+            watch: Watch::V1 {
+                event_fd,
+                _oom_control: oom_control,
+                memory_dir: memory_dir.to_owned(),
+            },
+        })
+    }
+
+    /// Waits at most `timeout` and returns the number of OOM kills in the meantime.
+    pub fn wait(&mut self, timeout: Duration) -> Result<u64> {
+        let timeout = PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX);
+        match &mut self.watch {
+            Watch::V1 {
+                event_fd,
+                memory_dir,
+                ..
+            } => {
+                if !wait_readable(event_fd, timeout)? {
+                    return Ok(0);
+                }
+                let count = event_fd
+                    .read()
+                    .map_err(|source| notify_error(source, "read the eventfd"))?;
+                // the eventfd is signaled as well when the cgroup is removed
+                if !memory_dir.join(CGROUP_EVENT_CONTROL).exists() {
+                    return Ok(0);
+                }
+                Ok(count)
+            }
+            Watch::V2 {
+                inotify,
+                events,
+                oom_kills,
+            } => {
+                if !wait_readable(inotify, timeout)? {
+                    return Ok(0);
+                }
+                match inotify.read_events() {
+                    Ok(_) | Err(nix::errno::Errno::EAGAIN) => {}
+                    Err(source) => return Err(notify_error(source, "read the inotify events")),
+                }
+                // the file is gone with the cgroup
+                let Ok(current) = read_oom_kills(events) else {
+                    return Ok(0);
+                };
+                let kills = current.saturating_sub(*oom_kills);
+                *oom_kills = current;
+                Ok(kills)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "v1")]
+fn legacy_memory_dir(pathname: &str, pid: Pid) -> Result<PathBuf> {
+    use crate::common::PathBufExt;
+    use crate::v1::{util, ControllerType};
+
+    let mount_point = util::get_subsystem_mount_point(&ControllerType::Memory)
+        .map_err(|_| OomError::NoMemoryCgroup(pid))?;
+    mount_point
+        .join_safely(Path::new(pathname))
+        .map_err(|_| OomError::NoMemoryCgroup(pid))
+}
+
+#[cfg(not(feature = "v1"))]
+fn legacy_memory_dir(_pathname: &str, _pid: Pid) -> Result<PathBuf> {
+    Err(OomError::V1NotEnabled)
+}
+
+// nix 0.28 already requires a newer toolchain than the MSRV of the crate
+#[allow(clippy::incompatible_msrv)]
+fn wait_readable<F: AsFd>(fd: &F, timeout: PollTimeout) -> Result<bool> {
+    let mut fds = [PollFd::new(fd.as_fd(), PollFlags::POLLIN)];
+    match poll(&mut fds, timeout) {
+        Ok(ready) => Ok(ready > 0),
+        Err(nix::errno::Errno::EINTR) => Ok(false),
+        Err(source) => Err(notify_error(source, "poll")),
+    }
+}
+
+/// The `oom_kill` entry of `memory.events`
+fn read_oom_kills(path: &Path) -> Result<u64> {
+    let content = fs::read_to_string(path).map_err(|source| OomError::Io {
+        source,
+        path: path.to_owned(),
+    })?;
+    Ok(content
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(key, _)| *key == "oom_kill")
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or_default())
+}
+
+fn notify_error(source: nix::Error, operation: &'static str) -> OomError {
+    OomError::Notify { source, operation }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_events(dir: &Path, oom_kills: u64) {
+        let events = format!("low 0\nhigh 0\nmax 4\noom {oom_kills}\noom_kill {oom_kills}\n");
+        fs::write(dir.join(MEMORY_EVENTS), events).unwrap();
+    }
+
+    #[test]
+    fn test_unified_oom_kills() {
+        let tmp = tempfile::tempdir().unwrap();
+        write_events(tmp.path(), 1);
+        let mut notifier = OomNotifier::unified(tmp.path()).unwrap();
+        assert_eq!(notifier.wait(Duration::from_millis(10)).unwrap(), 0);
+
+        write_events(tmp.path(), 3);
+        assert_eq!(notifier.wait(Duration::from_secs(5)).unwrap(), 2);
+        assert_eq!(notifier.wait(Duration::from_millis(10)).unwrap(), 0);
+
+        // the cgroup is removed when the container stops
+        fs::remove_file(tmp.path().join(MEMORY_EVENTS)).unwrap();
+        assert_eq!(notifier.wait(Duration::from_millis(10)).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_missing_memory_controller() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            OomNotifier::unified(tmp.path()),
+            Err(OomError::Notify { .. })
+        ));
+    }
+}
@@ -2,7 +2,7 @@ use std::fs;
 use std::io::{BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
 
-use oci_spec::runtime::{Hooks, Spec};
+use oci_spec::runtime::{Hooks, LinuxDeviceCgroup, Spec};
 use serde::{Deserialize, Serialize};
 
 use crate::utils;
@@ -35,7 +35,7 @@ pub enum ConfigError {
 
 type Result<T> = std::result::Result<T, ConfigError>;
 
-const YOUKI_CONFIG_NAME: &str = "youki_config.json";
+pub(crate) const YOUKI_CONFIG_NAME: &str = "youki_config.json";
 
 /// A configuration for passing information obtained during container creation to other commands.
 /// Keeping the information to a minimum improves performance.
@@ -44,19 +44,22 @@ const YOUKI_CONFIG_NAME: &str = "youki_config.json";
 pub struct YoukiConfig {
     pub hooks: Option<Hooks>,
     pub cgroup_path: PathBuf,
+    /// Rules of the device cgroup, kept so that they can be applied again when a device
+    /// the container was given changes device number.
+    #[serde(default)]
+    pub device_rules: Option<Vec<LinuxDeviceCgroup>>,
 }
 
 impl<'a> YoukiConfig {
     pub fn from_spec(spec: &'a Spec, container_id: &str) -> Result<Self> {
+        let linux = spec.linux().as_ref().ok_or(ConfigError::MissingLinux)?;
         Ok(YoukiConfig {
             hooks: spec.hooks().clone(),
-            cgroup_path: utils::get_cgroup_path(
-                spec.linux()
-                    .as_ref()
-                    .ok_or(ConfigError::MissingLinux)?
-                    .cgroups_path(),
-                container_id,
-            ),
+            cgroup_path: utils::get_cgroup_path(linux.cgroups_path(), container_id),
+            device_rules: linux
+                .resources()
+                .as_ref()
+                .and_then(|resources| resources.devices().clone()),
         })
     }
 
@@ -108,6 +111,10 @@ mod tests {
         let spec = Spec::default();
         let config = YoukiConfig::from_spec(&spec, container_id)?;
         assert_eq!(&config.hooks, spec.hooks());
+        assert_eq!(
+            &config.device_rules,
+            spec.linux().as_ref().unwrap().resources().as_ref().unwrap().devices()
+        );
         dbg!(&config.cgroup_path);
         assert_eq!(
             config.cgroup_path,
@@ -127,4 +134,17 @@ mod tests {
         assert_eq!(act, config);
         Ok(())
     }
+
+    #[test]
+    fn test_config_without_device_rules() -> Result<()> {
+        let tmp = tempfile::tempdir().expect("create temp dir");
+        // configs saved before the device rules were kept
+        fs::write(
+            tmp.path().join(YOUKI_CONFIG_NAME),
+            r#"{"hooks":null,"cgroup_path":":youki:sample"}"#,
+        )?;
+        let config = YoukiConfig::load(&tmp)?;
+        assert_eq!(config.device_rules, None);
+        Ok(())
+    }
 }
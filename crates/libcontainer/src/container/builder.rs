@@ -1,3 +1,4 @@
+use std::fs::File;
 use std::path::PathBuf;
 
 use super::init_builder::InitContainerBuilder;
@@ -24,6 +25,11 @@ pub struct ContainerBuilder {
     /// The function that actually runs on the container init process. Default
     /// is to execute the specified command in the oci spec.
     pub(super) executor: Box<dyn Executor>,
+    /// Standard streams of the container process, inherited from the
+    /// caller when not set
+    pub(super) stdin: Option<File>,
+    pub(super) stdout: Option<File>,
+    pub(super) stderr: Option<File>,
 }
 
 /// Builder that can be used to configure the common properties of
@@ -70,6 +76,9 @@ impl ContainerBuilder {
             console_socket: None,
             preserve_fds: 0,
             executor: workload::default::get_executor(),
+            stdin: None,
+            stdout: None,
+            stderr: None,
         }
     }
 
@@ -257,6 +266,38 @@ impl ContainerBuilder {
         self.executor = Box::new(executor);
         self
     }
+
+    /// Sets the standard input of the container process, e.g. a FIFO
+    /// given by the higher level runtime.
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use std::fs::File;
+    /// # use libcontainer::container::builder::ContainerBuilder;
+    /// # use libcontainer::syscall::syscall::SyscallType;
+    ///
+    /// ContainerBuilder::new(
+    ///     "74f1a4cb3801".to_owned(),
+    ///     SyscallType::default(),
+    /// )
+    /// .with_stdin(File::open("/run/containerd/fifo/74f1a4cb3801-stdin").unwrap());
+    /// ```
+    pub fn with_stdin(mut self, stdin: File) -> Self {
+        self.stdin = Some(stdin);
+        self
+    }
+
+    /// Sets the standard output of the container process.
+    pub fn with_stdout(mut self, stdout: File) -> Self {
+        self.stdout = Some(stdout);
+        self
+    }
+
+    /// Sets the standard error of the container process.
+    pub fn with_stderr(mut self, stderr: File) -> Self {
+        self.stderr = Some(stderr);
+        self
+    }
 }
 
 #[cfg(test)]
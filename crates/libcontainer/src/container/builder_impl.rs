@@ -1,6 +1,6 @@
-use std::fs;
+use std::fs::{self, File};
 use std::io::Write;
-use std::os::unix::prelude::RawFd;
+use std::os::unix::prelude::{AsRawFd, RawFd};
 use std::path::PathBuf;
 use std::rc::Rc;
 
@@ -49,6 +49,10 @@ pub(super) struct ContainerBuilderImpl {
     pub detached: bool,
     /// Default executes the specified execution of a generic command
     pub executor: Box<dyn Executor>,
+    /// Standard streams of the container process
+    pub stdin: Option<File>,
+    pub stdout: Option<File>,
+    pub stderr: Option<File>,
 }
 
 impl ContainerBuilderImpl {
@@ -154,6 +158,9 @@ impl ContainerBuilderImpl {
             cgroup_config,
             detached: self.detached,
             executor: self.executor.clone(),
+            stdin: self.stdin.as_ref().map(AsRawFd::as_raw_fd),
+            stdout: self.stdout.as_ref().map(AsRawFd::as_raw_fd),
+            stderr: self.stderr.as_ref().map(AsRawFd::as_raw_fd),
         };
 
         let (init_pid, need_to_clean_up_intel_rdt_dir) =
@@ -216,6 +223,8 @@ impl ContainerBuilderImpl {
                     errors.push(e.to_string());
                 }
             }
+
+            container.release_leases();
         }
 
         if !errors.is_empty() {
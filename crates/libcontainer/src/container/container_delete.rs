@@ -8,6 +8,7 @@ use super::{Container, ContainerStatus};
 use crate::config::YoukiConfig;
 use crate::error::LibcontainerError;
 use crate::hooks;
+use crate::lease::LeaseLedger;
 use crate::process::intel_rdt::delete_resctrl_subdirectory;
 
 impl Container {
@@ -125,6 +126,26 @@ impl Container {
             })?;
         }
 
+        // The leases of a container whose directory is gone are dropped by the
+        // next acquire anyway, so failing to release them does not fail delete.
+        self.release_leases();
+
         Ok(())
     }
+
+    /// Releases the devices leased to the container in the ledger of the runtime root.
+    pub(super) fn release_leases(&self) {
+        let Some(root_path) = self.root.parent() else {
+            return;
+        };
+        match LeaseLedger::new(root_path).release(self.id()) {
+            Ok(devices) if !devices.is_empty() => {
+                tracing::debug!(id = self.id(), ?devices, "released device leases");
+            }
+            Ok(_) => {}
+            Err(err) => {
+                tracing::warn!(id = self.id(), ?err, "failed to release device leases");
+            }
+        }
+    }
 }
@@ -1,13 +1,20 @@
+use std::io::{self, Write};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use libcgroups::common::CgroupManager;
+use libcgroups::oom::OomNotifier;
 
+use super::events::{cgroup_stats, ContainerEvent, EventSource};
 use super::{Container, ContainerStatus};
 use crate::error::LibcontainerError;
 
+/// Longest wait between two checks of the container status and of the event sources
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 impl Container {
-    /// Displays container events
+    /// Prints the events of the container as JSON lines, in the format of `runc events`:
+    /// its statistics every `interval` seconds, its OOM kills and the events of `sources`,
+    /// until the container stops. With `stats`, prints the statistics once and returns.
     ///
     /// # Example
     ///
@@ -23,13 +30,21 @@ impl Container {
     /// .as_init("/var/run/docker/bundle")
     /// .build()?;
     ///
-    /// container.events(5000, false)?;
+    /// container.events(5, false, &mut [])?;
     /// # Ok(())
     /// # }
     /// ```
-    pub fn events(&mut self, interval: u32, stats: bool) -> Result<(), LibcontainerError> {
+    pub fn events(
+        &mut self,
+        interval: u32,
+        stats: bool,
+        sources: &mut [Box<dyn EventSource>],
+    ) -> Result<(), LibcontainerError> {
         self.refresh_status()?;
-        if !self.state.status.eq(&ContainerStatus::Running) {
+        if !matches!(
+            self.state.status,
+            ContainerStatus::Running | ContainerStatus::Paused
+        ) {
             tracing::error!(id = ?self.id(), status = ?self.state.status, "container is not running");
             return Err(LibcontainerError::IncorrectStatus);
         }
@@ -40,26 +55,64 @@ impl Container {
                 systemd_cgroup: self.systemd(),
                 container_name: self.id().to_string(),
             })?;
-        match stats {
-            true => {
-                let stats = cgroup_manager.stats()?;
-                println!(
-                    "{}",
-                    serde_json::to_string_pretty(&stats)
-                        .map_err(LibcontainerError::OtherSerialization)?
-                );
-            }
-            false => loop {
-                let stats = cgroup_manager.stats()?;
-                println!(
-                    "{}",
-                    serde_json::to_string_pretty(&stats)
-                        .map_err(LibcontainerError::OtherSerialization)?
-                );
-                thread::sleep(Duration::from_secs(interval as u64));
-            },
+        let id = self.id().to_owned();
+        let mut stdout = io::stdout().lock();
+        if stats {
+            let event = ContainerEvent::stats(&id, &cgroup_stats(&cgroup_manager)?);
+            return emit(&mut stdout, &event);
         }
 
-        Ok(())
+        let mut oom_notifier = self
+            .pid()
+            .map(OomNotifier::for_process)
+            .transpose()
+            .unwrap_or_else(|err| {
+                tracing::warn!(?err, "OOM kills of the container will not be reported");
+                None
+            });
+        let interval = Duration::from_secs(interval.max(1) as u64);
+        let mut next_stats = Instant::now();
+        loop {
+            self.refresh_status()?;
+            if self.state.status == ContainerStatus::Stopped {
+                return Ok(());
+            }
+
+            let now = Instant::now();
+            if now >= next_stats {
+                match cgroup_stats(&cgroup_manager) {
+                    Ok(stats) => emit(&mut stdout, &ContainerEvent::stats(&id, &stats))?,
+                    Err(err) => tracing::warn!(?err, "failed to read the container statistics"),
+                }
+                next_stats = now + interval;
+            }
+            for source in sources.iter_mut() {
+                for event in source.poll(&id) {
+                    emit(&mut stdout, &event)?;
+                }
+            }
+
+            let timeout = next_stats.saturating_duration_since(now).min(POLL_INTERVAL);
+            let oom_kills = match &mut oom_notifier {
+                Some(notifier) => notifier
+                    .wait(timeout)
+                    .map_err(|err| LibcontainerError::OtherCgroup(err.to_string()))?,
+                None => {
+                    thread::sleep(timeout);
+                    0
+                }
+            };
+            for _ in 0..oom_kills {
+                emit(&mut stdout, &ContainerEvent::oom(&id))?;
+            }
+        }
     }
 }
+
+/// Writes the event as a compact JSON line, flushed right away for the readers of a pipe
+fn emit(out: &mut impl Write, event: &ContainerEvent) -> Result<(), LibcontainerError> {
+    let line = serde_json::to_string(event).map_err(LibcontainerError::OtherSerialization)?;
+    writeln!(out, "{line}")
+        .and_then(|_| out.flush())
+        .map_err(LibcontainerError::OtherIO)
+}
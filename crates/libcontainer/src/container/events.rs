@@ -0,0 +1,367 @@
+//! Events of [`Container::events`](super::Container::events), in the format of `runc events`.
+//!
+//! Every event is a compact JSON object on its own line, `{"type": ..., "id": ..., "data":
+//! ...}`, which containerd and Docker parse. `stats` events carry the statistics of the
+//! container cgroup with the field names runc uses, `oom` events have no data.
+use std::collections::HashMap;
+
+use libcgroups::common::{AnyCgroupManager, AnyManagerError, CgroupManager};
+use libcgroups::stats::{self, BlkioDeviceStat};
+use serde::{Deserialize, Serialize};
+
+/// Type of the events carrying the cgroup statistics
+pub const STATS_EVENT: &str = "stats";
+/// Type of the events of the processes killed by the OOM killer
+pub const OOM_EVENT: &str = "oom";
+
+/// An event about a container.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContainerEvent {
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Id of the container
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+impl ContainerEvent {
+    pub fn new<S: Into<String>, I: Into<String>>(
+        kind: S,
+        id: I,
+        data: Option<serde_json::Value>,
+    ) -> Self {
+        Self {
+            kind: kind.into(),
+            id: id.into(),
+            data,
+        }
+    }
+
+    pub fn stats<I: Into<String>>(id: I, stats: &stats::Stats) -> Self {
+        let data = serde_json::to_value(Stats::from(stats)).expect("stats are valid JSON");
+        Self::new(STATS_EVENT, id, Some(data))
+    }
+
+    pub fn oom<I: Into<String>>(id: I) -> Self {
+        Self::new(OOM_EVENT, id, None)
+    }
+}
+
+/// Adds events of other origins, for example about the devices of the container, to the
+/// stream of [`Container::events`](super::Container::events).
+pub trait EventSource {
+    /// Returns the events that happened since the last call.
+    fn poll(&mut self, container_id: &str) -> Vec<ContainerEvent>;
+}
+
+/// Returns the statistics of the cgroup with the cpu times in nanoseconds, as runc reports them.
+/// cgroup v2 reports them in microseconds.
+pub fn cgroup_stats(cgroup_manager: &AnyCgroupManager) -> Result<stats::Stats, AnyManagerError> {
+    let mut stats = cgroup_manager.stats()?;
+    if !matches!(cgroup_manager, AnyCgroupManager::V1(_)) {
+        let usage = &mut stats.cpu.usage;
+        for time in [
+            &mut usage.usage_total,
+            &mut usage.usage_user,
+            &mut usage.usage_kernel,
+            &mut stats.cpu.throttling.throttled_time,
+        ] {
+            *time = time.saturating_mul(1000);
+        }
+    }
+    Ok(stats)
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+/// Statistics of a container, as `runc events` reports them.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Stats {
+    pub cpu: Cpu,
+    pub memory: Memory,
+    pub pids: Pids,
+    pub blkio: Blkio,
+    pub hugetlb: HashMap<String, Hugetlb>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Cpu {
+    pub usage: CpuUsage,
+    pub throttling: Throttling,
+}
+
+/// Cpu times in nanoseconds
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct CpuUsage {
+    #[serde(skip_serializing_if = "is_zero")]
+    pub total: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub percpu: Vec<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub percpu_kernel: Vec<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub percpu_user: Vec<u64>,
+    pub kernel: u64,
+    pub user: u64,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Throttling {
+    #[serde(skip_serializing_if = "is_zero")]
+    pub periods: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub throttled_periods: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub throttled_time: u64,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Memory {
+    #[serde(skip_serializing_if = "is_zero")]
+    pub cache: u64,
+    pub usage: MemoryEntry,
+    pub swap: MemoryEntry,
+    pub kernel: MemoryEntry,
+    #[serde(rename = "kernelTCP")]
+    pub kernel_tcp: MemoryEntry,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub raw: HashMap<String, u64>,
+}
+
+/// Memory usage in bytes
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct MemoryEntry {
+    pub limit: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub usage: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub max: u64,
+    pub failcnt: u64,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Pids {
+    #[serde(skip_serializing_if = "is_zero")]
+    pub current: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub limit: u64,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Blkio {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub io_service_bytes_recursive: Vec<BlkioEntry>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub io_serviced_recursive: Vec<BlkioEntry>,
+    #[serde(rename = "ioQueueRecursive", skip_serializing_if = "Vec::is_empty")]
+    pub io_queued_recursive: Vec<BlkioEntry>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub io_service_time_recursive: Vec<BlkioEntry>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub io_wait_time_recursive: Vec<BlkioEntry>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub io_merged_recursive: Vec<BlkioEntry>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub io_time_recursive: Vec<BlkioEntry>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sectors_recursive: Vec<BlkioEntry>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct BlkioEntry {
+    #[serde(skip_serializing_if = "is_zero")]
+    pub major: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub minor: u64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub op: String,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub value: u64,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Hugetlb {
+    #[serde(skip_serializing_if = "is_zero")]
+    pub usage: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub max: u64,
+    pub failcnt: u64,
+}
+
+impl From<&stats::Stats> for Stats {
+    fn from(stats: &stats::Stats) -> Self {
+        let usage = &stats.cpu.usage;
+        let throttling = &stats.cpu.throttling;
+        let memory = &stats.memory;
+        let blkio = &stats.blkio;
+        let entries = |stats: &[BlkioDeviceStat]| -> Vec<BlkioEntry> {
+            stats.iter().map(BlkioEntry::from).collect()
+        };
+
+        Self {
+            cpu: Cpu {
+                usage: CpuUsage {
+                    total: usage.usage_total,
+                    percpu: usage.per_core_usage_total.clone(),
+                    percpu_kernel: usage.per_core_usage_kernel.clone(),
+                    percpu_user: usage.per_core_usage_user.clone(),
+                    kernel: usage.usage_kernel,
+                    user: usage.usage_user,
+                },
+                throttling: Throttling {
+                    periods: throttling.periods,
+                    throttled_periods: throttling.throttled_periods,
+                    throttled_time: throttling.throttled_time,
+                },
+            },
+            memory: Memory {
+                cache: memory.cache,
+                usage: MemoryEntry::from(&memory.memory),
+                swap: MemoryEntry::from(&memory.memswap),
+                kernel: MemoryEntry::from(&memory.kernel),
+                kernel_tcp: MemoryEntry::from(&memory.kernel_tcp),
+                raw: memory.stats.clone(),
+            },
+            pids: Pids {
+                current: stats.pids.current,
+                limit: stats.pids.limit,
+            },
+            blkio: Blkio {
+                io_service_bytes_recursive: entries(&blkio.service_bytes),
+                io_serviced_recursive: entries(&blkio.serviced),
+                io_queued_recursive: entries(&blkio.queued),
+                io_service_time_recursive: entries(&blkio.service_time),
+                io_wait_time_recursive: entries(&blkio.wait_time),
+                io_merged_recursive: entries(&blkio.merged),
+                io_time_recursive: entries(&blkio.time),
+                sectors_recursive: entries(&blkio.sectors),
+            },
+            hugetlb: stats
+                .hugetlb
+                .iter()
+                .map(|(size, hugetlb)| {
+                    let hugetlb = Hugetlb {
+                        usage: hugetlb.usage,
+                        max: hugetlb.max_usage,
+                        failcnt: hugetlb.fail_count,
+                    };
+                    (size.clone(), hugetlb)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<&stats::MemoryData> for MemoryEntry {
+    fn from(data: &stats::MemoryData) -> Self {
+        Self {
+            limit: data.limit,
+            usage: data.usage,
+            max: data.max_usage,
+            failcnt: data.fail_count,
+        }
+    }
+}
+
+impl From<&BlkioDeviceStat> for BlkioEntry {
+    fn from(stat: &BlkioDeviceStat) -> Self {
+        Self {
+            major: stat.major,
+            minor: stat.minor,
+            op: stat.op_type.clone().unwrap_or_default(),
+            value: stat.value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libcgroups::stats::{CpuUsage as CgroupCpuUsage, HugeTlbStats, MemoryData, PidStats};
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_stats_event() {
+        let mut stats = stats::Stats {
+            pids: PidStats {
+                current: 3,
+                limit: 0,
+            },
+            ..Default::default()
+        };
+        stats.cpu.usage = CgroupCpuUsage {
+            usage_total: 1500,
+            usage_user: 1000,
+            usage_kernel: 500,
+            per_core_usage_total: vec![1000, 500],
+            ..Default::default()
+        };
+        stats.memory.memory = MemoryData {
+            usage: 4096,
+            max_usage: 8192,
+            fail_count: 0,
+            limit: 1 << 20,
+        };
+        stats.memory.stats.insert("anon".to_owned(), 4096);
+        stats.blkio.service_bytes.push(BlkioDeviceStat {
+            major: 8,
+            minor: 0,
+            op_type: Some("Read".to_owned()),
+            value: 512,
+        });
+        stats.hugetlb.insert(
+            "2MB".to_owned(),
+            HugeTlbStats {
+                usage: 0,
+                max_usage: 0,
+                fail_count: 1,
+            },
+        );
+
+        let event = serde_json::to_value(ContainerEvent::stats("74f1a4cb3801", &stats)).unwrap();
+        assert_eq!(
+            event,
+            json!({
+                "type": "stats",
+                "id": "74f1a4cb3801",
+                "data": {
+                    "cpu": {
+                        "usage": {
+                            "total": 1500,
+                            "percpu": [1000, 500],
+                            "kernel": 500,
+                            "user": 1000,
+                        },
+                        "throttling": {},
+                    },
+                    "memory": {
+                        "usage": {"limit": 1048576, "usage": 4096, "max": 8192, "failcnt": 0},
+                        "swap": {"limit": 0, "failcnt": 0},
+                        "kernel": {"limit": 0, "failcnt": 0},
+                        "kernelTCP": {"limit": 0, "failcnt": 0},
+                        "raw": {"anon": 4096},
+                    },
+                    "pids": {"current": 3},
+                    "blkio": {
+                        "ioServiceBytesRecursive": [
+                            {"major": 8, "op": "Read", "value": 512},
+                        ],
+                    },
+                    "hugetlb": {"2MB": {"failcnt": 1}},
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_oom_event() {
+        let line = serde_json::to_string(&ContainerEvent::oom("74f1a4cb3801")).unwrap();
+        assert_eq!(line, r#"{"type":"oom","id":"74f1a4cb3801"}"#);
+    }
+}
@@ -1,4 +1,5 @@
 use std::fs;
+use std::os::unix::prelude::RawFd;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
@@ -12,6 +13,7 @@ use crate::config::YoukiConfig;
 use crate::error::{ErrInvalidSpec, LibcontainerError, MissingSpecError};
 use crate::notify_socket::NOTIFY_FILE;
 use crate::process::args::ContainerType;
+use crate::spec_modifier::{self, SpecModifier, SpecModifierError};
 use crate::{apparmor, tty, user_ns, utils};
 
 // Builder that can be used to configure the properties of a new container
@@ -20,6 +22,7 @@ pub struct InitContainerBuilder {
     bundle: PathBuf,
     use_systemd: bool,
     detached: bool,
+    spec_modifiers: Vec<Box<dyn SpecModifier>>,
 }
 
 impl InitContainerBuilder {
@@ -31,6 +34,7 @@ impl InitContainerBuilder {
             bundle,
             use_systemd: true,
             detached: true,
+            spec_modifiers: Vec::new(),
         }
     }
 
@@ -45,17 +49,107 @@ impl InitContainerBuilder {
         self
     }
 
+    /// Adds a modifier that rewrites the spec loaded from the bundle before
+    /// the container is created. Modifiers run in the order they are added.
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use libcontainer::container::builder::ContainerBuilder;
+    /// # use libcontainer::oci_spec::runtime::Spec;
+    /// # use libcontainer::spec_modifier::{SpecModifier, SpecModifierError};
+    /// # use libcontainer::syscall::syscall::SyscallType;
+    /// struct Hostname;
+    ///
+    /// impl SpecModifier for Hostname {
+    ///     fn name(&self) -> &str {
+    ///         "hostname"
+    ///     }
+    ///
+    ///     fn modify(&self, spec: &mut Spec) -> Result<(), SpecModifierError> {
+    ///         spec.set_hostname(Some("youki".to_owned()));
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// ContainerBuilder::new(
+    ///     "74f1a4cb3801".to_owned(),
+    ///     SyscallType::default(),
+    /// )
+    /// .as_init("/var/run/docker/bundle")
+    /// .with_spec_modifier(Hostname)
+    /// .build();
+    /// ```
+    pub fn with_spec_modifier(mut self, modifier: impl SpecModifier + 'static) -> Self {
+        self.spec_modifiers.push(Box::new(modifier));
+        self
+    }
+
     /// Creates a new container
     pub fn build(self) -> Result<Container, LibcontainerError> {
-        let spec = self.load_spec()?;
+        let mut spec = self.load_spec()?;
         let container_dir = self.create_container_dir()?;
 
         let mut container = self.create_container_state(&container_dir)?;
+        // Modifiers run once the container state exists, so that the devices they lease are
+        // not taken for the leases of a container that is gone. Until the container process
+        // is started, a failure removes the state and releases them again.
+        let prepared = self.prepare(&mut spec, &mut container, &container_dir);
+        let (rootfs, csocketfd, user_ns_config) = match prepared {
+            Ok(prepared) => prepared,
+            Err(err) => {
+                Self::remove_container_state(&container);
+                return Err(err);
+            }
+        };
+        let notify_path = container_dir.join(NOTIFY_FILE);
+
+        let mut builder_impl = ContainerBuilderImpl {
+            container_type: ContainerType::InitContainer,
+            syscall: self.base.syscall,
+            container_id: self.base.container_id,
+            pid_file: self.base.pid_file,
+            console_socket: csocketfd,
+            use_systemd: self.use_systemd,
+            spec: Rc::new(spec),
+            rootfs,
+            user_ns_config,
+            notify_path,
+            container: Some(container.clone()),
+            preserve_fds: self.base.preserve_fds,
+            detached: self.detached,
+            executor: self.base.executor,
+            stdin: self.base.stdin,
+            stdout: self.base.stdout,
+            stderr: self.base.stderr,
+        };
+
+        builder_impl.create()?;
+
+        container.refresh_state()?;
+
+        Ok(container)
+    }
+
+    /// Applies the spec modifiers and saves the config of the container, returns its root
+    /// file system, its console socket and its user namespace configuration.
+    fn prepare(
+        &self,
+        spec: &mut Spec,
+        container: &mut Container,
+        container_dir: &Path,
+    ) -> Result<(PathBuf, Option<RawFd>, Option<UserNamespaceConfig>), LibcontainerError> {
+        spec_modifier::apply_all(&self.spec_modifiers, spec).map_err(|err| match err {
+            SpecModifierError::Incompatible { name, reasons } => LibcontainerError::Incompatible {
+                checker: name,
+                reasons,
+            },
+            err => err.into(),
+        })?;
+        Self::validate_spec(spec)?;
         container
             .set_systemd(self.use_systemd)
             .set_annotations(spec.annotations().clone());
 
-        let notify_path = container_dir.join(NOTIFY_FILE);
         // convert path of root file system of the container to absolute path
         let rootfs = fs::canonicalize(spec.root().as_ref().ok_or(MissingSpecError::Root)?.path())
             .map_err(LibcontainerError::OtherIO)?;
@@ -64,7 +158,7 @@ impl InitContainerBuilder {
         // get file descriptors of console socket
         let csocketfd = if let Some(console_socket) = &self.base.console_socket {
             Some(tty::setup_console_socket(
-                &container_dir,
+                container_dir,
                 console_socket,
                 "console-socket",
             )?)
@@ -72,36 +166,23 @@ impl InitContainerBuilder {
             None
         };
 
-        let user_ns_config = UserNamespaceConfig::new(&spec)?;
+        let user_ns_config = UserNamespaceConfig::new(spec)?;
 
-        let config = YoukiConfig::from_spec(&spec, container.id())?;
-        config.save(&container_dir).map_err(|err| {
+        let config = YoukiConfig::from_spec(spec, container.id())?;
+        config.save(container_dir).map_err(|err| {
             tracing::error!(?container_dir, "failed to save config: {}", err);
             err
         })?;
 
-        let mut builder_impl = ContainerBuilderImpl {
-            container_type: ContainerType::InitContainer,
-            syscall: self.base.syscall,
-            container_id: self.base.container_id,
-            pid_file: self.base.pid_file,
-            console_socket: csocketfd,
-            use_systemd: self.use_systemd,
-            spec: Rc::new(spec),
-            rootfs,
-            user_ns_config,
-            notify_path,
-            container: Some(container.clone()),
-            preserve_fds: self.base.preserve_fds,
-            detached: self.detached,
-            executor: self.base.executor,
-        };
-
-        builder_impl.create()?;
-
-        container.refresh_state()?;
+        Ok((rootfs, csocketfd, user_ns_config))
+    }
 
-        Ok(container)
+    /// Removes the state of a container whose process was not started, with its leases.
+    fn remove_container_state(container: &Container) {
+        if let Err(err) = fs::remove_dir_all(&container.root) {
+            tracing::error!(container_root = ?container.root, ?err, "failed to delete container root");
+        }
+        container.release_leases();
     }
 
     fn create_container_dir(&self) -> Result<PathBuf, LibcontainerError> {
@@ -196,3 +277,77 @@ impl InitContainerBuilder {
         Ok(container)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::lease::LeaseLedger;
+    use crate::syscall::syscall::SyscallType;
+
+    /// Leases a device like the injectors do, then finds the container incompatible
+    struct Leasing {
+        ledger: LeaseLedger,
+        ran: Rc<Cell<bool>>,
+    }
+
+    impl SpecModifier for Leasing {
+        fn name(&self) -> &str {
+            "leasing"
+        }
+
+        fn modify(&self, _: &mut Spec) -> Result<(), SpecModifierError> {
+            self.ran.set(true);
+            self.ledger
+                .acquire("74f1a4cb3801", &["apex_0"], false)
+                .map_err(|err| SpecModifierError::Modify {
+                    name: self.name().to_owned(),
+                    source: err.into(),
+                })?;
+            Err(SpecModifierError::Incompatible {
+                name: self.name().to_owned(),
+                reasons: vec!["test".to_owned()],
+            })
+        }
+    }
+
+    #[test]
+    fn test_failed_modifier_removes_the_state() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let bundle = tempfile::tempdir()?;
+        fs::create_dir(bundle.path().join("rootfs"))?;
+        Spec::default().save(bundle.path().join("config.json"))?;
+        let ledger = LeaseLedger::new(root.path());
+
+        let build = |ran: Rc<Cell<bool>>| -> Result<LibcontainerError> {
+            let err = ContainerBuilder::new("74f1a4cb3801".to_owned(), SyscallType::default())
+                .with_root_path(root.path())?
+                .as_init(bundle.path())
+                .with_spec_modifier(Leasing {
+                    ledger: ledger.clone(),
+                    ran,
+                })
+                .build()
+                .expect_err("the container is not created");
+            Ok(err)
+        };
+
+        let err = build(Rc::default())?;
+        assert!(matches!(err, LibcontainerError::Incompatible { .. }));
+        assert!(!root.path().join("74f1a4cb3801").exists());
+        assert!(ledger.leases()?.is_empty());
+
+        // the id is free again: the modifiers of a new container with it run
+        let ran = Rc::new(Cell::new(false));
+        let err = build(ran.clone())?;
+        assert!(!matches!(err, LibcontainerError::Exist));
+        assert!(ran.get());
+        ledger.acquire("other", &["apex_0"], false)?;
+
+        Ok(())
+    }
+}
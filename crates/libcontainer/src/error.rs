@@ -62,6 +62,13 @@ pub enum LibcontainerError {
     CgroupGet(#[from] libcgroups::common::GetCgroupSetupError),
     #[error[transparent]]
     Checkpoint(#[from] crate::container::CheckpointError),
+    #[error(transparent)]
+    SpecModifier(#[from] crate::spec_modifier::SpecModifierError),
+    #[error("container is incompatible with this host ({checker}): {}", .reasons.join("; "))]
+    Incompatible {
+        checker: String,
+        reasons: Vec<String>,
+    },
 
     // Catch all errors that are not covered by the above
     #[error("syscall error")]
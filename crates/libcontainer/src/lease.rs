@@ -0,0 +1,408 @@
+//! Ledger of the devices leased to containers.
+//!
+//! Some devices must not be opened by two containers at once. Whoever hands a device to a
+//! container records a lease in a ledger kept in the runtime root, next to the container
+//! state directories, and [`Container::delete`](crate::container::Container::delete)
+//! releases every lease of the container. The ledger is a JSON file guarded by an exclusive
+//! `flock(2)` on a lock file, so concurrent runtime invocations see each other's leases.
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use nix::fcntl::{Flock, FlockArg};
+use serde::{Deserialize, Serialize};
+
+use crate::config::YOUKI_CONFIG_NAME;
+use crate::container::state::State;
+use crate::container::{Container, ContainerStatus};
+
+const LEDGER_FILE: &str = "device-leases.json";
+const LOCK_FILE: &str = "device-leases.lock";
+/// How long a container may be creating without a config before its creation is taken for
+/// abandoned
+const ABANDONED_CREATION: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum LeaseError {
+    #[error("failed to lock the lease ledger {path:?}")]
+    Lock { source: nix::Error, path: PathBuf },
+    #[error("failed to access the lease ledger {path:?}")]
+    Io {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("corrupted lease ledger {path:?}")]
+    Parse {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+    #[error("device {device} is already leased to container {holder}")]
+    Taken { device: String, holder: String },
+}
+
+type Result<T> = std::result::Result<T, LeaseError>;
+
+/// A device leased to a container.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    pub device: String,
+    pub container_id: String,
+    /// Shared leases may be held by several containers at once, an exclusive one may not
+    /// coexist with any other lease of the device.
+    pub shared: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Ledger {
+    /// Holders of each device
+    devices: BTreeMap<String, Vec<Holder>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Holder {
+    container_id: String,
+    shared: bool,
+}
+
+/// The lease ledger of a runtime root.
+///
+/// # Example
+///
+/// ```no_run
+/// use libcontainer::lease::LeaseLedger;
+///
+/// let ledger = LeaseLedger::new("/run/youki");
+/// ledger.acquire("74f1a4cb3801", &["apex_0"], false).unwrap();
+/// ledger.release("74f1a4cb3801").unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct LeaseLedger {
+    root: PathBuf,
+}
+
+impl LeaseLedger {
+    /// Creates a ledger kept in the runtime root, where the container state directories are
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Leases all the devices to the container, or none of them if one is taken. Leases
+    /// of containers that no longer exist or whose process is gone are dropped first.
+    /// Acquiring a device the container already holds only updates the lease mode.
+    pub fn acquire(&self, container_id: &str, devices: &[&str], shared: bool) -> Result<()> {
+        self.update(|ledger| {
+            self.prune(ledger, container_id);
+
+            for device in devices {
+                let holders = ledger.devices.get(*device).into_iter().flatten();
+                if let Some(holder) = holders
+                    .filter(|h| h.container_id != container_id)
+                    .find(|h| !(shared && h.shared))
+                {
+                    return Err(LeaseError::Taken {
+                        device: device.to_string(),
+                        holder: holder.container_id.clone(),
+                    });
+                }
+            }
+
+            for device in devices {
+                let holders = ledger.devices.entry(device.to_string()).or_default();
+                holders.retain(|h| h.container_id != container_id);
+                holders.push(Holder {
+                    container_id: container_id.to_owned(),
+                    shared,
+                });
+            }
+            Ok(())
+        })
+    }
+
+    /// Releases every lease of the container and returns the released devices.
+    pub fn release(&self, container_id: &str) -> Result<Vec<String>> {
+        if !self.ledger_path().exists() {
+            return Ok(Vec::new());
+        }
+
+        self.update(|ledger| {
+            let mut released = Vec::new();
+            for (device, holders) in ledger.devices.iter_mut() {
+                let count = holders.len();
+                holders.retain(|h| h.container_id != container_id);
+                if holders.len() != count {
+                    released.push(device.clone());
+                }
+            }
+            ledger.devices.retain(|_, holders| !holders.is_empty());
+            Ok(released)
+        })
+    }
+
+    /// Returns the current leases, by device.
+    pub fn leases(&self) -> Result<Vec<Lease>> {
+        let _lock = self.lock()?;
+        let ledger = self.load()?;
+        Ok(ledger
+            .devices
+            .into_iter()
+            .flat_map(|(device, holders)| {
+                holders.into_iter().map(move |holder| Lease {
+                    device: device.clone(),
+                    container_id: holder.container_id,
+                    shared: holder.shared,
+                })
+            })
+            .collect())
+    }
+
+    /// Runs `f` on the ledger with the lock held, and saves the ledger if `f` succeeds.
+    fn update<T>(&self, f: impl FnOnce(&mut Ledger) -> Result<T>) -> Result<T> {
+        let _lock = self.lock()?;
+        let mut ledger = self.load()?;
+        let result = f(&mut ledger)?;
+        self.save(&ledger)?;
+        Ok(result)
+    }
+
+    /// Drops the holders whose container is gone: its state directory was removed, it was
+    /// started and its process no longer runs, or its creation was abandoned.
+    fn prune(&self, ledger: &mut Ledger, current: &str) {
+        let mut stale: BTreeMap<String, bool> = BTreeMap::new();
+        for holder in ledger.devices.values().flatten() {
+            if holder.container_id == current || stale.contains_key(&holder.container_id) {
+                continue;
+            }
+            let is_stale = self.is_stale(&holder.container_id);
+            if is_stale {
+                tracing::warn!(
+                    id = holder.container_id,
+                    "dropping the device leases of a container that is gone"
+                );
+            }
+            stale.insert(holder.container_id.clone(), is_stale);
+        }
+
+        for holders in ledger.devices.values_mut() {
+            holders.retain(|h| !stale.get(&h.container_id).copied().unwrap_or_default());
+        }
+        ledger.devices.retain(|_, holders| !holders.is_empty());
+    }
+
+    fn is_stale(&self, container_id: &str) -> bool {
+        let container_root = self.root.join(container_id);
+        if !container_root.exists() {
+            return true;
+        }
+
+        let state = match State::load(&container_root) {
+            Ok(state) => state,
+            Err(err) => {
+                tracing::debug!(id = container_id, ?err, "failed to load lease holder");
+                return false;
+            }
+        };
+        if state.pid.is_none() {
+            // a container that is still being created has no pid yet, one whose runtime died
+            // before saving its config never gets one
+            return state.status == ContainerStatus::Creating
+                && !container_root.join(YOUKI_CONFIG_NAME).exists()
+                && is_older_than(&State::file_path(&container_root), ABANDONED_CREATION);
+        }
+
+        match Container::load(container_root) {
+            Ok(container) => container.status() == ContainerStatus::Stopped,
+            Err(err) => {
+                tracing::debug!(id = container_id, ?err, "failed to load lease holder");
+                false
+            }
+        }
+    }
+
+    fn lock(&self) -> Result<Flock<File>> {
+        let path = self.root.join(LOCK_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|err| LeaseError::Io {
+                source: err,
+                path: path.clone(),
+            })?;
+        Flock::lock(file, FlockArg::LockExclusive)
+            .map_err(|(_, err)| LeaseError::Lock { source: err, path })
+    }
+
+    fn load(&self) -> Result<Ledger> {
+        let path = self.ledger_path();
+        match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .map_err(|err| LeaseError::Parse { source: err, path }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Ledger::default()),
+            Err(err) => Err(LeaseError::Io { source: err, path }),
+        }
+    }
+
+    fn save(&self, ledger: &Ledger) -> Result<()> {
+        let path = self.ledger_path();
+        let tmp = path.with_extension("json.tmp");
+        let content = serde_json::to_vec(ledger).map_err(|err| LeaseError::Parse {
+            source: err,
+            path: path.clone(),
+        })?;
+        fs::write(&tmp, content)
+            .and_then(|_| fs::rename(&tmp, &path))
+            .map_err(|err| LeaseError::Io { source: err, path })
+    }
+
+    fn ledger_path(&self) -> PathBuf {
+        self.root.join(LEDGER_FILE)
+    }
+}
+
+fn is_older_than(path: &Path, age: Duration) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map(|modified| {
+            SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default()
+                > age
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use nix::sys::stat::utimes;
+    use nix::sys::time::TimeVal;
+
+    use super::*;
+
+    fn create_container(root: &Path, id: &str, pid: Option<i32>) {
+        create_container_with_status(root, id, ContainerStatus::Created, pid);
+    }
+
+    fn create_container_with_status(
+        root: &Path,
+        id: &str,
+        status: ContainerStatus,
+        pid: Option<i32>,
+    ) {
+        let container_root = root.join(id);
+        fs::create_dir_all(&container_root).unwrap();
+        let container = Container::new(id, status, pid, root, &container_root).unwrap();
+        container.save().unwrap();
+        assert!(State::file_path(&container_root).exists());
+    }
+
+    #[test]
+    fn test_exclusive_leases() {
+        let tmp = tempfile::tempdir().unwrap();
+        create_container(tmp.path(), "first", None);
+        create_container(tmp.path(), "second", None);
+        let ledger = LeaseLedger::new(tmp.path());
+
+        ledger.acquire("first", &["apex_0"], false).unwrap();
+        // acquiring again is idempotent
+        ledger.acquire("first", &["apex_0"], false).unwrap();
+
+        let result = ledger.acquire("second", &["apex_1", "apex_0"], false);
+        assert!(matches!(
+            result,
+            Err(LeaseError::Taken { ref device, ref holder }) if device == "apex_0" && holder == "first"
+        ));
+        // nothing was leased to the second container
+        assert_eq!(ledger.leases().unwrap().len(), 1);
+        assert!(matches!(
+            ledger.acquire("second", &["apex_0"], true),
+            Err(LeaseError::Taken { .. })
+        ));
+
+        assert_eq!(ledger.release("first").unwrap(), vec!["apex_0"]);
+        ledger
+            .acquire("second", &["apex_1", "apex_0"], false)
+            .unwrap();
+        assert_eq!(
+            ledger.leases().unwrap(),
+            vec![
+                Lease {
+                    device: "apex_0".to_owned(),
+                    container_id: "second".to_owned(),
+                    shared: false,
+                },
+                Lease {
+                    device: "apex_1".to_owned(),
+                    container_id: "second".to_owned(),
+                    shared: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shared_leases() {
+        let tmp = tempfile::tempdir().unwrap();
+        for id in ["a", "b", "c"] {
+            create_container(tmp.path(), id, None);
+        }
+        let ledger = LeaseLedger::new(tmp.path());
+
+        ledger.acquire("a", &["apex_0"], true).unwrap();
+        ledger.acquire("b", &["apex_0"], true).unwrap();
+        assert!(matches!(
+            ledger.acquire("c", &["apex_0"], false),
+            Err(LeaseError::Taken { .. })
+        ));
+
+        ledger.release("a").unwrap();
+        ledger.release("b").unwrap();
+        ledger.acquire("c", &["apex_0"], false).unwrap();
+    }
+
+    #[test]
+    fn test_stale_leases_are_dropped() {
+        let tmp = tempfile::tempdir().unwrap();
+        // the container directory was removed without releasing the lease
+        create_container(tmp.path(), "removed", None);
+        // the container process is gone
+        create_container(tmp.path(), "crashed", Some(i32::MAX));
+        // still being created, no pid yet
+        create_container_with_status(tmp.path(), "creating", ContainerStatus::Creating, None);
+        // the runtime died while creating it
+        create_container_with_status(tmp.path(), "abandoned", ContainerStatus::Creating, None);
+        let abandoned = State::file_path(&tmp.path().join("abandoned"));
+        utimes(&abandoned, &TimeVal::new(0, 0), &TimeVal::new(0, 0)).unwrap();
+        create_container(tmp.path(), "new", None);
+        let ledger = LeaseLedger::new(tmp.path());
+
+        ledger.acquire("removed", &["apex_0"], false).unwrap();
+        ledger.acquire("crashed", &["apex_1"], false).unwrap();
+        ledger.acquire("creating", &["apex_2"], false).unwrap();
+        ledger.acquire("abandoned", &["apex_3"], false).unwrap();
+        fs::remove_dir_all(tmp.path().join("removed")).unwrap();
+
+        ledger
+            .acquire("new", &["apex_0", "apex_1", "apex_3"], false)
+            .unwrap();
+        assert!(matches!(
+            ledger.acquire("new", &["apex_2"], false),
+            Err(LeaseError::Taken { ref holder, .. }) if holder == "creating"
+        ));
+    }
+
+    #[test]
+    fn test_release_without_ledger() {
+        let tmp = tempfile::tempdir().unwrap();
+        let ledger = LeaseLedger::new(tmp.path());
+        assert!(ledger.release("unknown").unwrap().is_empty());
+        assert!(!tmp.path().join(LEDGER_FILE).exists());
+    }
+}
@@ -5,6 +5,7 @@ pub mod config;
 pub mod container;
 pub mod error;
 pub mod hooks;
+pub mod lease;
 pub mod namespaces;
 pub mod notify_socket;
 pub mod process;
@@ -12,6 +13,7 @@ pub mod rootfs;
 #[cfg(feature = "libseccomp")]
 pub mod seccomp;
 pub mod signal;
+pub mod spec_modifier;
 pub mod syscall;
 pub mod test_utils;
 pub mod tty;
@@ -313,17 +313,6 @@ pub fn container_init_process(
     }
 
     if matches!(args.container_type, ContainerType::InitContainer) {
-        // create_container hook needs to be called after the namespace setup, but
-        // before pivot_root is called. This runs in the container namespaces.
-        if let Some(hooks) = hooks {
-            hooks::run_hooks(hooks.create_container().as_ref(), container, None).map_err(
-                |err| {
-                    tracing::error!(?err, "failed to run create container hooks");
-                    InitProcessError::Hooks(err)
-                },
-            )?;
-        }
-
         let in_user_ns = utils::is_in_new_userns().map_err(InitProcessError::Io)?;
         let bind_service = namespaces.get(LinuxNamespaceType::User)?.is_some() || in_user_ns;
         let rootfs = RootFS::new();
@@ -339,6 +328,17 @@ pub fn container_init_process(
                 InitProcessError::RootFS(err)
             })?;
 
+        // create_container hook needs to be called after the namespace setup and the
+        // mounts, but before pivot_root is called. This runs in the container namespaces.
+        if let Some(hooks) = hooks {
+            hooks::run_hooks(hooks.create_container().as_ref(), container, None).map_err(
+                |err| {
+                    tracing::error!(?err, "failed to run create container hooks");
+                    InitProcessError::Hooks(err)
+                },
+            )?;
+        }
+
         // Entering into the rootfs jail. If mount namespace is specified, then
         // we use pivot_root, but if we are on the host mount namespace, we will
         // use simple chroot. Scary things will happen if you try to pivot_root
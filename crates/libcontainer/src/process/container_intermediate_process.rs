@@ -29,6 +29,8 @@ pub enum IntermediateProcessError {
     Procfs(#[from] procfs::ProcError),
     #[error("exec notify failed")]
     ExecNotify(#[source] nix::Error),
+    #[error("failed to set up the standard streams")]
+    Stdio(#[source] nix::Error),
     #[error(transparent)]
     MissingSpec(#[from] crate::error::MissingSpecError),
     #[error("other error")]
@@ -45,6 +47,13 @@ pub fn container_intermediate_process(
 ) -> Result<()> {
     let (inter_sender, inter_receiver) = intermediate_chan;
     let (init_sender, init_receiver) = init_chan;
+
+    // only this process and the init process it creates get the given streams
+    for (fd, target) in [(args.stdin, 0), (args.stdout, 1), (args.stderr, 2)] {
+        if let Some(fd) = fd {
+            nix::unistd::dup2(fd, target).map_err(IntermediateProcessError::Stdio)?;
+        }
+    }
     let command = args.syscall.create_syscall();
     let spec = &args.spec;
     let linux = spec.linux().as_ref().ok_or(MissingSpecError::Linux)?;
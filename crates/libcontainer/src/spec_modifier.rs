@@ -0,0 +1,122 @@
+//! Hooks that rewrite the runtime spec of a new container before it is created.
+//!
+//! A spec modifier runs once per init container, after `config.json` has been loaded from the
+//! bundle and validated, and before anything is derived from it (the youki config, cgroups,
+//! rootfs). This is how users of `libcontainer` can inject devices, mounts or environment
+//! variables the bundle author did not write into the spec.
+use oci_spec::runtime::Spec;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SpecModifierError {
+    #[error("{name} rejected the container spec: {reason}")]
+    Rejected { name: String, reason: String },
+    /// The container cannot run on this host, e.g. its workload needs a newer driver
+    #[error("{name} found the container incompatible with this host: {}", .reasons.join("; "))]
+    Incompatible { name: String, reasons: Vec<String> },
+    #[error("{name} failed to modify the container spec")]
+    Modify {
+        name: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+pub trait SpecModifier {
+    /// Name of the modifier, used in logs and errors
+    fn name(&self) -> &str;
+
+    /// Modifies the spec in place. Returning an error aborts the creation
+    /// of the container.
+    fn modify(&self, spec: &mut Spec) -> Result<(), SpecModifierError>;
+}
+
+/// Runs the modifiers in the order they were registered.
+pub(crate) fn apply_all(
+    modifiers: &[Box<dyn SpecModifier>],
+    spec: &mut Spec,
+) -> Result<(), SpecModifierError> {
+    for modifier in modifiers {
+        tracing::debug!(modifier = modifier.name(), "applying spec modifier");
+        modifier.modify(spec).map_err(|err| {
+            tracing::error!(modifier = modifier.name(), ?err, "failed to modify spec");
+            err
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    struct Recorder {
+        name: &'static str,
+        calls: Rc<RefCell<Vec<&'static str>>>,
+        fail: bool,
+    }
+
+    impl SpecModifier for Recorder {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn modify(&self, spec: &mut Spec) -> Result<(), SpecModifierError> {
+            self.calls.borrow_mut().push(self.name);
+            if self.fail {
+                return Err(SpecModifierError::Rejected {
+                    name: self.name.to_owned(),
+                    reason: "test".to_owned(),
+                });
+            }
+            spec.set_hostname(Some(self.name.to_owned()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_apply_in_order() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let modifiers: Vec<Box<dyn SpecModifier>> = vec![
+            Box::new(Recorder {
+                name: "first",
+                calls: calls.clone(),
+                fail: false,
+            }),
+            Box::new(Recorder {
+                name: "second",
+                calls: calls.clone(),
+                fail: false,
+            }),
+        ];
+
+        let mut spec = Spec::default();
+        apply_all(&modifiers, &mut spec).unwrap();
+        assert_eq!(*calls.borrow(), vec!["first", "second"]);
+        assert_eq!(spec.hostname().as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn test_stop_at_first_error() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let modifiers: Vec<Box<dyn SpecModifier>> = vec![
+            Box::new(Recorder {
+                name: "failing",
+                calls: calls.clone(),
+                fail: true,
+            }),
+            Box::new(Recorder {
+                name: "never",
+                calls: calls.clone(),
+                fail: false,
+            }),
+        ];
+
+        let result = apply_all(&modifiers, &mut Spec::default());
+        assert!(matches!(result, Err(SpecModifierError::Rejected { .. })));
+        assert_eq!(*calls.borrow(), vec!["failing"]);
+    }
+}
@@ -1,90 +1,232 @@
-use std::path::PathBuf;
-use std::process::Command;
-use std::{env, fs};
+//! Compiles the C++ side of the `driver` module and links it against libedgetpu.
+//!
+//! The link mode is picked in this order:
+//!
+//! 1. `stub` feature: the in-tree fake of `stub/include`, nothing is linked.
+//! 2. `LIBEDGETPU_DIR`: a prebuilt libedgetpu, with the headers in `include/` and the
+//!    library in `lib/` (or both right in the directory).
+//! 3. `EDGETPU_RUNTIME_DIR`: the `edgetpu_runtime` directory of the runtime zip extracted by
+//!    `edgetpu::dep::download`. The `max-freq` feature picks the `direct` library over the
+//!    `throttled` one.
+//! 4. pkg-config.
+//!
+//! Except for the stub, the TensorFlow Lite C headers are needed too. They are taken from
+//! `TFLITE_DIR` (laid out like `LIBEDGETPU_DIR`) or found with pkg-config.
+//!
+//! `docker build ./build` still produces a `/libedgetpu` and a `/tensorflow` directory that
+//! can be copied out of the image and used as `LIBEDGETPU_DIR` and `TFLITE_DIR`.
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-use cpp_build;
+const DRIVER_SOURCE: &str = "src/driver/driver.rs";
+const STUB_INCLUDE_DIR: &str = "stub/include";
+/// Header included by the driver, relative to an include directory
+const EDGETPU_HEADER: &str = "tflite/public/edgetpu.h";
+
+const LIBEDGETPU_DIR_ENV: &str = "LIBEDGETPU_DIR";
+const RUNTIME_DIR_ENV: &str = "EDGETPU_RUNTIME_DIR";
+const TFLITE_DIR_ENV: &str = "TFLITE_DIR";
+
+const LIBEDGETPU_PKG_NAMES: [&str; 2] = ["edgetpu", "libedgetpu"];
+const TFLITE_PKG_NAMES: [&str; 2] = ["tensorflowlite_c", "tensorflow-lite"];
+
+/// Headers and libraries of a dependency.
+#[derive(Debug, Default)]
+struct Package {
+    include_dirs: Vec<PathBuf>,
+    /// Directories to search libraries in, pkg-config already told cargo about its own
+    link_dirs: Vec<PathBuf>,
+    /// Libraries to link, as `cargo:rustc-link-lib` values
+    libs: Vec<String>,
+}
+
+impl Package {
+    /// A prebuilt package laid out as `include/` and `lib/`, or with everything at the top.
+    fn from_dir(dir: &Path, lib: &str) -> Self {
+        let include_dir = dir.join("include");
+        let lib_dir = dir.join("lib");
+        Self {
+            include_dirs: vec![if include_dir.is_dir() {
+                include_dir
+            } else {
+                dir.to_owned()
+            }],
+            link_dirs: vec![if lib_dir.is_dir() {
+                lib_dir
+            } else {
+                dir.to_owned()
+            }],
+            libs: vec![lib.to_owned()],
+        }
+    }
+
+    fn from_pkg_config(names: &[&str]) -> Option<Self> {
+        names.iter().find_map(|name| {
+            let library = pkg_config::Config::new()
+                .cargo_metadata(true)
+                .probe(name)
+                .ok()?;
+            Some(Self {
+                include_dirs: library.include_paths,
+                ..Default::default()
+            })
+        })
+    }
+}
 
 fn main() {
-    // Build the Docker image
-    if !Command::new("docker")
-        .args(["build", "-t", "libedgetpu-builder", "./build"])
-        .status()
-        .expect("Failed to start Docker build")
-        .success()
-    {
-        panic!("Failed to build Docker image for TensorFlow Lite and libedgetpu");
+    println!("cargo:rerun-if-changed={DRIVER_SOURCE}");
+    println!("cargo:rerun-if-changed={STUB_INCLUDE_DIR}");
+    for var in [LIBEDGETPU_DIR_ENV, RUNTIME_DIR_ENV, TFLITE_DIR_ENV] {
+        println!("cargo:rerun-if-env-changed={var}");
     }
 
-    // Get the output directory from the environment variable set by Cargo
-    let out_dir = env::var("OUT_DIR").expect("OUT_DIR not set");
-    let output_path = PathBuf::from(&out_dir);
-
-    // Create a temporary container to extract the build artifacts
-    let container_id = Command::new("docker")
-        .args(["create", "libedgetpu-builder"])
-        .output()
-        .expect("Failed to create Docker container")
-        .stdout;
-    let container_id = String::from_utf8_lossy(&container_id).trim().to_string();
-
-    fs::create_dir_all(output_path.join("tensorflow").to_str().unwrap())
-        .expect("Failed to create TensorFlow Lite include path");
-    fs::create_dir_all(output_path.join("libedgetpu").to_str().unwrap())
-        .expect("Failed to create TensorFlow Lite include path");
-
-    let tensorflow_include_path = output_path.join("tensorflow/include");
-    let tensorflow_lib_path = output_path.join("tensorflow/lib");
-    let libedgetpu_include_path = output_path.join("libedgetpu/include");
-    let libedgetpu_lib_path = output_path.join("libedgetpu/lib");
-
-    Command::new("docker")
-        .args([
-            "cp",
-            &format!("{}:/tensorflow", container_id),
-            output_path.to_str().unwrap(),
-        ])
-        .status()
-        .expect("Failed to copy TensorFlow Lite lib");
-
-    Command::new("docker")
-        .args([
-            "cp",
-            &format!("{}:/libedgetpu", container_id),
-            output_path.to_str().unwrap(),
-        ])
-        .status()
-        .expect("Failed to copy libedgetpu lib");
-
-    // Remove the temporary container
-    Command::new("docker")
-        .args(["rm", &container_id])
-        .status()
-        .expect("Failed to remove Docker container");
-
-    // Define include and lib paths
-    let tflite_include_path = tensorflow_include_path.to_str().unwrap();
-    let tflite_lib_path = tensorflow_lib_path.to_str().unwrap();
-    let edgetpu_include_path = libedgetpu_include_path.to_str().unwrap();
-    let edgetpu_lib_path = libedgetpu_lib_path.to_str().unwrap();
-
-    cpp_build::Config::new()
-        .include(tflite_include_path)
-        .include(edgetpu_include_path)
-        .flag_if_supported(format!("-L{}", tflite_lib_path).as_str())
-        .flag_if_supported(format!("-L{}", edgetpu_lib_path).as_str())
-        .flag_if_supported("-ltensorflowlite_c")
-        .flag_if_supported("-ledgetpu")
-        .flag_if_supported("-lpthread")
-        .flag_if_supported("-lm")
-        .flag_if_supported("-ldl")
-        .build("src/driver/driver.rs");
-
-    // Setup linking for TensorFlow Lite
-    println!("cargo:rustc-link-search=native={}", tflite_lib_path);
-    // Setup linking for libedgetpu
-    println!("cargo:rustc-link-search=native={}", edgetpu_lib_path);
-
-    println!("cargo:rustc-link-lib=tensorflowlite_c");
-    println!("cargo:rustc-link-lib=dylib=edgetpu");
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR not set"));
+    let mut config = cpp_build::Config::new();
+
+    if env::var_os("CARGO_FEATURE_STUB").is_some() {
+        config
+            .include(STUB_INCLUDE_DIR)
+            .flag_if_supported("-std=c++17")
+            .build(DRIVER_SOURCE);
+        return;
+    }
+
+    let edgetpu = libedgetpu_package(&out_dir);
+    let tflite = tflite_package();
+    for package in [&edgetpu, &tflite] {
+        for dir in &package.include_dirs {
+            config.include(dir);
+        }
+    }
+    config.flag_if_supported("-std=c++17").build(DRIVER_SOURCE);
+
+    for package in [&edgetpu, &tflite] {
+        for dir in &package.link_dirs {
+            println!("cargo:rustc-link-search=native={}", dir.display());
+        }
+        for lib in &package.libs {
+            println!("cargo:rustc-link-lib={lib}");
+        }
+    }
     println!("cargo:rustc-link-lib=pthread");
 }
+
+fn libedgetpu_package(out_dir: &Path) -> Package {
+    if let Some(dir) = env::var_os(LIBEDGETPU_DIR_ENV) {
+        let mut package = Package::from_dir(Path::new(&dir), "dylib=edgetpu");
+        package.include_dirs = package
+            .include_dirs
+            .iter()
+            .map(|dir| header_dir(dir, out_dir))
+            .collect();
+        package.link_dirs = package
+            .link_dirs
+            .iter()
+            .map(|dir| link_dir(dir, out_dir))
+            .collect();
+        return package;
+    }
+
+    if let Some(dir) = env::var_os(RUNTIME_DIR_ENV) {
+        return runtime_zip_package(Path::new(&dir), out_dir);
+    }
+
+    if let Some(package) = Package::from_pkg_config(&LIBEDGETPU_PKG_NAMES) {
+        return package;
+    }
+
+    panic!(
+        "libedgetpu was not found: set {LIBEDGETPU_DIR_ENV} to a prebuilt libedgetpu, \
+         {RUNTIME_DIR_ENV} to an extracted edgetpu_runtime zip, install it with a pkg-config \
+         file, or enable the `stub` feature"
+    );
+}
+
+/// The `edgetpu_runtime` directory of the release zip: the header is in `libedgetpu/` and
+/// the libraries in `libedgetpu/<throttled|direct>/<arch>/`.
+fn runtime_zip_package(runtime_dir: &Path, out_dir: &Path) -> Package {
+    let root = runtime_dir.join("libedgetpu");
+    let freq_dir = if env::var_os("CARGO_FEATURE_MAX_FREQ").is_some() {
+        "direct"
+    } else {
+        "throttled"
+    };
+    let arch = match env::var("CARGO_CFG_TARGET_ARCH").as_deref() {
+        Ok("x86_64") => "k8",
+        Ok("aarch64") => "aarch64",
+        Ok("arm") => "armv7a",
+        Ok(arch) => panic!("the Edge TPU runtime zip has no library for {arch}"),
+        Err(err) => panic!("CARGO_CFG_TARGET_ARCH not set: {err}"),
+    };
+
+    Package {
+        include_dirs: vec![header_dir(&root, out_dir)],
+        link_dirs: vec![link_dir(&root.join(freq_dir).join(arch), out_dir)],
+        libs: vec!["dylib=edgetpu".to_owned()],
+    }
+}
+
+fn tflite_package() -> Package {
+    if let Some(dir) = env::var_os(TFLITE_DIR_ENV) {
+        let mut package = Package::from_dir(Path::new(&dir), "tensorflowlite_c");
+        // the headers are enough when libedgetpu is linked statically against TFLite
+        if !package.link_dirs.iter().any(|dir| has_library(dir, "tensorflowlite_c")) {
+            package.libs.clear();
+        }
+        return package;
+    }
+
+    Package::from_pkg_config(&TFLITE_PKG_NAMES).unwrap_or_else(|| {
+        panic!(
+            "the TensorFlow Lite C headers were not found: set {TFLITE_DIR_ENV} or install \
+             them with a pkg-config file"
+        )
+    })
+}
+
+/// Returns an include directory providing `tflite/public/edgetpu.h`. Prebuilt packages
+/// often ship a bare `edgetpu.h`, which is then wrapped in `OUT_DIR`.
+fn header_dir(dir: &Path, out_dir: &Path) -> PathBuf {
+    if dir.join(EDGETPU_HEADER).is_file() {
+        return dir.to_owned();
+    }
+
+    let header = dir.join("edgetpu.h");
+    if !header.is_file() {
+        panic!("neither {EDGETPU_HEADER} nor edgetpu.h is in {}", dir.display());
+    }
+    let wrapper_dir = out_dir.join("edgetpu-include");
+    let wrapper = wrapper_dir.join(EDGETPU_HEADER);
+    fs::create_dir_all(wrapper.parent().unwrap()).expect("failed to create the header wrapper");
+    fs::write(&wrapper, format!("#include \"{}\"\n", header.display()))
+        .expect("failed to write the header wrapper");
+    wrapper_dir
+}
+
+/// Returns a directory providing `libedgetpu.so`. Releases only ship the versioned
+/// `libedgetpu.so.1.0`, which is then linked from `OUT_DIR`.
+fn link_dir(dir: &Path, out_dir: &Path) -> PathBuf {
+    if has_library(dir, "edgetpu") {
+        return dir.to_owned();
+    }
+
+    let versioned = ["libedgetpu.so.1.0", "libedgetpu.so.1"]
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| panic!("libedgetpu is not in {}", dir.display()));
+    let link_dir = out_dir.join("edgetpu-lib");
+    let link = link_dir.join("libedgetpu.so");
+    fs::create_dir_all(&link_dir).expect("failed to create the library directory");
+    let _ = fs::remove_file(&link);
+    std::os::unix::fs::symlink(&versioned, &link).expect("failed to link libedgetpu.so");
+    link_dir
+}
+
+fn has_library(dir: &Path, name: &str) -> bool {
+    ["so", "a", "dylib"]
+        .iter()
+        .any(|ext| dir.join(format!("lib{name}.{ext}")).exists())
+}
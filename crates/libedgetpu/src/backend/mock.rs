@@ -0,0 +1,347 @@
+//! Scriptable in-memory backend.
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::{DeviceContext, EdgeTpuBackend};
+use crate::driver::{DeviceRecord, DeviceType, EdgeTPUError, STATUS_ERROR};
+
+/// Runtime version reported by default, the one of the last libedgetpu release
+pub const MOCK_RUNTIME_VERSION: &str = "BuildLabel(mock), RuntimeVersion(14)";
+
+#[derive(Debug)]
+struct State {
+    /// Devices currently plugged in, in enumeration order
+    devices: Vec<DeviceRecord>,
+    /// Paths of the devices that fail to open
+    failing: HashSet<String>,
+    verbosity: Option<i32>,
+    opened: usize,
+    runtime_version: String,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            devices: Vec::new(),
+            failing: HashSet::new(),
+            verbosity: None,
+            opened: 0,
+            runtime_version: MOCK_RUNTIME_VERSION.to_owned(),
+        }
+    }
+}
+
+/// Backend simulating Edge TPUs.
+///
+/// PCI devices are named like their nodes (`/dev/apex_0`), USB devices like their sysfs
+/// directories (`/sys/bus/usb/devices/2-1`), as libedgetpu reports them. Clones share the
+/// simulated devices, so a test can keep a clone to make devices fail or disappear while
+/// the code under test holds the backend.
+///
+/// # Example
+///
+/// ```
+/// use libedgetpu::backend::{EdgeTpuBackend, MockBackend};
+/// use libedgetpu::driver::DeviceType;
+///
+/// let backend = MockBackend::new().with_pci_devices(2);
+/// let context = backend
+///     .open_device_path(DeviceType::ApexPCI, "/dev/apex_1")
+///     .unwrap();
+/// assert!(context.is_ready());
+///
+/// backend.remove_device("/dev/apex_1");
+/// assert!(!context.is_ready());
+/// assert_eq!(backend.enumerate_devices().unwrap().len(), 1);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MockBackend {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `count` PCI devices, numbered after the ones already there
+    pub fn with_pci_devices(self, count: usize) -> Self {
+        let first = self.count(DeviceType::ApexPCI);
+        for index in first..first + count {
+            self.add_device(DeviceType::ApexPCI, &format!("/dev/apex_{index}"));
+        }
+        self
+    }
+
+    /// Adds `count` USB devices, plugged in the ports of bus 2 after the ones already there
+    pub fn with_usb_devices(self, count: usize) -> Self {
+        let first = self.count(DeviceType::ApexUSB);
+        for index in first..first + count {
+            self.add_device(
+                DeviceType::ApexUSB,
+                &format!("/sys/bus/usb/devices/2-{}", index + 1),
+            );
+        }
+        self
+    }
+
+    /// Sets the version the simulated runtime reports
+    pub fn with_runtime_version(self, version: &str) -> Self {
+        self.state().runtime_version = version.to_owned();
+        self
+    }
+
+    /// Plugs in a device
+    pub fn add_device(&self, device_type: DeviceType, path: &str) {
+        self.state().devices.push(DeviceRecord {
+            device_type,
+            path: path.to_owned(),
+        });
+    }
+
+    /// Unplugs a device. Its contexts stop being ready.
+    pub fn remove_device(&self, path: &str) {
+        self.state().devices.retain(|device| device.path != path);
+    }
+
+    /// Makes opening the device fail, or succeed again
+    pub fn fail_open(&self, path: &str, fail: bool) {
+        let mut state = self.state();
+        if fail {
+            state.failing.insert(path.to_owned());
+        } else {
+            state.failing.remove(path);
+        }
+    }
+
+    /// Last verbosity set
+    pub fn verbosity(&self) -> Option<i32> {
+        self.state().verbosity
+    }
+
+    /// Number of devices opened so far
+    pub fn opened(&self) -> usize {
+        self.state().opened
+    }
+
+    fn count(&self, device_type: DeviceType) -> usize {
+        self.state()
+            .devices
+            .iter()
+            .filter(|device| device.device_type == device_type)
+            .count()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // a test that panicked while holding the lock has failed already
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn open(
+        &self,
+        target: &str,
+        matches: impl Fn(&DeviceRecord) -> bool,
+        options: HashMap<String, String>,
+    ) -> Result<Box<dyn DeviceContext>, EdgeTPUError> {
+        let mut state = self.state();
+        let record = state
+            .devices
+            .iter()
+            .find(|device| matches(device))
+            .cloned()
+            .ok_or_else(|| EdgeTPUError::OpenFailed {
+                target: target.to_owned(),
+                status: STATUS_ERROR,
+                message: "no such device is available".to_owned(),
+            })?;
+        if state.failing.contains(&record.path) {
+            return Err(EdgeTPUError::OpenFailed {
+                target: record.path,
+                status: STATUS_ERROR,
+                message: "simulated open failure".to_owned(),
+            });
+        }
+        state.opened += 1;
+
+        Ok(Box::new(MockContext {
+            state: Arc::clone(&self.state),
+            record,
+            options,
+        }))
+    }
+}
+
+impl EdgeTpuBackend for MockBackend {
+    fn enumerate_devices(&self) -> Result<Vec<DeviceRecord>, EdgeTPUError> {
+        Ok(self.state().devices.clone())
+    }
+
+    fn open_device(&self) -> Result<Box<dyn DeviceContext>, EdgeTPUError> {
+        self.open("any Edge TPU", |_| true, HashMap::new())
+    }
+
+    fn open_device_type(
+        &self,
+        device_type: DeviceType,
+    ) -> Result<Box<dyn DeviceContext>, EdgeTPUError> {
+        self.open(
+            &format!("any {device_type:?} Edge TPU"),
+            |device| device.device_type == device_type,
+            HashMap::new(),
+        )
+    }
+
+    fn open_device_path(
+        &self,
+        device_type: DeviceType,
+        path: &str,
+    ) -> Result<Box<dyn DeviceContext>, EdgeTPUError> {
+        self.open_device_options(device_type, path, HashMap::new())
+    }
+
+    fn open_device_options(
+        &self,
+        device_type: DeviceType,
+        path: &str,
+        options: HashMap<String, String>,
+    ) -> Result<Box<dyn DeviceContext>, EdgeTPUError> {
+        self.open(
+            &format!("{device_type:?} Edge TPU {path}"),
+            |device| device.device_type == device_type && device.path == path,
+            options,
+        )
+    }
+
+    fn set_verbosity(&self, verbosity: i32) -> Result<(), EdgeTPUError> {
+        if !(0..=10).contains(&verbosity) {
+            return Err(EdgeTPUError::SetVerbosityFailed {
+                verbosity,
+                status: STATUS_ERROR,
+                message: "verbosity must be between 0 and 10".to_owned(),
+            });
+        }
+        self.state().verbosity = Some(verbosity);
+        Ok(())
+    }
+
+    fn runtime_version(&self) -> Result<String, EdgeTPUError> {
+        Ok(self.state().runtime_version.clone())
+    }
+}
+
+#[derive(Debug)]
+struct MockContext {
+    state: Arc<Mutex<State>>,
+    record: DeviceRecord,
+    options: HashMap<String, String>,
+}
+
+impl DeviceContext for MockContext {
+    fn is_ready(&self) -> bool {
+        let state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        state.devices.contains(&self.record)
+    }
+
+    fn device_enum_record(&self) -> Result<DeviceRecord, EdgeTPUError> {
+        Ok(self.record.clone())
+    }
+
+    fn device_options(&self) -> HashMap<String, String> {
+        self.options.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enumerate_simulated_devices() {
+        let backend = MockBackend::new().with_pci_devices(2).with_usb_devices(1);
+        let paths: Vec<String> = backend
+            .enumerate_devices()
+            .unwrap()
+            .into_iter()
+            .map(|device| device.path)
+            .collect();
+        assert_eq!(
+            paths,
+            vec!["/dev/apex_0", "/dev/apex_1", "/sys/bus/usb/devices/2-1"]
+        );
+
+        let backend = backend.with_pci_devices(1);
+        assert_eq!(backend.enumerate_devices().unwrap()[3].path, "/dev/apex_2");
+    }
+
+    #[test]
+    fn test_open_devices() {
+        let backend = MockBackend::new().with_pci_devices(1).with_usb_devices(1);
+
+        let context = backend.open_device_type(DeviceType::ApexUSB).unwrap();
+        assert_eq!(
+            context.device_enum_record().unwrap().device_type,
+            DeviceType::ApexUSB
+        );
+        let options = HashMap::from([("Performance".to_owned(), "Max".to_owned())]);
+        let context = backend
+            .open_device_options(DeviceType::ApexPCI, "/dev/apex_0", options.clone())
+            .unwrap();
+        assert_eq!(context.device_options(), options);
+        assert!(backend
+            .open_device_path(DeviceType::ApexUSB, "/dev/apex_0")
+            .is_err());
+        assert_eq!(backend.opened(), 2);
+    }
+
+    #[test]
+    fn test_open_failures() {
+        let backend = MockBackend::new().with_usb_devices(1);
+        let path = "/sys/bus/usb/devices/2-1";
+
+        backend.fail_open(path, true);
+        assert!(matches!(
+            backend.open_device(),
+            Err(EdgeTPUError::OpenFailed { ref target, .. }) if target == path
+        ));
+        backend.fail_open(path, false);
+        assert!(backend.open_device().is_ok());
+
+        assert!(MockBackend::new().open_device().is_err());
+    }
+
+    #[test]
+    fn test_device_disappears() {
+        let backend = MockBackend::new().with_usb_devices(2);
+        // the code under test holds its own clone
+        let shared: Box<dyn EdgeTpuBackend> = Box::new(backend.clone());
+        let context = shared
+            .open_device_path(DeviceType::ApexUSB, "/sys/bus/usb/devices/2-2")
+            .unwrap();
+        assert!(context.is_ready());
+
+        backend.remove_device("/sys/bus/usb/devices/2-2");
+        assert!(!context.is_ready());
+        assert_eq!(shared.enumerate_devices().unwrap().len(), 1);
+        assert!(shared
+            .open_device_path(DeviceType::ApexUSB, "/sys/bus/usb/devices/2-2")
+            .is_err());
+    }
+
+    #[test]
+    fn test_set_verbosity() {
+        let backend = MockBackend::new();
+        assert_eq!(backend.verbosity(), None);
+        backend.set_verbosity(5).unwrap();
+        assert_eq!(backend.verbosity(), Some(5));
+        assert!(backend.set_verbosity(11).is_err());
+        assert_eq!(backend.verbosity(), Some(5));
+    }
+
+    #[test]
+    fn test_runtime_version() {
+        let backend = MockBackend::new();
+        assert_eq!(backend.runtime_version().unwrap(), MOCK_RUNTIME_VERSION);
+        let backend = backend.with_runtime_version("RuntimeVersion(13)");
+        assert_eq!(backend.runtime_version().unwrap(), "RuntimeVersion(13)");
+    }
+}
@@ -0,0 +1,139 @@
+//! Pluggable access to Edge TPUs.
+//!
+//! [`EdgeTpuBackend`] covers what the runtime needs from libedgetpu: enumerating the
+//! devices, opening them and tuning the library. [`EdgeTpuDeviceManager`] implements it on
+//! top of the libedgetpu singleton, and [`MockBackend`] simulates devices, so that code
+//! written against the trait can be tested on machines without accelerators.
+mod mock;
+
+use std::collections::HashMap;
+
+pub use mock::{MockBackend, MOCK_RUNTIME_VERSION};
+
+use crate::driver::{DeviceRecord, DeviceType, EdgeTPUError, EdgeTpuContext, EdgeTpuDeviceManager};
+
+/// An opened Edge TPU.
+pub trait DeviceContext: Send {
+    /// Whether the device can still run inferences
+    fn is_ready(&self) -> bool;
+
+    fn device_enum_record(&self) -> Result<DeviceRecord, EdgeTPUError>;
+
+    /// Options the device was opened with
+    fn device_options(&self) -> HashMap<String, String>;
+}
+
+/// A provider of Edge TPUs.
+///
+/// # Example
+///
+/// ```
+/// use libedgetpu::backend::{EdgeTpuBackend, MockBackend};
+/// use libedgetpu::driver::DeviceType;
+///
+/// fn open_first_usb(backend: &dyn EdgeTpuBackend) -> bool {
+///     backend.open_device_type(DeviceType::ApexUSB).is_ok()
+/// }
+///
+/// assert!(open_first_usb(&MockBackend::new().with_usb_devices(1)));
+/// assert!(!open_first_usb(&MockBackend::new().with_pci_devices(2)));
+/// ```
+pub trait EdgeTpuBackend: Send + Sync {
+    fn enumerate_devices(&self) -> Result<Vec<DeviceRecord>, EdgeTPUError>;
+
+    /// Opens any available device
+    fn open_device(&self) -> Result<Box<dyn DeviceContext>, EdgeTPUError>;
+
+    /// Opens any available device of the given type
+    fn open_device_type(
+        &self,
+        device_type: DeviceType,
+    ) -> Result<Box<dyn DeviceContext>, EdgeTPUError>;
+
+    fn open_device_path(
+        &self,
+        device_type: DeviceType,
+        path: &str,
+    ) -> Result<Box<dyn DeviceContext>, EdgeTPUError>;
+
+    fn open_device_options(
+        &self,
+        device_type: DeviceType,
+        path: &str,
+        options: HashMap<String, String>,
+    ) -> Result<Box<dyn DeviceContext>, EdgeTPUError>;
+
+    /// Sets the verbosity of the library logs, from 0 to 10
+    fn set_verbosity(&self, verbosity: i32) -> Result<(), EdgeTPUError>;
+
+    /// Version of the runtime, e.g. `BuildLabel(COMPILER=...), RuntimeVersion(14)`
+    fn runtime_version(&self) -> Result<String, EdgeTPUError>;
+}
+
+impl DeviceContext for EdgeTpuContext {
+    fn is_ready(&self) -> bool {
+        EdgeTpuContext::is_ready(self)
+    }
+
+    fn device_enum_record(&self) -> Result<DeviceRecord, EdgeTPUError> {
+        self.get_device_enum_record()
+    }
+
+    fn device_options(&self) -> HashMap<String, String> {
+        EdgeTpuContext::device_options(self)
+    }
+}
+
+impl EdgeTpuBackend for EdgeTpuDeviceManager {
+    fn enumerate_devices(&self) -> Result<Vec<DeviceRecord>, EdgeTPUError> {
+        EdgeTpuDeviceManager::enumerate_devices(self)
+    }
+
+    fn open_device(&self) -> Result<Box<dyn DeviceContext>, EdgeTPUError> {
+        Ok(Box::new(EdgeTpuDeviceManager::open_device(self)?))
+    }
+
+    fn open_device_type(
+        &self,
+        device_type: DeviceType,
+    ) -> Result<Box<dyn DeviceContext>, EdgeTPUError> {
+        Ok(Box::new(EdgeTpuDeviceManager::open_device_type(
+            self,
+            device_type,
+        )?))
+    }
+
+    fn open_device_path(
+        &self,
+        device_type: DeviceType,
+        path: &str,
+    ) -> Result<Box<dyn DeviceContext>, EdgeTPUError> {
+        Ok(Box::new(EdgeTpuDeviceManager::open_device_path(
+            self,
+            device_type,
+            path,
+        )?))
+    }
+
+    fn open_device_options(
+        &self,
+        device_type: DeviceType,
+        path: &str,
+        options: HashMap<String, String>,
+    ) -> Result<Box<dyn DeviceContext>, EdgeTPUError> {
+        Ok(Box::new(EdgeTpuDeviceManager::open_device_options(
+            self,
+            device_type,
+            path,
+            options,
+        )?))
+    }
+
+    fn set_verbosity(&self, verbosity: i32) -> Result<(), EdgeTPUError> {
+        EdgeTpuDeviceManager::set_verbosity(self, verbosity)
+    }
+
+    fn runtime_version(&self) -> Result<String, EdgeTPUError> {
+        EdgeTpuDeviceManager::runtime_version(self)
+    }
+}
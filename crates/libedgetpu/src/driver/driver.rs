@@ -1,290 +1,615 @@
 use std::collections::HashMap;
-use std::ffi::{CStr, CString};
+use std::ffi::CString;
+use std::fmt;
 use std::os::raw::c_char;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use cpp::*;
 use thiserror::Error;
 
+// Ownership across the boundary: strings going to C++ are `CString`s owned by the Rust
+// caller, and strings coming back are copied by Rust callbacks while C++ still owns them.
+// Only the `shared_ptr` of a context is held by Rust, standard strings and maps are not
+// relocatable and never leave C++. No C++ exception crosses the boundary either, `guarded`
+// hands them to Rust as error messages.
 cpp! {{
-    #include <string.h>
+    #include <cstring>
+    #include <exception>
+    #include <memory>
+    #include <stdexcept>
+    #include <string>
     #include "tflite/public/edgetpu.h"
-    
-     struct NoDelete {
-        void operator()(edgetpu::EdgeTpuManager* ptr) const {
-            // Do nothing
+
+    extern "C" void edgetpu_driver_set_error(void* error, const char* message, size_t len);
+
+    using ContextPtr = std::shared_ptr<edgetpu::EdgeTpuContext>;
+
+    static edgetpu::EdgeTpuManager* manager() {
+        auto* manager = edgetpu::EdgeTpuManager::GetSingleton();
+        if (manager == nullptr) {
+            throw std::runtime_error("the Edge TPU manager could not be created");
+        }
+        return manager;
+    }
+
+    // Runs `f`, storing the message of the exception it throws in the Rust `String` at
+    // `error`. Returns the status libedgetpu reports the failure with.
+    template <typename F>
+    static int guarded(void* error, F&& f) {
+        const char* message = "unknown C++ exception";
+        try {
+            f();
+            return kTfLiteOk;
+        } catch (const std::exception& e) {
+            message = e.what();
+        } catch (...) {
         }
-    };
+        edgetpu_driver_set_error(error, message, std::strlen(message));
+        return kTfLiteError;
+    }
 
-    extern "C" std::shared_ptr<edgetpu::EdgeTpuManager> get_edgetpu_manager_singleton() {
-        return std::shared_ptr<edgetpu::EdgeTpuManager>(edgetpu::EdgeTpuManager::GetSingleton(), NoDelete());
+    // `arity` is the number of arguments given to libedgetpu: none, the type, the type and
+    // path, or the type, path and options. A fresh context is not shared with other users
+    // of the device.
+    static ContextPtr open_context(
+        bool fresh,
+        int arity,
+        int device_type,
+        const char* path,
+        const char* const* keys,
+        const char* const* values,
+        size_t option_count
+    ) {
+        auto type = static_cast<edgetpu::DeviceType>(device_type);
+        edgetpu::EdgeTpuManager::DeviceOptions options;
+        for (size_t i = 0; i < option_count; ++i) {
+            options[keys[i]] = values[i];
+        }
+        if (fresh) {
+            switch (arity) {
+                case 0: return edgetpu::EdgeTpuManager::NewEdgeTpuContext();
+                case 1: return edgetpu::EdgeTpuManager::NewEdgeTpuContext(type);
+                case 2: return edgetpu::EdgeTpuManager::NewEdgeTpuContext(type, path);
+                default: return edgetpu::EdgeTpuManager::NewEdgeTpuContext(type, path, options);
+            }
+        }
+        switch (arity) {
+            case 0: return manager()->OpenDevice();
+            case 1: return manager()->OpenDevice(type);
+            case 2: return manager()->OpenDevice(type, path);
+            default: return manager()->OpenDevice(type, path, options);
+        }
     }
 }}
 
+/// Status libedgetpu reports on success, `kTfLiteOk`.
+pub const STATUS_OK: i32 = 0;
+/// Status libedgetpu reports on failure, `kTfLiteError`.
+pub const STATUS_ERROR: i32 = 1;
+
 #[derive(Debug, Error)]
 pub enum EdgeTPUError {
-    #[error("failed to open device")]
-    OpenFailed,
-    #[error("failed to set verbosity")]
-    SetVerbosityFailed,
+    #[error("failed to open {target}: {message} (status {status})")]
+    OpenFailed {
+        target: String,
+        status: i32,
+        message: String,
+    },
+    #[error("failed to set verbosity {verbosity}: {message} (status {status})")]
+    SetVerbosityFailed {
+        verbosity: i32,
+        status: i32,
+        message: String,
+    },
+    #[error("libedgetpu failed to {operation}: {message} (status {status})")]
+    Library {
+        operation: &'static str,
+        status: i32,
+        message: String,
+    },
+    #[error("libedgetpu reported unknown device type {0}")]
+    UnknownDeviceType(i32),
+    #[error("{0:?} contains a NUL byte")]
+    NulByte(String),
 }
 
+type Result<T> = std::result::Result<T, EdgeTPUError>;
+
 #[derive(PartialEq, Copy, Clone, Debug)]
 pub enum DeviceType {
     ApexPCI,
     ApexUSB,
 }
 
+impl DeviceType {
+    /// Value of the `edgetpu::DeviceType` enum
+    fn as_raw(self) -> i32 {
+        match self {
+            Self::ApexPCI => 0,
+            Self::ApexUSB => 1,
+        }
+    }
+
+    fn from_raw(raw: i32) -> Result<Self> {
+        match raw {
+            0 => Ok(Self::ApexPCI),
+            1 => Ok(Self::ApexUSB),
+            _ => Err(EdgeTPUError::UnknownDeviceType(raw)),
+        }
+    }
+}
+
 #[derive(PartialEq, Clone, Debug)]
 pub struct DeviceRecord {
     pub device_type: DeviceType,
     pub path: String,
 }
 
-cpp_class!(unsafe struct InnerEdgeTpuContext as "std::shared_ptr<edgetpu::EdgeTpuContext>");
-cpp_class!(unsafe struct InnerEdgeTpuDeviceManager as "std::shared_ptr<edgetpu::EdgeTpuManager>");
-cpp_class!(unsafe struct EdgeTpuDeviceType as "edgetpu::DeviceType");
-cpp_class!(unsafe struct EdgeTpuDeviceOptions as "edgetpu::EdgeTpuManager::DeviceOptions");
+cpp_class!(unsafe struct InnerEdgeTpuContext as "ContextPtr");
 
+/// Copies a string C++ keeps ownership of.
+unsafe fn copy_string(data: *const c_char, len: usize) -> String {
+    let bytes = std::slice::from_raw_parts(data as *const u8, len);
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// Called by `guarded` with the message of the exception it caught.
+#[no_mangle]
+unsafe extern "C" fn edgetpu_driver_set_error(
+    error: *mut String,
+    message: *const c_char,
+    len: usize,
+) {
+    *error = copy_string(message, len);
+}
+
+fn to_cstring(value: &str) -> Result<CString> {
+    CString::new(value).map_err(|_| EdgeTPUError::NulByte(value.to_owned()))
+}
+
+/// An opened Edge TPU. The device is released when the last clone of the context and of
+/// the contexts libedgetpu handed out for it are dropped.
 #[derive(Clone)]
 pub struct EdgeTpuContext {
-    inner: Arc<Mutex<InnerEdgeTpuContext>>
+    inner: Arc<Mutex<InnerEdgeTpuContext>>,
+}
+
+impl fmt::Debug for EdgeTpuContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EdgeTpuContext")
+            .field("record", &self.get_device_enum_record().ok())
+            .finish()
+    }
 }
 
 impl EdgeTpuContext {
+    fn new(inner: InnerEdgeTpuContext) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    fn inner(&self) -> MutexGuard<'_, InnerEdgeTpuContext> {
+        self.inner.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
     pub fn is_ready(&self) -> bool {
-        let inner = self.inner.lock().unwrap().clone();
-        let ok = cpp!(unsafe [inner as "std::shared_ptr<edgetpu::EdgeTpuContext>"] -> i32 as "int" {
-            return !!inner->IsReady();
-        });
-        ok != 0 
-    }
-
-    pub fn get_device_enum_record(&self) -> Result<DeviceRecord, EdgeTPUError> {
-        let inner = self.inner.lock().unwrap().clone();
-        let (device_type, path) = cpp!(unsafe [inner as "std::shared_ptr<edgetpu::EdgeTpuContext>"] -> (u8, *mut c_char) as "std::tuple<char, const char*>" {
-            const auto& device = inner->GetDeviceEnumRecord();
-            std::string path(device.path);
-            char device_type;
-            if (device.type == edgetpu::DeviceType::kApexPci) {
-                device_type = 0;
-            } else if (device.type == edgetpu::DeviceType::kApexUsb) {
-                device_type = 1;
+        let inner = self.inner();
+        let inner = &*inner;
+        cpp!(unsafe [inner as "const ContextPtr*"] -> bool as "bool" {
+            try {
+                return (*inner)->IsReady();
+            } catch (...) {
+                return false;
             }
-            return std::make_tuple(device_type, strdup(path.c_str()));
+        })
+    }
+
+    pub fn get_device_enum_record(&self) -> Result<DeviceRecord> {
+        let inner = self.inner();
+        let inner = &*inner;
+        let mut record: Option<(i32, String)> = None;
+        let record_ptr = &mut record;
+        let mut error = String::new();
+        let error_ptr = &mut error;
+
+        let status = cpp!(unsafe [inner as "const ContextPtr*", record_ptr as "void*", error_ptr as "void*"] -> i32 as "int" {
+            return guarded(error_ptr, [&] {
+                const auto& device = (*inner)->GetDeviceEnumRecord();
+                int device_type = static_cast<int>(device.type);
+                const char* path = device.path.data();
+                size_t path_len = device.path.size();
+                rust!(edgetpu_device_enum_record_cb [
+                    record_ptr: *mut Option<(i32, String)> as "void*",
+                    device_type: i32 as "int",
+                    path: *const c_char as "const char*",
+                    path_len: usize as "size_t"
+                ] {
+                    let path = unsafe { copy_string(path, path_len) };
+                    unsafe { *record_ptr = Some((device_type, path)) };
+                });
+            });
         });
+        if status != STATUS_OK {
+            return Err(EdgeTPUError::Library {
+                operation: "get the device record",
+                status,
+                message: error,
+            });
+        }
 
-        let path = unsafe { CString::from_raw(path).to_string_lossy().into_owned() };
-        let device_type = match device_type {
-            0 => DeviceType::ApexPCI,
-            1 => DeviceType::ApexUSB,
-            _ => return Err(EdgeTPUError::OpenFailed),
-        };
-        Ok(DeviceRecord { device_type, path })
+        let (device_type, path) = record.ok_or_else(|| EdgeTPUError::Library {
+            operation: "get the device record",
+            status: STATUS_ERROR,
+            message: "no record was returned".to_owned(),
+        })?;
+        Ok(DeviceRecord {
+            device_type: DeviceType::from_raw(device_type)?,
+            path,
+        })
     }
 
     pub fn device_options(&self) -> HashMap<String, String> {
-        let inner = self.inner.lock().unwrap().clone();
+        let inner = self.inner();
+        let inner = &*inner;
         let mut options = HashMap::new();
         let options_ptr = &mut options;
-        cpp!(unsafe [inner as "std::shared_ptr<edgetpu::EdgeTpuContext>", options_ptr as "void *"] {
-            auto opts = inner->GetDeviceOptions();
-            for (const auto& element : opts) {
-                const char* key = element.first.c_str();
-                const char* value = element.second.c_str();
-
-                rust!(edgetpu_device_options_cb [options_ptr: *mut HashMap<String, String> as "void *", key: *const c_char as "const char *", value: *const c_char as "const char *"] {
-                    let key = unsafe { CStr::from_ptr(key) }.to_string_lossy().into_owned();
-                    let value = unsafe { CStr::from_ptr(value) }.to_string_lossy().into_owned();
-                    options_ptr.as_mut().unwrap().insert(key, value);
-                });
-            }
+        let mut error = String::new();
+        let error_ptr = &mut error;
+
+        let status = cpp!(unsafe [inner as "const ContextPtr*", options_ptr as "void*", error_ptr as "void*"] -> i32 as "int" {
+            return guarded(error_ptr, [&] {
+                for (const auto& element : (*inner)->GetDeviceOptions()) {
+                    const char* key = element.first.data();
+                    size_t key_len = element.first.size();
+                    const char* value = element.second.data();
+                    size_t value_len = element.second.size();
+                    rust!(edgetpu_device_options_cb [
+                        options_ptr: *mut HashMap<String, String> as "void*",
+                        key: *const c_char as "const char*",
+                        key_len: usize as "size_t",
+                        value: *const c_char as "const char*",
+                        value_len: usize as "size_t"
+                    ] {
+                        let key = unsafe { copy_string(key, key_len) };
+                        let value = unsafe { copy_string(value, value_len) };
+                        unsafe { (*options_ptr).insert(key, value) };
+                    });
+                }
+            });
         });
+        if status != STATUS_OK {
+            // the options are informative, a failure is the same as none
+            return HashMap::new();
+        }
         options
     }
 }
 
-#[derive(Clone)]
+/// Which device to open.
+enum Selector<'a> {
+    Any,
+    Type(DeviceType),
+    Path(DeviceType, &'a str),
+    Options(DeviceType, &'a str, HashMap<String, String>),
+}
+
+impl fmt::Display for Selector<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Any => write!(f, "any Edge TPU"),
+            Self::Type(device_type) => write!(f, "any {device_type:?} Edge TPU"),
+            Self::Path(device_type, path) | Self::Options(device_type, path, _) => {
+                write!(f, "{device_type:?} Edge TPU {path}")
+            }
+        }
+    }
+}
+
+/// Handle on the libedgetpu manager singleton.
+#[derive(Clone, Debug)]
 pub struct EdgeTpuDeviceManager {
-    inner: Arc<Mutex<InnerEdgeTpuDeviceManager>>, // Use Arc<Mutex<_>> to simulate shared_ptr behavior
+    _private: (),
 }
 
 impl EdgeTpuDeviceManager {
+    /// Returns the manager, or `None` if libedgetpu failed to create it.
     pub fn get_singleton() -> Option<EdgeTpuDeviceManager> {
-        let inner = cpp!(unsafe [] -> InnerEdgeTpuDeviceManager as "std::shared_ptr<edgetpu::EdgeTpuManager>" {
-            return get_edgetpu_manager_singleton();
+        let available = cpp!(unsafe [] -> bool as "bool" {
+            try {
+                return edgetpu::EdgeTpuManager::GetSingleton() != nullptr;
+            } catch (...) {
+                return false;
+            }
         });
-
-        Some(EdgeTpuDeviceManager {
-            inner: Arc::new(Mutex::new(inner)),
-        })
+        available.then_some(EdgeTpuDeviceManager { _private: () })
     }
 
-    pub fn enumerate_devices(&self) -> Vec<DeviceRecord> {
-        let mut devices = Vec::new();
-        let devices_ptr = &mut devices;
-
-        cpp!(unsafe [devices_ptr as "void *"] {
-            const auto& available_tpus = get_edgetpu_manager_singleton()->EnumerateEdgeTpu();
-            for (const auto& device : available_tpus) {
-                std::string device_path = device.path;
-                int device_type;
-                if (device.type == edgetpu::DeviceType::kApexPci) {
-                    device_type = 0;
-                } else if (device.type == edgetpu::DeviceType::kApexUsb) {
-                    device_type = 1;
-                }
-
-                rust!(enumerate_devices_cb [devices_ptr: *mut Vec<DeviceRecord> as "void *", device_path: *mut c_char as "std::string", device_type: u8 as "char"] {
-                    let path = unsafe { CStr::from_ptr(device_path) }.to_string_lossy().into_owned();
-                    let device_type = match device_type {
-                        0 => DeviceType::ApexPCI,
-                        1 => DeviceType::ApexUSB,
-                        _ => panic!("unresolved device type"),
-                    };
-                    devices_ptr.as_mut().unwrap().push(DeviceRecord{
-                        device_type,
-                        path: path,
+    pub fn enumerate_devices(&self) -> Result<Vec<DeviceRecord>> {
+        let mut raw: Vec<(i32, String)> = Vec::new();
+        let raw_ptr = &mut raw;
+        let mut error = String::new();
+        let error_ptr = &mut error;
+
+        let status = cpp!(unsafe [raw_ptr as "void*", error_ptr as "void*"] -> i32 as "int" {
+            return guarded(error_ptr, [&] {
+                for (const auto& device : manager()->EnumerateEdgeTpu()) {
+                    int device_type = static_cast<int>(device.type);
+                    const char* path = device.path.data();
+                    size_t path_len = device.path.size();
+                    rust!(edgetpu_enumerate_devices_cb [
+                        raw_ptr: *mut Vec<(i32, String)> as "void*",
+                        device_type: i32 as "int",
+                        path: *const c_char as "const char*",
+                        path_len: usize as "size_t"
+                    ] {
+                        let path = unsafe { copy_string(path, path_len) };
+                        unsafe { (*raw_ptr).push((device_type, path)) };
                     });
-                });
-            }
+                }
+            });
         });
+        if status != STATUS_OK {
+            return Err(EdgeTPUError::Library {
+                operation: "enumerate the Edge TPUs",
+                status,
+                message: error,
+            });
+        }
 
-        devices
+        raw.into_iter()
+            .map(|(device_type, path)| {
+                Ok(DeviceRecord {
+                    device_type: DeviceType::from_raw(device_type)?,
+                    path,
+                })
+            })
+            .collect()
     }
 
-    pub fn open_device(&self) -> Result<EdgeTpuContext, EdgeTPUError> {
-        let inner = cpp!(unsafe [] -> InnerEdgeTpuContext as "std::shared_ptr<edgetpu::EdgeTpuContext>" {
-            return get_edgetpu_manager_singleton()->OpenDevice();
-        });
-
-        let ok = cpp!(unsafe [inner as "std::shared_ptr<edgetpu::EdgeTpuContext>"] -> i32 as "int" {
-            return !!inner;
-        });
+    /// Opens any available device. libedgetpu shares the context with the other users of
+    /// the device.
+    pub fn open_device(&self) -> Result<EdgeTpuContext> {
+        self.open(false, Selector::Any)
+    }
 
-        match ok {
-            0 => Err(EdgeTPUError::OpenFailed),
-            _ => Ok(EdgeTpuContext {
-                inner: Arc::new(Mutex::new(inner)),
-            }),
-        }
+    pub fn open_device_type(&self, device_type: DeviceType) -> Result<EdgeTpuContext> {
+        self.open(false, Selector::Type(device_type))
     }
 
-    pub fn open_device_type(&self, device_type: DeviceType) -> Result<EdgeTpuContext, EdgeTPUError> {
-        let device_type = match device_type {
-            DeviceType::ApexPCI => cpp!(unsafe [] -> EdgeTpuDeviceType as "edgetpu::DeviceType" {
-                return edgetpu::DeviceType::kApexPci;
-            }),
-            DeviceType::ApexUSB => cpp!(unsafe [] -> EdgeTpuDeviceType as "edgetpu::DeviceType" {
-                return edgetpu::DeviceType::kApexUsb;
-            }),
-        };
+    pub fn open_device_path(&self, device_type: DeviceType, path: &str) -> Result<EdgeTpuContext> {
+        self.open(false, Selector::Path(device_type, path))
+    }
 
-        let inner = cpp!(unsafe [device_type as "edgetpu::DeviceType"] -> InnerEdgeTpuContext as "std::shared_ptr<edgetpu::EdgeTpuContext>" {
-            return get_edgetpu_manager_singleton()->OpenDevice(device_type);
-        });
+    pub fn open_device_options(
+        &self,
+        device_type: DeviceType,
+        path: &str,
+        options: HashMap<String, String>,
+    ) -> Result<EdgeTpuContext> {
+        self.open(false, Selector::Options(device_type, path, options))
+    }
 
-        let ok = cpp!(unsafe [inner as "std::shared_ptr<edgetpu::EdgeTpuContext>"] -> i32 as "int" {
-            return !!inner;
-        });
+    /// Creates a context that is not shared with other users of the device, like
+    /// `EdgeTpuManager::NewEdgeTpuContext`.
+    pub fn new_context(&self) -> Result<EdgeTpuContext> {
+        self.open(true, Selector::Any)
+    }
 
-        match ok {
-            0 => Err(EdgeTPUError::OpenFailed),
-            _ => Ok(EdgeTpuContext {
-                inner: Arc::new(Mutex::new(inner)),
-            }),
-        }
+    pub fn new_context_type(&self, device_type: DeviceType) -> Result<EdgeTpuContext> {
+        self.open(true, Selector::Type(device_type))
     }
 
-    pub fn open_device_path(&self, device_type: DeviceType, path: &str) -> Result<EdgeTpuContext, EdgeTPUError> {
-        let device_type = match device_type {
-            DeviceType::ApexPCI => cpp!(unsafe [] -> EdgeTpuDeviceType as "edgetpu::DeviceType" {
-                return edgetpu::DeviceType::kApexPci;
-            }),
-            DeviceType::ApexUSB => cpp!(unsafe [] -> EdgeTpuDeviceType as "edgetpu::DeviceType" {
-                return edgetpu::DeviceType::kApexUsb;
-            }),
-        };
+    pub fn new_context_path(&self, device_type: DeviceType, path: &str) -> Result<EdgeTpuContext> {
+        self.open(true, Selector::Path(device_type, path))
+    }
 
-        let path_s = CString::new(path).unwrap();
-        let path = path_s.as_ptr();
+    pub fn new_context_options(
+        &self,
+        device_type: DeviceType,
+        path: &str,
+        options: HashMap<String, String>,
+    ) -> Result<EdgeTpuContext> {
+        self.open(true, Selector::Options(device_type, path, options))
+    }
 
-        let inner = cpp!(unsafe [device_type as "edgetpu::DeviceType", path as "const char *"] -> InnerEdgeTpuContext as "std::shared_ptr<edgetpu::EdgeTpuContext>" {
-            auto device_path = std::string(path);
-            return get_edgetpu_manager_singleton()->OpenDevice(device_type, device_path);
+    /// Returns the contexts of the devices opened through the manager and still in use.
+    pub fn opened_devices(&self) -> Result<Vec<EdgeTpuContext>> {
+        let mut contexts: Vec<EdgeTpuContext> = Vec::new();
+        let contexts_ptr = &mut contexts;
+        let mut error = String::new();
+        let error_ptr = &mut error;
+
+        let status = cpp!(unsafe [contexts_ptr as "void*", error_ptr as "void*"] -> i32 as "int" {
+            return guarded(error_ptr, [&] {
+                for (const auto& context : manager()->GetOpenedDevices()) {
+                    const ContextPtr* context_ptr = &context;
+                    rust!(edgetpu_opened_devices_cb [
+                        contexts_ptr: *mut Vec<EdgeTpuContext> as "void*",
+                        context_ptr: *const InnerEdgeTpuContext as "const ContextPtr*"
+                    ] {
+                        let inner = unsafe { (*context_ptr).clone() };
+                        unsafe { (*contexts_ptr).push(EdgeTpuContext::new(inner)) };
+                    });
+                }
+            });
         });
+        if status != STATUS_OK {
+            return Err(EdgeTPUError::Library {
+                operation: "list the opened Edge TPUs",
+                status,
+                message: error,
+            });
+        }
+        Ok(contexts)
+    }
 
-        let ok = cpp!(unsafe [inner as "std::shared_ptr<edgetpu::EdgeTpuContext>"] -> i32 as "int" {
-            return !!inner;
+    /// Version of the runtime, e.g. `BuildLabel(COMPILER=...), RuntimeVersion(14)`
+    pub fn runtime_version(&self) -> Result<String> {
+        let mut version = String::new();
+        let version_ptr = &mut version;
+        let mut error = String::new();
+        let error_ptr = &mut error;
+        let status = cpp!(unsafe [version_ptr as "void*", error_ptr as "void*"] -> i32 as "int" {
+            return guarded(error_ptr, [&] {
+                std::string version = manager()->Version();
+                const char* data = version.data();
+                size_t len = version.size();
+                rust!(edgetpu_runtime_version_cb [
+                    version_ptr: *mut String as "void*",
+                    data: *const c_char as "const char*",
+                    len: usize as "size_t"
+                ] {
+                    unsafe { *version_ptr = copy_string(data, len) };
+                });
+            });
         });
+        if status != STATUS_OK {
+            return Err(EdgeTPUError::Library {
+                operation: "get the runtime version",
+                status,
+                message: error,
+            });
+        }
+        Ok(version)
+    }
 
-        match ok {
-            0 => Err(EdgeTPUError::OpenFailed),
-            _ => Ok(EdgeTpuContext {
-                inner: Arc::new(Mutex::new(inner)),
-            }),
+    /// Sets the verbosity of the library logs, from 0 to 10
+    pub fn set_verbosity(&self, verbosity: i32) -> Result<()> {
+        let mut error = String::new();
+        let error_ptr = &mut error;
+        let status = cpp!(unsafe [verbosity as "int", error_ptr as "void*"] -> i32 as "int" {
+            int status = kTfLiteOk;
+            int thrown = guarded(error_ptr, [&] { status = manager()->SetVerbosity(verbosity); });
+            return thrown != kTfLiteOk ? thrown : status;
+        });
+        match status {
+            0 => Ok(()),
+            status => {
+                let mut message = error;
+                if message.is_empty() {
+                    message = "rejected by libedgetpu".to_owned();
+                }
+                Err(EdgeTPUError::SetVerbosityFailed {
+                    verbosity,
+                    status,
+                    message,
+                })
+            }
         }
     }
 
-    pub fn open_device_options(
-        &self,
-        device_type: DeviceType,
-        path: &str,
-        options: HashMap<String, String>,
-    ) -> Result<EdgeTpuContext, EdgeTPUError> {
-        let device_type = match device_type {
-            DeviceType::ApexPCI => cpp!(unsafe [] -> EdgeTpuDeviceType as "edgetpu::DeviceType" {
-                return edgetpu::DeviceType::kApexPci;
-            }),
-            DeviceType::ApexUSB => cpp!(unsafe [] -> EdgeTpuDeviceType as "edgetpu::DeviceType" {
-                return edgetpu::DeviceType::kApexUsb;
-            }),
+    fn open(&self, fresh: bool, selector: Selector) -> Result<EdgeTpuContext> {
+        let no_options = HashMap::new();
+        let (arity, device_type, path, options) = match &selector {
+            Selector::Any => (0, 0, "", &no_options),
+            Selector::Type(device_type) => (1, device_type.as_raw(), "", &no_options),
+            Selector::Path(device_type, path) => (2, device_type.as_raw(), *path, &no_options),
+            Selector::Options(device_type, path, options) => {
+                (3, device_type.as_raw(), *path, options)
+            }
         };
 
-        let mut device_options = cpp!(unsafe [] -> EdgeTpuDeviceOptions as "edgetpu::EdgeTpuManager::DeviceOptions" {
-            edgetpu::EdgeTpuManager::DeviceOptions x;
-            x.reserve(4);
-            return x;
+        let path = to_cstring(path)?;
+        let path = path.as_ptr();
+        let mut keys = Vec::with_capacity(options.len());
+        let mut values = Vec::with_capacity(options.len());
+        for (key, value) in options {
+            keys.push(to_cstring(key)?);
+            values.push(to_cstring(value)?);
+        }
+        let key_ptrs: Vec<*const c_char> = keys.iter().map(|key| key.as_ptr()).collect();
+        let value_ptrs: Vec<*const c_char> = values.iter().map(|value| value.as_ptr()).collect();
+        let (keys_ptr, values_ptr, option_count) =
+            (key_ptrs.as_ptr(), value_ptrs.as_ptr(), key_ptrs.len());
+
+        let mut context = InnerEdgeTpuContext::default();
+        let mut error = String::new();
+        let error_ptr = &mut error;
+        let status = cpp!(unsafe [
+            fresh as "bool",
+            arity as "int",
+            device_type as "int",
+            path as "const char*",
+            keys_ptr as "const char* const*",
+            values_ptr as "const char* const*",
+            option_count as "size_t",
+            mut context as "ContextPtr",
+            error_ptr as "void*"
+        ] -> i32 as "int" {
+            return guarded(error_ptr, [&] {
+                context = open_context(
+                    fresh, arity, device_type, path, keys_ptr, values_ptr, option_count);
+            });
         });
+        if status != STATUS_OK {
+            return Err(EdgeTPUError::OpenFailed {
+                target: selector.to_string(),
+                status,
+                message: error,
+            });
+        }
 
-        for (key, val) in options {
-            let ks = CString::new(key.as_str()).unwrap();
-            let vs = CString::new(val.as_str()).unwrap();
-            let k = ks.as_ptr();
-            let v = vs.as_ptr();
-            cpp!(unsafe [mut device_options as "edgetpu::EdgeTpuManager::DeviceOptions", k as "const char *", v as "const char *"] {
-                device_options.insert({std::string(k), std::string(v)});
+        let opened = cpp!(unsafe [context as "ContextPtr"] -> bool as "bool" {
+            return context != nullptr;
+        });
+        if !opened {
+            // libedgetpu returns no context for a device it cannot open
+            return Err(EdgeTPUError::OpenFailed {
+                target: selector.to_string(),
+                status: STATUS_ERROR,
+                message: "no such device is available".to_owned(),
             });
         }
+        Ok(EdgeTpuContext::new(context))
+    }
+}
 
-        let path_s = CString::new(path).unwrap();
-        let path = path_s.as_ptr();
+/// Scripting of the in-tree fake libedgetpu, for tests built with the `stub` feature.
+#[cfg(feature = "stub")]
+pub mod stub {
+    use std::ffi::CString;
+    use std::os::raw::{c_char, c_int};
 
-        let inner = cpp!(unsafe [device_type as "edgetpu::DeviceType", path as "const char *", mut device_options as "edgetpu::EdgeTpuManager::DeviceOptions"] -> InnerEdgeTpuContext as "std::shared_ptr<edgetpu::EdgeTpuContext>" {
-            auto device_path = std::string(path);
-            return get_edgetpu_manager_singleton()->OpenDevice(device_type, device_path, device_options);
-        });
+    use super::DeviceType;
 
-        let ok = cpp!(unsafe [inner as "std::shared_ptr<edgetpu::EdgeTpuContext>"] -> i32 as "int" {
-            return !!inner;
-        });
+    extern "C" {
+        fn edgetpu_stub_reset();
+        fn edgetpu_stub_add_device(device_type: c_int, path: *const c_char);
+        fn edgetpu_stub_remove_device(path: *const c_char);
+        fn edgetpu_stub_set_throwing(throwing: bool);
+        fn edgetpu_stub_live_contexts() -> c_int;
+    }
 
-        match ok {
-            0 => Err(EdgeTPUError::OpenFailed),
-            _ => Ok(EdgeTpuContext {
-                inner: Arc::new(Mutex::new(inner)),
-            }),
-        }
+    /// Removes every device and stops throwing. Contexts still alive are kept alive.
+    pub fn reset() {
+        unsafe { edgetpu_stub_reset() }
     }
 
-    pub fn set_verbosity(&self, verbosity: i32) -> Result<(), EdgeTPUError> {
-        let status = cpp!(unsafe [verbosity as "int"] -> i32 as "int" {
-            return get_edgetpu_manager_singleton()->SetVerbosity(verbosity);
-        });
-        match status {
-            0 => Err(EdgeTPUError::SetVerbosityFailed),
-            _ => Ok(()),
-        }
+    pub fn add_device(device_type: DeviceType, path: &str) {
+        add_raw_device(device_type.as_raw(), path);
+    }
+
+    /// Adds a device of any type, including ones the driver does not know
+    pub fn add_raw_device(device_type: i32, path: &str) {
+        let path = CString::new(path).expect("device path contains a NUL byte");
+        unsafe { edgetpu_stub_add_device(device_type, path.as_ptr()) }
+    }
+
+    /// Unplugs a device, its contexts stop being ready
+    pub fn remove_device(path: &str) {
+        let path = CString::new(path).expect("device path contains a NUL byte");
+        unsafe { edgetpu_stub_remove_device(path.as_ptr()) }
+    }
+
+    /// Makes the manager throw a C++ exception on enumeration and open
+    pub fn set_throwing(throwing: bool) {
+        unsafe { edgetpu_stub_set_throwing(throwing) }
+    }
+
+    /// Number of contexts not destroyed yet
+    pub fn live_contexts() -> usize {
+        unsafe { edgetpu_stub_live_contexts() as usize }
     }
 }
 
@@ -293,9 +618,148 @@ mod tests {
     use super::*;
 
     #[test]
+    #[cfg_attr(feature = "stub", ignore = "the stub has no device")]
     fn test_enumerate_devices() {
         let manager = EdgeTpuDeviceManager::get_singleton().expect("Failed to get singleton");
-        let devices = manager.enumerate_devices();
+        let devices = manager.enumerate_devices().unwrap();
         assert!(!devices.is_empty(), "No devices found");
     }
-}
\ No newline at end of file
+
+    #[cfg(feature = "stub")]
+    mod stubbed {
+        use std::sync::{Mutex, MutexGuard};
+
+        use super::super::stub;
+        use super::*;
+
+        const USB_PATH: &str = "/sys/bus/usb/devices/2-1";
+
+        /// The fake is process wide, tests using it run one at a time
+        fn setup() -> (MutexGuard<'static, ()>, EdgeTpuDeviceManager) {
+            static LOCK: Mutex<()> = Mutex::new(());
+            let guard = LOCK.lock().unwrap_or_else(|err| err.into_inner());
+            stub::reset();
+            stub::add_device(DeviceType::ApexPCI, "/dev/apex_0");
+            stub::add_device(DeviceType::ApexUSB, USB_PATH);
+            (guard, EdgeTpuDeviceManager::get_singleton().unwrap())
+        }
+
+        #[test]
+        fn test_enumerate_devices() {
+            let (_guard, manager) = setup();
+            assert_eq!(
+                manager.enumerate_devices().unwrap(),
+                vec![
+                    DeviceRecord {
+                        device_type: DeviceType::ApexPCI,
+                        path: "/dev/apex_0".to_owned(),
+                    },
+                    DeviceRecord {
+                        device_type: DeviceType::ApexUSB,
+                        path: USB_PATH.to_owned(),
+                    },
+                ]
+            );
+
+            stub::add_raw_device(7, "/dev/unknown");
+            assert!(matches!(
+                manager.enumerate_devices(),
+                Err(EdgeTPUError::UnknownDeviceType(7))
+            ));
+        }
+
+        #[test]
+        fn test_open_and_release() {
+            let (_guard, manager) = setup();
+            let live = stub::live_contexts();
+
+            let context = manager.open_device_type(DeviceType::ApexUSB).unwrap();
+            assert!(context.is_ready());
+            assert_eq!(context.get_device_enum_record().unwrap().path, USB_PATH);
+
+            let opened = manager.opened_devices().unwrap();
+            assert_eq!(opened.len(), 1);
+            assert_eq!(
+                opened[0].get_device_enum_record().unwrap().device_type,
+                DeviceType::ApexUSB
+            );
+            drop(opened);
+            let clone = context.clone();
+            drop(context);
+            assert_eq!(stub::live_contexts(), live + 1);
+            drop(clone);
+            assert_eq!(stub::live_contexts(), live);
+            assert!(manager.opened_devices().unwrap().is_empty());
+
+            // a fresh context is not listed as opened
+            let fresh = manager
+                .new_context_path(DeviceType::ApexPCI, "/dev/apex_0")
+                .unwrap();
+            assert!(manager.opened_devices().unwrap().is_empty());
+            stub::remove_device("/dev/apex_0");
+            assert!(!fresh.is_ready());
+            drop(fresh);
+            assert_eq!(stub::live_contexts(), live);
+        }
+
+        #[test]
+        fn test_open_with_options() {
+            let (_guard, manager) = setup();
+            let options = HashMap::from([("Performance".to_owned(), "Max".to_owned())]);
+            let context = manager
+                .open_device_options(DeviceType::ApexUSB, USB_PATH, options.clone())
+                .unwrap();
+            assert_eq!(context.device_options(), options);
+
+            let context = manager.new_context_type(DeviceType::ApexPCI).unwrap();
+            assert!(context.device_options().is_empty());
+        }
+
+        #[test]
+        fn test_open_failures() {
+            let (_guard, manager) = setup();
+            let result = manager.open_device_path(DeviceType::ApexPCI, "/dev/apex_1");
+            assert!(matches!(
+                result,
+                Err(EdgeTPUError::OpenFailed { ref target, status: STATUS_ERROR, .. })
+                    if target == "ApexPCI Edge TPU /dev/apex_1"
+            ));
+            assert!(matches!(
+                manager.open_device_path(DeviceType::ApexPCI, "/dev/apex\0_0"),
+                Err(EdgeTPUError::NulByte(_))
+            ));
+
+            stub::set_throwing(true);
+            assert!(matches!(
+                manager.open_device(),
+                Err(EdgeTPUError::OpenFailed { status: STATUS_ERROR, ref message, .. })
+                    if message == "simulated libedgetpu failure"
+            ));
+            assert!(matches!(
+                manager.enumerate_devices(),
+                Err(EdgeTPUError::Library {
+                    status: STATUS_ERROR,
+                    ..
+                })
+            ));
+        }
+
+        #[test]
+        fn test_manager_settings() {
+            let (_guard, manager) = setup();
+            assert!(manager
+                .runtime_version()
+                .unwrap()
+                .contains("RuntimeVersion"));
+            manager.set_verbosity(3).unwrap();
+            assert!(matches!(
+                manager.set_verbosity(11),
+                Err(EdgeTPUError::SetVerbosityFailed {
+                    verbosity: 11,
+                    status: STATUS_ERROR,
+                    ..
+                })
+            ));
+        }
+    }
+}
@@ -1 +1,3 @@
-pub mod driver;
+#[allow(clippy::module_inception)]
+mod driver;
+pub use driver::*;
@@ -1 +1,5 @@
+// the C++ blocks of the driver are expanded recursively by the `cpp` macros
+#![recursion_limit = "512"]
+
+pub mod backend;
 pub mod driver;
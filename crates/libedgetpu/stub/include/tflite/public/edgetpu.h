@@ -0,0 +1,298 @@
+// In-tree fake of the libedgetpu API, used by the `stub` feature.
+//
+// It declares the subset of `tflite/public/edgetpu.h` the driver uses and implements it
+// in this header, so nothing has to be linked and the crates depending on libedgetpu build
+// and test on any machine. The manager starts without devices; tests script it through the
+// `edgetpu_stub_*` functions at the end of this file.
+//
+// The fake owns everything through standard containers and smart pointers, and counts the
+// contexts alive, so leaks and double frees show up in tests and under sanitizers.
+#ifndef LIBEDGETPU_STUB_EDGETPU_H_
+#define LIBEDGETPU_STUB_EDGETPU_H_
+
+#include <algorithm>
+#include <memory>
+#include <mutex>
+#include <stdexcept>
+#include <string>
+#include <unordered_map>
+#include <utility>
+#include <vector>
+
+typedef enum TfLiteStatus {
+  kTfLiteOk = 0,
+  kTfLiteError = 1,
+} TfLiteStatus;
+
+namespace edgetpu {
+
+enum class DeviceType {
+  kApexPci = 0,
+  kApexUsb = 1,
+};
+
+class EdgeTpuContext;
+
+class EdgeTpuManager {
+ public:
+  using DeviceOptions = std::unordered_map<std::string, std::string>;
+
+  struct DeviceEnumerationRecord {
+    DeviceType type;
+    std::string path;
+
+    friend bool operator==(const DeviceEnumerationRecord& lhs,
+                           const DeviceEnumerationRecord& rhs) {
+      return lhs.type == rhs.type && lhs.path == rhs.path;
+    }
+  };
+
+  static EdgeTpuManager* GetSingleton();
+
+  static std::unique_ptr<EdgeTpuContext> NewEdgeTpuContext();
+  static std::unique_ptr<EdgeTpuContext> NewEdgeTpuContext(DeviceType device_type);
+  static std::unique_ptr<EdgeTpuContext> NewEdgeTpuContext(
+      DeviceType device_type, const std::string& device_path);
+  static std::unique_ptr<EdgeTpuContext> NewEdgeTpuContext(
+      DeviceType device_type, const std::string& device_path,
+      const DeviceOptions& options);
+
+  std::vector<DeviceEnumerationRecord> EnumerateEdgeTpu() const;
+
+  std::shared_ptr<EdgeTpuContext> OpenDevice();
+  std::shared_ptr<EdgeTpuContext> OpenDevice(DeviceType device_type);
+  std::shared_ptr<EdgeTpuContext> OpenDevice(DeviceType device_type,
+                                             const std::string& device_path);
+  std::shared_ptr<EdgeTpuContext> OpenDevice(DeviceType device_type,
+                                             const std::string& device_path,
+                                             const DeviceOptions& options);
+
+  std::vector<std::shared_ptr<EdgeTpuContext>> GetOpenedDevices() const;
+
+  TfLiteStatus SetVerbosity(int verbosity);
+
+  std::string Version() const;
+};
+
+class EdgeTpuContext {
+ public:
+  virtual ~EdgeTpuContext() = default;
+
+  virtual const EdgeTpuManager::DeviceEnumerationRecord& GetDeviceEnumRecord()
+      const = 0;
+
+  virtual EdgeTpuManager::DeviceOptions GetDeviceOptions() const = 0;
+
+  virtual bool IsReady() const = 0;
+};
+
+}  // namespace edgetpu
+
+namespace edgetpu_stub {
+
+using edgetpu::DeviceType;
+using edgetpu::EdgeTpuContext;
+using edgetpu::EdgeTpuManager;
+
+struct State {
+  std::mutex mutex;
+  std::vector<EdgeTpuManager::DeviceEnumerationRecord> devices;
+  // like libedgetpu, the manager does not keep the devices it opened alive
+  std::vector<std::weak_ptr<EdgeTpuContext>> opened;
+  bool throwing = false;
+  int live_contexts = 0;
+};
+
+inline State& state() {
+  static State state;
+  return state;
+}
+
+class Context : public EdgeTpuContext {
+ public:
+  Context(EdgeTpuManager::DeviceEnumerationRecord record,
+          EdgeTpuManager::DeviceOptions options)
+      : record_(std::move(record)), options_(std::move(options)) {
+    std::lock_guard<std::mutex> lock(state().mutex);
+    ++state().live_contexts;
+  }
+
+  ~Context() override {
+    std::lock_guard<std::mutex> lock(state().mutex);
+    --state().live_contexts;
+  }
+
+  const EdgeTpuManager::DeviceEnumerationRecord& GetDeviceEnumRecord()
+      const override {
+    return record_;
+  }
+
+  EdgeTpuManager::DeviceOptions GetDeviceOptions() const override {
+    return options_;
+  }
+
+  bool IsReady() const override {
+    std::lock_guard<std::mutex> lock(state().mutex);
+    const auto& devices = state().devices;
+    return std::find(devices.begin(), devices.end(), record_) != devices.end();
+  }
+
+ private:
+  EdgeTpuManager::DeviceEnumerationRecord record_;
+  EdgeTpuManager::DeviceOptions options_;
+};
+
+// Creates a context on the first device matching the request, or returns null.
+inline std::unique_ptr<Context> NewContext(const DeviceType* device_type,
+                                           const std::string* device_path,
+                                           EdgeTpuManager::DeviceOptions options) {
+  std::unique_lock<std::mutex> lock(state().mutex);
+  if (state().throwing) {
+    throw std::runtime_error("simulated libedgetpu failure");
+  }
+  for (const auto& device : state().devices) {
+    if ((device_type == nullptr || device.type == *device_type) &&
+        (device_path == nullptr || device.path == *device_path)) {
+      auto record = device;
+      lock.unlock();
+      return std::unique_ptr<Context>(new Context(std::move(record), std::move(options)));
+    }
+  }
+  return nullptr;
+}
+
+inline std::shared_ptr<EdgeTpuContext> Open(const DeviceType* device_type,
+                                            const std::string* device_path,
+                                            EdgeTpuManager::DeviceOptions options) {
+  std::shared_ptr<EdgeTpuContext> context =
+      NewContext(device_type, device_path, std::move(options));
+  if (context) {
+    std::lock_guard<std::mutex> lock(state().mutex);
+    state().opened.push_back(context);
+  }
+  return context;
+}
+
+}  // namespace edgetpu_stub
+
+namespace edgetpu {
+
+inline EdgeTpuManager* EdgeTpuManager::GetSingleton() {
+  static EdgeTpuManager manager;
+  return &manager;
+}
+
+inline std::unique_ptr<EdgeTpuContext> EdgeTpuManager::NewEdgeTpuContext() {
+  return edgetpu_stub::NewContext(nullptr, nullptr, {});
+}
+
+inline std::unique_ptr<EdgeTpuContext> EdgeTpuManager::NewEdgeTpuContext(
+    DeviceType device_type) {
+  return edgetpu_stub::NewContext(&device_type, nullptr, {});
+}
+
+inline std::unique_ptr<EdgeTpuContext> EdgeTpuManager::NewEdgeTpuContext(
+    DeviceType device_type, const std::string& device_path) {
+  return edgetpu_stub::NewContext(&device_type, &device_path, {});
+}
+
+inline std::unique_ptr<EdgeTpuContext> EdgeTpuManager::NewEdgeTpuContext(
+    DeviceType device_type, const std::string& device_path,
+    const DeviceOptions& options) {
+  return edgetpu_stub::NewContext(&device_type, &device_path, options);
+}
+
+inline std::vector<EdgeTpuManager::DeviceEnumerationRecord>
+EdgeTpuManager::EnumerateEdgeTpu() const {
+  std::lock_guard<std::mutex> lock(edgetpu_stub::state().mutex);
+  if (edgetpu_stub::state().throwing) {
+    throw std::runtime_error("simulated libedgetpu failure");
+  }
+  return edgetpu_stub::state().devices;
+}
+
+inline std::shared_ptr<EdgeTpuContext> EdgeTpuManager::OpenDevice() {
+  return edgetpu_stub::Open(nullptr, nullptr, {});
+}
+
+inline std::shared_ptr<EdgeTpuContext> EdgeTpuManager::OpenDevice(
+    DeviceType device_type) {
+  return edgetpu_stub::Open(&device_type, nullptr, {});
+}
+
+inline std::shared_ptr<EdgeTpuContext> EdgeTpuManager::OpenDevice(
+    DeviceType device_type, const std::string& device_path) {
+  return edgetpu_stub::Open(&device_type, &device_path, {});
+}
+
+inline std::shared_ptr<EdgeTpuContext> EdgeTpuManager::OpenDevice(
+    DeviceType device_type, const std::string& device_path,
+    const DeviceOptions& options) {
+  return edgetpu_stub::Open(&device_type, &device_path, options);
+}
+
+inline std::vector<std::shared_ptr<EdgeTpuContext>>
+EdgeTpuManager::GetOpenedDevices() const {
+  std::lock_guard<std::mutex> lock(edgetpu_stub::state().mutex);
+  std::vector<std::shared_ptr<EdgeTpuContext>> opened;
+  for (const auto& weak : edgetpu_stub::state().opened) {
+    if (auto context = weak.lock()) {
+      opened.push_back(std::move(context));
+    }
+  }
+  return opened;
+}
+
+inline TfLiteStatus EdgeTpuManager::SetVerbosity(int verbosity) {
+  return verbosity < 0 || verbosity > 10 ? kTfLiteError : kTfLiteOk;
+}
+
+inline std::string EdgeTpuManager::Version() const {
+  return "BuildLabel(stub), RuntimeVersion(14)";
+}
+
+}  // namespace edgetpu
+
+// Scripting of the fake, called from the Rust tests.
+extern "C" {
+
+void edgetpu_stub_reset() {
+  auto& state = edgetpu_stub::state();
+  std::lock_guard<std::mutex> lock(state.mutex);
+  state.devices.clear();
+  state.opened.clear();
+  state.throwing = false;
+}
+
+// `device_type` is not checked, so that tests can simulate types the driver does not know.
+void edgetpu_stub_add_device(int device_type, const char* path) {
+  auto& state = edgetpu_stub::state();
+  std::lock_guard<std::mutex> lock(state.mutex);
+  state.devices.push_back({static_cast<edgetpu::DeviceType>(device_type), path});
+}
+
+void edgetpu_stub_remove_device(const char* path) {
+  auto& state = edgetpu_stub::state();
+  std::lock_guard<std::mutex> lock(state.mutex);
+  auto& devices = state.devices;
+  devices.erase(std::remove_if(devices.begin(), devices.end(),
+                               [&](const edgetpu::EdgeTpuManager::DeviceEnumerationRecord&
+                                       device) { return device.path == path; }),
+                devices.end());
+}
+
+void edgetpu_stub_set_throwing(bool throwing) {
+  auto& state = edgetpu_stub::state();
+  std::lock_guard<std::mutex> lock(state.mutex);
+  state.throwing = throwing;
+}
+
+int edgetpu_stub_live_contexts() {
+  auto& state = edgetpu_stub::state();
+  std::lock_guard<std::mutex> lock(state.mutex);
+  return state.live_contexts;
+}
+
+}  // extern "C"
+
+#endif  // LIBEDGETPU_STUB_EDGETPU_H_
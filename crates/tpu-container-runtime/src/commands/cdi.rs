@@ -0,0 +1,119 @@
+//! Contains the CDI spec commands
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use edgetpu::cdi::generate::{SpecFormat, SpecGenerator};
+use edgetpu::cdi::{Registry, DEFAULT_SPEC_DIRS};
+use tabwriter::TabWriter;
+
+/// Manage the Container Device Interface spec of the host Edge TPUs
+#[derive(Parser, Debug)]
+pub struct Cdi {
+    #[clap(subcommand)]
+    pub command: CdiCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CdiCommand {
+    Generate(Generate),
+    List(List),
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Format {
+    Json,
+    Yaml,
+}
+
+impl From<Format> for SpecFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Json => SpecFormat::Json,
+            Format::Yaml => SpecFormat::Yaml,
+        }
+    }
+}
+
+/// Generate a CDI spec for the Edge TPUs of this host
+#[derive(Parser, Debug)]
+pub struct Generate {
+    /// File to write the spec to, e.g. /etc/cdi/coral.yaml (default: stdout)
+    #[clap(long, short)]
+    pub output: Option<PathBuf>,
+    /// Format of the spec (default: from the output file extension, else yaml)
+    #[clap(long, value_enum)]
+    pub format: Option<Format>,
+}
+
+/// List the CDI devices defined by the spec directories
+#[derive(Parser, Debug)]
+pub struct List {
+    /// Spec directory, by increasing priority (default: /etc/cdi and /var/run/cdi)
+    #[clap(long = "spec-dir")]
+    pub spec_dirs: Vec<PathBuf>,
+}
+
+pub fn cdi(args: Cdi) -> Result<()> {
+    match args.command {
+        CdiCommand::Generate(generate) => generate_spec(generate),
+        CdiCommand::List(list) => list_devices(list),
+    }
+}
+
+fn generate_spec(args: Generate) -> Result<()> {
+    let spec = SpecGenerator::new()
+        .generate()
+        .context("failed to generate the CDI spec")?;
+    let format = match (args.format, &args.output) {
+        (Some(format), _) => format.into(),
+        (None, Some(output)) => SpecFormat::from_path(output),
+        (None, None) => SpecFormat::Yaml,
+    };
+    let content = format.encode(&spec)?;
+
+    let Some(output) = args.output else {
+        print!("{content}");
+        return Ok(());
+    };
+
+    if let Some(dir) = output.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    // replace the spec atomically, runtimes may be reading the directory
+    let tmp = output.with_extension("tmp");
+    fs::write(&tmp, content).with_context(|| format!("failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, &output)
+        .with_context(|| format!("failed to move the spec to {}", output.display()))?;
+    tracing::info!(
+        "wrote {} CDI devices to {}",
+        spec.devices.len(),
+        output.display()
+    );
+
+    Ok(())
+}
+
+fn list_devices(args: List) -> Result<()> {
+    let spec_dirs = if args.spec_dirs.is_empty() {
+        DEFAULT_SPEC_DIRS.iter().map(PathBuf::from).collect()
+    } else {
+        args.spec_dirs
+    };
+    let registry = Registry::load(&spec_dirs)?;
+
+    let mut tab_writer = TabWriter::new(io::stdout());
+    writeln!(&mut tab_writer, "NAME\tSPEC")?;
+    for name in registry.device_names() {
+        let spec = registry
+            .spec_of(name)
+            .map(|loaded| loaded.path().display().to_string())
+            .unwrap_or_default();
+        writeln!(&mut tab_writer, "{name}\t{spec}")?;
+    }
+    tab_writer.flush()?;
+
+    Ok(())
+}
@@ -2,10 +2,13 @@
 use std::path::PathBuf;
 
 use anyhow::Result;
+use edgetpu::cdi::CdiInjector;
+use edgetpu::model::validate::ModelValidator;
 use libcontainer::container::builder::ContainerBuilder;
 use libcontainer::syscall::syscall::SyscallType;
 use liboci_cli::Create;
 
+use crate::commands::{edgetpu_injector, usb_follow};
 use crate::workload::executor::default_executor;
 
 // One thing to note is that in the end, container is just another process in Linux
@@ -18,13 +21,23 @@ pub fn create(args: Create, root_path: PathBuf, systemd_cgroup: bool) -> Result<
         .with_executor(default_executor())
         .with_pid_file(args.pid_file.as_ref())?
         .with_console_socket(args.console_socket.as_ref())
-        .with_root_path(root_path)?
+        .with_root_path(root_path.clone())?
         .with_preserved_fds(args.preserve_fds)
         .validate_id()?
         .as_init(&args.bundle)
+        .with_spec_modifier(edgetpu_injector(&root_path, &args.container_id)?)
+        .with_spec_modifier(CdiInjector::new())
+        .with_spec_modifier(ModelValidator::new())
         .with_systemd(systemd_cgroup)
         .with_detach(true)
         .build()?;
 
+    if let Err(err) = usb_follow::spawn(&root_path, &args.container_id) {
+        tracing::warn!(
+            ?err,
+            "USB Edge TPUs will not be followed when they re-enumerate"
+        );
+    }
+
     Ok(())
 }
@@ -1,13 +1,162 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
+use edgetpu::events::{Event, EventLog};
+use edgetpu::health::HEALTH_EVENT;
+use edgetpu::reset::RESET_EVENT;
+use edgetpu::thermal::THERMAL_EVENT;
+use edgetpu::uevent::REENUMERATION_EVENT;
+use libcontainer::container::events::{ContainerEvent, EventSource};
+use libcontainer::lease::LeaseLedger;
 use liboci_cli::Events;
 
 use crate::commands::load_container;
 
+/// An Edge TPU of the container was quarantined after failing its health probes
+const TPU_LOST_EVENT: &str = "tpu-lost";
+/// An Edge TPU of the container was reset or came back with another device node
+const TPU_REENUMERATED_EVENT: &str = "tpu-reenumerated";
+/// An Edge TPU of the container reached the warning or critical temperature
+const TPU_OVERHEAT_EVENT: &str = "tpu-overheat";
+
 pub fn events(args: Events, root_path: PathBuf) -> Result<()> {
-    let mut container = load_container(root_path, &args.container_id)?;
+    let mut container = load_container(&root_path, &args.container_id)?;
+    let mut sources: Vec<Box<dyn EventSource>> = vec![Box::new(TpuEvents::new(&root_path)?)];
     container
-        .events(args.interval, args.stats)
+        .events(args.interval, args.stats, &mut sources)
         .with_context(|| format!("failed to get events from container {}", args.container_id))
 }
+
+/// Relays the events of the Edge TPU event log about the devices leased to the container.
+struct TpuEvents {
+    log: EventLog,
+    leases: LeaseLedger,
+    offset: u64,
+}
+
+impl TpuEvents {
+    /// Follows the events recorded from now on
+    fn new(root_path: &Path) -> Result<Self> {
+        let log = EventLog::new(root_path);
+        let (_, offset) = log.read_since(0)?;
+        Ok(Self {
+            log,
+            leases: LeaseLedger::new(root_path),
+            offset,
+        })
+    }
+}
+
+impl EventSource for TpuEvents {
+    fn poll(&mut self, container_id: &str) -> Vec<ContainerEvent> {
+        let events = match self.log.read_since(self.offset) {
+            Ok((events, offset)) => {
+                self.offset = offset;
+                events
+            }
+            Err(err) => {
+                tracing::warn!(?err, "failed to read the Edge TPU events");
+                return Vec::new();
+            }
+        };
+        if events.is_empty() {
+            return Vec::new();
+        }
+
+        let devices: Vec<_> = match self.leases.leases() {
+            Ok(leases) => leases
+                .into_iter()
+                .filter(|lease| lease.container_id == container_id)
+                .map(|lease| lease.device)
+                .collect(),
+            Err(err) => {
+                tracing::warn!(?err, "failed to read the device leases");
+                return Vec::new();
+            }
+        };
+        events
+            .iter()
+            .filter(|event| devices.contains(&event.id))
+            .filter_map(|event| container_event(event, container_id))
+            .collect()
+    }
+}
+
+/// The event of the container for an event of one of its Edge TPUs, if it is one the
+/// container is told about.
+fn container_event(event: &Event, container_id: &str) -> Option<ContainerEvent> {
+    let kind = match event.kind.as_str() {
+        HEALTH_EVENT if event.data["to"] == "quarantined" => TPU_LOST_EVENT,
+        RESET_EVENT => TPU_REENUMERATED_EVENT,
+        REENUMERATION_EVENT if event.data["container"] == container_id => TPU_REENUMERATED_EVENT,
+        THERMAL_EVENT if event.data["level"] != "normal" => TPU_OVERHEAT_EVENT,
+        _ => return None,
+    };
+
+    let mut data = serde_json::json!({
+        "device": event.id,
+        "timestamp": event.timestamp,
+    });
+    if let Some(fields) = event.data.as_object() {
+        for (key, value) in fields {
+            data[key] = value.clone();
+        }
+    }
+    Some(ContainerEvent::new(kind, container_id, Some(data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn event(kind: &str, id: &str, data: serde_json::Value) -> Event {
+        Event {
+            kind: kind.to_owned(),
+            id: id.to_owned(),
+            timestamp: 1700000000,
+            data,
+        }
+    }
+
+    #[test]
+    fn test_container_event() {
+        let quarantined = event(
+            HEALTH_EVENT,
+            "apex_0",
+            json!({"from": "failing", "to": "quarantined", "reason": "sysfs: status is dead"}),
+        );
+        let line = serde_json::to_string(&container_event(&quarantined, "camera").unwrap());
+        assert_eq!(
+            line.unwrap(),
+            r#"{"type":"tpu-lost","id":"camera","data":{"device":"apex_0","from":"failing","reason":"sysfs: status is dead","timestamp":1700000000,"to":"quarantined"}}"#
+        );
+
+        let critical = event(THERMAL_EVENT, "apex_0", json!({"level": "critical"}));
+        let reset = event(RESET_EVENT, "apex_0", json!({"name": "apex_1"}));
+        let reenumerated = event(
+            REENUMERATION_EVENT,
+            "usb-2-1",
+            json!({"container": "camera"}),
+        );
+        for (event, kind) in [
+            (critical, TPU_OVERHEAT_EVENT),
+            (reset, TPU_REENUMERATED_EVENT),
+            (reenumerated.clone(), TPU_REENUMERATED_EVENT),
+        ] {
+            assert_eq!(container_event(&event, "camera").unwrap().kind, kind);
+        }
+
+        let ignored = [
+            event(HEALTH_EVENT, "apex_0", json!({"to": "failing"})),
+            event(THERMAL_EVENT, "apex_0", json!({"level": "normal"})),
+            event("edgetpu-other", "apex_0", json!({})),
+        ];
+        for event in ignored {
+            assert_eq!(container_event(&event, "camera"), None);
+        }
+        // followed for another container
+        assert_eq!(container_event(&reenumerated, "inference"), None);
+    }
+}
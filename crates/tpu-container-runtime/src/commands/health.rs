@@ -0,0 +1,114 @@
+//! Contains the Edge TPU health commands
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand};
+use edgetpu::events::EventLog;
+use edgetpu::health::{HealthLedger, HealthMonitor};
+use libcontainer::lease::LeaseLedger;
+use tabwriter::TabWriter;
+
+/// Probe the health of the host Edge TPUs and manage their quarantine
+#[derive(Parser, Debug)]
+pub struct Health {
+    #[clap(subcommand)]
+    pub command: HealthCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum HealthCommand {
+    Monitor(Monitor),
+    Status(Status),
+    Clear(Clear),
+}
+
+/// Probe the Edge TPUs periodically, quarantining the ones that keep failing
+#[derive(Parser, Debug)]
+pub struct Monitor {
+    /// Seconds between two rounds of probes
+    #[clap(long, default_value_t = 10)]
+    pub interval: u64,
+    /// Failed probes in a row that quarantine a device
+    #[clap(long, default_value_t = 3)]
+    pub quarantine_after: u32,
+    /// Passed probes in a row that bring a quarantined device back
+    #[clap(long, default_value_t = 3)]
+    pub recover_after: u32,
+    /// Probe once and exit
+    #[clap(long)]
+    pub once: bool,
+}
+
+/// Show the health of the probed Edge TPUs
+#[derive(Parser, Debug)]
+pub struct Status {}
+
+/// Mark a quarantined Edge TPU healthy again
+#[derive(Parser, Debug)]
+pub struct Clear {
+    /// Name of the device, e.g. apex_0 or usb-2-1
+    pub device: String,
+}
+
+pub fn health(args: Health, root_path: PathBuf) -> Result<()> {
+    match args.command {
+        HealthCommand::Monitor(monitor) => run_monitor(monitor, root_path),
+        HealthCommand::Status(_) => print_status(root_path),
+        HealthCommand::Clear(clear) => clear_device(clear, root_path),
+    }
+}
+
+fn run_monitor(args: Monitor, root_path: PathBuf) -> Result<()> {
+    let monitor = HealthMonitor::new(HealthLedger::new(&root_path))
+        .with_leases(LeaseLedger::new(&root_path))
+        .with_events(EventLog::new(&root_path))
+        .with_quarantine_after(args.quarantine_after)
+        .with_recover_after(args.recover_after);
+    if args.once {
+        monitor.probe_all()?;
+        return Ok(());
+    }
+
+    // the ledger is replaced atomically, the monitor can be killed at any time
+    let interval = Duration::from_secs(args.interval.max(1));
+    loop {
+        if let Err(err) = monitor.probe_all() {
+            tracing::warn!(?err, "failed to probe the Edge TPUs");
+        }
+        thread::sleep(interval);
+    }
+}
+
+fn print_status(root_path: PathBuf) -> Result<()> {
+    let devices = HealthLedger::new(root_path).devices()?;
+
+    let mut tab_writer = TabWriter::new(io::stdout());
+    writeln!(&mut tab_writer, "DEVICE\tSTATUS\tFAILURES\tLAST FAILURE")?;
+    for (device, health) in devices {
+        writeln!(
+            &mut tab_writer,
+            "{device}\t{}\t{}\t{}",
+            health.status,
+            health.failures,
+            health.last_failure.unwrap_or_default()
+        )?;
+    }
+    tab_writer.flush()?;
+
+    Ok(())
+}
+
+fn clear_device(args: Clear, root_path: PathBuf) -> Result<()> {
+    let ledger = HealthLedger::new(&root_path);
+    if !ledger.devices()?.contains_key(&args.device) {
+        bail!("Edge TPU {} was never probed", args.device);
+    }
+    if let Some(transition) = ledger.clear(&args.device)? {
+        EventLog::new(&root_path).emit(&transition.to_event())?;
+        println!("{transition}");
+    }
+    Ok(())
+}
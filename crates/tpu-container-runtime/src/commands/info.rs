@@ -1,65 +1,290 @@
 //! Contains functions related to printing information about system running Youki
+use std::collections::BTreeMap;
 #[cfg(feature = "v2")]
 use std::collections::HashSet;
 use std::fs;
-use std::path::Path;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use edgetpu::info::{EdgeTpuInfo, HostInfo, NodeAccess};
 #[cfg(feature = "v2")]
 use libcgroups::{common::CgroupSetup, v2::controller_type::ControllerType};
 use libcontainer::user_ns;
 use procfs::{CpuInfo, Current, Meminfo};
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+use tabwriter::TabWriter;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Format {
+    Text,
+    Json,
+}
+
 /// Show information about the system
 #[derive(Parser, Debug)]
-pub struct Info {}
+pub struct Info {
+    #[clap(long, value_enum, default_value = "text")]
+    pub format: Format,
+}
+
+/// Everything `info` reports
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SystemInfo {
+    version: &'static str,
+    commit: &'static str,
+    kernel: Kernel,
+    operating_system: Option<String>,
+    cores: Option<usize>,
+    /// In MiB
+    total_memory: Option<u64>,
+    cgroups: Cgroups,
+    /// `None` when the kernel config cannot be read
+    namespaces: Option<Namespaces>,
+    /// `None` when the bounding set cannot be read
+    capabilities: Option<Features>,
+    edgetpu: EdgeTpuInfo,
+}
+
+#[derive(Serialize, Debug)]
+struct Kernel {
+    release: String,
+    version: String,
+    architecture: String,
+}
+
+#[derive(Serialize, Debug)]
+struct Cgroups {
+    setup: Option<String>,
+    mounts: BTreeMap<String, PathBuf>,
+    #[cfg(feature = "v2")]
+    controllers: Option<Features>,
+}
+
+#[derive(Serialize, Debug)]
+struct Namespaces {
+    /// `None` when the kernel config does not say
+    enabled: Option<bool>,
+    features: Features,
+}
+
+/// Status of features, in the order they are printed. Serialized as an object.
+#[derive(Debug, Default)]
+struct Features(Vec<(String, &'static str)>);
+
+impl Features {
+    fn push<S: Into<String>>(&mut self, name: S, status: &'static str) {
+        self.0.push((name.into(), status));
+    }
+}
+
+impl Serialize for Features {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (name, status) in &self.0 {
+            map.serialize_entry(name, status)?;
+        }
+        map.end()
+    }
+}
 
-pub fn info(_: Info) -> Result<()> {
-    print_youki();
-    print_kernel();
-    print_os();
-    print_hardware();
-    print_cgroups();
-    print_namespaces();
-    print_capabilities();
+pub fn info(args: Info) -> Result<()> {
+    let info = SystemInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: env!("VERGEN_GIT_SHA"),
+        kernel: kernel(),
+        operating_system: os(),
+        cores: CpuInfo::current().ok().map(|cpu_info| cpu_info.num_cores()),
+        total_memory: Meminfo::current()
+            .ok()
+            .map(|mem_info| mem_info.mem_total / u64::pow(1024, 2)),
+        cgroups: cgroups(),
+        namespaces: namespaces(),
+        capabilities: capabilities(),
+        edgetpu: HostInfo::new().collect(),
+    };
+
+    match args.format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(&info)?),
+        Format::Text => print_text(&info)?,
+    }
 
     Ok(())
 }
 
-/// print Version of Youki
-pub fn print_youki() {
-    println!("{:<18}{}", "Version", env!("CARGO_PKG_VERSION"));
-    println!("{:<18}{}", "Commit", env!("VERGEN_GIT_SHA"));
+fn print_text(info: &SystemInfo) -> Result<()> {
+    println!("{:<18}{}", "Version", info.version);
+    println!("{:<18}{}", "Commit", info.commit);
+    println!("{:<18}{}", "Kernel-Release", info.kernel.release);
+    println!("{:<18}{}", "Kernel-Version", info.kernel.version);
+    println!("{:<18}{}", "Architecture", info.kernel.architecture);
+    if let Some(os) = &info.operating_system {
+        println!("{:<18}{}", "Operating System", os);
+    }
+    if let Some(cores) = info.cores {
+        println!("{:<18}{}", "Cores", cores);
+    }
+    if let Some(memory) = info.total_memory {
+        println!("{:<18}{}", "Total Memory", memory);
+    }
+
+    if let Some(setup) = &info.cgroups.setup {
+        println!("{:<18}{}", "Cgroup setup", setup);
+    }
+    println!("Cgroup mounts");
+    for (name, mount_point) in &info.cgroups.mounts {
+        println!("  {:<16}{}", name, mount_point.display());
+    }
+    #[cfg(feature = "v2")]
+    if let Some(controllers) = &info.cgroups.controllers {
+        println!("CGroup v2 controllers");
+        print_features(controllers);
+    }
+
+    if let Some(namespaces) = &info.namespaces {
+        match namespaces.enabled {
+            Some(true) => println!("{:<18}enabled", "Namespaces"),
+            Some(false) => println!("{:<18}disabled", "Namespaces"),
+            None => {}
+        }
+        print_features(&namespaces.features);
+    }
+
+    println!("Capabilities");
+    match &info.capabilities {
+        Some(capabilities) => {
+            for (name, status) in &capabilities.0 {
+                println!("{:<17} {}", name, status);
+            }
+        }
+        None => println!("<cannot find cap info>"),
+    }
+
+    print_edgetpu(&info.edgetpu)
+}
+
+fn print_features(features: &Features) {
+    for (name, status) in &features.0 {
+        println!("  {:<16}{}", name, status);
+    }
 }
 
-/// Print Kernel Release, Version and Architecture
-pub fn print_kernel() {
+fn print_edgetpu(edgetpu: &EdgeTpuInfo) -> Result<()> {
+    println!("Edge TPU");
+    for module in &edgetpu.modules {
+        let status = if module.loaded {
+            "loaded"
+        } else {
+            "not loaded"
+        };
+        println!("  {:<16}{}", module.name, status);
+    }
+
+    match &edgetpu.library {
+        Some(library) => {
+            println!("  {:<16}{}", "libedgetpu", library.path.display());
+            println!(
+                "    {:<14}{}",
+                "version",
+                library.version.as_deref().unwrap_or("unknown")
+            );
+            let frequency = library
+                .frequency
+                .map(|frequency| frequency.to_string())
+                .unwrap_or_else(|| "unknown".to_owned());
+            let variants: Vec<_> = library.variants.iter().map(|v| v.to_string()).collect();
+            if variants.is_empty() {
+                println!("    {:<14}{}", "frequency", frequency);
+            } else {
+                println!(
+                    "    {:<14}{} (available: {})",
+                    "frequency",
+                    frequency,
+                    variants.join(", ")
+                );
+            }
+        }
+        None => println!("  {:<16}not installed", "libedgetpu"),
+    }
+
+    if edgetpu.udev_rules.present {
+        println!(
+            "  {:<16}{}",
+            "udev rules",
+            edgetpu.udev_rules.path.display()
+        );
+    } else {
+        println!("  {:<16}missing", "udev rules");
+    }
+
+    if let Some(err) = &edgetpu.discovery_error {
+        println!("  {:<16}{}", "Devices", err);
+        return Ok(());
+    }
+    if edgetpu.devices.is_empty() {
+        println!("  {:<16}none", "Devices");
+        return Ok(());
+    }
+    println!("  Devices");
+    let mut tab_writer = TabWriter::new(io::stdout());
+    writeln!(
+        &mut tab_writer,
+        "    NAME\tTYPE\tPATH\tBUS\tSERIAL\tMODE\tOWNER\tACCESS"
+    )?;
+    for device in &edgetpu.devices {
+        let (mode, owner, access) = match &device.node {
+            Some(node) => (
+                node.mode.clone(),
+                format!("{}:{}", node.owner, node.group),
+                access(node),
+            ),
+            None => ("missing".to_owned(), "-".to_owned(), "none"),
+        };
+        writeln!(
+            &mut tab_writer,
+            "    {}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            device.name,
+            device.kind,
+            device.path.display(),
+            device.bus,
+            device.serial.as_deref().unwrap_or("-"),
+            mode,
+            owner,
+            access
+        )?;
+    }
+    tab_writer.flush()?;
+
+    Ok(())
+}
+
+/// What the current user may do with a device node
+fn access(node: &NodeAccess) -> &'static str {
+    match (node.readable, node.writable) {
+        (true, true) => "read-write",
+        (true, false) => "read-only",
+        (false, true) => "write-only",
+        (false, false) => "none",
+    }
+}
+
+/// Kernel Release, Version and Architecture
+fn kernel() -> Kernel {
     let uname = nix::sys::utsname::uname().unwrap();
-    println!(
-        "{:<18}{}",
-        "Kernel-Release",
-        uname.release().to_string_lossy()
-    );
-    println!(
-        "{:<18}{}",
-        "Kernel-Version",
-        uname.version().to_string_lossy()
-    );
-    println!(
-        "{:<18}{}",
-        "Architecture",
-        uname.machine().to_string_lossy()
-    );
+    Kernel {
+        release: uname.release().to_string_lossy().into_owned(),
+        version: uname.version().to_string_lossy().into_owned(),
+        architecture: uname.machine().to_string_lossy().into_owned(),
+    }
 }
 
-/// Prints OS Distribution information
+/// OS Distribution information
 // see https://www.freedesktop.org/software/systemd/man/os-release.html
-pub fn print_os() {
-    if let Some(os) = try_read_os_from("/etc/os-release") {
-        println!("{:<18}{}", "Operating System", os);
-    } else if let Some(os) = try_read_os_from("/usr/lib/os-release") {
-        println!("{:<18}{}", "Operating System", os);
-    }
+fn os() -> Option<String> {
+    try_read_os_from("/etc/os-release").or_else(|| try_read_os_from("/usr/lib/os-release"))
 }
 
 /// Helper function to read the OS Distribution info
@@ -99,87 +324,63 @@ fn find_parameter<'a>(content: &'a str, param_name: &str) -> Option<&'a str> {
         .and_then(|l| l.split_terminator('=').last())
 }
 
-/// Print Hardware information of system
-pub fn print_hardware() {
-    if let Ok(cpu_info) = CpuInfo::current() {
-        println!("{:<18}{}", "Cores", cpu_info.num_cores());
-    }
-
-    if let Ok(mem_info) = Meminfo::current() {
-        println!(
-            "{:<18}{}",
-            "Total Memory",
-            mem_info.mem_total / u64::pow(1024, 2)
-        );
+/// cgroups info of system
+fn cgroups() -> Cgroups {
+    Cgroups {
+        setup: libcgroups::common::get_cgroup_setup()
+            .ok()
+            .map(|cgroup_setup| cgroup_setup.to_string()),
+        mounts: cgroup_mounts(),
+        #[cfg(feature = "v2")]
+        controllers: cgroup_v2_controllers(),
     }
 }
 
-/// Print cgroups info of system
-pub fn print_cgroups() {
-    print_cgroups_setup();
-    print_cgroup_mounts();
-    #[cfg(feature = "v2")]
-    print_cgroup_v2_controllers();
-}
-
-pub fn print_cgroups_setup() {
-    let cgroup_setup = libcgroups::common::get_cgroup_setup();
-    if let Ok(cgroup_setup) = &cgroup_setup {
-        println!("{:<18}{}", "Cgroup setup", cgroup_setup);
-    }
-}
-
-pub fn print_cgroup_mounts() {
-    println!("Cgroup mounts");
+fn cgroup_mounts() -> BTreeMap<String, PathBuf> {
+    #[allow(unused_mut)]
+    let mut mounts = BTreeMap::new();
     #[cfg(feature = "v1")]
     if let Ok(v1_mounts) = libcgroups::v1::util::list_supported_mount_points() {
-        let mut v1_mounts: Vec<String> = v1_mounts
-            .iter()
-            .map(|kv| format!("  {:<16}{}", kv.0.to_string(), kv.1.display()))
-            .collect();
-
-        v1_mounts.sort();
-        for cgroup_mount in v1_mounts {
-            println!("{cgroup_mount}");
+        for (controller, mount_point) in v1_mounts {
+            mounts.insert(controller.to_string(), mount_point);
         }
     }
 
     #[cfg(feature = "v2")]
     if let Ok(mount_point) = libcgroups::v2::util::get_unified_mount_point() {
-        println!("  {:<16}{}", "unified", mount_point.display());
+        mounts.insert("unified".to_owned(), mount_point);
     }
+
+    mounts
 }
 
 #[cfg(feature = "v2")]
-pub fn print_cgroup_v2_controllers() {
-    let cgroup_setup = libcgroups::common::get_cgroup_setup();
-    let unified = libcgroups::v2::util::get_unified_mount_point();
-
-    if let Ok(cgroup_setup) = cgroup_setup {
-        if let Ok(unified) = &unified {
-            if matches!(cgroup_setup, CgroupSetup::Hybrid | CgroupSetup::Unified) {
-                if let Ok(controllers) = libcgroups::v2::util::get_available_controllers(unified) {
-                    println!("CGroup v2 controllers");
-                    let active_controllers: HashSet<ControllerType> =
-                        controllers.into_iter().collect();
-                    for controller in libcgroups::v2::controller_type::CONTROLLER_TYPES {
-                        let status = if active_controllers.contains(controller) {
-                            "attached"
-                        } else {
-                            "detached"
-                        };
-
-                        println!("  {:<16}{}", controller.to_string(), status);
-                    }
-                }
-
-                if let Some(config) = read_kernel_config() {
-                    let display = FeatureDisplay::with_status("device", "attached", "detached");
-                    print_feature_status(&config, "CONFIG_CGROUP_BPF", display);
-                }
-            }
+fn cgroup_v2_controllers() -> Option<Features> {
+    let cgroup_setup = libcgroups::common::get_cgroup_setup().ok()?;
+    let unified = libcgroups::v2::util::get_unified_mount_point().ok()?;
+    if !matches!(cgroup_setup, CgroupSetup::Hybrid | CgroupSetup::Unified) {
+        return None;
+    }
+
+    let mut features = Features::default();
+    if let Ok(controllers) = libcgroups::v2::util::get_available_controllers(&unified) {
+        let active_controllers: HashSet<ControllerType> = controllers.into_iter().collect();
+        for controller in libcgroups::v2::controller_type::CONTROLLER_TYPES {
+            let status = if active_controllers.contains(controller) {
+                "attached"
+            } else {
+                "detached"
+            };
+
+            features.push(controller.to_string(), status);
         }
     }
+
+    if let Some(config) = read_kernel_config() {
+        let display = FeatureDisplay::with_status("device", "attached", "detached");
+        feature_status(&mut features, &config, "CONFIG_CGROUP_BPF", display);
+    }
+    Some(features)
 }
 
 fn read_kernel_config() -> Option<String> {
@@ -195,33 +396,55 @@ fn read_kernel_config() -> Option<String> {
     fs::read_to_string(kernel_config).ok()
 }
 
-pub fn print_namespaces() {
-    if let Some(content) = read_kernel_config() {
-        if let Some(ns_enabled) = find_parameter(&content, "CONFIG_NAMESPACES") {
-            if ns_enabled == "y" {
-                println!("{:<18}enabled", "Namespaces");
-            } else {
-                println!("{:<18}disabled", "Namespaces");
-                return;
-            }
-        }
+fn namespaces() -> Option<Namespaces> {
+    let content = read_kernel_config()?;
+    let enabled = find_parameter(&content, "CONFIG_NAMESPACES").map(|flag| flag == "y");
+    let mut features = Features::default();
+    if enabled == Some(false) {
+        return Some(Namespaces { enabled, features });
+    }
 
-        // mount namespace is always enabled if namespaces are enabled
-        println!("  {:<16}enabled", "mount");
-        print_feature_status(&content, "CONFIG_UTS_NS", FeatureDisplay::new("uts"));
-        print_feature_status(&content, "CONFIG_IPC_NS", FeatureDisplay::new("ipc"));
+    // mount namespace is always enabled if namespaces are enabled
+    features.push("mount", "enabled");
+    feature_status(
+        &mut features,
+        &content,
+        "CONFIG_UTS_NS",
+        FeatureDisplay::new("uts"),
+    );
+    feature_status(
+        &mut features,
+        &content,
+        "CONFIG_IPC_NS",
+        FeatureDisplay::new("ipc"),
+    );
 
-        let user_display = match user_ns::unprivileged_user_ns_enabled() {
-            Ok(false) => FeatureDisplay::with_status("user", "enabled (root only)", "disabled"),
-            _ => FeatureDisplay::new("user"),
-        };
-        print_feature_status(&content, "CONFIG_USER_NS", user_display);
-        print_feature_status(&content, "CONFIG_PID_NS", FeatureDisplay::new("pid"));
-        print_feature_status(&content, "CONFIG_NET_NS", FeatureDisplay::new("network"));
-        // While the CONFIG_CGROUP_NS kernel feature exists, it is obsolete and should not be used. CGroup namespaces
-        // are instead enabled with CONFIG_CGROUPS.
-        print_feature_status(&content, "CONFIG_CGROUPS", FeatureDisplay::new("cgroup"))
-    }
+    let user_display = match user_ns::unprivileged_user_ns_enabled() {
+        Ok(false) => FeatureDisplay::with_status("user", "enabled (root only)", "disabled"),
+        _ => FeatureDisplay::new("user"),
+    };
+    feature_status(&mut features, &content, "CONFIG_USER_NS", user_display);
+    feature_status(
+        &mut features,
+        &content,
+        "CONFIG_PID_NS",
+        FeatureDisplay::new("pid"),
+    );
+    feature_status(
+        &mut features,
+        &content,
+        "CONFIG_NET_NS",
+        FeatureDisplay::new("network"),
+    );
+    // While the CONFIG_CGROUP_NS kernel feature exists, it is obsolete and should not be used. CGroup namespaces
+    // are instead enabled with CONFIG_CGROUPS.
+    feature_status(
+        &mut features,
+        &content,
+        "CONFIG_CGROUPS",
+        FeatureDisplay::new("cgroup"),
+    );
+    Some(Namespaces { enabled, features })
 }
 
 #[inline]
@@ -233,51 +456,35 @@ fn is_cap_available(caps: &caps::CapsHashSet, cap: caps::Capability) -> &'static
     }
 }
 
-pub fn print_capabilities() {
-    println!("Capabilities");
-    if let Ok(current) = caps::read(None, caps::CapSet::Bounding) {
-        println!(
-            "{:<17} {}",
-            "CAP_BPF",
-            is_cap_available(&current, caps::Capability::CAP_BPF)
-        );
-        println!(
-            "{:<17} {}",
-            "CAP_PERFMON",
-            is_cap_available(&current, caps::Capability::CAP_PERFMON)
-        );
-        println!(
-            "{:<17} {}",
-            "CAP_CHECKPOINT_RESTORE",
-            is_cap_available(&current, caps::Capability::CAP_CHECKPOINT_RESTORE)
-        );
-    } else {
-        println!("<cannot find cap info>");
+fn capabilities() -> Option<Features> {
+    let current = caps::read(None, caps::CapSet::Bounding).ok()?;
+    let mut features = Features::default();
+    for cap in [
+        caps::Capability::CAP_BPF,
+        caps::Capability::CAP_PERFMON,
+        caps::Capability::CAP_CHECKPOINT_RESTORE,
+    ] {
+        features.push(cap.to_string(), is_cap_available(&current, cap));
     }
+    Some(features)
 }
 
-fn print_feature_status(config: &str, feature: &str, display: FeatureDisplay) {
-    if let Some(status_flag) = find_parameter(config, feature) {
-        let status = if status_flag == "y" {
-            display.enabled
-        } else {
-            display.disabled
-        };
-
-        println!("  {:<16}{}", display.name, status);
-    } else {
-        println!("  {:<16}{}", display.name, display.disabled);
-    }
+fn feature_status(features: &mut Features, config: &str, feature: &str, display: FeatureDisplay) {
+    let status = match find_parameter(config, feature) {
+        Some("y") => display.enabled,
+        _ => display.disabled,
+    };
+    features.push(display.name, status);
 }
 
-struct FeatureDisplay<'a> {
-    name: &'a str,
-    enabled: &'a str,
-    disabled: &'a str,
+struct FeatureDisplay {
+    name: &'static str,
+    enabled: &'static str,
+    disabled: &'static str,
 }
 
-impl<'a> FeatureDisplay<'a> {
-    fn new(name: &'a str) -> Self {
+impl FeatureDisplay {
+    fn new(name: &'static str) -> Self {
         Self {
             name,
             enabled: "enabled",
@@ -285,7 +492,7 @@ impl<'a> FeatureDisplay<'a> {
         }
     }
 
-    fn with_status(name: &'a str, enabled: &'a str, disabled: &'a str) -> Self {
+    fn with_status(name: &'static str, enabled: &'static str, disabled: &'static str) -> Self {
         Self {
             name,
             enabled,
@@ -0,0 +1,414 @@
+//! Contains the metrics exporter command
+//!
+//! Besides the device metrics of [`DeviceCollector`], the exporter reports the containers of
+//! the root directory, labelled by container id. The statistics come from the cgroup of the
+//! containers that are running or paused, the times are in seconds whatever the cgroup
+//! version. Limits are left out when the container has none.
+//!
+//! | Metric | Type | Labels | Description |
+//! |---|---|---|---|
+//! | `tpu_runtime_container_status` | gauge | `container`, `status` | 1 for the current status, 0 for the others |
+//! | `tpu_runtime_container_cpu_usage_seconds_total` | counter | `container` | CPU time consumed |
+//! | `tpu_runtime_container_cpu_user_seconds_total` | counter | `container` | CPU time consumed in user mode |
+//! | `tpu_runtime_container_cpu_system_seconds_total` | counter | `container` | CPU time consumed in kernel mode |
+//! | `tpu_runtime_container_cpu_periods_total` | counter | `container` | Enforcement periods of the CPU quota |
+//! | `tpu_runtime_container_cpu_throttled_periods_total` | counter | `container` | Periods the container was throttled in |
+//! | `tpu_runtime_container_cpu_throttled_seconds_total` | counter | `container` | Time the container was throttled for |
+//! | `tpu_runtime_container_memory_usage_bytes` | gauge | `container` | Memory used |
+//! | `tpu_runtime_container_memory_max_usage_bytes` | gauge | `container` | Peak of the memory used |
+//! | `tpu_runtime_container_memory_limit_bytes` | gauge | `container` | Memory limit |
+//! | `tpu_runtime_container_memory_failures_total` | counter | `container` | Times the memory limit was hit |
+//! | `tpu_runtime_container_memory_swap_usage_bytes` | gauge | `container` | Memory and swap used |
+//! | `tpu_runtime_container_memory_cache_bytes` | gauge | `container` | Page cache used |
+//! | `tpu_runtime_container_pressure_ratio` | gauge | `container`, `resource`, `kind`, `window` | Pressure stall information: share of time stalled on `cpu`, `memory` or `io`, by `some` or `full` of the tasks, averaged over `10s`, `60s` or `300s` |
+//! | `tpu_runtime_container_pids` | gauge | `container` | Tasks in the container |
+//! | `tpu_runtime_container_pids_limit` | gauge | `container` | Task limit |
+//! | `tpu_runtime_container_blkio_bytes_total` | counter | `container`, `major`, `minor`, `op` | Bytes transferred to and from the block devices |
+//! | `tpu_runtime_container_blkio_ios_total` | counter | `container`, `major`, `minor`, `op` | I/O operations on the block devices |
+//! | `tpu_runtime_container_hugetlb_usage_bytes` | gauge | `container`, `size` | Huge pages used |
+//! | `tpu_runtime_container_hugetlb_max_usage_bytes` | gauge | `container`, `size` | Peak of the huge pages used |
+//! | `tpu_runtime_container_hugetlb_failures_total` | counter | `container`, `size` | Times the huge page limit was hit |
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use edgetpu::discovery::{Discovery, DEFAULT_SYSFS_ROOT};
+use edgetpu::metrics::{Collector, DeviceCollector, MetricFamily, MetricsServer};
+use libcgroups::stats::{BlkioDeviceStat, PSIStats, Stats};
+use libcontainer::container::events::cgroup_stats;
+use libcontainer::container::state::State;
+use libcontainer::container::{Container, ContainerStatus};
+
+const STATUSES: [ContainerStatus; 5] = [
+    ContainerStatus::Creating,
+    ContainerStatus::Created,
+    ContainerStatus::Running,
+    ContainerStatus::Stopped,
+    ContainerStatus::Paused,
+];
+
+/// Export Prometheus metrics of the containers and the Edge TPUs
+#[derive(Parser, Debug)]
+pub struct Metrics {
+    #[clap(subcommand)]
+    pub command: MetricsCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MetricsCommand {
+    Serve(Serve),
+}
+
+/// Serve the metrics over HTTP at /metrics
+#[derive(Parser, Debug)]
+pub struct Serve {
+    /// TCP address to listen on
+    #[clap(long, default_value = "127.0.0.1:9400")]
+    pub listen: String,
+    /// Listen on this unix socket instead of a TCP address
+    #[clap(long, conflicts_with = "listen")]
+    pub unix_socket: Option<PathBuf>,
+    /// Directory sysfs is mounted on
+    #[clap(long, default_value = DEFAULT_SYSFS_ROOT)]
+    pub sysfs_root: PathBuf,
+}
+
+pub fn metrics(args: Metrics, root_path: PathBuf) -> Result<()> {
+    match args.command {
+        MetricsCommand::Serve(serve) => serve_metrics(serve, root_path),
+    }
+}
+
+fn serve_metrics(args: Serve, root_path: PathBuf) -> Result<()> {
+    let server = match &args.unix_socket {
+        Some(path) => MetricsServer::bind_unix(path)?,
+        None => MetricsServer::bind_tcp(args.listen.as_str())?,
+    };
+    let mut server = server
+        .with_collector(ContainerCollector {
+            root_path: root_path.clone(),
+        })
+        .with_collector(
+            DeviceCollector::new(&root_path)
+                .with_discovery(Discovery::new().with_sysfs_root(&args.sysfs_root)),
+        );
+    match &args.unix_socket {
+        Some(path) => tracing::info!(path = ?path, "serving metrics"),
+        None => tracing::info!(address = args.listen, "serving metrics"),
+    }
+    server.serve().context("failed to serve the metrics")
+}
+
+/// Collects the metrics of the containers of the root directory.
+struct ContainerCollector {
+    root_path: PathBuf,
+}
+
+impl ContainerCollector {
+    fn containers(&self) -> Result<Vec<Container>> {
+        let mut containers = Vec::new();
+        for container_dir in fs::read_dir(&self.root_path)? {
+            let container_dir = container_dir?.path();
+            if !State::file_path(&container_dir).exists() {
+                continue;
+            }
+            match Container::load(container_dir.clone()) {
+                Ok(container) => containers.push(container),
+                // deleted meanwhile
+                Err(err) => tracing::debug!(?err, ?container_dir, "failed to load a container"),
+            }
+        }
+        Ok(containers)
+    }
+}
+
+impl Collector for ContainerCollector {
+    fn collect(&mut self) -> Vec<MetricFamily> {
+        let mut families = ContainerFamilies::new();
+        let containers = self.containers().unwrap_or_else(|err| {
+            tracing::warn!(?err, "failed to list the containers");
+            Vec::new()
+        });
+        for mut container in containers {
+            if let Err(err) = container.refresh_status() {
+                tracing::debug!(?err, id = container.id(), "failed to refresh the status");
+            }
+            let id = container.id().to_owned();
+            let status = container.status();
+            for candidate in STATUSES {
+                families.status.push(
+                    vec![
+                        ("container", id.clone()),
+                        ("status", candidate.to_string().to_lowercase()),
+                    ],
+                    if candidate == status { 1.0 } else { 0.0 },
+                );
+            }
+            if !matches!(status, ContainerStatus::Running | ContainerStatus::Paused) {
+                continue;
+            }
+            match container_stats(&container) {
+                Ok(stats) => families.add(&id, &stats),
+                Err(err) => tracing::warn!(?err, id, "failed to read the container statistics"),
+            }
+        }
+        families.into_vec()
+    }
+}
+
+fn container_stats(container: &Container) -> Result<Stats> {
+    let cgroup_manager =
+        libcgroups::common::create_cgroup_manager(libcgroups::common::CgroupConfig {
+            cgroup_path: container.spec()?.cgroup_path,
+            systemd_cgroup: container.systemd(),
+            container_name: container.id().to_string(),
+        })?;
+    Ok(cgroup_stats(&cgroup_manager)?)
+}
+
+struct ContainerFamilies {
+    status: MetricFamily,
+    cpu_usage: MetricFamily,
+    cpu_user: MetricFamily,
+    cpu_system: MetricFamily,
+    cpu_periods: MetricFamily,
+    cpu_throttled_periods: MetricFamily,
+    cpu_throttled: MetricFamily,
+    memory_usage: MetricFamily,
+    memory_max_usage: MetricFamily,
+    memory_limit: MetricFamily,
+    memory_failures: MetricFamily,
+    memory_swap_usage: MetricFamily,
+    memory_cache: MetricFamily,
+    pressure: MetricFamily,
+    pids: MetricFamily,
+    pids_limit: MetricFamily,
+    blkio_bytes: MetricFamily,
+    blkio_ios: MetricFamily,
+    hugetlb_usage: MetricFamily,
+    hugetlb_max_usage: MetricFamily,
+    hugetlb_failures: MetricFamily,
+}
+
+impl ContainerFamilies {
+    fn new() -> Self {
+        let gauge = |name: &str, help: &str| {
+            MetricFamily::gauge(format!("tpu_runtime_container_{name}"), help)
+        };
+        let counter = |name: &str, help: &str| {
+            MetricFamily::counter(format!("tpu_runtime_container_{name}"), help)
+        };
+        Self {
+            status: gauge("status", "Status of the container"),
+            cpu_usage: counter("cpu_usage_seconds", "CPU time consumed"),
+            cpu_user: counter("cpu_user_seconds", "CPU time consumed in user mode"),
+            cpu_system: counter("cpu_system_seconds", "CPU time consumed in kernel mode"),
+            cpu_periods: counter("cpu_periods", "Enforcement periods of the CPU quota"),
+            cpu_throttled_periods: counter(
+                "cpu_throttled_periods",
+                "Periods the container was throttled in",
+            ),
+            cpu_throttled: counter(
+                "cpu_throttled_seconds",
+                "Time the container was throttled for",
+            ),
+            memory_usage: gauge("memory_usage_bytes", "Memory used"),
+            memory_max_usage: gauge("memory_max_usage_bytes", "Peak of the memory used"),
+            memory_limit: gauge("memory_limit_bytes", "Memory limit"),
+            memory_failures: counter("memory_failures", "Times the memory limit was hit"),
+            memory_swap_usage: gauge("memory_swap_usage_bytes", "Memory and swap used"),
+            memory_cache: gauge("memory_cache_bytes", "Page cache used"),
+            pressure: gauge(
+                "pressure_ratio",
+                "Share of the time the tasks were stalled on a resource",
+            ),
+            pids: gauge("pids", "Tasks in the container"),
+            pids_limit: gauge("pids_limit", "Task limit"),
+            blkio_bytes: counter(
+                "blkio_bytes",
+                "Bytes transferred to and from the block devices",
+            ),
+            blkio_ios: counter("blkio_ios", "I/O operations on the block devices"),
+            hugetlb_usage: gauge("hugetlb_usage_bytes", "Huge pages used"),
+            hugetlb_max_usage: gauge("hugetlb_max_usage_bytes", "Peak of the huge pages used"),
+            hugetlb_failures: counter("hugetlb_failures", "Times the huge page limit was hit"),
+        }
+    }
+
+    fn add(&mut self, id: &str, stats: &Stats) {
+        let labels = || vec![("container", id.to_owned())];
+        let seconds = |nanoseconds: u64| nanoseconds as f64 / 1e9;
+
+        let cpu = &stats.cpu;
+        self.cpu_usage
+            .push(labels(), seconds(cpu.usage.usage_total));
+        self.cpu_user.push(labels(), seconds(cpu.usage.usage_user));
+        self.cpu_system
+            .push(labels(), seconds(cpu.usage.usage_kernel));
+        self.cpu_periods
+            .push(labels(), cpu.throttling.periods as f64);
+        self.cpu_throttled_periods
+            .push(labels(), cpu.throttling.throttled_periods as f64);
+        self.cpu_throttled
+            .push(labels(), seconds(cpu.throttling.throttled_time));
+
+        let memory = &stats.memory;
+        self.memory_usage.push(labels(), memory.memory.usage as f64);
+        self.memory_max_usage
+            .push(labels(), memory.memory.max_usage as f64);
+        if is_limited(memory.memory.limit) {
+            self.memory_limit.push(labels(), memory.memory.limit as f64);
+        }
+        self.memory_failures
+            .push(labels(), memory.memory.fail_count as f64);
+        self.memory_swap_usage
+            .push(labels(), memory.memswap.usage as f64);
+        self.memory_cache.push(labels(), memory.cache as f64);
+
+        for (resource, psi) in [
+            ("cpu", &cpu.psi),
+            ("memory", &memory.psi),
+            ("io", &stats.blkio.psi),
+        ] {
+            self.add_pressure(id, resource, psi);
+        }
+
+        self.pids.push(labels(), stats.pids.current as f64);
+        if is_limited(stats.pids.limit) {
+            self.pids_limit.push(labels(), stats.pids.limit as f64);
+        }
+
+        add_blkio(&mut self.blkio_bytes, id, &stats.blkio.service_bytes);
+        add_blkio(&mut self.blkio_ios, id, &stats.blkio.serviced);
+
+        let hugetlb: BTreeMap<_, _> = stats.hugetlb.iter().collect();
+        for (size, usage) in hugetlb {
+            let labels = || vec![("container", id.to_owned()), ("size", size.clone())];
+            self.hugetlb_usage.push(labels(), usage.usage as f64);
+            self.hugetlb_max_usage
+                .push(labels(), usage.max_usage as f64);
+            self.hugetlb_failures
+                .push(labels(), usage.fail_count as f64);
+        }
+    }
+
+    fn add_pressure(&mut self, id: &str, resource: &'static str, psi: &PSIStats) {
+        for (kind, data) in [("some", &psi.some), ("full", &psi.full)] {
+            for (window, percent) in [
+                ("10s", data.avg10),
+                ("60s", data.avg60),
+                ("300s", data.avg300),
+            ] {
+                self.pressure.push(
+                    vec![
+                        ("container", id.to_owned()),
+                        ("resource", resource.to_owned()),
+                        ("kind", kind.to_owned()),
+                        ("window", window.to_owned()),
+                    ],
+                    percent / 100.0,
+                );
+            }
+        }
+    }
+
+    fn into_vec(self) -> Vec<MetricFamily> {
+        vec![
+            self.status,
+            self.cpu_usage,
+            self.cpu_user,
+            self.cpu_system,
+            self.cpu_periods,
+            self.cpu_throttled_periods,
+            self.cpu_throttled,
+            self.memory_usage,
+            self.memory_max_usage,
+            self.memory_limit,
+            self.memory_failures,
+            self.memory_swap_usage,
+            self.memory_cache,
+            self.pressure,
+            self.pids,
+            self.pids_limit,
+            self.blkio_bytes,
+            self.blkio_ios,
+            self.hugetlb_usage,
+            self.hugetlb_max_usage,
+            self.hugetlb_failures,
+        ]
+    }
+}
+
+/// Whether a limit is set, `max` being read as `u64::MAX`
+fn is_limited(limit: u64) -> bool {
+    limit != 0 && limit != u64::MAX
+}
+
+fn add_blkio(family: &mut MetricFamily, id: &str, entries: &[BlkioDeviceStat]) {
+    for entry in entries {
+        family.push(
+            vec![
+                ("container", id.to_owned()),
+                ("major", entry.major.to_string()),
+                ("minor", entry.minor.to_string()),
+                (
+                    "op",
+                    entry.op_type.clone().unwrap_or_default().to_lowercase(),
+                ),
+            ],
+            entry.value as f64,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use edgetpu::metrics::{encode, Format};
+    use libcgroups::stats::HugeTlbStats;
+
+    use super::*;
+
+    #[test]
+    fn test_container_families() {
+        let mut stats = Stats::default();
+        stats.cpu.usage.usage_total = 1_500_000_000;
+        stats.cpu.throttling.throttled_periods = 3;
+        stats.memory.memory.usage = 4096;
+        stats.memory.memory.limit = u64::MAX;
+        stats.memory.psi.some.avg10 = 12.5;
+        stats.pids.current = 7;
+        stats.blkio.service_bytes.push(BlkioDeviceStat {
+            major: 8,
+            minor: 0,
+            op_type: Some("Read".to_owned()),
+            value: 512,
+        });
+        stats.hugetlb.insert(
+            "2MB".to_owned(),
+            HugeTlbStats {
+                usage: 2097152,
+                ..Default::default()
+            },
+        );
+
+        let mut families = ContainerFamilies::new();
+        families.add("camera", &stats);
+        let text = encode(&families.into_vec(), Format::Prometheus);
+        for line in [
+            r#"tpu_runtime_container_cpu_usage_seconds_total{container="camera"} 1.5"#,
+            r#"tpu_runtime_container_cpu_throttled_periods_total{container="camera"} 3"#,
+            r#"tpu_runtime_container_memory_usage_bytes{container="camera"} 4096"#,
+            r#"tpu_runtime_container_pressure_ratio{container="camera",resource="memory",kind="some",window="10s"} 0.125"#,
+            r#"tpu_runtime_container_pids{container="camera"} 7"#,
+            r#"tpu_runtime_container_blkio_bytes_total{container="camera",major="8",minor="0",op="read"} 512"#,
+            r#"tpu_runtime_container_hugetlb_usage_bytes{container="camera",size="2MB"} 2097152"#,
+        ] {
+            assert!(text.contains(&format!("{line}\n")), "{line} not in\n{text}");
+        }
+        // unlimited
+        assert!(!text.contains("tpu_runtime_container_memory_limit_bytes"));
+        assert!(!text.contains("tpu_runtime_container_pids_limit"));
+    }
+}
@@ -2,9 +2,15 @@ use std::fs;
 use std::path::{Path, PathBuf};
 
 use anyhow::{bail, Context, Result};
+use edgetpu::health::HealthLedger;
+use edgetpu::image::frequency::{FrequencyPolicy, DEFAULT_POLICY_PATH};
+use edgetpu::image::HostLibraries;
+use edgetpu::injection::EdgeTpuInjector;
 use libcgroups::common::AnyCgroupManager;
 use libcontainer::container::Container;
+use libcontainer::lease::LeaseLedger;
 
+pub mod cdi;
 pub mod checkpoint;
 pub mod completion;
 pub mod create;
@@ -12,9 +18,12 @@ pub mod delete;
 pub mod events;
 pub mod exec;
 pub mod features;
+pub mod health;
 pub mod info;
 pub mod kill;
 pub mod list;
+pub mod metrics;
+pub mod model;
 pub mod pause;
 pub mod ps;
 pub mod resume;
@@ -22,7 +31,9 @@ pub mod run;
 pub mod spec_json;
 pub mod start;
 pub mod state;
+pub mod tpu;
 pub mod update;
+pub mod usb_follow;
 
 fn construct_container_root<P: AsRef<Path>>(root_path: P, container_id: &str) -> Result<PathBuf> {
     // resolves relative paths, symbolic links etc. and get complete path
@@ -65,3 +76,13 @@ fn create_cgroup_manager<P: AsRef<Path>>(
         },
     )?)
 }
+
+/// Injector of the Edge TPUs a new container requests, leased to it in the root directory.
+/// TPUs quarantined by `health monitor` are refused.
+fn edgetpu_injector(root_path: &Path, container_id: &str) -> Result<EdgeTpuInjector> {
+    let policy = FrequencyPolicy::load(DEFAULT_POLICY_PATH)?;
+    Ok(EdgeTpuInjector::new()
+        .with_leases(LeaseLedger::new(root_path), container_id)
+        .with_health(HealthLedger::new(root_path))
+        .with_host_libraries(HostLibraries::new().with_frequency_policy(policy)))
+}
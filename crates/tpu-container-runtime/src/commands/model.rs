@@ -0,0 +1,165 @@
+//! Contains the Edge TPU model commands
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use edgetpu::model::{ModelInfo, TensorInfo};
+use tabwriter::TabWriter;
+
+/// Inspect models compiled for the Edge TPU
+#[derive(Parser, Debug)]
+pub struct Model {
+    #[clap(subcommand)]
+    pub command: ModelCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ModelCommand {
+    Inspect(Inspect),
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Format {
+    Table,
+    Json,
+}
+
+/// Show the Edge TPU operators and the tensors of a compiled `.tflite` model
+#[derive(Parser, Debug)]
+pub struct Inspect {
+    /// Model file, e.g. model/mobilenet_v2_1.0_224_quant_edgetpu.tflite
+    pub file: PathBuf,
+    #[clap(long, value_enum, default_value = "table")]
+    pub format: Format,
+}
+
+pub fn model(args: Model) -> Result<()> {
+    match args.command {
+        ModelCommand::Inspect(inspect) => inspect_model(inspect),
+    }
+}
+
+fn inspect_model(args: Inspect) -> Result<()> {
+    let model = ModelInfo::load(&args.file)
+        .with_context(|| format!("failed to inspect {}", args.file.display()))?;
+
+    match args.format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(&model)?),
+        Format::Table => print_table(&model)?,
+    }
+
+    Ok(())
+}
+
+fn print_table(model: &ModelInfo) -> Result<()> {
+    let mut tab_writer = TabWriter::new(io::stdout());
+    writeln!(&mut tab_writer, "Schema version:\t{}", model.schema_version)?;
+    if let Some(version) = model.min_runtime_version() {
+        writeln!(&mut tab_writer, "Minimum runtime version:\t{version}")?;
+    } else {
+        writeln!(
+            &mut tab_writer,
+            "Minimum runtime version:\tnot compiled for the Edge TPU"
+        )?;
+    }
+    writeln!(&mut tab_writer)?;
+
+    if !model.edgetpu_ops.is_empty() {
+        writeln!(
+            &mut tab_writer,
+            "SUBGRAPH\tOPERATOR\tCOMPILER\tRUNTIME\tCACHING TOKEN\tON-CHIP\tOFF-CHIP"
+        )?;
+        for op in &model.edgetpu_ops {
+            writeln!(
+                &mut tab_writer,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                op.subgraph_name
+                    .clone()
+                    .unwrap_or_else(|| op.subgraph.to_string()),
+                op.operator,
+                op.compiler_version.as_deref().unwrap_or("-"),
+                op.min_runtime_version,
+                op.parameter_caching_token
+                    .map(|token| format!("{token:#018x}"))
+                    .unwrap_or_else(|| "-".to_owned()),
+                format_bytes(op.on_chip_parameter_bytes),
+                format_bytes(op.off_chip_parameter_bytes),
+            )?;
+        }
+        writeln!(&mut tab_writer)?;
+    }
+
+    writeln!(
+        &mut tab_writer,
+        "OF\tDIRECTION\tNAME\tTYPE\tSHAPE\tSCALE\tZERO POINT"
+    )?;
+    let mut tensors: Vec<(String, &str, &TensorInfo)> = Vec::new();
+    tensors.extend(
+        model
+            .inputs
+            .iter()
+            .map(|tensor| ("model".to_owned(), "input", tensor)),
+    );
+    tensors.extend(
+        model
+            .outputs
+            .iter()
+            .map(|tensor| ("model".to_owned(), "output", tensor)),
+    );
+    for op in &model.edgetpu_ops {
+        let of = format!("op {}/{}", op.subgraph, op.operator);
+        tensors.extend(op.inputs.iter().map(|tensor| (of.clone(), "input", tensor)));
+        tensors.extend(
+            op.outputs
+                .iter()
+                .map(|tensor| (of.clone(), "output", tensor)),
+        );
+    }
+    for (of, direction, tensor) in tensors {
+        let (scale, zero_point) = match &tensor.quantization {
+            Some(quantization) => (join(&quantization.scale), join(&quantization.zero_point)),
+            None => ("-".to_owned(), "-".to_owned()),
+        };
+        let shape = tensor
+            .shape
+            .iter()
+            .map(|dim| dim.to_string())
+            .collect::<Vec<_>>()
+            .join("x");
+        writeln!(
+            &mut tab_writer,
+            "{of}\t{direction}\t{}\t{}\t{shape}\t{scale}\t{zero_point}",
+            tensor.name.as_deref().unwrap_or("-"),
+            tensor.tensor_type,
+        )?;
+    }
+    tab_writer.flush()?;
+
+    Ok(())
+}
+
+/// Joins per-channel parameters, abbreviated past a few channels
+fn join<T: ToString>(values: &[T]) -> String {
+    const SHOWN: usize = 4;
+    let mut joined: Vec<String> = values.iter().take(SHOWN).map(T::to_string).collect();
+    if values.len() > SHOWN {
+        joined.push(format!("... ({} channels)", values.len()));
+    }
+    joined.join(",")
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes}B")
+    } else {
+        format!("{value:.2}{}", UNITS[unit])
+    }
+}
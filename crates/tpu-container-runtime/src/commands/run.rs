@@ -1,6 +1,8 @@
 use std::path::PathBuf;
 
 use anyhow::{Context, Result};
+use edgetpu::cdi::CdiInjector;
+use edgetpu::model::validate::ModelValidator;
 use libcontainer::container::builder::ContainerBuilder;
 use libcontainer::syscall::syscall::SyscallType;
 use liboci_cli::Run;
@@ -9,6 +11,7 @@ use nix::sys::signalfd::SigSet;
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
 
+use crate::commands::{edgetpu_injector, usb_follow};
 use crate::workload::executor::default_executor;
 
 pub fn run(args: Run, root_path: PathBuf, systemd_cgroup: bool) -> Result<i32> {
@@ -16,14 +19,24 @@ pub fn run(args: Run, root_path: PathBuf, systemd_cgroup: bool) -> Result<i32> {
         .with_executor(default_executor())
         .with_pid_file(args.pid_file.as_ref())?
         .with_console_socket(args.console_socket.as_ref())
-        .with_root_path(root_path)?
+        .with_root_path(root_path.clone())?
         .with_preserved_fds(args.preserve_fds)
         .validate_id()?
         .as_init(&args.bundle)
+        .with_spec_modifier(edgetpu_injector(&root_path, &args.container_id)?)
+        .with_spec_modifier(CdiInjector::new())
+        .with_spec_modifier(ModelValidator::new())
         .with_systemd(systemd_cgroup)
         .with_detach(args.detach)
         .build()?;
 
+    if let Err(err) = usb_follow::spawn(&root_path, &args.container_id) {
+        tracing::warn!(
+            ?err,
+            "USB Edge TPUs will not be followed when they re-enumerate"
+        );
+    }
+
     container
         .start()
         .with_context(|| format!("failed to start container {}", args.container_id))?;
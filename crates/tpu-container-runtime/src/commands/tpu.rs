@@ -0,0 +1,308 @@
+//! Contains the Edge TPU operator commands
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use edgetpu::discovery::{Discovery, EdgeTpuDevice, DEFAULT_SYSFS_ROOT};
+use edgetpu::events::{Event, EventLog};
+use edgetpu::health::{DeviceHealth, HealthLedger};
+use edgetpu::holders::{Holder, HolderScanner, DEFAULT_PROCFS_ROOT};
+use edgetpu::image::frequency::{FrequencyPolicy, DEFAULT_POLICY_PATH};
+use edgetpu::info::DeviceInfo;
+use edgetpu::injection::DeviceRequest;
+use edgetpu::reset::{DeviceReset, RESET_EVENT};
+use edgetpu::thermal::{ApexTelemetry, ThermalGovernor};
+use libcontainer::container::state::State;
+use libcontainer::container::Container;
+use libcontainer::lease::{Lease, LeaseLedger};
+use serde::Serialize;
+use tabwriter::TabWriter;
+
+/// Inspect and manage the Edge TPUs of this host
+#[derive(Parser, Debug)]
+pub struct Tpu {
+    /// Directory sysfs is mounted on
+    #[clap(long, global = true, default_value = DEFAULT_SYSFS_ROOT)]
+    pub sysfs_root: PathBuf,
+    /// Directory procfs is mounted on
+    #[clap(long, global = true, default_value = DEFAULT_PROCFS_ROOT)]
+    pub procfs_root: PathBuf,
+    #[clap(subcommand)]
+    pub command: TpuCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TpuCommand {
+    List(List),
+    Inspect(Inspect),
+    Holders(Holders),
+    Reset(Reset),
+    Thermal(Thermal),
+}
+
+/// List the Edge TPUs with their health and leases
+#[derive(Parser, Debug)]
+pub struct List {}
+
+/// Show everything known about an Edge TPU as JSON
+#[derive(Parser, Debug)]
+pub struct Inspect {
+    /// Device name (apex_0, usb-2-1), serial number or index
+    pub device: String,
+}
+
+/// Show the processes, and their containers, holding an Edge TPU open
+#[derive(Parser, Debug)]
+pub struct Holders {
+    /// Device name (apex_0, usb-2-1), serial number or index
+    pub device: String,
+}
+
+/// Reset an Edge TPU by unbinding it from its driver and binding it again
+#[derive(Parser, Debug)]
+pub struct Reset {
+    /// Device name (apex_0, usb-2-1), serial number or index
+    pub device: String,
+    /// Seconds to wait for the device to come back
+    #[clap(long, default_value_t = 10)]
+    pub timeout: u64,
+    /// Reset the device even if processes hold it open
+    #[clap(long)]
+    pub force: bool,
+}
+
+/// Pause the containers of the Edge TPUs reaching the critical temperature of the frequency
+/// policy, and resume them once the devices cooled down
+#[derive(Parser, Debug)]
+pub struct Thermal {
+    /// Seconds between two temperature readings
+    #[clap(long, default_value_t = 5)]
+    pub interval: u64,
+    /// Frequency policy setting the thermal limit
+    #[clap(long, default_value = DEFAULT_POLICY_PATH)]
+    pub policy: PathBuf,
+    /// Read the temperatures once and exit
+    #[clap(long)]
+    pub once: bool,
+}
+
+/// What `tpu inspect` prints
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DeviceState {
+    #[serde(flatten)]
+    device: DeviceInfo,
+    major: u32,
+    minor: u32,
+    health: Option<DeviceHealth>,
+    thermal: Option<ApexTelemetry>,
+    leases: Vec<Lease>,
+    holders: Vec<Holder>,
+}
+
+pub fn tpu(args: Tpu, root_path: PathBuf) -> Result<()> {
+    let discovery = Discovery::new().with_sysfs_root(&args.sysfs_root);
+    match args.command {
+        TpuCommand::List(_) => list_devices(&discovery, &root_path),
+        TpuCommand::Inspect(inspect) => {
+            let device = find_device(&discovery, &inspect.device)?;
+            inspect_device(&device, &discovery, &args.procfs_root, &root_path)
+        }
+        TpuCommand::Holders(holders) => {
+            let device = find_device(&discovery, &holders.device)?;
+            print_holders(&device, &args.procfs_root, &root_path)
+        }
+        TpuCommand::Reset(reset) => {
+            let device = find_device(&discovery, &reset.device)?;
+            reset_device(reset, device, discovery, &args.procfs_root, &root_path)
+        }
+        TpuCommand::Thermal(thermal) => run_governor(thermal, discovery, root_path),
+    }
+}
+
+fn list_devices(discovery: &Discovery, root_path: &Path) -> Result<()> {
+    let devices = discovery.discover()?;
+    let health = HealthLedger::new(root_path).devices()?;
+    let leases = LeaseLedger::new(root_path).leases()?;
+
+    let mut tab_writer = TabWriter::new(io::stdout());
+    writeln!(
+        &mut tab_writer,
+        "NAME\tTYPE\tPATH\tBUS\tSERIAL\tHEALTH\tLEASED BY"
+    )?;
+    for device in devices {
+        let info = DeviceInfo::new(&device);
+        let status = health
+            .get(&info.name)
+            .map(|health| health.status.to_string())
+            .unwrap_or_else(|| "-".to_owned());
+        let holders: Vec<_> = leases
+            .iter()
+            .filter(|lease| lease.device == info.name)
+            .map(|lease| lease.container_id.as_str())
+            .collect();
+        writeln!(
+            &mut tab_writer,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            info.name,
+            info.kind,
+            info.path.display(),
+            info.bus,
+            info.serial.as_deref().unwrap_or("-"),
+            status,
+            if holders.is_empty() {
+                "-".to_owned()
+            } else {
+                holders.join(",")
+            }
+        )?;
+    }
+    tab_writer.flush()?;
+
+    Ok(())
+}
+
+fn inspect_device(
+    device: &EdgeTpuDevice,
+    discovery: &Discovery,
+    procfs_root: &Path,
+    root_path: &Path,
+) -> Result<()> {
+    let name = device.name();
+    let state = DeviceState {
+        device: DeviceInfo::new(device),
+        major: device.major,
+        minor: device.minor,
+        health: HealthLedger::new(root_path).devices()?.remove(&name),
+        thermal: ApexTelemetry::read(discovery.sysfs_root(), &name),
+        leases: LeaseLedger::new(root_path)
+            .leases()?
+            .into_iter()
+            .filter(|lease| lease.device == name)
+            .collect(),
+        holders: holder_scanner(procfs_root, root_path)?.scan(device)?,
+    };
+    println!("{}", serde_json::to_string_pretty(&state)?);
+
+    Ok(())
+}
+
+fn print_holders(device: &EdgeTpuDevice, procfs_root: &Path, root_path: &Path) -> Result<()> {
+    let holders = holder_scanner(procfs_root, root_path)?.scan(device)?;
+
+    let mut tab_writer = TabWriter::new(io::stdout());
+    writeln!(&mut tab_writer, "PID\tCOMMAND\tCONTAINER\tFDS\tCGROUP")?;
+    for holder in holders {
+        let fds: Vec<_> = holder.fds.iter().map(|fd| fd.to_string()).collect();
+        writeln!(
+            &mut tab_writer,
+            "{}\t{}\t{}\t{}\t{}",
+            holder.pid,
+            holder.command,
+            holder.container.as_deref().unwrap_or("-"),
+            fds.join(","),
+            holder.cgroup.as_deref().unwrap_or("-")
+        )?;
+    }
+    tab_writer.flush()?;
+
+    Ok(())
+}
+
+fn reset_device(
+    args: Reset,
+    device: EdgeTpuDevice,
+    discovery: Discovery,
+    procfs_root: &Path,
+    root_path: &Path,
+) -> Result<()> {
+    let holders = holder_scanner(procfs_root, root_path)?.scan(&device)?;
+    if !holders.is_empty() && !args.force {
+        let pids: Vec<_> = holders.iter().map(|h| h.pid.to_string()).collect();
+        bail!(
+            "{} is held open by the processes {}, stop them or reset with --force",
+            device.name(),
+            pids.join(", ")
+        );
+    }
+
+    let reset = DeviceReset::new(discovery)
+        .with_timeout(Duration::from_secs(args.timeout))
+        .reset(&device)?;
+    EventLog::new(root_path).emit(&Event::new(
+        RESET_EVENT,
+        device.name(),
+        serde_json::json!({ "bus": device.bus_path, "name": reset.name() }),
+    ))?;
+    if reset.name() == device.name() {
+        println!("{} was reset", device.name());
+    } else {
+        println!("{} was reset and is now {}", device.name(), reset.name());
+    }
+
+    Ok(())
+}
+
+fn run_governor(args: Thermal, discovery: Discovery, root_path: PathBuf) -> Result<()> {
+    let policy = FrequencyPolicy::load(&args.policy)?;
+    let Some(limit) = policy.thermal() else {
+        bail!("{} sets no thermal limit", args.policy.display());
+    };
+    if limit.critical_temperature.is_none() {
+        tracing::warn!("no criticalTemperature is set, no container will be paused");
+    }
+    let governor = ThermalGovernor::new(root_path, limit.clone()).with_discovery(discovery);
+    if args.once {
+        governor.step()?;
+        return Ok(());
+    }
+
+    // the state is replaced atomically, the governor can be killed at any time
+    let interval = Duration::from_secs(args.interval.max(1));
+    loop {
+        if let Err(err) = governor.step() {
+            tracing::warn!(?err, "failed to apply the thermal limit");
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// Picks the one device the selector names.
+fn find_device(discovery: &Discovery, selector: &str) -> Result<EdgeTpuDevice> {
+    let devices = discovery.discover()?;
+    let request: DeviceRequest = selector.parse()?;
+    let mut selected = request.resolve(&devices)?;
+    if selected.len() != 1 {
+        bail!(
+            "{selector} selects {} Edge TPUs, select one",
+            selected.len()
+        );
+    }
+    Ok(selected.remove(0))
+}
+
+/// A scanner attributing the processes to the containers of the runtime root.
+fn holder_scanner(procfs_root: &Path, root_path: &Path) -> Result<HolderScanner> {
+    let mut scanner = HolderScanner::new().with_procfs_root(procfs_root);
+    let entries = fs::read_dir(root_path)
+        .with_context(|| format!("failed to read {}", root_path.display()))?;
+    for container_dir in entries {
+        let container_dir = container_dir?.path();
+        if !State::file_path(&container_dir).exists() {
+            continue;
+        }
+        let config = Container::load(container_dir.clone()).and_then(|c| {
+            let config = c.spec()?;
+            Ok((c.id().to_owned(), config.cgroup_path))
+        });
+        match config {
+            Ok((id, cgroup_path)) => scanner = scanner.with_container(id, cgroup_path),
+            Err(err) => tracing::warn!(?err, ?container_dir, "failed to load the container"),
+        }
+    }
+    Ok(scanner)
+}
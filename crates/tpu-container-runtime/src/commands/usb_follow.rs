@@ -0,0 +1,161 @@
+//! Keeps the USB Edge TPUs of a container usable when they re-enumerate
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use edgetpu::discovery::Discovery;
+use edgetpu::events::EventLog;
+use edgetpu::injection::edits::container_dev_path;
+use edgetpu::uevent::{Reenumeration, UEventSocket, UsbFollower};
+use libcgroups::common::{CgroupManager, ControllerOpt};
+use libcontainer::container::{Container, ContainerStatus};
+use libcontainer::lease::LeaseLedger;
+use libcontainer::oci_spec::runtime::LinuxResourcesBuilder;
+use nix::unistd::{self, ForkResult};
+
+use crate::commands::{container_exists, create_cgroup_manager, load_container};
+
+/// Prefix of the lease names of USB accelerators, followed by their port path
+const USB_LEASE_PREFIX: &str = "usb-";
+/// How often the state of the container is checked while no uevent comes
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Follow the USB Edge TPUs leased to a container when they re-enumerate
+#[derive(Parser, Debug)]
+#[clap(hide = true)]
+pub struct UsbFollow {
+    #[clap(value_parser = clap::builder::NonEmptyStringValueParser::new(), required = true)]
+    pub container_id: String,
+}
+
+/// Starts a detached `usb-follow` process if USB accelerators were leased to the container.
+pub fn spawn(root_path: &Path, container_id: &str) -> Result<()> {
+    if usb_ports(root_path, container_id)?.is_empty() {
+        return Ok(());
+    }
+
+    let mut command = Command::new("/proc/self/exe");
+    command
+        .arg("--root")
+        .arg(root_path)
+        .arg("usb-follow")
+        .arg(container_id)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // double fork: the intermediate child exits at once and is reaped below, the follower
+    // is reparented to init and does not linger as a zombie of the runtime
+    unsafe {
+        command.pre_exec(|| match unistd::fork()? {
+            ForkResult::Parent { .. } => nix::libc::_exit(0),
+            ForkResult::Child => unistd::setsid().map(drop).map_err(Into::into),
+        });
+    }
+    let mut child = command
+        .spawn()
+        .context("failed to start following the USB Edge TPUs")?;
+    child
+        .wait()
+        .context("failed to wait for the USB Edge TPU follower to detach")?;
+    tracing::debug!("following the USB Edge TPUs of the container");
+
+    Ok(())
+}
+
+pub fn usb_follow(args: UsbFollow, root_path: PathBuf) -> Result<()> {
+    // listen before looking at the devices, so that no re-enumeration is missed
+    let socket = UEventSocket::open()?;
+    socket.set_timeout(POLL_INTERVAL)?;
+
+    let ports = usb_ports(&root_path, &args.container_id)?;
+    let discovery = Discovery::new();
+    let mut follower = UsbFollower::new();
+    for device in discovery.usb_devices()? {
+        if ports.contains(&device.bus_path) {
+            let path = container_dev_path(&device, discovery.dev_root());
+            follower.follow(&device.bus_path, (device.major, device.minor), path);
+        }
+    }
+    if follower.is_empty() {
+        tracing::warn!("none of the leased USB Edge TPUs is plugged in");
+        return Ok(());
+    }
+
+    loop {
+        if !container_exists(&root_path, &args.container_id)? {
+            return Ok(());
+        }
+        let container = load_container(&root_path, &args.container_id)?;
+        if container.status() == ContainerStatus::Stopped {
+            return Ok(());
+        }
+
+        let event = match socket.recv() {
+            Ok(Some(event)) => event,
+            Ok(None) => continue,
+            Err(err) => {
+                tracing::warn!(?err, "skipping uevent");
+                continue;
+            }
+        };
+        if let Some(reenumeration) = follower.handle(&event) {
+            tracing::info!(
+                port = reenumeration.port,
+                "USB Edge TPU re-enumerated as {}",
+                reenumeration.dev_path.display()
+            );
+            if let Err(err) = expose(&root_path, &container, &reenumeration) {
+                tracing::error!(?err, "failed to expose the re-enumerated USB Edge TPU");
+            }
+            let event = reenumeration.to_event(&args.container_id);
+            if let Err(err) = EventLog::new(&root_path).emit(&event) {
+                tracing::warn!(?err, "failed to record the re-enumeration");
+            }
+        }
+    }
+}
+
+/// Creates the new device node in the container and allows it in the device cgroup
+fn expose(root_path: &Path, container: &Container, reenumeration: &Reenumeration) -> Result<()> {
+    let Some(pid) = container.pid() else {
+        bail!("container {} has no init process", container.id());
+    };
+    reenumeration.create_node(&PathBuf::from(format!("/proc/{pid}/root")))?;
+
+    let mut config = container.spec()?;
+    let mut rules = config.device_rules.take().unwrap_or_default();
+    reenumeration.update_rules(&mut rules)?;
+
+    // v1 rewrites devices.allow, v2 attaches a new eBPF program built from all the rules
+    let cmanager = create_cgroup_manager(root_path, container.id())?;
+    cmanager.apply(&ControllerOpt {
+        resources: &LinuxResourcesBuilder::default()
+            .devices(rules.clone())
+            .build()?,
+        disable_oom_killer: false,
+        oom_score_adj: None,
+        freezer_state: None,
+    })?;
+
+    config.device_rules = Some(rules);
+    config.save(&container.root)?;
+    Ok(())
+}
+
+/// Port paths of the USB accelerators leased to the container
+fn usb_ports(root_path: &Path, container_id: &str) -> Result<Vec<String>> {
+    let leases = LeaseLedger::new(root_path).leases()?;
+    Ok(leases
+        .into_iter()
+        .filter(|lease| lease.container_id == container_id)
+        .filter_map(|lease| {
+            lease
+                .device
+                .strip_prefix(USB_LEASE_PREFIX)
+                .map(str::to_owned)
+        })
+        .collect())
+}
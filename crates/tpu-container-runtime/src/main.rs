@@ -69,6 +69,12 @@ enum SubCommand {
     // Youki specific extensions
     Info(info::Info),
     Completion(commands::completion::Completion),
+    Cdi(commands::cdi::Cdi),
+    Model(commands::model::Model),
+    Health(commands::health::Health),
+    Tpu(commands::tpu::Tpu),
+    Metrics(commands::metrics::Metrics),
+    UsbFollow(commands::usb_follow::UsbFollow),
 }
 
 /// This is the entry point in the container runtime. The binary is run by a high-level container runtime,
@@ -146,6 +152,14 @@ fn main() -> Result<()> {
         SubCommand::Completion(completion) => {
             commands::completion::completion(completion, &mut app)
         }
+        SubCommand::Cdi(cdi) => commands::cdi::cdi(cdi),
+        SubCommand::Model(model) => commands::model::model(model),
+        SubCommand::Health(health) => commands::health::health(health, root_path),
+        SubCommand::Tpu(tpu) => commands::tpu::tpu(tpu, root_path),
+        SubCommand::Metrics(metrics) => commands::metrics::metrics(metrics, root_path),
+        SubCommand::UsbFollow(usb_follow) => {
+            commands::usb_follow::usb_follow(usb_follow, root_path)
+        }
     };
 
     if let Err(ref e) = cmd_result {
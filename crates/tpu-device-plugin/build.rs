@@ -0,0 +1,81 @@
+use tonic_build::manual::{Builder, Method, Service};
+
+const CODEC: &str = "tonic::codec::ProstCodec";
+
+fn method(
+    name: &str,
+    route: &str,
+    input: &str,
+    output: &str,
+) -> tonic_build::manual::MethodBuilder {
+    Method::builder()
+        .name(name)
+        .route_name(route)
+        .input_type(format!("super::{input}"))
+        .output_type(format!("super::{output}"))
+        .codec_path(CODEC)
+}
+
+// The kubelet device plugin API, `k8s.io/kubelet/pkg/apis/deviceplugin/v1beta1/api.proto`.
+// The messages are written by hand in `src/api.rs`, only the services are generated so the
+// build does not need protoc.
+fn main() {
+    let registration = Service::builder()
+        .name("Registration")
+        .package("v1beta1")
+        .method(method("register", "Register", "RegisterRequest", "Empty").build())
+        .build();
+
+    let device_plugin = Service::builder()
+        .name("DevicePlugin")
+        .package("v1beta1")
+        .method(
+            method(
+                "get_device_plugin_options",
+                "GetDevicePluginOptions",
+                "Empty",
+                "DevicePluginOptions",
+            )
+            .build(),
+        )
+        .method(
+            method(
+                "list_and_watch",
+                "ListAndWatch",
+                "Empty",
+                "ListAndWatchResponse",
+            )
+            .server_streaming()
+            .build(),
+        )
+        .method(
+            method(
+                "get_preferred_allocation",
+                "GetPreferredAllocation",
+                "PreferredAllocationRequest",
+                "PreferredAllocationResponse",
+            )
+            .build(),
+        )
+        .method(
+            method(
+                "allocate",
+                "Allocate",
+                "AllocateRequest",
+                "AllocateResponse",
+            )
+            .build(),
+        )
+        .method(
+            method(
+                "pre_start_container",
+                "PreStartContainer",
+                "PreStartContainerRequest",
+                "PreStartContainerResponse",
+            )
+            .build(),
+        )
+        .build();
+
+    Builder::new().compile(&[registration, device_plugin]);
+}
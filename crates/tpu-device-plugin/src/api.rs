@@ -0,0 +1,171 @@
+//! Messages and services of the kubelet device plugin API, version `v1beta1`.
+//!
+//! The messages mirror `k8s.io/kubelet/pkg/apis/deviceplugin/v1beta1/api.proto` field for
+//! field, only the tags matter on the wire. The client and server of both services are
+//! generated by `build.rs`.
+use std::collections::HashMap;
+
+/// Version of the API the plugin registers with
+pub const VERSION: &str = "v1beta1";
+/// Directory of the kubelet and device plugin sockets
+pub const DEVICE_PLUGIN_PATH: &str = "/var/lib/kubelet/device-plugins";
+/// Name of the kubelet registration socket in [`DEVICE_PLUGIN_PATH`]
+pub const KUBELET_SOCKET: &str = "kubelet.sock";
+pub const HEALTHY: &str = "Healthy";
+pub const UNHEALTHY: &str = "Unhealthy";
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DevicePluginOptions {
+    /// Whether `PreStartContainer` must be called before each container start
+    #[prost(bool, tag = "1")]
+    pub pre_start_required: bool,
+    /// Whether `GetPreferredAllocation` is implemented
+    #[prost(bool, tag = "2")]
+    pub get_preferred_allocation_available: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RegisterRequest {
+    #[prost(string, tag = "1")]
+    pub version: String,
+    /// Name of the plugin socket, relative to [`DEVICE_PLUGIN_PATH`]
+    #[prost(string, tag = "2")]
+    pub endpoint: String,
+    /// Extended resource name, `vendor-domain/resource`
+    #[prost(string, tag = "3")]
+    pub resource_name: String,
+    #[prost(message, optional, tag = "4")]
+    pub options: Option<DevicePluginOptions>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Empty {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListAndWatchResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub devices: Vec<Device>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TopologyInfo {
+    #[prost(message, repeated, tag = "1")]
+    pub nodes: Vec<NumaNode>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NumaNode {
+    #[prost(int64, tag = "1")]
+    pub id: i64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Device {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    /// [`HEALTHY`] or [`UNHEALTHY`]
+    #[prost(string, tag = "2")]
+    pub health: String,
+    #[prost(message, optional, tag = "3")]
+    pub topology: Option<TopologyInfo>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PreStartContainerRequest {
+    #[prost(string, repeated, tag = "1")]
+    pub devices_ids: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PreStartContainerResponse {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PreferredAllocationRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub container_requests: Vec<ContainerPreferredAllocationRequest>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ContainerPreferredAllocationRequest {
+    #[prost(string, repeated, tag = "1")]
+    pub available_device_ids: Vec<String>,
+    #[prost(string, repeated, tag = "2")]
+    pub must_include_device_ids: Vec<String>,
+    #[prost(int32, tag = "3")]
+    pub allocation_size: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PreferredAllocationResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub container_responses: Vec<ContainerPreferredAllocationResponse>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ContainerPreferredAllocationResponse {
+    #[prost(string, repeated, tag = "1")]
+    pub device_ids: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AllocateRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub container_requests: Vec<ContainerAllocateRequest>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ContainerAllocateRequest {
+    #[prost(string, repeated, tag = "1")]
+    pub devices_ids: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AllocateResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub container_responses: Vec<ContainerAllocateResponse>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ContainerAllocateResponse {
+    #[prost(map = "string, string", tag = "1")]
+    pub envs: HashMap<String, String>,
+    #[prost(message, repeated, tag = "2")]
+    pub mounts: Vec<Mount>,
+    #[prost(message, repeated, tag = "3")]
+    pub devices: Vec<DeviceSpec>,
+    #[prost(map = "string, string", tag = "4")]
+    pub annotations: HashMap<String, String>,
+    #[prost(message, repeated, tag = "5")]
+    pub cdi_devices: Vec<CdiDevice>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Mount {
+    #[prost(string, tag = "1")]
+    pub container_path: String,
+    #[prost(string, tag = "2")]
+    pub host_path: String,
+    #[prost(bool, tag = "3")]
+    pub read_only: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeviceSpec {
+    #[prost(string, tag = "1")]
+    pub container_path: String,
+    #[prost(string, tag = "2")]
+    pub host_path: String,
+    /// cgroup permissions of the device, any of `r`, `w` and `m`
+    #[prost(string, tag = "3")]
+    pub permissions: String,
+}
+
+/// Fully qualified CDI device name, `vendor/class=name`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CdiDevice {
+    #[prost(string, tag = "1")]
+    pub name: String,
+}
+
+include!(concat!(env!("OUT_DIR"), "/v1beta1.Registration.rs"));
+include!(concat!(env!("OUT_DIR"), "/v1beta1.DevicePlugin.rs"));
@@ -0,0 +1,279 @@
+//! The Edge TPUs advertised to the kubelet.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use edgetpu::cdi::generate::{CLASS, VENDOR};
+use edgetpu::cdi::QualifiedName;
+use edgetpu::discovery::{DeviceType, Discovery, DiscoveryError, EdgeTpuDevice};
+use edgetpu::health::HealthLedger;
+use tokio::sync::watch;
+
+use crate::api;
+
+/// Value of the apex `status` attribute of a working accelerator
+const APEX_ALIVE: &str = "ALIVE";
+
+/// An Edge TPU as the kubelet sees it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PluginDevice {
+    pub tpu: EdgeTpuDevice,
+    /// Name of the device in the `coral.ai/edgetpu` CDI spec: its serial number, or its
+    /// discovery index when it has none
+    pub cdi_name: String,
+    pub healthy: bool,
+    pub numa_node: Option<i64>,
+}
+
+impl PluginDevice {
+    /// Device ID reported to the kubelet, the name of the TPU: `apex_0`, `usb-2-1`.
+    pub fn id(&self) -> String {
+        self.tpu.name()
+    }
+
+    pub fn to_api(&self) -> api::Device {
+        api::Device {
+            id: self.id(),
+            health: if self.healthy {
+                api::HEALTHY
+            } else {
+                api::UNHEALTHY
+            }
+            .to_owned(),
+            topology: self.numa_node.map(|id| api::TopologyInfo {
+                nodes: vec![api::NumaNode { id }],
+            }),
+        }
+    }
+}
+
+/// Lists the Edge TPUs of the host with their health and NUMA node.
+#[derive(Debug, Clone, Default)]
+pub struct Inventory {
+    discovery: Discovery,
+    /// Health ledger of the runtime, quarantined devices are unhealthy
+    health: Option<HealthLedger>,
+}
+
+impl Inventory {
+    pub fn new(discovery: Discovery) -> Self {
+        Self {
+            discovery,
+            health: None,
+        }
+    }
+
+    /// Reports the devices quarantined in the health ledger of the runtime as unhealthy
+    pub fn with_health(mut self, ledger: HealthLedger) -> Self {
+        self.health = Some(ledger);
+        self
+    }
+
+    pub fn discovery(&self) -> &Discovery {
+        &self.discovery
+    }
+
+    pub fn scan(&self) -> Result<Vec<PluginDevice>, DiscoveryError> {
+        let tpus = self.discovery.discover()?;
+        let quarantined = match &self.health {
+            Some(ledger) => ledger.quarantined().unwrap_or_else(|err| {
+                log::warn!("failed to read the health ledger: {err}");
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        Ok(tpus
+            .into_iter()
+            .enumerate()
+            .map(|(index, tpu)| {
+                let sysfs_dir = self.sysfs_dir(&tpu);
+                let cdi_name = tpu
+                    .serial
+                    .clone()
+                    .filter(|serial| QualifiedName::new(VENDOR, CLASS, serial).is_ok())
+                    .unwrap_or_else(|| index.to_string());
+                PluginDevice {
+                    healthy: self.is_healthy(&tpu) && !quarantined.contains(&tpu.name()),
+                    numa_node: numa_node(self.discovery.sysfs_root(), &sysfs_dir),
+                    cdi_name,
+                    tpu,
+                }
+            })
+            .collect())
+    }
+
+    /// Scans the host every `interval` and publishes the devices whenever they change.
+    ///
+    /// The first scan happens before returning. A failed scan keeps the previous devices,
+    /// the task stops once every receiver is dropped.
+    pub fn watch(self, interval: Duration) -> watch::Receiver<Vec<PluginDevice>> {
+        let initial = self.scan().unwrap_or_else(|err| {
+            log::warn!("failed to discover the Edge TPUs: {err}");
+            Vec::new()
+        });
+        let (sender, receiver) = watch::channel(initial);
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {}
+                    _ = sender.closed() => return,
+                }
+                match self.scan() {
+                    Ok(devices) => {
+                        sender.send_if_modified(|current| {
+                            if *current == devices {
+                                return false;
+                            }
+                            log::info!(
+                                "Edge TPUs changed: {}",
+                                devices
+                                    .iter()
+                                    .map(|d| d.id())
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            );
+                            *current = devices;
+                            true
+                        });
+                    }
+                    Err(err) => log::warn!("failed to discover the Edge TPUs: {err}"),
+                }
+            }
+        });
+        receiver
+    }
+
+    /// Directory of the device in sysfs
+    fn sysfs_dir(&self, tpu: &EdgeTpuDevice) -> PathBuf {
+        match tpu.device_type() {
+            DeviceType::ApexPCI => self
+                .discovery
+                .sysfs_root()
+                .join("class/apex")
+                .join(tpu.name())
+                .join("device"),
+            DeviceType::ApexUSB => PathBuf::from(&tpu.record.path),
+        }
+    }
+
+    /// An apex whose driver reports anything but `ALIVE` is unhealthy. USB accelerators
+    /// have no such state, they are healthy as long as they are plugged in.
+    fn is_healthy(&self, tpu: &EdgeTpuDevice) -> bool {
+        if tpu.device_type() != DeviceType::ApexPCI {
+            return true;
+        }
+        let status = self
+            .discovery
+            .sysfs_root()
+            .join("class/apex")
+            .join(tpu.name())
+            .join("status");
+        match fs::read_to_string(status) {
+            Ok(status) => status.trim() == APEX_ALIVE,
+            // older drivers do not have the attribute
+            Err(err) => err.kind() == std::io::ErrorKind::NotFound,
+        }
+    }
+}
+
+/// Finds the NUMA node of a device through the `numa_node` attribute of the closest PCI
+/// device above it. `-1` means the platform does not know.
+fn numa_node(sysfs_root: &Path, device_dir: &Path) -> Option<i64> {
+    let sysfs_root = fs::canonicalize(sysfs_root).ok()?;
+    let device_dir = fs::canonicalize(device_dir).ok()?;
+    device_dir
+        .ancestors()
+        .take_while(|dir| dir.starts_with(&sysfs_root) && *dir != sysfs_root)
+        .find_map(|dir| fs::read_to_string(dir.join("numa_node")).ok())
+        .and_then(|node| node.trim().parse().ok())
+        .filter(|node| *node >= 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use edgetpu::discovery::fixture::*;
+    use edgetpu::health::HealthMonitor;
+    use libedgetpu::backend::MockBackend;
+
+    use super::*;
+
+    #[test]
+    fn test_scan() {
+        let tmp = tempfile::tempdir().unwrap();
+        add_apex(tmp.path(), 0, "0000:01:00.0");
+        set_numa_node(tmp.path(), 0, 1);
+        add_apex(tmp.path(), 1, "0000:02:00.0");
+        set_numa_node(tmp.path(), 1, -1);
+        add_usb(tmp.path(), "2-1", ("18d1", "9302"), 2, 3, "1a2b3c");
+        write(tmp.path(), "class/apex/apex_1/status", "THERMAL_SHUTDOWN\n");
+
+        let devices = Inventory::new(Discovery::new().with_sysfs_root(tmp.path()))
+            .scan()
+            .unwrap();
+        let devices: Vec<_> = devices.iter().map(PluginDevice::to_api).collect();
+        assert_eq!(
+            devices,
+            vec![
+                api::Device {
+                    id: "apex_0".to_owned(),
+                    health: api::HEALTHY.to_owned(),
+                    topology: Some(api::TopologyInfo {
+                        nodes: vec![api::NumaNode { id: 1 }]
+                    }),
+                },
+                api::Device {
+                    id: "apex_1".to_owned(),
+                    health: api::UNHEALTHY.to_owned(),
+                    topology: None,
+                },
+                api::Device {
+                    id: "usb-2-1".to_owned(),
+                    health: api::HEALTHY.to_owned(),
+                    topology: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_quarantined_devices() {
+        let tmp = tempfile::tempdir().unwrap();
+        let sysfs = tmp.path().join("sys");
+        add_apex(&sysfs, 0, "0000:01:00.0");
+        add_apex(&sysfs, 1, "0000:02:00.0");
+        write(&sysfs, "class/apex/apex_1/status", "DEAD\n");
+        let ledger = HealthLedger::new(tmp.path());
+        HealthMonitor::new(ledger.clone())
+            .with_discovery(Discovery::new().with_sysfs_root(&sysfs))
+            .with_backend(MockBackend::new().with_pci_devices(2))
+            .with_quarantine_after(1)
+            .probe_all()
+            .unwrap();
+        // the driver brought the device back, but the runtime still refuses it
+        write(&sysfs, "class/apex/apex_1/status", "ALIVE\n");
+
+        let devices = Inventory::new(Discovery::new().with_sysfs_root(&sysfs))
+            .with_health(ledger)
+            .scan()
+            .unwrap();
+        let healthy: Vec<_> = devices.iter().map(|d| (d.id(), d.healthy)).collect();
+        assert_eq!(
+            healthy,
+            vec![("apex_0".to_owned(), true), ("apex_1".to_owned(), false)]
+        );
+    }
+
+    #[test]
+    fn test_cdi_names() {
+        let tmp = tempfile::tempdir().unwrap();
+        add_apex(tmp.path(), 0, "0000:01:00.0");
+        add_usb(tmp.path(), "2-1", ("18d1", "9302"), 2, 3, "1a2b3c");
+        add_usb(tmp.path(), "2-2", ("18d1", "9302"), 2, 4, "not/valid");
+
+        let devices = Inventory::new(Discovery::new().with_sysfs_root(tmp.path()))
+            .scan()
+            .unwrap();
+        let names: Vec<_> = devices.iter().map(|d| d.cdi_name.as_str()).collect();
+        assert_eq!(names, vec!["0", "1a2b3c", "2"]);
+    }
+}
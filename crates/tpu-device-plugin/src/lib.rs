@@ -0,0 +1,7 @@
+//! Kubelet device plugin advertising the Edge TPUs of a node as `coral.ai/edgetpu`.
+// tonic::Status is the error of every gRPC call
+#![allow(clippy::result_large_err)]
+pub mod api;
+pub mod devices;
+pub mod plugin;
+pub mod server;
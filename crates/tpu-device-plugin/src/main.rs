@@ -0,0 +1,81 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use edgetpu::dep::util::{init_logger, install_path_of};
+use edgetpu::discovery::{Discovery, DEFAULT_DEV_ROOT, DEFAULT_SYSFS_ROOT};
+use edgetpu::health::HealthLedger;
+use tokio::signal::unix::{signal, SignalKind};
+use tpu_device_plugin::api::DEVICE_PLUGIN_PATH;
+use tpu_device_plugin::devices::Inventory;
+use tpu_device_plugin::plugin::{AllocationMode, EdgeTpuPlugin};
+use tpu_device_plugin::server::{PluginServer, DEFAULT_ENDPOINT};
+
+/// Advertises the Edge TPUs of the node to the kubelet as `coral.ai/edgetpu` resources
+#[derive(Parser, Debug)]
+#[clap(version, author = env!("CARGO_PKG_AUTHORS"))]
+struct Opts {
+    /// Directory of the kubelet and device plugin sockets
+    #[clap(long, default_value = DEVICE_PLUGIN_PATH)]
+    plugin_dir: PathBuf,
+    /// Name of the plugin socket in the plugin directory
+    #[clap(long, default_value = DEFAULT_ENDPOINT)]
+    endpoint: String,
+    /// How containers get their Edge TPUs
+    #[clap(long, value_enum, default_value_t = AllocationMode::Cdi)]
+    allocation: AllocationMode,
+    /// Seconds between two scans of the Edge TPUs
+    #[clap(long, default_value_t = 5)]
+    poll_interval: u64,
+    #[clap(long, default_value = DEFAULT_SYSFS_ROOT)]
+    sysfs_root: PathBuf,
+    #[clap(long, default_value = DEFAULT_DEV_ROOT)]
+    dev_root: PathBuf,
+    /// Directory the host file system is mounted on, where the host libedgetpu is looked up
+    #[clap(long, default_value = "/")]
+    host_root: PathBuf,
+    /// Host directory libedgetpu is installed in, mounted with `--allocation device-specs`
+    #[clap(long)]
+    library_dir: Option<PathBuf>,
+    /// Root of tpu-container-runtime, the devices its health monitor quarantined are
+    /// reported unhealthy
+    #[clap(long)]
+    runtime_root: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    init_logger();
+    let opts = Opts::parse();
+
+    let interval = Duration::from_secs(opts.poll_interval.max(1));
+    let discovery = Discovery::new()
+        .with_sysfs_root(&opts.sysfs_root)
+        .with_dev_root(&opts.dev_root);
+    let mut inventory = Inventory::new(discovery);
+    if let Some(root) = &opts.runtime_root {
+        inventory = inventory.with_health(HealthLedger::new(root));
+    }
+    let devices = inventory.watch(interval);
+    let plugin = EdgeTpuPlugin::new(devices)
+        .with_mode(opts.allocation)
+        .with_dev_root(&opts.dev_root)
+        .with_host_root(&opts.host_root)
+        .with_library_dir(opts.library_dir.unwrap_or_else(install_path_of));
+
+    let mut terminate = signal(SignalKind::terminate()).context("failed to handle SIGTERM")?;
+    let shutdown = async {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    };
+    PluginServer::new()
+        .with_plugin_dir(&opts.plugin_dir)
+        .with_endpoint(opts.endpoint)
+        .with_interval(interval)
+        .run(plugin, shutdown)
+        .await
+        .context("device plugin failed")
+}
@@ -0,0 +1,461 @@
+//! The `DevicePlugin` service of the Edge TPUs.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+use edgetpu::cdi::generate::{CLASS, VENDOR};
+use edgetpu::dep::util::install_path_of;
+use edgetpu::discovery::DEFAULT_DEV_ROOT;
+use edgetpu::injection::edits::container_dev_path;
+use edgetpu::injection::{LIBEDGETPU_FILES, VISIBLE_DEVICES_ENV};
+use tokio::sync::watch;
+use tokio_stream::wrappers::WatchStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+use crate::api::{self, device_plugin_server::DevicePlugin};
+use crate::devices::PluginDevice;
+
+/// Extended resource the Edge TPUs are advertised as
+pub const RESOURCE_NAME: &str = "coral.ai/edgetpu";
+
+/// How `Allocate` hands the Edge TPUs to a container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum AllocationMode {
+    /// CDI device names, for container runtimes that resolve them from the
+    /// `coral.ai/edgetpu` spec
+    #[default]
+    Cdi,
+    /// Device nodes and mounts of the host libedgetpu, understood by every runtime
+    DeviceSpecs,
+    /// The `EDGETPU_VISIBLE_DEVICES` variable alone, for tpu-container-runtime to inject
+    /// the Edge TPUs it names
+    Env,
+}
+
+/// Serves the Edge TPUs published by [`crate::devices::Inventory::watch`].
+#[derive(Debug, Clone)]
+pub struct EdgeTpuPlugin {
+    devices: watch::Receiver<Vec<PluginDevice>>,
+    mode: AllocationMode,
+    dev_root: PathBuf,
+    host_root: PathBuf,
+    library_dir: PathBuf,
+}
+
+impl EdgeTpuPlugin {
+    pub fn new(devices: watch::Receiver<Vec<PluginDevice>>) -> Self {
+        Self {
+            devices,
+            mode: AllocationMode::default(),
+            dev_root: PathBuf::from(DEFAULT_DEV_ROOT),
+            host_root: PathBuf::from("/"),
+            library_dir: install_path_of(),
+        }
+    }
+
+    pub fn with_mode(mut self, mode: AllocationMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets the directory the device nodes are created in, it is `/dev` in the container
+    pub fn with_dev_root<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.dev_root = path.into();
+        self
+    }
+
+    /// Sets the directory the host file system is mounted on, it is `/` on the host
+    pub fn with_host_root<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.host_root = path.into();
+        self
+    }
+
+    /// Sets the host directory libedgetpu is installed in
+    pub fn with_library_dir<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.library_dir = path.into();
+        self
+    }
+
+    fn allocate_container(
+        &self,
+        devices: &[PluginDevice],
+        ids: &[String],
+    ) -> Result<api::ContainerAllocateResponse, Status> {
+        let mut allocated = Vec::new();
+        for id in ids {
+            let device = devices
+                .iter()
+                .find(|device| device.id() == *id)
+                .ok_or_else(|| Status::invalid_argument(format!("unknown Edge TPU {id:?}")))?;
+            allocated.push(device);
+        }
+
+        let mut response = api::ContainerAllocateResponse::default();
+        match self.mode {
+            AllocationMode::Cdi => {
+                response.cdi_devices = allocated
+                    .iter()
+                    .map(|device| api::CdiDevice {
+                        name: format!("{VENDOR}/{CLASS}={}", device.cdi_name),
+                    })
+                    .collect();
+            }
+            AllocationMode::DeviceSpecs => {
+                response.devices = allocated
+                    .iter()
+                    .map(|device| {
+                        // the kubelet resolves the host path on the host, not in the
+                        // plugin's view of its /dev
+                        let path = path_string(&container_dev_path(&device.tpu, &self.dev_root));
+                        api::DeviceSpec {
+                            container_path: path.clone(),
+                            host_path: path,
+                            permissions: "rw".to_owned(),
+                        }
+                    })
+                    .collect();
+                response.mounts = self.library_mounts();
+            }
+            AllocationMode::Env => {
+                // the runtime injects and leases the devices itself, they must not be
+                // handed over another way as well
+                response.envs = HashMap::from([(VISIBLE_DEVICES_ENV.to_owned(), ids.join(","))]);
+            }
+        }
+        Ok(response)
+    }
+
+    /// Read-only mounts of the host libedgetpu at the same paths in the container. The
+    /// files are looked up in the host root, the kubelet resolves their paths on the host.
+    fn library_mounts(&self) -> Vec<api::Mount> {
+        LIBEDGETPU_FILES
+            .iter()
+            .map(|file| self.library_dir.join(file))
+            .filter(|path| {
+                let relative = path.strip_prefix("/").unwrap_or(path);
+                self.host_root.join(relative).exists()
+            })
+            .map(|path| api::Mount {
+                container_path: path_string(&path),
+                host_path: path_string(&path),
+                read_only: true,
+            })
+            .collect()
+    }
+}
+
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+/// Picks `size` devices: the required ones, then the available devices of the NUMA nodes
+/// already used, then those of the nodes with the most available devices.
+fn preferred_allocation(
+    devices: &[PluginDevice],
+    request: &api::ContainerPreferredAllocationRequest,
+) -> Vec<String> {
+    let size = usize::try_from(request.allocation_size).unwrap_or_default();
+    let numa_node = |id: &str| {
+        devices
+            .iter()
+            .find(|device| device.id() == id)
+            .and_then(|device| device.numa_node)
+    };
+
+    let mut chosen = request.must_include_device_ids.clone();
+    let used_nodes: Vec<_> = chosen.iter().map(|id| numa_node(id)).collect();
+    let candidates: Vec<&String> = request
+        .available_device_ids
+        .iter()
+        .filter(|id| !chosen.contains(id))
+        .collect();
+    let mut per_node: HashMap<Option<i64>, usize> = HashMap::new();
+    for id in &candidates {
+        *per_node.entry(numa_node(id)).or_default() += 1;
+    }
+
+    let mut ranked: Vec<(usize, &String)> = candidates.into_iter().enumerate().collect();
+    ranked.sort_by_key(|(order, id)| {
+        let node = numa_node(id);
+        (
+            !used_nodes.contains(&node),
+            std::cmp::Reverse(per_node[&node]),
+            node,
+            *order,
+        )
+    });
+    let missing = size.saturating_sub(chosen.len());
+    chosen.extend(ranked.into_iter().take(missing).map(|(_, id)| id.clone()));
+    chosen
+}
+
+type ListAndWatchStream =
+    Pin<Box<dyn Stream<Item = Result<api::ListAndWatchResponse, Status>> + Send + 'static>>;
+
+#[tonic::async_trait]
+impl DevicePlugin for EdgeTpuPlugin {
+    type ListAndWatchStream = ListAndWatchStream;
+
+    async fn get_device_plugin_options(
+        &self,
+        _request: Request<api::Empty>,
+    ) -> Result<Response<api::DevicePluginOptions>, Status> {
+        Ok(Response::new(api::DevicePluginOptions {
+            pre_start_required: false,
+            get_preferred_allocation_available: true,
+        }))
+    }
+
+    async fn list_and_watch(
+        &self,
+        _request: Request<api::Empty>,
+    ) -> Result<Response<Self::ListAndWatchStream>, Status> {
+        let stream = WatchStream::new(self.devices.clone()).map(|devices| {
+            Ok(api::ListAndWatchResponse {
+                devices: devices.iter().map(PluginDevice::to_api).collect(),
+            })
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn get_preferred_allocation(
+        &self,
+        request: Request<api::PreferredAllocationRequest>,
+    ) -> Result<Response<api::PreferredAllocationResponse>, Status> {
+        let devices = self.devices.borrow().clone();
+        let container_responses = request
+            .into_inner()
+            .container_requests
+            .iter()
+            .map(|request| api::ContainerPreferredAllocationResponse {
+                device_ids: preferred_allocation(&devices, request),
+            })
+            .collect();
+        Ok(Response::new(api::PreferredAllocationResponse {
+            container_responses,
+        }))
+    }
+
+    async fn allocate(
+        &self,
+        request: Request<api::AllocateRequest>,
+    ) -> Result<Response<api::AllocateResponse>, Status> {
+        let devices = self.devices.borrow().clone();
+        let container_responses = request
+            .into_inner()
+            .container_requests
+            .iter()
+            .map(|request| self.allocate_container(&devices, &request.devices_ids))
+            .collect::<Result<_, _>>()?;
+        Ok(Response::new(api::AllocateResponse {
+            container_responses,
+        }))
+    }
+
+    async fn pre_start_container(
+        &self,
+        _request: Request<api::PreStartContainerRequest>,
+    ) -> Result<Response<api::PreStartContainerResponse>, Status> {
+        Ok(Response::new(api::PreStartContainerResponse {}))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use edgetpu::discovery::fixture::*;
+    use edgetpu::discovery::Discovery;
+
+    use super::*;
+    use crate::devices::Inventory;
+
+    fn scan(sysfs_root: &Path) -> watch::Receiver<Vec<PluginDevice>> {
+        let devices = Inventory::new(Discovery::new().with_sysfs_root(sysfs_root))
+            .scan()
+            .unwrap();
+        watch::channel(devices).1
+    }
+
+    fn allocate_request(ids: &[&str]) -> Request<api::AllocateRequest> {
+        Request::new(api::AllocateRequest {
+            container_requests: vec![api::ContainerAllocateRequest {
+                devices_ids: ids.iter().map(|id| id.to_string()).collect(),
+            }],
+        })
+    }
+
+    #[tokio::test]
+    async fn test_allocate_cdi() {
+        let tmp = tempfile::tempdir().unwrap();
+        add_apex(tmp.path(), 0, "0000:01:00.0");
+        add_usb(tmp.path(), "2-1", ("18d1", "9302"), 2, 3, "1a2b3c");
+        let plugin = EdgeTpuPlugin::new(scan(tmp.path()));
+
+        let response = plugin
+            .allocate(allocate_request(&["apex_0", "usb-2-1"]))
+            .await
+            .unwrap()
+            .into_inner();
+        let container = &response.container_responses[0];
+        let names: Vec<_> = container
+            .cdi_devices
+            .iter()
+            .map(|d| d.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["coral.ai/edgetpu=0", "coral.ai/edgetpu=1a2b3c"]);
+        assert!(container.envs.is_empty());
+        assert!(container.devices.is_empty());
+
+        let status = plugin
+            .allocate(allocate_request(&["apex_9"]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_allocate_env() {
+        let tmp = tempfile::tempdir().unwrap();
+        add_apex(tmp.path(), 0, "0000:01:00.0");
+        add_usb(tmp.path(), "2-1", ("18d1", "9302"), 2, 3, "1a2b3c");
+        let plugin = EdgeTpuPlugin::new(scan(tmp.path())).with_mode(AllocationMode::Env);
+
+        let response = plugin
+            .allocate(allocate_request(&["apex_0", "usb-2-1"]))
+            .await
+            .unwrap()
+            .into_inner();
+        let container = &response.container_responses[0];
+        assert_eq!(
+            container.envs,
+            HashMap::from([(VISIBLE_DEVICES_ENV.to_owned(), "apex_0,usb-2-1".to_owned())])
+        );
+        assert!(container.cdi_devices.is_empty());
+        assert!(container.devices.is_empty());
+        assert!(container.mounts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_allocate_device_specs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let sysfs = tmp.path().join("sys");
+        let lib_dir = tmp.path().join("lib");
+        add_apex(&sysfs, 0, "0000:01:00.0");
+        add_usb(&sysfs, "2-1", ("18d1", "9302"), 2, 3, "1a2b3c");
+        fs::create_dir_all(&lib_dir).unwrap();
+        fs::write(lib_dir.join("libedgetpu.so.1"), "").unwrap();
+
+        let plugin = EdgeTpuPlugin::new(scan(&sysfs))
+            .with_mode(AllocationMode::DeviceSpecs)
+            .with_library_dir(&lib_dir);
+        let response = plugin
+            .allocate(allocate_request(&["usb-2-1", "apex_0"]))
+            .await
+            .unwrap()
+            .into_inner();
+        let container = &response.container_responses[0];
+        assert_eq!(
+            container.devices,
+            vec![
+                api::DeviceSpec {
+                    container_path: "/dev/bus/usb/002/003".to_owned(),
+                    host_path: "/dev/bus/usb/002/003".to_owned(),
+                    permissions: "rw".to_owned(),
+                },
+                api::DeviceSpec {
+                    container_path: "/dev/apex_0".to_owned(),
+                    host_path: "/dev/apex_0".to_owned(),
+                    permissions: "rw".to_owned(),
+                },
+            ]
+        );
+        let library = path_string(&lib_dir.join("libedgetpu.so.1"));
+        assert_eq!(
+            container.mounts,
+            vec![api::Mount {
+                container_path: library.clone(),
+                host_path: library,
+                read_only: true,
+            }]
+        );
+        assert!(container.cdi_devices.is_empty());
+        assert!(container.envs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_allocate_device_specs_with_host_root() {
+        let tmp = tempfile::tempdir().unwrap();
+        let sysfs = tmp.path().join("sys");
+        let host = tmp.path().join("host");
+        add_apex(&sysfs, 0, "0000:01:00.0");
+        fs::create_dir_all(host.join("usr/lib/edgetpu")).unwrap();
+        fs::write(host.join("usr/lib/edgetpu/libedgetpu.so.1"), "").unwrap();
+        let discovery = Discovery::new()
+            .with_sysfs_root(&sysfs)
+            .with_dev_root("/host/dev");
+        let devices = Inventory::new(discovery).scan().unwrap();
+
+        let plugin = EdgeTpuPlugin::new(watch::channel(devices).1)
+            .with_mode(AllocationMode::DeviceSpecs)
+            .with_dev_root("/host/dev")
+            .with_host_root(&host)
+            .with_library_dir("/usr/lib/edgetpu");
+        let response = plugin
+            .allocate(allocate_request(&["apex_0"]))
+            .await
+            .unwrap()
+            .into_inner();
+        let container = &response.container_responses[0];
+        assert_eq!(
+            container.devices,
+            vec![api::DeviceSpec {
+                container_path: "/dev/apex_0".to_owned(),
+                host_path: "/dev/apex_0".to_owned(),
+                permissions: "rw".to_owned(),
+            }]
+        );
+        assert_eq!(
+            container.mounts,
+            vec![api::Mount {
+                container_path: "/usr/lib/edgetpu/libedgetpu.so.1".to_owned(),
+                host_path: "/usr/lib/edgetpu/libedgetpu.so.1".to_owned(),
+                read_only: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_preferred_allocation() {
+        let tmp = tempfile::tempdir().unwrap();
+        add_apex(tmp.path(), 0, "0000:01:00.0");
+        set_numa_node(tmp.path(), 0, 0);
+        add_apex(tmp.path(), 1, "0000:41:00.0");
+        set_numa_node(tmp.path(), 1, 1);
+        add_apex(tmp.path(), 2, "0000:42:00.0");
+        set_numa_node(tmp.path(), 2, 1);
+        add_apex(tmp.path(), 3, "0000:02:00.0");
+        set_numa_node(tmp.path(), 3, 0);
+        let devices = scan(tmp.path()).borrow().clone();
+        let request = |available: &[&str], must_include: &[&str], size| {
+            let request = api::ContainerPreferredAllocationRequest {
+                available_device_ids: available.iter().map(|id| id.to_string()).collect(),
+                must_include_device_ids: must_include.iter().map(|id| id.to_string()).collect(),
+                allocation_size: size,
+            };
+            preferred_allocation(&devices, &request)
+        };
+
+        let all = ["apex_0", "apex_1", "apex_2", "apex_3"];
+        // the required device pulls in its neighbour on the same node
+        assert_eq!(request(&all, &["apex_1"], 2), vec!["apex_1", "apex_2"]);
+        assert_eq!(request(&all, &["apex_3"], 2), vec!["apex_3", "apex_0"]);
+        // the node with the most free devices comes first
+        assert_eq!(
+            request(&["apex_0", "apex_1", "apex_2"], &[], 2),
+            vec!["apex_1", "apex_2"]
+        );
+        assert_eq!(request(&all, &[], 0), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,383 @@
+//! Serving the plugin on its unix socket and registering it with the kubelet.
+//!
+//! The kubelet removes every socket of the device plugin directory when it restarts, the
+//! plugin then has to serve on a new socket and register again. [`PluginServer::run`] does so
+//! whenever its socket disappears or the kubelet socket is recreated.
+use std::fs;
+use std::future::Future;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use hyper_util::rt::TokioIo;
+use tokio::net::{UnixListener, UnixStream};
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::transport::{Channel, Endpoint, Server, Uri};
+
+use crate::api::{
+    self, device_plugin_server::DevicePluginServer, registration_client::RegistrationClient,
+};
+use crate::plugin::{EdgeTpuPlugin, RESOURCE_NAME};
+
+/// Name of the plugin socket in the device plugin directory
+pub const DEFAULT_ENDPOINT: &str = "coral-edgetpu.sock";
+
+#[derive(Debug, thiserror::Error)]
+pub enum PluginError {
+    #[error("failed to listen on {path:?}")]
+    Listen {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("failed to connect to {path:?}")]
+    Connect {
+        source: tonic::transport::Error,
+        path: PathBuf,
+    },
+    #[error("the kubelet refused the registration")]
+    Register(#[source] tonic::Status),
+    #[error("the device plugin server failed")]
+    Serve(#[source] tonic::transport::Error),
+}
+
+/// Opens a gRPC channel over the unix socket `path`.
+pub async fn connect(path: &Path) -> Result<Channel, PluginError> {
+    let socket = path.to_owned();
+    // the URI is required by the endpoint but never used, the connector dials the socket
+    Endpoint::from_static("http://[::]:50051")
+        .connect_with_connector(tower::service_fn(move |_: Uri| {
+            let socket = socket.clone();
+            async move { Ok::<_, std::io::Error>(TokioIo::new(UnixStream::connect(socket).await?)) }
+        }))
+        .await
+        .map_err(|source| PluginError::Connect {
+            source,
+            path: path.to_owned(),
+        })
+}
+
+/// Runs the device plugin in a kubelet device plugin directory.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use tpu_device_plugin::devices::Inventory;
+/// use tpu_device_plugin::plugin::EdgeTpuPlugin;
+/// use tpu_device_plugin::server::PluginServer;
+///
+/// # async fn run() -> Result<(), tpu_device_plugin::server::PluginError> {
+/// let devices = Inventory::default().watch(Duration::from_secs(5));
+/// PluginServer::new()
+///     .run(EdgeTpuPlugin::new(devices), tokio::signal::ctrl_c())
+///     .await
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct PluginServer {
+    plugin_dir: PathBuf,
+    endpoint: String,
+    resource_name: String,
+    interval: Duration,
+}
+
+impl Default for PluginServer {
+    fn default() -> Self {
+        Self {
+            plugin_dir: PathBuf::from(api::DEVICE_PLUGIN_PATH),
+            endpoint: DEFAULT_ENDPOINT.to_owned(),
+            resource_name: RESOURCE_NAME.to_owned(),
+            interval: Duration::from_secs(5),
+        }
+    }
+}
+
+impl PluginServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the directory of the kubelet and plugin sockets
+    pub fn with_plugin_dir<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.plugin_dir = path.into();
+        self
+    }
+
+    /// Sets the name of the plugin socket in the plugin directory
+    pub fn with_endpoint<S: Into<String>>(mut self, endpoint: S) -> Self {
+        self.endpoint = endpoint.into();
+        self
+    }
+
+    /// Sets how often the sockets are checked and a failed registration is retried
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn socket_path(&self) -> PathBuf {
+        self.plugin_dir.join(&self.endpoint)
+    }
+
+    pub fn kubelet_socket_path(&self) -> PathBuf {
+        self.plugin_dir.join(api::KUBELET_SOCKET)
+    }
+
+    /// Registers the plugin with the kubelet.
+    pub async fn register(&self) -> Result<(), PluginError> {
+        let mut client = RegistrationClient::new(connect(&self.kubelet_socket_path()).await?);
+        client
+            .register(api::RegisterRequest {
+                version: api::VERSION.to_owned(),
+                endpoint: self.endpoint.clone(),
+                resource_name: self.resource_name.clone(),
+                options: Some(api::DevicePluginOptions {
+                    pre_start_required: false,
+                    get_preferred_allocation_available: true,
+                }),
+            })
+            .await
+            .map_err(PluginError::Register)?;
+        Ok(())
+    }
+
+    /// Serves the plugin and keeps it registered until `shutdown` completes.
+    pub async fn run<F: Future>(
+        &self,
+        plugin: EdgeTpuPlugin,
+        shutdown: F,
+    ) -> Result<(), PluginError> {
+        tokio::pin!(shutdown);
+        loop {
+            let socket = self.socket_path();
+            let mut server = tokio::spawn(self.serve(plugin.clone())?);
+
+            let restart = tokio::select! {
+                _ = &mut shutdown => false,
+                restart = self.keep_registered(&socket) => restart,
+                result = &mut server => {
+                    match result {
+                        Ok(result) => result.map_err(PluginError::Serve)?,
+                        Err(err) => log::warn!("device plugin server task failed: {err}"),
+                    }
+                    true
+                }
+            };
+            // a graceful shutdown would wait for the endless ListAndWatch streams
+            server.abort();
+            if !restart {
+                let _ = fs::remove_file(&socket);
+                return Ok(());
+            }
+            log::info!("the kubelet restarted, registering again");
+        }
+    }
+
+    /// Listens on the plugin socket, replacing a stale one.
+    fn serve(
+        &self,
+        plugin: EdgeTpuPlugin,
+    ) -> Result<impl Future<Output = Result<(), tonic::transport::Error>>, PluginError> {
+        let socket = self.socket_path();
+        let listen_error = |source| PluginError::Listen {
+            source,
+            path: socket.clone(),
+        };
+        match fs::remove_file(&socket) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(listen_error(err))
+            }
+            _ => {}
+        }
+        let listener = UnixListener::bind(&socket).map_err(listen_error)?;
+        log::info!("serving {} on {socket:?}", self.resource_name);
+
+        Ok(Server::builder()
+            .add_service(DevicePluginServer::new(plugin))
+            .serve_with_incoming(UnixListenerStream::new(listener)))
+    }
+
+    /// Registers, retrying until it succeeds, then returns once the kubelet restarted.
+    async fn keep_registered(&self, socket: &Path) -> bool {
+        let kubelet = loop {
+            match self.register().await {
+                Ok(()) => {
+                    log::info!("registered {} with the kubelet", self.resource_name);
+                    break inode(&self.kubelet_socket_path());
+                }
+                Err(err) => log::warn!("failed to register with the kubelet: {err}"),
+            }
+            tokio::time::sleep(self.interval).await;
+        };
+
+        loop {
+            tokio::time::sleep(self.interval).await;
+            if !socket.exists() || inode(&self.kubelet_socket_path()) != kubelet {
+                return true;
+            }
+        }
+    }
+}
+
+fn inode(path: &Path) -> Option<(u64, u64)> {
+    fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+}
+
+#[cfg(test)]
+mod tests {
+    use edgetpu::discovery::fixture::*;
+    use edgetpu::discovery::Discovery;
+    use tokio::sync::{mpsc, oneshot};
+    use tokio_stream::StreamExt;
+    use tonic::{Request, Response, Status};
+
+    use super::*;
+    use crate::api::device_plugin_client::DevicePluginClient;
+    use crate::api::registration_server::{Registration, RegistrationServer};
+    use crate::devices::Inventory;
+
+    const INTERVAL: Duration = Duration::from_millis(20);
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Stands in for the kubelet registration service, forwarding each request
+    struct FakeKubelet {
+        requests: mpsc::UnboundedSender<api::RegisterRequest>,
+    }
+
+    #[tonic::async_trait]
+    impl Registration for FakeKubelet {
+        async fn register(
+            &self,
+            request: Request<api::RegisterRequest>,
+        ) -> Result<Response<api::Empty>, Status> {
+            let _ = self.requests.send(request.into_inner());
+            Ok(Response::new(api::Empty {}))
+        }
+    }
+
+    fn start_kubelet(plugin_dir: &Path) -> mpsc::UnboundedReceiver<api::RegisterRequest> {
+        let (requests, received) = mpsc::unbounded_channel();
+        let socket = plugin_dir.join(api::KUBELET_SOCKET);
+        let _ = fs::remove_file(&socket);
+        let listener = UnixListener::bind(socket).unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(RegistrationServer::new(FakeKubelet { requests }))
+                .serve_with_incoming(UnixListenerStream::new(listener)),
+        );
+        received
+    }
+
+    async fn next<T>(future: impl Future<Output = Option<T>>) -> T {
+        tokio::time::timeout(TIMEOUT, future)
+            .await
+            .expect("timed out")
+            .expect("closed")
+    }
+
+    fn ids(response: &api::ListAndWatchResponse) -> Vec<(&str, &str)> {
+        response
+            .devices
+            .iter()
+            .map(|device| (device.id.as_str(), device.health.as_str()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_register_and_watch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let sysfs = tmp.path().join("sys");
+        let plugin_dir = tmp.path().join("device-plugins");
+        fs::create_dir_all(&plugin_dir).unwrap();
+        add_apex(&sysfs, 0, "0000:01:00.0");
+
+        let mut registrations = start_kubelet(&plugin_dir);
+        let devices = Inventory::new(Discovery::new().with_sysfs_root(&sysfs)).watch(INTERVAL);
+        let server = PluginServer::new()
+            .with_plugin_dir(&plugin_dir)
+            .with_interval(INTERVAL);
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let running = tokio::spawn({
+            let server = server.clone();
+            async move { server.run(EdgeTpuPlugin::new(devices), stopped).await }
+        });
+
+        let registration = next(registrations.recv()).await;
+        assert_eq!(
+            registration,
+            api::RegisterRequest {
+                version: api::VERSION.to_owned(),
+                endpoint: DEFAULT_ENDPOINT.to_owned(),
+                resource_name: RESOURCE_NAME.to_owned(),
+                options: Some(api::DevicePluginOptions {
+                    pre_start_required: false,
+                    get_preferred_allocation_available: true,
+                }),
+            }
+        );
+
+        let mut client = DevicePluginClient::new(connect(&server.socket_path()).await.unwrap());
+        let mut updates = client
+            .list_and_watch(api::Empty {})
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            ids(&next(updates.next()).await.unwrap()),
+            vec![("apex_0", api::HEALTHY)]
+        );
+
+        add_usb(&sysfs, "2-1", ("18d1", "9302"), 2, 3, "1a2b3c");
+        assert_eq!(
+            ids(&next(updates.next()).await.unwrap()),
+            vec![("apex_0", api::HEALTHY), ("usb-2-1", api::HEALTHY)]
+        );
+
+        write(&sysfs, "class/apex/apex_0/status", "DEAD\n");
+        assert_eq!(
+            ids(&next(updates.next()).await.unwrap()),
+            vec![("apex_0", api::UNHEALTHY), ("usb-2-1", api::HEALTHY)]
+        );
+
+        fs::remove_dir_all(sysfs.join("bus/usb/devices/2-1")).unwrap();
+        assert_eq!(
+            ids(&next(updates.next()).await.unwrap()),
+            vec![("apex_0", api::UNHEALTHY)]
+        );
+
+        shutdown.send(()).unwrap();
+        next(async { running.await.ok() }).await.unwrap();
+        assert!(!server.socket_path().exists());
+    }
+
+    #[tokio::test]
+    async fn test_register_again_after_kubelet_restart() {
+        let tmp = tempfile::tempdir().unwrap();
+        let plugin_dir = tmp.path().join("device-plugins");
+        fs::create_dir_all(&plugin_dir).unwrap();
+
+        let mut registrations = start_kubelet(&plugin_dir);
+        let devices = Inventory::new(Discovery::new().with_sysfs_root(tmp.path().join("sys")))
+            .watch(INTERVAL);
+        let server = PluginServer::new()
+            .with_plugin_dir(&plugin_dir)
+            .with_endpoint("test.sock")
+            .with_interval(INTERVAL);
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let running = tokio::spawn({
+            let server = server.clone();
+            async move { server.run(EdgeTpuPlugin::new(devices), stopped).await }
+        });
+        assert_eq!(next(registrations.recv()).await.endpoint, "test.sock");
+
+        // a restarting kubelet wipes the plugin sockets
+        fs::remove_file(server.socket_path()).unwrap();
+        let mut registrations = start_kubelet(&plugin_dir);
+        assert_eq!(next(registrations.recv()).await.endpoint, "test.sock");
+        assert!(server.socket_path().exists());
+
+        shutdown.send(()).unwrap();
+        next(async { running.await.ok() }).await.unwrap();
+    }
+}